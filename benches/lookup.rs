@@ -0,0 +1,49 @@
+//! Times rendering templates that mostly look up variables, which is where
+//! the hash of object maps and of the globals matters: one looks up
+//! variables assigned in the template, the other fields of the globals.
+//! Compare `cargo bench` with `cargo bench --features fast-hash`.
+
+extern crate liquid;
+
+use liquid::{Context, LiquidOptions, Object, Renderable, Value};
+
+use std::time::Instant;
+
+const RENDERS: u32 = 2000;
+
+fn time<F: FnMut()>(name: &str, mut render: F) {
+    let start = Instant::now();
+    for _ in 0..RENDERS {
+        render();
+    }
+    let elapsed = start.elapsed();
+    println!("{}: {:?} per render", name, elapsed / RENDERS);
+}
+
+fn main() {
+    let text = "{% assign title = 'Hello' %}{% assign count = 3 %}\
+                {% for i in (0..50) %}{{ title }}{{ count }}{{ i }}{{ for_loop.index }}{% endfor %}";
+    let assigned = liquid::parse(text, LiquidOptions::default()).unwrap();
+    time("assigned variables", || {
+        let mut context = Context::new();
+        assigned.render(&mut context).unwrap();
+    });
+
+    let text = "{% for i in (0..50) %}{{ site.title }}{{ page.title }}{{ page.author.name }}\
+                {% endfor %}";
+    let fields = liquid::parse(text, LiquidOptions::default()).unwrap();
+    let mut author = Object::default();
+    author.insert("name".to_owned(), Value::str("Ann"));
+    let mut page = Object::default();
+    page.insert("title".to_owned(), Value::str("Home"));
+    page.insert("author".to_owned(), Value::Object(author));
+    let mut site = Object::default();
+    site.insert("title".to_owned(), Value::str("Example"));
+    let mut base = Context::new();
+    base.set_val("site", Value::Object(site)).set_val("page", Value::Object(page));
+    let globals = base.shared_globals();
+    time("fields of globals", || {
+        let mut context = Context::with_globals(globals.clone());
+        fields.render(&mut context).unwrap();
+    });
+}
@@ -0,0 +1,137 @@
+//! `#[derive(LiquidObject)]` turns a struct with named fields into a
+//! `liquid::Value::Object`, so that it can be handed to a template without
+//! going through serde.
+//!
+//! Every field has to implement `Into<liquid::Value>`, which includes the
+//! numbers, strings and booleans, vectors and maps of those, and other
+//! structs that derive `LiquidObject`. Fields can be given a different name
+//! in templates with `#[liquid(rename = "...")]`, and left out altogether
+//! with `#[liquid(skip)]`.
+//!
+//! ```
+//! #[macro_use]
+//! extern crate liquid_derive;
+//! extern crate liquid;
+//!
+//! #[derive(LiquidObject)]
+//! struct Post {
+//!     title: String,
+//!     #[liquid(rename = "read-time")]
+//!     read_time: u32,
+//!     #[liquid(skip)]
+//!     # #[allow(dead_code)]
+//!     draft: bool,
+//! }
+//!
+//! # fn main() {
+//! let post = Post { title: "Hello".to_owned(), read_time: 3, draft: false };
+//! let context = liquid::Context::with_values(post.into());
+//! assert!(context.get_val("draft").is_none());
+//! # }
+//! ```
+//!
+//! This generates `From<Post>` for both `liquid::Value` and
+//! `liquid::Object`, the latter being what `Context::with_values` expects.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+#[macro_use]
+extern crate syn;
+
+use proc_macro::TokenStream;
+use syn::{Data, DeriveInput, Error, Fields, Field, LitStr, Result};
+
+#[proc_macro_derive(LiquidObject, attributes(liquid))]
+pub fn derive_liquid_object(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// How a single field appears in templates, if at all.
+struct FieldOptions {
+    name: String,
+    skip: bool,
+}
+
+fn field_options(field: &Field) -> Result<FieldOptions> {
+    let mut options = FieldOptions {
+        name: field.ident.as_ref().map(|i| i.to_string()).unwrap_or_default(),
+        skip: false,
+    };
+
+    for attr in &field.attrs {
+        if !attr.path().is_ident("liquid") {
+            continue;
+        }
+        try!(attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                options.skip = true;
+                Ok(())
+            } else if meta.path.is_ident("rename") {
+                let name: LitStr = try!(try!(meta.value()).parse());
+                options.name = name.value();
+                Ok(())
+            } else {
+                Err(meta.error("expected `rename = \"...\"` or `skip`"))
+            }
+        }));
+    }
+
+    Ok(options)
+}
+
+fn expand(input: &DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let fields = match input.data {
+        Data::Struct(ref data) => {
+            match data.fields {
+                Fields::Named(ref fields) => &fields.named,
+                _ => {
+                    return Err(Error::new_spanned(&input.ident,
+                                                  "LiquidObject needs a struct with named fields"))
+                }
+            }
+        }
+        _ => return Err(Error::new_spanned(&input.ident, "LiquidObject can only be derived for structs")),
+    };
+
+    let mut inserts = vec![];
+    for field in fields {
+        let options = try!(field_options(field));
+        if options.skip {
+            continue;
+        }
+        let ident = &field.ident;
+        let name = options.name;
+        inserts.push(quote! {
+            fields.insert(#name.to_owned(), ::liquid::Value::from(value.#ident));
+        });
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::std::convert::From<#ident #ty_generics>
+            for ::liquid::Object #where_clause
+        {
+            #[allow(unused_variables, unused_mut)]
+            fn from(value: #ident #ty_generics) -> Self {
+                let mut fields = ::liquid::Object::default();
+                #(#inserts)*
+                fields
+            }
+        }
+
+        impl #impl_generics ::std::convert::From<#ident #ty_generics> for ::liquid::Value
+            #where_clause
+        {
+            fn from(value: #ident #ty_generics) -> Self {
+                ::liquid::Value::Object(::std::convert::From::from(value))
+            }
+        }
+    })
+}
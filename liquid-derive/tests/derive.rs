@@ -0,0 +1,59 @@
+extern crate liquid;
+#[macro_use]
+extern crate liquid_derive;
+
+use liquid::LiquidOptions;
+use liquid::Renderable;
+use liquid::Context;
+use liquid::Value;
+use liquid::Object;
+use liquid::parse;
+
+#[derive(LiquidObject)]
+struct Author {
+    name: &'static str,
+}
+
+#[derive(LiquidObject)]
+struct Post {
+    title: String,
+    tags: Vec<&'static str>,
+    author: Author,
+    #[liquid(rename = "read-time")]
+    read_time: u32,
+    #[liquid(skip)]
+    #[allow(dead_code)]
+    draft: bool,
+}
+
+fn post() -> Post {
+    Post {
+        title: "Hello".to_owned(),
+        tags: vec!["a", "b"],
+        author: Author { name: "Ann" },
+        read_time: 3,
+        draft: true,
+    }
+}
+
+#[test]
+fn renders_derived_objects() {
+    let text = "{{ title }} by {{ author.name }} ({{ read-time }}){% for t in tags %} #{{ t }}{% endfor %}";
+    let template = parse(text, LiquidOptions::default()).unwrap();
+    let mut context = Context::with_values(post().into());
+    assert_eq!(template.render(&mut context).unwrap(),
+               Some("Hello by Ann (3) #a #b".to_owned()));
+}
+
+#[test]
+fn renames_and_skips_fields() {
+    let fields: Object = post().into();
+    let mut names: Vec<_> = fields.keys().cloned().collect();
+    names.sort();
+    assert_eq!(names, vec!["author", "read-time", "tags", "title"]);
+
+    match Value::from(post()) {
+        Value::Object(ref fields) => assert_eq!(fields["read-time"], Value::Num(3f32)),
+        _ => panic!("expected an object"),
+    }
+}
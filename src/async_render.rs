@@ -0,0 +1,558 @@
+use {Renderable, LiquidOptions, ParseState, Token};
+use builder::{self, Parser};
+use cache::CacheStore;
+use context::{self, Context};
+use error::Result;
+use lexer;
+use locked;
+use template::Template;
+use value::Value;
+use visitor::{walk, Visitor};
+use lexer::Span;
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{self, Poll};
+
+/// A future that can be kept without naming its type, as returned by
+/// async partial sources, async cache stores and async globals.
+pub type BoxFuture<T> = Pin<Box<Future<Output = T>>>;
+
+/// Somewhere partials can be loaded from without blocking, such as a
+/// database or a remote store, for
+/// [Parser::parse_async](struct.Parser.html#method.parse_async). Add one
+/// to a parser with `ParserBuilder::async_partials`.
+///
+/// ## Example
+/// ```
+/// use liquid::{AsyncPartialSource, BoxFuture};
+/// use std::future;
+///
+/// struct Shared;
+///
+/// impl AsyncPartialSource for Shared {
+///     fn load(&self, name: &str) -> BoxFuture<Option<String>> {
+///         let source = if name == "footer" { Some("bye".to_owned()) } else { None };
+///         Box::pin(future::ready(source))
+///     }
+/// }
+/// ```
+pub trait AsyncPartialSource: Send + Sync {
+    /// Loads the source of the named partial, resolving to `None` if this
+    /// source doesn't have it.
+    fn load(&self, name: &str) -> BoxFuture<Option<String>>;
+}
+
+/// A store for rendered template fragments that is used without blocking,
+/// by the `cache` block in renders started with
+/// [Template::render_async](struct.Template.html#method.render_async).
+/// Install one on the rendering [Context](struct.Context.html) with
+/// `set_async_cache_store`.
+///
+/// The fragments a render needs are fetched before it begins, and those it
+/// renders afresh are stored once it has finished.
+pub trait AsyncCacheStore: Send + Sync {
+    /// Fetches the fragment stored under the given key, if there is one and
+    /// it has not expired.
+    fn get(&self, key: &str) -> BoxFuture<Option<String>>;
+
+    /// Stores a rendered fragment under the given key. If a time-to-live is
+    /// supplied, the fragment should be discarded after that many seconds.
+    fn set(&self, key: &str, value: String, ttl: Option<u64>) -> BoxFuture<()>;
+}
+
+/// Collects the names of the partials a template includes by name.
+struct PartialNames(Vec<String>);
+
+impl PartialNames {
+    fn add(&mut self, arguments: &[Token]) {
+        if let Some(&Token::StringLiteral(ref name)) = arguments.first() {
+            self.0.push(name.to_string());
+        }
+    }
+}
+
+impl Visitor for PartialNames {
+    fn visit_tag(&mut self, name: &str, arguments: &[Token], _span: Span) {
+        if name == "include" {
+            self.add(arguments);
+        }
+    }
+
+    fn visit_block(&mut self, name: &str, arguments: &[Token], _span: Span) -> bool {
+        if name == "extends" || name == "layout" {
+            self.add(arguments);
+        }
+        true
+    }
+}
+
+/// The names of the partials a template includes, extends or is laid out
+/// in. Templates that don't lex are left for the parse to report.
+fn partial_names(text: &str, options: &LiquidOptions) -> Vec<String> {
+    let mut names = PartialNames(vec![]);
+    if let Ok(elements) = lexer::tokenize_with_options(text, &ParseState::new(options)) {
+        walk(&elements, options, &mut names);
+    }
+    names.0
+}
+
+/// A partial being loaded from the async source at `source`.
+struct Loading {
+    name: String,
+    source: usize,
+    future: BoxFuture<Option<String>>,
+}
+
+/// The future returned by
+/// [Parser::parse_async](struct.Parser.html#method.parse_async), which
+/// resolves to the parsed template.
+pub struct ParseAsync<'a> {
+    parser: &'a Parser,
+    text: String,
+
+    /// The partials still to be looked for, and every one looked for.
+    wanted: Vec<String>,
+    seen: HashSet<String>,
+
+    loading: Option<Loading>,
+    loaded: HashMap<String, String>,
+}
+
+impl<'a> ParseAsync<'a> {
+    pub fn new(parser: &'a Parser, text: &str) -> ParseAsync<'a> {
+        let mut parse = ParseAsync {
+            parser: parser,
+            text: text.to_owned(),
+            wanted: vec![],
+            seen: HashSet::new(),
+            loading: None,
+            loaded: HashMap::new(),
+        };
+        parse.want_partials_of(text);
+        parse
+    }
+
+    fn want_partials_of(&mut self, text: &str) {
+        for name in partial_names(text, self.parser.options()) {
+            if self.seen.insert(name.clone()) {
+                self.wanted.push(name);
+            }
+        }
+    }
+
+    /// Starts loading the named partial from the async sources from
+    /// `source` on, unless there are no more of them.
+    fn load(&mut self, name: String, source: usize) {
+        if let Some(async_source) = builder::async_partials(self.parser).get(source) {
+            let future = async_source.load(&name);
+            self.loading = Some(Loading {
+                name: name,
+                source: source,
+                future: future,
+            });
+        }
+    }
+
+    /// Looks for the named partial with the parser's other means first,
+    /// only loading it from the async sources when they don't have it.
+    fn find(&mut self, name: String) {
+        let options = self.parser.options();
+        let found = options.templates
+                           .get(&name)
+                           .cloned()
+                           .or_else(|| {
+                               options.partials.iter().filter_map(|p| p.get(&name)).next()
+                                      .map(|source| source.into_owned())
+                           });
+        match found {
+            Some(source) => self.want_partials_of(&source),
+            None => self.load(name, 0),
+        }
+    }
+}
+
+impl<'a> Future for ParseAsync<'a> {
+    type Output = Result<Template>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Result<Template>> {
+        let this = &mut *self;
+        loop {
+            if let Some(mut loading) = this.loading.take() {
+                match loading.future.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        this.loading = Some(loading);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(Some(source)) => {
+                        this.want_partials_of(&source);
+                        this.loaded.insert(loading.name, source);
+                    }
+                    Poll::Ready(None) => this.load(loading.name, loading.source + 1),
+                }
+                continue;
+            }
+            match this.wanted.pop() {
+                Some(name) => this.find(name),
+                None => {
+                    let loaded = mem::replace(&mut this.loaded, HashMap::new());
+                    return Poll::Ready(builder::parse_loaded(this.parser, &this.text, loaded));
+                }
+            }
+        }
+    }
+}
+
+/// Stands in for the async cache store during a render: it serves the
+/// fragments fetched ahead of the render, and keeps the keys it didn't
+/// have and the fragments it was given.
+#[derive(Default)]
+struct Prefetched {
+    hits: HashMap<String, String>,
+    misses: Mutex<Vec<String>>,
+    writes: Mutex<Vec<(String, String, Option<u64>)>>,
+}
+
+impl CacheStore for Prefetched {
+    fn get(&self, key: &str) -> Option<String> {
+        let hit = self.hits.get(key).cloned();
+        if hit.is_none() {
+            locked(&self.misses).push(key.to_owned());
+        }
+        hit
+    }
+
+    fn set(&self, key: &str, value: String, ttl: Option<u64>) {
+        locked(&self.writes).push((key.to_owned(), value, ttl));
+    }
+}
+
+/// Something being fetched for a render.
+enum Fetch {
+    Value(String, BoxFuture<Value>),
+    Fragment(String, BoxFuture<Option<String>>),
+}
+
+enum Step {
+    /// Rendering on a trial fork to find what the render needs, or, once
+    /// nothing more is needed, rendering for real.
+    Render,
+    Fetching(Vec<Fetch>),
+    Storing(Vec<BoxFuture<()>>),
+}
+
+/// The future returned by
+/// [Template::render_async](struct.Template.html#method.render_async),
+/// which resolves to what the template renders.
+pub struct RenderAsync<'a> {
+    template: &'a Template,
+    context: &'a mut Context,
+    step: Step,
+
+    /// The fragments fetched from the async cache store so far, and the
+    /// keys they were fetched under, found or not.
+    hits: HashMap<String, String>,
+    fetched: HashSet<String>,
+
+    output: Option<Result<Option<String>>>,
+}
+
+impl<'a> RenderAsync<'a> {
+    pub fn new(template: &'a Template, context: &'a mut Context) -> RenderAsync<'a> {
+        RenderAsync {
+            template: template,
+            context: context,
+            step: Step::Render,
+            hits: HashMap::new(),
+            fetched: HashSet::new(),
+            output: None,
+        }
+    }
+
+    /// Renders the template on a trial fork of the context, returning what
+    /// it needs that hasn't been fetched yet.
+    fn trial(&mut self) -> Vec<Fetch> {
+        let asked = Arc::new(Mutex::new(vec![]));
+        let store = Arc::new(Prefetched { hits: self.hits.clone(), ..Prefetched::default() });
+        let mut trial = context::trial_fork(self.context, &asked);
+        if self.context.async_cache_store().is_some() {
+            context::replace_cache_store(&mut trial, Some(store.clone()));
+        }
+        let _ = self.template.render(&mut trial);
+        drop(trial);
+
+        let mut fetches = vec![];
+        let mut names = mem::replace(&mut *locked(&asked), vec![]);
+        names.sort();
+        names.dedup();
+        for name in names {
+            if let Some(future) = context::fetch_async_val(self.context, &name) {
+                fetches.push(Fetch::Value(name, future));
+            }
+        }
+        if let Some(cache) = self.context.async_cache_store() {
+            for key in mem::replace(&mut *locked(&store.misses), vec![]) {
+                if self.fetched.insert(key.clone()) {
+                    let future = cache.get(&key);
+                    fetches.push(Fetch::Fragment(key, future));
+                }
+            }
+        }
+        fetches
+    }
+
+    /// Renders the template for real, with the fragments fetched, returning
+    /// the fragments that are to be stored.
+    fn render(&mut self) -> Vec<BoxFuture<()>> {
+        if self.context.async_cache_store().is_none() {
+            self.output = Some(self.template.render(self.context));
+            return vec![];
+        }
+
+        let hits = mem::replace(&mut self.hits, HashMap::new());
+        let store = Arc::new(Prefetched { hits: hits, ..Prefetched::default() });
+        let previous = context::replace_cache_store(self.context, Some(store.clone()));
+        self.output = Some(self.template.render(self.context));
+        context::replace_cache_store(self.context, previous);
+
+        let writes = mem::replace(&mut *locked(&store.writes), vec![]);
+        match self.context.async_cache_store() {
+            Some(cache) => {
+                writes.into_iter().map(|(key, value, ttl)| cache.set(&key, value, ttl)).collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Whether anything needs fetching before the render, in which case
+    /// it is worked out with trial renders.
+    fn needs_trial(&self) -> bool {
+        self.context.async_cache_store().is_some() ||
+        !context::async_val_names(self.context).is_empty()
+    }
+}
+
+impl<'a> Future for RenderAsync<'a> {
+    type Output = Result<Option<String>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Result<Option<String>>> {
+        let this = &mut *self;
+        loop {
+            match mem::replace(&mut this.step, Step::Render) {
+                Step::Render => {
+                    let fetches = if this.needs_trial() { this.trial() } else { vec![] };
+                    this.step = if fetches.is_empty() {
+                        Step::Storing(this.render())
+                    } else {
+                        Step::Fetching(fetches)
+                    };
+                }
+                Step::Fetching(fetches) => {
+                    let mut pending = vec![];
+                    for fetch in fetches {
+                        match fetch {
+                            Fetch::Value(name, mut future) => {
+                                match future.as_mut().poll(cx) {
+                                    Poll::Ready(value) => {
+                                        context::resolve_async_val(this.context, &name, value)
+                                    }
+                                    Poll::Pending => pending.push(Fetch::Value(name, future)),
+                                }
+                            }
+                            Fetch::Fragment(key, mut future) => {
+                                match future.as_mut().poll(cx) {
+                                    Poll::Ready(Some(fragment)) => {
+                                        this.hits.insert(key, fragment);
+                                    }
+                                    Poll::Ready(None) => (),
+                                    Poll::Pending => pending.push(Fetch::Fragment(key, future)),
+                                }
+                            }
+                        }
+                    }
+                    if !pending.is_empty() {
+                        this.step = Step::Fetching(pending);
+                        return Poll::Pending;
+                    }
+                }
+                Step::Storing(mut stores) => {
+                    stores.retain_mut(|future| future.as_mut().poll(cx).is_pending());
+                    if !stores.is_empty() {
+                        this.step = Step::Storing(stores);
+                        return Poll::Pending;
+                    }
+                    return Poll::Ready(this.output.take().unwrap_or(Ok(None)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AsyncCacheStore, AsyncPartialSource, BoxFuture};
+    use builder::ParserBuilder;
+    use context::Context;
+    use value::Value;
+    use {parse, LiquidOptions};
+
+    use std::future::{self, Future};
+    use std::pin::Pin;
+    use std::ptr;
+    use std::sync::{Arc, Mutex};
+    use std::task::{self, Poll, RawWaker, RawWakerVTable, Waker};
+
+    /// Polls a future until it is ready. The futures in these tests wake
+    /// themselves, so there is nothing to wait for in between.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        fn raw() -> RawWaker {
+            RawWaker::new(ptr::null(), &VTABLE)
+        }
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(|_| raw(), |_| (), |_| (), |_| ());
+
+        let waker = unsafe { Waker::from_raw(raw()) };
+        let mut cx = task::Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// Resolves to its value the second time it is polled, as a future
+    /// waiting on the network would after a while.
+    struct Later<T>(Option<T>, bool);
+
+    impl<T: Unpin> Future for Later<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<T> {
+            if !self.1 {
+                self.1 = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(self.0.take().unwrap())
+        }
+    }
+
+    fn later<T: Unpin + 'static>(value: T) -> BoxFuture<T> {
+        Box::pin(Later(Some(value), false))
+    }
+
+    struct Partials(Arc<Mutex<Vec<String>>>);
+
+    impl AsyncPartialSource for Partials {
+        fn load(&self, name: &str) -> BoxFuture<Option<String>> {
+            self.0.lock().unwrap().push(name.to_owned());
+            let source = match name {
+                "outer" => Some("[{% include 'inner' %}]"),
+                "inner" => Some("{{ name }}"),
+                _ => None,
+            };
+            later(source.map(|s| s.to_owned()))
+        }
+    }
+
+    #[test]
+    fn partials_are_loaded_before_parsing() {
+        let asked = Arc::new(Mutex::new(vec![]));
+        let parser = ParserBuilder::with_liquid()
+                         .include_source("known", "{% include 'outer' %}")
+                         .async_partials(Box::new(Partials(asked.clone())))
+                         .build();
+        let template = block_on(parser.parse_async("{% include 'known' %}!")).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("name", Value::str("Ann"));
+        assert_eq!(block_on(template.render_async(&mut context)).unwrap(),
+                   Some("[Ann]!".to_owned()));
+        // the partial added by name is never asked for
+        assert_eq!(*asked.lock().unwrap(), vec!["outer".to_owned(), "inner".to_owned()]);
+    }
+
+    #[test]
+    fn partials_missing_everywhere_fail_to_parse() {
+        let parser = ParserBuilder::with_liquid()
+                         .async_partials(Box::new(Partials(Arc::new(Mutex::new(vec![])))))
+                         .build();
+        assert!(block_on(parser.parse_async("{% include 'missing' %}")).is_err());
+    }
+
+    #[test]
+    fn async_values_are_only_fetched_when_used() {
+        let template = parse("{% if show %}{{ user }}{% endif %}", LiquidOptions::default())
+                           .unwrap();
+        let fetched = Arc::new(Mutex::new(vec![]));
+        let mut context = Context::new();
+        for &(name, value) in &[("show", true), ("hidden", true)] {
+            let fetched = fetched.clone();
+            context.set_async_val(name, move |_| {
+                fetched.lock().unwrap().push(name);
+                later(Value::Bool(value))
+            });
+        }
+        let user = fetched.clone();
+        context.set_async_val("user", move |_| {
+            user.lock().unwrap().push("user");
+            Box::pin(future::ready(Value::str("Ann")))
+        });
+
+        assert_eq!(block_on(template.render_async(&mut context)).unwrap(),
+                   Some("Ann".to_owned()));
+        // `user` is only found to be needed once `show` has been fetched
+        assert_eq!(*fetched.lock().unwrap(), vec!["show", "user"]);
+        assert_eq!(context.get_val("user"), Some(&Value::str("Ann")));
+    }
+
+    #[derive(Default)]
+    struct Fragments(Mutex<Vec<(String, String)>>);
+
+    impl AsyncCacheStore for Arc<Fragments> {
+        fn get(&self, key: &str) -> BoxFuture<Option<String>> {
+            let found = self.0.lock().unwrap().iter().find(|e| e.0 == key).map(|e| e.1.clone());
+            later(found)
+        }
+
+        fn set(&self, key: &str, value: String, _ttl: Option<u64>) -> BoxFuture<()> {
+            self.0.lock().unwrap().push((key.to_owned(), value));
+            later(())
+        }
+    }
+
+    #[test]
+    fn cached_fragments_are_fetched_and_stored() {
+        let store = Arc::new(Fragments::default());
+        store.0.lock().unwrap().push(("header".to_owned(), "cached".to_owned()));
+        let text = "{% cache 'header' %}{{ n }}{% endcache %}-\
+                    {% cache 'footer' %}{{ n }}{% endcache %}";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("n", 1);
+        context.set_async_cache_store(Box::new(store.clone()));
+        assert_eq!(block_on(template.render_async(&mut context)).unwrap(),
+                   Some("cached-1".to_owned()));
+        assert_eq!(store.0.lock().unwrap()[1], ("footer".to_owned(), "1".to_owned()));
+
+        context.set_val("n", 2);
+        assert_eq!(block_on(template.render_async(&mut context)).unwrap(),
+                   Some("cached-1".to_owned()));
+        assert!(context.cache_store().is_none());
+    }
+
+    #[test]
+    fn trial_renders_leave_nothing_behind() {
+        let template = parse("{% cycle 'a', 'b' %}{{ later }}{% cycle 'a', 'b' %}",
+                             LiquidOptions::default())
+                           .unwrap();
+        let mut context = Context::new();
+        context.set_async_val("later", |_| later(Value::str("-")));
+        assert_eq!(block_on(template.render_async(&mut context)).unwrap(),
+                   Some("a-b".to_owned()));
+    }
+}
@@ -0,0 +1,457 @@
+use {LiquidOptions, ParseState, ErrorMode, UnknownTagMode, WhitespaceMode, Limits, Tag, Block,
+     Transform};
+use template::Template;
+use filters::Filter;
+use partials::{PartialSource, TemplateCache};
+use lexer::{self, Delimiters};
+use parser;
+use fold::constant_folder;
+use error::{Error, Result};
+#[cfg(feature = "async")]
+use async_render::{AsyncPartialSource, ParseAsync};
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Collects the tags, blocks, filters, partials and settings used to parse
+/// templates, and produces a [Parser](struct.Parser.html) from them. This
+/// is the place to set everything up once, rather than registering filters
+/// on each context. All of the built-in tags, blocks and filters are
+/// available to begin with, and are replaced by any registered under the
+/// same name.
+///
+/// ## Example
+/// ```
+/// use liquid::{ParserBuilder, Renderable, Context, Value};
+///
+/// let parser = ParserBuilder::with_liquid()
+///     .filter("shout", Box::new(|input, _args| Ok(Value::Str(input.to_string() + "!"))))
+///     .include_source("greeting", "{{ 'hello' | shout }}")
+///     .build();
+///
+/// let template = parser.parse("{% include 'greeting' %}").unwrap();
+/// let output = template.render(&mut Context::new());
+/// assert_eq!(output.unwrap(), Some("hello!".to_owned()));
+/// ```
+pub struct ParserBuilder {
+    options: LiquidOptions,
+    filters: HashMap<String, Box<Filter>>,
+    fold_constants: bool,
+    #[cfg(feature = "async")]
+    async_partials: Vec<Box<AsyncPartialSource>>,
+}
+
+impl ParserBuilder {
+    pub fn new() -> ParserBuilder {
+        ParserBuilder::with_options(LiquidOptions::default())
+    }
+
+    /// Starts with the standard Liquid tags, blocks and filters and the
+    /// default settings. This is the same as `new`, under a name that says
+    /// what you get.
+    pub fn with_liquid() -> ParserBuilder {
+        ParserBuilder::new()
+    }
+
+    /// Starts from an existing set of options, rather than the defaults.
+    pub fn with_options(options: LiquidOptions) -> ParserBuilder {
+        ParserBuilder {
+            options: options,
+            filters: HashMap::new(),
+            fold_constants: false,
+            #[cfg(feature = "async")]
+            async_partials: vec![],
+        }
+    }
+
+    pub fn tag(mut self, name: &str, tag: Box<Tag>) -> ParserBuilder {
+        self.options.register_tag(name, tag);
+        self
+    }
+
+    pub fn block(mut self, name: &str, block: Box<Block>) -> ParserBuilder {
+        self.options.register_block(name, block);
+        self
+    }
+
+    /// Adds a pass to run over every node as it is parsed. See
+    /// [Transform](type.Transform.html).
+    pub fn transform(mut self, transform: Box<Transform>) -> ParserBuilder {
+        self.options.register_transform(transform);
+        self
+    }
+
+    /// Registers a filter for every template produced by the parser. These
+    /// take precedence over the built-in filters.
+    pub fn filter(mut self, name: &str, filter: Box<Filter>) -> ParserBuilder {
+        self.filters.insert(name.to_owned(), filter);
+        self
+    }
+
+    /// Renders outputs made up only of literals while parsing, using the
+    /// built-in filters and those registered here. See
+    /// [constant_folder](fn.constant_folder.html).
+    pub fn fold_constants(mut self) -> ParserBuilder {
+        self.fold_constants = true;
+        self
+    }
+
+    pub fn delimiters(mut self, delimiters: Delimiters) -> ParserBuilder {
+        self.options.delimiters = delimiters;
+        self
+    }
+
+    pub fn error_mode(mut self, mode: ErrorMode) -> ParserBuilder {
+        self.options.error_mode = mode;
+        self
+    }
+
+    pub fn unknown_tags(mut self, mode: UnknownTagMode) -> ParserBuilder {
+        self.options.unknown_tags = mode;
+        self
+    }
+
+    pub fn whitespace(mut self, mode: WhitespaceMode) -> ParserBuilder {
+        self.options.whitespace = mode;
+        self
+    }
+
+    /// Sets limits on the size and complexity of the templates parsed, for
+    /// when they come from untrusted sources.
+    pub fn limits(mut self, limits: Limits) -> ParserBuilder {
+        self.options.limits = limits;
+        self
+    }
+
+    /// Sets the directory that `include` and friends load partials from.
+    pub fn file_system(mut self, root: PathBuf) -> ParserBuilder {
+        self.options.file_system = Some(root);
+        self
+    }
+
+    /// Adds a partial that `include` can load by name, from its source.
+    /// These are tried before any other partial sources.
+    pub fn include_source(mut self, name: &str, source: &str) -> ParserBuilder {
+        self.options.templates.insert(name.to_owned(), source.to_owned());
+        self
+    }
+
+    /// Adds somewhere for `include` and friends to load partials from,
+    /// tried after any added before it. See
+    /// [PartialSource](trait.PartialSource.html).
+    pub fn partials(mut self, source: Box<PartialSource>) -> ParserBuilder {
+        self.options.partials.push(source);
+        self
+    }
+
+    /// Adds somewhere for [Parser::parse_async](struct.Parser.html#method.parse_async)
+    /// to load partials from without blocking, tried after any added before
+    /// it. See [AsyncPartialSource](trait.AsyncPartialSource.html). Only
+    /// available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn async_partials(mut self, source: Box<AsyncPartialSource>) -> ParserBuilder {
+        self.async_partials.push(source);
+        self
+    }
+
+    pub fn build(self) -> Parser {
+        let mut options = self.options;
+        options.register_known_blocks();
+        let filters = Arc::new(self.filters);
+        if self.fold_constants {
+            options.register_transform(constant_folder(Some(filters.clone())));
+        }
+        Parser {
+            options: options,
+            filters: filters,
+            #[cfg(feature = "async")]
+            async_partials: self.async_partials,
+        }
+    }
+}
+
+impl Default for ParserBuilder {
+    fn default() -> ParserBuilder {
+        ParserBuilder::new()
+    }
+}
+
+/// Parses templates using a fixed set of tags, blocks, filters and
+/// settings. Create one with a [ParserBuilder](struct.ParserBuilder.html).
+pub struct Parser {
+    options: LiquidOptions,
+    filters: Arc<HashMap<String, Box<Filter>>>,
+    #[cfg(feature = "async")]
+    async_partials: Vec<Box<AsyncPartialSource>>,
+}
+
+impl Parser {
+    pub fn parse(&self, text: &str) -> Result<Template> {
+        let mut template = try!(::parse_template(text, &self.options));
+        template.filters = Some(self.filters.clone());
+        Ok(template)
+    }
+
+    /// Parses a template like `parse`, reusing the template kept in `cache`
+    /// if the same source has been parsed before, and keeping the new one
+    /// otherwise. A cache should only be used with one parser, since the
+    /// templates it keeps were parsed with that parser's tags and filters.
+    ///
+    /// ```
+    /// use liquid::{ParserBuilder, TemplateCache};
+    /// use std::sync::Arc;
+    ///
+    /// let parser = ParserBuilder::with_liquid().build();
+    /// let cache = TemplateCache::new(100);
+    /// let first = parser.parse_cached("Hi {{ name }}", &cache).unwrap();
+    /// let second = parser.parse_cached("Hi {{ name }}", &cache).unwrap();
+    /// assert!(Arc::ptr_eq(&first, &second));
+    /// ```
+    pub fn parse_cached(&self, text: &str, cache: &TemplateCache) -> Result<Arc<Template>> {
+        if let Some(template) = cache.get(text) {
+            return Ok(template);
+        }
+        let template = Arc::new(try!(self.parse(text)));
+        cache.insert(text, template.clone());
+        Ok(template)
+    }
+
+    /// Restores a template serialized with
+    /// [Template::to_bytes](struct.Template.html#method.to_bytes) and
+    /// gives it this parser's filters, which aren't serialized along with
+    /// it. Only available with the `serde` feature.
+    ///
+    /// ```
+    /// use liquid::{Context, ParserBuilder, Renderable, Value};
+    ///
+    /// let parser = ParserBuilder::new()
+    ///     .filter("shout", Box::new(|input, _| Ok(Value::Str(input.to_string() + "!"))))
+    ///     .build();
+    /// let bytes = parser.parse("{{ 'hi' | shout }}").unwrap().to_bytes().unwrap();
+    /// let template = parser.from_bytes(&bytes).unwrap();
+    /// assert_eq!(template.render(&mut Context::new()).unwrap(), Some("hi!".to_owned()));
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn from_bytes(&self, bytes: &[u8]) -> Result<Template> {
+        let mut template = try!(Template::from_bytes(bytes));
+        template.filters = Some(self.filters.clone());
+        Ok(template)
+    }
+
+    /// Parses a template read from a stream. The source is tokenized a line
+    /// at a time rather than being read into memory up front. Since the
+    /// source isn't kept, errors report their location as a byte offset.
+    pub fn parse_reader<R: Read>(&self, reader: R) -> Result<Template> {
+        let state = ParseState::new(&self.options);
+        let elements = lexer::tokenize_reader(reader, &state)
+                           .and_then(|tokens| parser::parse(&tokens, &state));
+        let mut template = try!(::finish_template(elements, &state));
+        template.filters = Some(self.filters.clone());
+        Ok(template)
+    }
+
+    /// Parses a template like `parse`, once the partials it uses that can
+    /// only be found in the async partial sources have been loaded from
+    /// them. The partials the template names with `include`, `extends` and
+    /// `layout`, and those they name in turn, are loaded first, one after
+    /// another; the template is then parsed as usual. Only available with
+    /// the `async` feature.
+    ///
+    /// Partials found by the other means are never loaded from the async
+    /// sources, and `include_relative` still reads its partials from their
+    /// files.
+    #[cfg(feature = "async")]
+    pub fn parse_async(&self, text: &str) -> ParseAsync {
+        ParseAsync::new(self, text)
+    }
+
+    /// Makes a template available to `include`, `extends` and `layout`
+    /// under the given name, ahead of any file of the same name.
+    pub fn add_template(&mut self, name: &str, source: &str) {
+        self.options.templates.insert(name.to_owned(), source.to_owned());
+    }
+
+    /// Parses the template added under the given name, reporting the
+    /// location of any errors against that name.
+    pub fn parse_template(&self, name: &str) -> Result<Template> {
+        let source = match self.options.templates.get(name) {
+            Some(source) => source,
+            None => return Err(Error::Other(format!("Unknown template {}", name))),
+        };
+        let mut template = try!(::parse_named_template(source, Some(name), &self.options));
+        template.filters = Some(self.filters.clone());
+        Ok(template)
+    }
+
+    /// The options used when parsing.
+    pub fn options(&self) -> &LiquidOptions {
+        &self.options
+    }
+
+    /// The options used when parsing, for changing between parses.
+    pub fn options_mut(&mut self) -> &mut LiquidOptions {
+        &mut self.options
+    }
+}
+
+/// The async partial sources of a parser, in the order they are tried.
+#[cfg(feature = "async")]
+pub fn async_partials(parser: &Parser) -> &[Box<AsyncPartialSource>] {
+    &parser.async_partials
+}
+
+/// Parses a template with partials that have been loaded ahead of the
+/// parse, for `Parser::parse_async`.
+#[cfg(feature = "async")]
+pub fn parse_loaded(parser: &Parser, text: &str, loaded: HashMap<String, String>)
+                    -> Result<Template> {
+    let mut state = ParseState::new(&parser.options);
+    for (name, source) in loaded {
+        state.add_partial(&name, source);
+    }
+    let name = parser.options.template_name.as_ref().map(|n| &n[..]);
+    let mut template = try!(::parse_in_state(text, name, &state));
+    template.filters = Some(parser.filters.clone());
+    Ok(template)
+}
+
+#[cfg(test)]
+mod test {
+    use super::ParserBuilder;
+    use Renderable;
+    use UnknownTagMode;
+    use context::Context;
+    use lexer::Delimiters;
+    use value::Value;
+    use filters::FilterResult;
+
+    fn exclaim(input: &Value, _args: &[Value]) -> FilterResult {
+        Ok(Value::Str(input.to_string() + "!"))
+    }
+
+    #[test]
+    fn parses_many_templates() {
+        let parser = ParserBuilder::new().filter("exclaim", Box::new(exclaim)).build();
+
+        let mut context = Context::new();
+        context.set_val("name", Value::str("world"));
+        for &(text, expected) in &[("{{ name | exclaim }}", "world!"),
+                                   ("{% if true %}{{ 'hi' | exclaim }}{% endif %}", "hi!")] {
+            let template = parser.parse(text).unwrap();
+            assert_eq!(template.render(&mut context).unwrap(), Some(expected.to_owned()));
+        }
+    }
+
+    #[test]
+    fn filters_override_builtins() {
+        let parser = ParserBuilder::new().filter("upcase", Box::new(exclaim)).build();
+        let template = parser.parse("{% for i in (1..2) %}{{ 'a' | upcase }}{% endfor %}")
+                             .unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(), Some("a!".to_owned()));
+    }
+
+    #[test]
+    fn folds_constants_with_registered_filters() {
+        let parser = ParserBuilder::new()
+                         .filter("exclaim", Box::new(exclaim))
+                         .fold_constants()
+                         .build();
+        let template = parser.parse("{{ 'a' | exclaim }}").unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(), Some("a!".to_owned()));
+    }
+
+    #[test]
+    fn parse_reader_matches_parse() {
+        use std::io::Cursor;
+
+        let parser = ParserBuilder::new().build();
+        let text = concat!("line one\n",
+                           "{% for i in (1..3) -%}\n",
+                           "  {{ i }}\n",
+                           "{%- endfor %}\n",
+                           "{% if true %}ünïcode {{ 'x' }}{% endif %}\n",
+                           "{{ 'a' }} {{- 'b' }}");
+
+        let mut context = Context::new();
+        let expected = parser.parse(text).unwrap().render(&mut context).unwrap();
+        let streamed = parser.parse_reader(Cursor::new(text.as_bytes())).unwrap();
+        assert_eq!(streamed.render(&mut context).unwrap(), expected);
+    }
+
+    #[test]
+    fn parse_reader_checks_size_limit() {
+        use std::io::{Cursor, Read};
+        use Limits;
+
+        let parser = ParserBuilder::new()
+                         .limits(Limits { max_bytes: Some(10), ..Default::default() })
+                         .build();
+        assert!(parser.parse_reader(Cursor::new(&b"short"[..])).is_ok());
+
+        // an endless template is only read up to the limit
+        let endless = Cursor::new(&b"x"[..]).chain(::std::io::repeat(b'y'));
+        assert!(parser.parse_reader(endless).err().unwrap().is_limit());
+    }
+
+    #[test]
+    fn parse_reader_reports_byte_offsets() {
+        use std::io::Cursor;
+
+        let parser = ParserBuilder::new().build();
+        let err = parser.parse_reader(Cursor::new(&b"ok\n{% if %}{% endif %}"[..])).err().unwrap();
+        assert!(err.to_string().starts_with("byte 3: "));
+    }
+
+    #[test]
+    fn settings_apply_to_every_parse() {
+        let parser = ParserBuilder::new()
+                         .unknown_tags(UnknownTagMode::Drop)
+                         .delimiters(Delimiters {
+                             output_start: "[[".to_owned(),
+                             output_end: "]]".to_owned(),
+                             ..Default::default()
+                         })
+                         .build();
+
+        let template = parser.parse("[[ 'x' ]]{% nope %}").unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(), Some("x".to_owned()));
+        assert_eq!(template.warnings.len(), 1);
+
+        // warnings don't carry over between templates
+        let template = parser.parse("{{ 'y' }}").unwrap();
+        assert_eq!(template.warnings.len(), 0);
+    }
+
+    #[test]
+    fn threads_parse_with_one_parser_at_once() {
+        use std::sync::{Arc, Barrier};
+        use std::thread;
+
+        let parser = Arc::new(ParserBuilder::new()
+                                  .unknown_tags(UnknownTagMode::Drop)
+                                  .include_source("inner", "{% nope %}[{{ n }}]")
+                                  .build());
+        // each thread waits for the others, so the parses overlap
+        let barrier = Arc::new(Barrier::new(4));
+        let threads: Vec<_> = (0..4)
+                                  .map(|i| {
+                                      let parser = parser.clone();
+                                      let barrier = barrier.clone();
+                                      thread::spawn(move || {
+                                          barrier.wait();
+                                          let text = format!("{}{{% include 'inner' %}}", i);
+                                          parser.parse(&text).unwrap()
+                                      })
+                                  })
+                                  .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            let template = thread.join().unwrap();
+            let mut context = Context::new();
+            context.set_val("n", Value::Num(i as f32));
+            assert_eq!(template.render(&mut context).unwrap(), Some(format!("{}[{}]", i, i)));
+            assert_eq!(template.warnings.len(), 1);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use locked;
+
+/// A store for rendered template fragments, used by the `cache` block.
+///
+/// Applications can provide their own implementation (backed by memcached,
+/// Redis, etc.) and install it on the rendering
+/// [Context](struct.Context.html) with `set_cache_store`. A store is
+/// shared by a context and its forks, so it is only given `&self`, and
+/// keeps whatever it changes behind a lock of its own.
+pub trait CacheStore {
+    /// Fetches the fragment stored under the given key, if there is one and
+    /// it has not expired.
+    fn get(&self, key: &str) -> Option<String>;
+
+    /// Stores a rendered fragment under the given key. If a time-to-live is
+    /// supplied, the fragment should be discarded after that many seconds.
+    fn set(&self, key: &str, value: String, ttl: Option<u64>);
+}
+
+/// A simple in-memory `CacheStore`.
+#[derive(Default)]
+pub struct MemoryCacheStore {
+    entries: Mutex<HashMap<String, (String, Option<Instant>)>>,
+}
+
+impl MemoryCacheStore {
+    pub fn new() -> MemoryCacheStore {
+        MemoryCacheStore::default()
+    }
+}
+
+impl CacheStore for MemoryCacheStore {
+    fn get(&self, key: &str) -> Option<String> {
+        match locked(&self.entries).get(key) {
+            Some(&(_, Some(expiry))) if expiry <= Instant::now() => None,
+            Some(&(ref value, _)) => Some(value.clone()),
+            None => None,
+        }
+    }
+
+    fn set(&self, key: &str, value: String, ttl: Option<u64>) {
+        let expiry = ttl.map(|secs| Instant::now() + Duration::from_secs(secs));
+        locked(&self.entries).insert(key.to_owned(), (value, expiry));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CacheStore, MemoryCacheStore};
+
+    #[test]
+    fn memory_store_get_and_set() {
+        let store = MemoryCacheStore::new();
+        assert_eq!(store.get("a"), None);
+
+        store.set("a", "alpha".to_owned(), None);
+        assert_eq!(store.get("a"), Some("alpha".to_owned()));
+
+        store.set("b", "beta".to_owned(), Some(0));
+        assert_eq!(store.get("b"), None);
+    }
+}
@@ -1,19 +1,242 @@
+use cache::CacheStore;
+#[cfg(feature = "async")]
+use async_render::{AsyncCacheStore, BoxFuture};
 use error::{Result, Error};
-use filters::Filter;
-use std::collections::HashMap;
-use token::Token::{self, Identifier, StringLiteral, NumberLiteral, BooleanLiteral};
-use value::Value;
+use escape::{Escaper, HtmlEscaper};
+use i18n::I18n;
+use filters::{self, Filter, NumberPolicy, builtin_filters};
+use random::{RandomSource, SeededRandom};
+use date::{Clock, SystemClock, DateTime};
+use instrument::Instrument;
+use ErrorMode;
+use locked;
+use validate::{Warning, WarningKind};
+use lexer::Span;
+use symbol::{Symbol, SymbolMap};
+use stats::RenderStats;
+use std::any::Any;
+use std::borrow::Cow;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::slice;
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::{Arc, Mutex};
+use template::Template;
+use token::Token::{self, Identifier, StringLiteral, NumberLiteral, BooleanLiteral, NilLiteral,
+                   EmptyLiteral, BlankLiteral, Range};
+use value::{Value, Object};
 
 
 #[derive(Clone)]
 pub enum Interrupt { Continue, Break }
 
-type ValueMap = HashMap<String, Value>;
+/// A variable path such as `a.b[0]["c d"]`, split into its parts once
+/// so that a template can look it up on every render without reading the
+/// path again.
+///
+/// # Examples
+///
+/// ```
+/// # use liquid::{Value, Context, VariablePath};
+/// let mut ctx = Context::new();
+/// ctx.set_val("list", Value::Array(vec![Value::str("a"), Value::str("b")]));
+/// let path = VariablePath::new("list[1]");
+/// assert_eq!(ctx.get_path(&path), Some(&Value::str("b")));
+/// ```
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VariablePath {
+    name: Symbol,
+    /// The variable the path starts from, or `None` if the path has an
+    /// unterminated index or doesn't start with a name, so it can't name
+    /// anything.
+    root: Option<Symbol>,
+    /// The parts of the path after the variable it starts from.
+    parts: Vec<PathPart>,
+}
+
+/// One link in a variable path, such as `a`, `.b`, `[0]` or `[i]`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum PathPart {
+    Key(String),
+    Position(f32),
+    Variable(VariablePath),
+}
+
+impl VariablePath {
+    pub fn new(name: &str) -> VariablePath {
+        VariablePath::from_symbol(Symbol::from(name))
+    }
+
+    pub fn from_symbol(name: Symbol) -> VariablePath {
+        let mut parts = split_path(&name).unwrap_or_default();
+        let root = match parts.first() {
+            // a plain name is its own root, so shares its symbol
+            Some(&PathPart::Key(ref key)) if *key == *name => Some(name.clone()),
+            Some(&PathPart::Key(ref key)) => Some(Symbol::from(&key[..])),
+            _ => None,
+        };
+        if root.is_some() {
+            parts.remove(0);
+        }
+        VariablePath {
+            name: name,
+            root: root,
+            parts: parts,
+        }
+    }
+
+    /// The path as it was written.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Splits a variable path like `a.b[0]["c d"]` into its parts, or returns
+/// None if it has an unterminated index.
+fn split_path(path: &str) -> Option<Vec<PathPart>> {
+    let mut parts = vec![];
+    let mut rest = path;
+    while !rest.is_empty() {
+        if rest.starts_with('[') {
+            let end = match closing_bracket(rest) {
+                Some(end) => end,
+                None => return None,
+            };
+            parts.push(index_part(rest[1..end].trim()));
+            rest = &rest[end + 1..];
+        } else {
+            if rest.starts_with('.') {
+                rest = &rest[1..];
+            }
+            let end = rest.find(|c| c == '.' || c == '[').unwrap_or(rest.len());
+            parts.push(PathPart::Key(rest[..end].to_owned()));
+            rest = &rest[end..];
+        }
+    }
+    Some(parts)
+}
+
+/// Finds the `]` that closes the `[` at the start of `path`, skipping over
+/// quoted keys and the brackets of indexes within it.
+fn closing_bracket(path: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in path.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => {
+                match c {
+                    '\'' | '"' => quote = Some(c),
+                    '[' => depth += 1,
+                    ']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(i);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Reads the contents of a `[...]` index: a number, a quoted string or the
+/// path of another variable.
+fn index_part(index: &str) -> PathPart {
+    if index.len() >= 2 &&
+       (index.starts_with('\'') && index.ends_with('\'') ||
+        index.starts_with('"') && index.ends_with('"')) {
+        return PathPart::Key(index[1..index.len() - 1].to_owned());
+    }
+    match index.parse::<f32>() {
+        Ok(n) => PathPart::Position(n),
+        Err(_) => PathPart::Variable(VariablePath::new(index)),
+    }
+}
+
+fn by_key<'v>(value: &'v Value, key: &str) -> Option<&'v Value> {
+    match *value {
+        Value::Object(ref x) => x.get(key),
+        _ => None,
+    }
+}
+
+/// Indexes into an array, counting from the end for negative positions.
+fn by_position(value: &Value, n: f32) -> Option<&Value> {
+    match *value {
+        Value::Array(ref x) => {
+            let i = if n < 0f32 { x.len() as f32 + n } else { n };
+            if i < 0f32 { None } else { x.get(i as usize) }
+        }
+        _ => None,
+    }
+}
+
+/// A global whose value is only worked out the first time it is used.
+#[derive(Clone)]
+struct LazyValue {
+    provider: Arc<Fn(&Context) -> Value>,
+    value: OnceCell<Value>,
+}
+
+/// The state a tag keeps in a register. Registers are copied along with
+/// the rest of a context when it is forked, so they have to be `Clone`.
+trait Register: Any {
+    fn clone_register(&self) -> Box<Register>;
+    fn as_any(&self) -> &Any;
+    fn as_any_mut(&mut self) -> &mut Any;
+    fn into_any(self: Box<Self>) -> Box<Any>;
+}
+
+impl<T: Any + Clone> Register for T {
+    fn clone_register(&self) -> Box<Register> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<Any> {
+        self
+    }
+}
+
+/// Makes a `date` filter that tells the time with the given clock.
+fn date_filter(clock: Arc<Mutex<Box<Clock>>>, default_format: &str) -> Box<Filter> {
+    let default_format = default_format.to_owned();
+    Box::new(move |input, args| {
+        let now = DateTime::from_system_time(locked(&clock).now());
+        filters::date(input, args, &now, &default_format)
+    })
+}
+
+/// A frame of the scope stack. A name that maps to `None` has been
+/// assigned `nil`, which hides any global of the same name. The frames are
+/// looked up on almost every variable reference, so they are keyed by
+/// symbols, whose names have already been hashed.
+type Scope = SymbolMap<Option<Value>>;
 
-#[derive(Default)]
 pub struct Context {
-    stack: Vec<ValueMap>,
-    globals: ValueMap,
+    /// The variables created while rendering, by tags such as `assign` and
+    /// `for`. The first frame lasts for the whole render.
+    stack: Vec<Scope>,
+
+    /// The data supplied by the host. Templates can shadow these, but can't
+    /// change them, so the same globals can be shared between renders.
+    globals: Arc<Object>,
+
+    /// Globals that are worked out when they're first used.
+    lazy_globals: HashMap<String, LazyValue>,
 
     /// The current interrupt state. The interrupt state is used by
     /// the `break` and `continue` tags to halt template rendering
@@ -22,13 +245,132 @@ pub struct Context {
     /// is cleared, and the `for_loop` carries on processing as directed.
     interrupt: Option<Interrupt>,
 
-    /// The indices of all the cycles encountered during rendering.
-    cycles: HashMap<String, usize>,
+    /// The number of loops currently being rendered. Interrupts are only
+    /// valid inside a loop, which will consume them.
+    loop_depth: usize,
+
+    /// State kept by tags between one use and the next during a render,
+    /// such as the position of each `cycle`. Keyed by the name of the tag
+    /// and a name chosen by the tag.
+    registers: HashMap<(String, String), Box<Register>>,
+
+    /// The overrides for named `block`s registered by the `extends` tags
+    /// currently being rendered, ordered from the most-derived template
+    /// to the least.
+    block_overrides: HashMap<String, Vec<Arc<Template>>>,
+
+    /// The store used by the `cache` block to save rendered fragments,
+    /// shared with forks of the context.
+    cache_store: Option<Arc<CacheStore>>,
+
+    /// Globals that are fetched without blocking, when a render started
+    /// with `Template::render_async` first uses them.
+    #[cfg(feature = "async")]
+    async_globals: HashMap<String, Arc<Fn(&Context) -> BoxFuture<Value>>>,
+
+    /// The store used by the `cache` block in renders started with
+    /// `Template::render_async`, shared with forks of the context.
+    #[cfg(feature = "async")]
+    async_cache_store: Option<Arc<AsyncCacheStore>>,
+
+    /// The filters the context provides itself: `sample`, `date` and,
+    /// once translations are set, `t` and `l`. These are only used when
+    /// no filter of the same name has been registered.
+    own_filters: HashMap<String, Box<Filter>>,
+
+    /// Filters registered with the `Parser` that produced the template
+    /// being rendered. These take precedence over `filters`.
+    shared_filters: Option<Arc<HashMap<String, Box<Filter>>>>,
+
+    /// The filters added to the context before it was last forked, newest
+    /// first, shared with its forks. These come after `filters`.
+    forked_filters: Vec<Arc<HashMap<String, Box<Filter>>>>,
+
+    /// The built-in filters, used when a filter isn't found anywhere else.
+    builtin_filters: &'static HashMap<String, Box<Filter>>,
+
+    /// What the arithmetic filters do about division by zero and numbers
+    /// that aren't finite.
+    number_policy: NumberPolicy,
+
+    /// The arithmetic filters for the number policy, looked up just before
+    /// the built-in ones, when these don't follow it.
+    number_filters: Option<&'static HashMap<String, Box<Filter>>>,
+
+    /// Whether looking up a variable that isn't defined is an error,
+    /// rather than rendering as nothing.
+    strict_variables: bool,
+
+    /// The current locale and translations, used by the `t` and `l`
+    /// filters.
+    i18n: Option<Arc<I18n>>,
+
+    /// How the results of `{{ }}` outputs are escaped, if at all.
+    escaper: Option<Arc<Escaper>>,
+
+    /// Called when a lookup doesn't find a value. See
+    /// `set_undefined_handler`.
+    undefined_handler: Option<Arc<Fn(&str) -> Result<Option<Value>>>>,
+
+    /// Whether using a filter that hasn't been registered is an error,
+    /// rather than leaving the value unfiltered.
+    strict_filters: bool,
+
+    /// Where the `sample` filter and other random choices get their
+    /// numbers from. Shared with the `sample` filter of this context.
+    random: Arc<Mutex<Box<RandomSource>>>,
+
+    /// Tells the time for `now`, `today` and the `date` filter. Shared
+    /// with the `date` filter of this context.
+    clock: Arc<Mutex<Box<Clock>>>,
+
+    /// What happens when an output or tag fails to render.
+    error_mode: ErrorMode,
+
+    /// The errors kept in `ErrorMode::Warn`.
+    render_errors: Vec<Error>,
+
+    /// Called on each output or tag that fails to render, before the
+    /// error mode is. See `set_error_handler`.
+    error_handler: Option<Arc<Fn(&Error) -> Option<String>>>,
+
+    /// Problems found during the render that didn't stop it. Kept in a
+    /// cell since they are found by lookups, which don't change the
+    /// context otherwise.
+    render_warnings: RefCell<Vec<Warning>>,
+
+    /// Filters that give a warning when they are used.
+    deprecated_filters: HashSet<String>,
+
+    /// Told about each node as it is rendered. See `set_instrument`.
+    instrument: Option<Arc<Instrument>>,
+
+    /// When the render has to finish by. See `set_deadline`.
+    deadline: Option<Instant>,
+
+    /// Whether panics in filters and tags become render errors. See
+    /// `set_catch_panics`.
+    catch_panics: bool,
+
+    /// What has been done by the renders since the context was made or
+    /// last reset. Kept in a cell so that filters can be counted while one
+    /// is borrowed from the context.
+    stats: Cell<RenderStats>,
+
+    /// Whether a template is being rendered with the context, so that
+    /// templates rendered within it don't count their output again.
+    rendering: bool,
 
     // Public for backwards compatability
     pub filters: HashMap<String, Box<Filter>>
 }
 
+impl Default for Context {
+    fn default() -> Context {
+        Context::new()
+    }
+}
+
 impl Context {
     /// Creates a new, empty rendering context.
     ///
@@ -40,25 +382,288 @@ impl Context {
     /// assert_eq!(ctx.get_val("test"), None);
     /// ```
     pub fn new() -> Context {
-        Context::with_values_and_filters(HashMap::new(), HashMap::new())
+        Context::with_values_and_filters(Object::default(), HashMap::new())
     }
 
-    pub fn with_values(values: HashMap<String, Value>) -> Context {
+    pub fn with_values(values: Object) -> Context {
         Context::with_values_and_filters(values, HashMap::new())
     }
 
     pub fn with_filters(filters: HashMap<String, Box<Filter>>)
                         -> Context {
-        Context::with_values_and_filters(HashMap::new(), filters)
+        Context::with_values_and_filters(Object::default(), filters)
+    }
+
+    /// Creates a context whose globals are shared with other contexts.
+    /// Changing the globals of one context with `set_val` copies them
+    /// first, so the others aren't affected.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Value, Context, Object};
+    /// # use std::sync::Arc;
+    /// let mut globals = Object::default();
+    /// globals.insert("site".to_owned(), Value::str("example.com"));
+    /// let globals = Arc::new(globals);
+    ///
+    /// for _ in 0..2 {
+    ///     let ctx = Context::with_globals(globals.clone());
+    ///     assert_eq!(ctx.get_val("site"), Some(&Value::str("example.com")));
+    /// }
+    /// ```
+    pub fn with_globals(globals: Arc<Object>) -> Context {
+        let mut context = Context::new();
+        context.globals = globals;
+        context
+    }
+
+    /// The globals of the context, shared rather than copied. See
+    /// [get_global_path](#method.get_global_path).
+    pub fn shared_globals(&self) -> Arc<Object> {
+        self.globals.clone()
+    }
+
+    /// Clears everything a render leaves behind, such as assigned
+    /// variables, the state of `cycle` tags, the results of lazy values and
+    /// the statistics, so the context can be used for another render. The globals
+    /// and settings are kept.
+    pub fn reset(&mut self) {
+        self.stack = vec![Scope::default()];
+        self.interrupt = None;
+        self.loop_depth = 0;
+        self.registers.clear();
+        self.block_overrides.clear();
+        self.render_errors.clear();
+        self.render_warnings.borrow_mut().clear();
+        self.stats.set(RenderStats::default());
+        for lazy in self.lazy_globals.values_mut() {
+            lazy.value = OnceCell::new();
+        }
+    }
+
+    /// Takes a cheap copy of the globals and settings of the context, from
+    /// which any number of new contexts can be made. This lets a server
+    /// set up a base context once and make one for each request without
+    /// copying the globals. Filters added to the context, lazy values, the
+    /// handlers, the random source, the clock and the deadline aren't
+    /// kept; see [with_render_snapshot](#method.with_render_snapshot) for
+    /// a snapshot that keeps those that can be shared.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Value, Context};
+    /// let mut base = Context::new();
+    /// base.set_val("site", Value::str("example.com"));
+    /// base.set_strict_variables(true);
+    /// let snapshot = base.snapshot();
+    ///
+    /// let mut ctx = snapshot.context();
+    /// ctx.set_val("user", Value::str("ann"));
+    /// assert_eq!(ctx.get_val("site"), Some(&Value::str("example.com")));
+    /// assert!(ctx.strict_variables());
+    /// assert_eq!(snapshot.context().get_val("user"), None);
+    /// ```
+    pub fn snapshot(&self) -> ContextSnapshot {
+        ContextSnapshot {
+            globals: self.globals.clone(),
+            strict_variables: self.strict_variables,
+            strict_filters: self.strict_filters,
+            number_policy: self.number_policy,
+            escaper: self.escaper.clone(),
+            i18n: self.i18n.clone(),
+            error_mode: self.error_mode,
+            catch_panics: self.catch_panics,
+            instrument: self.instrument.clone(),
+            render: None,
+        }
+    }
+
+    /// Calls `f` with a snapshot like [snapshot](#method.snapshot) that also
+    /// keeps everything else a render with this context depends on that
+    /// can be shared between threads: the filters added to the context and
+    /// those of the template being rendered, the deprecated filters, the
+    /// random source, the clock and the deadline. Contexts made from it
+    /// render just as this one would, on any thread, so `f` can hand it to
+    /// other threads to render parts of a page at the same time. The
+    /// handlers and lazy values still aren't kept.
+    ///
+    /// The filters of the context are lent to the snapshot while `f` runs,
+    /// and given back when it returns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Value, Context};
+    /// use std::thread;
+    ///
+    /// let mut base = Context::new();
+    /// base.add_filter("shout", Box::new(|input, _| Ok(Value::Str(input.to_string() + "!"))));
+    /// let shouted = base.with_render_snapshot(|snapshot| {
+    ///     let snapshot = snapshot.clone();
+    ///     thread::spawn(move || {
+    ///         let ctx = snapshot.context();
+    ///         ctx.get_filter("shout").unwrap()(&Value::str("hi"), &[]).unwrap()
+    ///     }).join().unwrap()
+    /// });
+    /// assert_eq!(shouted, Value::str("hi!"));
+    /// assert!(base.get_filter("shout").is_some());
+    /// ```
+    pub fn with_render_snapshot<T, F>(&mut self, f: F) -> T
+        where F: FnOnce(&ContextSnapshot) -> T
+    {
+        let filters = Arc::new(::std::mem::replace(&mut self.filters, HashMap::new()));
+        let mut snapshot = self.snapshot();
+        snapshot.render = Some(RenderSettings {
+            filters: filters.clone(),
+            forked_filters: self.forked_filters.clone(),
+            shared_filters: self.shared_filters.clone(),
+            deprecated_filters: self.deprecated_filters.clone(),
+            random: self.random.clone(),
+            clock: self.clock.clone(),
+            deadline: self.deadline,
+        });
+        let result = f(&snapshot);
+        drop(snapshot);
+
+        self.filters = match Arc::try_unwrap(filters) {
+            Ok(filters) => filters,
+            // `f` kept a copy of the snapshot, so the filters can only be
+            // shared with it from now on
+            Err(filters) => calling_shared(&filters),
+        };
+        result
+    }
+
+    /// Makes a new context that carries on from this one, with the same
+    /// variables, globals, settings, filters, lazy values, handlers and
+    /// registers. Only the scope stack, the registers and the overrides of
+    /// `extends` are copied; the rest is shared, so this takes the same time
+    /// however much data the globals hold, and a server can set up a base
+    /// context once and fork it for each request.
+    ///
+    /// Changing the fork doesn't change this context, except through what
+    /// the two share on purpose: the random source, the clock and the cache
+    /// store. Lazy values already worked out aren't worked out again.
+    ///
+    /// The filters added to this context are moved into a table that it
+    /// shares with its forks, which is why forking needs `&mut self`.
+    /// `get_filter` still finds them, but the `filters` field no longer
+    /// holds them.
+    ///
+    /// The fork doesn't get what earlier renders left for the caller: the
+    /// errors kept in `ErrorMode::Warn`, the render warnings and the
+    /// statistics, which start out empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Value, Context};
+    /// let mut base = Context::new();
+    /// base.set_val("site", Value::str("example.com"));
+    /// base.add_filter("shout", Box::new(|input, _| Ok(Value::Str(input.to_string() + "!"))));
+    ///
+    /// let mut request = base.fork();
+    /// request.set_val("path", Value::str("/"));
+    /// assert_eq!(request.get_val("site"), Some(&Value::str("example.com")));
+    /// assert!(request.get_filter("shout").is_some());
+    /// assert_eq!(base.get_val("path"), None);
+    /// ```
+    pub fn fork(&mut self) -> Context {
+        if !self.filters.is_empty() {
+            let added = ::std::mem::replace(&mut self.filters, HashMap::new());
+            self.forked_filters.insert(0, Arc::new(added));
+        }
+        let mut context = Context {
+            stack: self.stack.clone(),
+            interrupt: self.interrupt.clone(),
+            loop_depth: self.loop_depth,
+            registers: self.registers
+                           .iter()
+                           .map(|(key, register)| (key.clone(), (**register).clone_register()))
+                           .collect(),
+            block_overrides: self.block_overrides.clone(),
+            cache_store: self.cache_store.clone(),
+            #[cfg(feature = "async")]
+            async_globals: self.async_globals.clone(),
+            #[cfg(feature = "async")]
+            async_cache_store: self.async_cache_store.clone(),
+            own_filters: own_filters(&self.random, &self.clock),
+            shared_filters: self.shared_filters.clone(),
+            forked_filters: self.forked_filters.clone(),
+            builtin_filters: self.builtin_filters,
+            number_policy: self.number_policy,
+            number_filters: self.number_filters,
+            globals: self.globals.clone(),
+            lazy_globals: self.lazy_globals.clone(),
+            strict_variables: self.strict_variables,
+            strict_filters: self.strict_filters,
+            undefined_handler: self.undefined_handler.clone(),
+            escaper: self.escaper.clone(),
+            i18n: None,
+            random: self.random.clone(),
+            clock: self.clock.clone(),
+            error_mode: self.error_mode,
+            error_handler: self.error_handler.clone(),
+            render_errors: vec![],
+            render_warnings: RefCell::new(vec![]),
+            deprecated_filters: self.deprecated_filters.clone(),
+            instrument: self.instrument.clone(),
+            deadline: self.deadline,
+            catch_panics: self.catch_panics,
+            stats: Cell::new(RenderStats::default()),
+            rendering: self.rendering,
+            filters: HashMap::new(),
+        };
+        if let Some(ref i18n) = self.i18n {
+            context.share_i18n(i18n.clone());
+        }
+        context
     }
 
-    pub fn with_values_and_filters(values: HashMap<String, Value>,
+    pub fn with_values_and_filters(values: Object,
                                    filters: HashMap<String, Box<Filter>>) -> Context {
+        let random: Arc<Mutex<Box<RandomSource>>> =
+            Arc::new(Mutex::new(Box::new(SeededRandom::from_time())));
+        let clock: Arc<Mutex<Box<Clock>>> = Arc::new(Mutex::new(Box::new(SystemClock)));
+        let own_filters = own_filters(&random, &clock);
         Context {
-            stack: vec!(HashMap::new()),
+            stack: vec!(Scope::default()),
             interrupt: None,
-            cycles: HashMap::new(),
-            globals: values,
+            loop_depth: 0,
+            registers: HashMap::new(),
+            block_overrides: HashMap::new(),
+            cache_store: None,
+            #[cfg(feature = "async")]
+            async_globals: HashMap::new(),
+            #[cfg(feature = "async")]
+            async_cache_store: None,
+            own_filters: own_filters,
+            shared_filters: None,
+            forked_filters: vec![],
+            builtin_filters: builtin_filters(),
+            number_policy: NumberPolicy::Strict,
+            number_filters: None,
+            globals: Arc::new(values),
+            lazy_globals: HashMap::new(),
+            strict_variables: false,
+            strict_filters: true,
+            undefined_handler: None,
+            escaper: None,
+            i18n: None,
+            random: random,
+            clock: clock,
+            error_mode: ErrorMode::Strict,
+            error_handler: None,
+            render_errors: vec![],
+            render_warnings: RefCell::new(vec![]),
+            deprecated_filters: HashSet::new(),
+            instrument: None,
+            deadline: None,
+            catch_panics: false,
+            stats: Cell::new(RenderStats::default()),
+            rendering: false,
             filters: filters
         }
     }
@@ -66,157 +671,1084 @@ impl Context {
     pub fn cycle_element(&mut self, name: &str, values: &[Token]) ->
             Result<Option<Value>> {
         let index = {
-            let i = self.cycles.entry(name.to_owned()).or_insert(0);
+            let i = self.register_or_insert_with("cycle", name, || 0usize);
             let j = *i;
             *i = (*i + 1) % values.len();
             j
         };
 
-        if index >= values.len() {
-            return Err(Error::Render(
-                format!("cycle index {} out of bounds {}", index, values.len()
-            )));
-        }
+        if index >= values.len() {
+            return Err(Error::Render(
+                format!("cycle index {} out of bounds {}", index, values.len()
+            )));
+        }
+
+        self.evaluate(&values[index])
+    }
+
+    /// Gets the state a tag stored under `name` with `set_register`, if
+    /// there is any and it has the type asked for.
+    pub fn get_register<T: Any>(&self, tag: &str, name: &str) -> Option<&T> {
+        self.registers
+            .get(&(tag.to_owned(), name.to_owned()))
+            .and_then(|r| (**r).as_any().downcast_ref())
+    }
+
+    pub fn get_register_mut<T: Any>(&mut self, tag: &str, name: &str) -> Option<&mut T> {
+        self.registers
+            .get_mut(&(tag.to_owned(), name.to_owned()))
+            .and_then(|r| (**r).as_any_mut().downcast_mut())
+    }
+
+    /// Stores some state for a tag, which lasts for as long as the context
+    /// does. Unlike variables, registers can't be seen by templates, and
+    /// can hold values of any type that can be cloned, which they are when
+    /// the context is forked. Each tag should use its own name as `tag`,
+    /// so that tags don't interfere with each other.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::Context;
+    /// let mut ctx = Context::new();
+    /// ctx.set_register("increment", "counter", 1usize);
+    /// *ctx.get_register_mut::<usize>("increment", "counter").unwrap() += 1;
+    /// assert_eq!(ctx.get_register::<usize>("increment", "counter"), Some(&2));
+    ///
+    /// // asking for the wrong type finds nothing
+    /// assert_eq!(ctx.get_register::<String>("increment", "counter"), None);
+    /// ```
+    pub fn set_register<T: Any + Clone>(&mut self, tag: &str, name: &str, value: T) {
+        self.registers.insert((tag.to_owned(), name.to_owned()), Box::new(value));
+    }
+
+    /// Gets a tag's state, first setting it to the result of `default` if
+    /// there isn't any of the right type.
+    pub fn register_or_insert_with<T, F>(&mut self, tag: &str, name: &str, default: F) -> &mut T
+        where T: Any + Clone,
+              F: FnOnce() -> T
+    {
+        let key = (tag.to_owned(), name.to_owned());
+        let fresh = match self.registers.get(&key) {
+            Some(r) => !(**r).as_any().is::<T>(),
+            None => true,
+        };
+        if fresh {
+            self.registers.insert(key.clone(), Box::new(default()));
+        }
+        self.registers.get_mut(&key).and_then(|r| (**r).as_any_mut().downcast_mut()).unwrap()
+    }
+
+    /// Removes a tag's state, returning it if it had the type asked for.
+    pub fn remove_register<T: Any>(&mut self, tag: &str, name: &str) -> Option<T> {
+        self.registers
+            .remove(&(tag.to_owned(), name.to_owned()))
+            .and_then(|r| r.into_any().downcast().ok())
+            .map(|r| *r)
+    }
+
+    /// What the `content_for` blocks rendered so far have added to the
+    /// named section.
+    pub fn content_for(&self, name: &str) -> Option<&str> {
+        self.get_register::<String>("content_for", name).map(|s| &s[..])
+    }
+
+    /// Registers an override for the named block. Overrides are consulted
+    /// in the order they were pushed, so the most-derived template must
+    /// push its overrides first.
+    pub fn push_block_override(&mut self, name: &str, template: Arc<Template>) {
+        self.block_overrides.entry(name.to_owned()).or_insert_with(Vec::new).push(template);
+    }
+
+    /// Removes the most recently pushed override for the named block.
+    pub fn pop_block_override(&mut self, name: &str) {
+        let empty = match self.block_overrides.get_mut(name) {
+            Some(overrides) => {
+                overrides.pop();
+                overrides.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.block_overrides.remove(name);
+        }
+    }
+
+    /// Fetches all of the overrides currently registered for the named
+    /// block, most-derived first.
+    pub fn block_overrides(&self, name: &str) -> Vec<Arc<Template>> {
+        self.block_overrides.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Sets the store used by `{% cache %}` blocks. Without a store,
+    /// cache blocks simply render their contents every time. Forks of the
+    /// context share its store.
+    pub fn set_cache_store(&mut self, store: Box<CacheStore>) {
+        self.cache_store = Some(Arc::from(store));
+    }
+
+    pub fn cache_store(&self) -> Option<&CacheStore> {
+        self.cache_store.as_ref().map(|s| &**s)
+    }
+
+    /// Sets the store used by `{% cache %}` blocks in renders started with
+    /// [Template::render_async](struct.Template.html#method.render_async),
+    /// in place of the one set with `set_cache_store`. Forks of the
+    /// context share it. Only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn set_async_cache_store(&mut self, store: Box<AsyncCacheStore>) {
+        self.async_cache_store = Some(Arc::from(store));
+    }
+
+    #[cfg(feature = "async")]
+    pub fn async_cache_store(&self) -> Option<&AsyncCacheStore> {
+        self.async_cache_store.as_ref().map(|s| &**s)
+    }
+
+    pub fn add_filter(&mut self, name: &str, filter: Box<Filter>) {
+        self.filters.insert(name.to_owned(), filter);
+    }
+
+    /// Looks up a filter, trying the filters registered with the parser
+    /// first, then those added to the context (or to the context it was
+    /// forked from), then those the context provides itself, then the
+    /// built-in ones.
+    pub fn get_filter<'b>(&'b self, name: &str) -> Option<&'b Box<Filter>> {
+        self.shared_filters
+            .as_ref()
+            .and_then(|shared| shared.get(name))
+            .or_else(|| self.filters.get(name))
+            .or_else(|| self.forked_filters.iter().filter_map(|f| f.get(name)).next())
+            .or_else(|| self.own_filters.get(name))
+            .or_else(|| self.number_filters.as_ref().and_then(|filters| filters.get(name)))
+            .or_else(|| self.builtin_filters.get(name))
+    }
+
+    /// How the named filter is called, such as
+    /// `replace: <search>, <replacement>`, when it is a built-in one that
+    /// hasn't been replaced by a filter registered with the parser or
+    /// added to the context.
+    pub fn filter_signature(&self, name: &str) -> Option<String> {
+        let shared = self.shared_filters.as_ref().map_or(false, |s| s.contains_key(name));
+        let forked = self.forked_filters.iter().any(|f| f.contains_key(name));
+        if shared || forked || self.filters.contains_key(name) {
+            return None;
+        }
+        filters::signature(name)
+    }
+
+    /// Sets the filters shared by all templates from a `Parser`.
+    pub fn set_shared_filters(&mut self, filters: Arc<HashMap<String, Box<Filter>>>) {
+        self.shared_filters = Some(filters);
+    }
+
+    /// Sets or clears the filters shared by all templates from a `Parser`,
+    /// returning the ones they replace.
+    pub fn replace_shared_filters(&mut self,
+                                  filters: Option<Arc<HashMap<String, Box<Filter>>>>)
+                                  -> Option<Arc<HashMap<String, Box<Filter>>>> {
+        ::std::mem::replace(&mut self.shared_filters, filters)
+    }
+
+    pub fn interrupted(&self) -> bool {
+        self.interrupt.is_some()
+    }
+
+    /// Sets the interrupt state. Any previous state is obliterated.
+    pub fn set_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt = Some(interrupt);
+    }
+
+    /// Fetches and clears the interrupt state.
+    pub fn pop_interrupt(&mut self) -> Option<Interrupt> {
+        let rval = self.interrupt.clone();
+        self.interrupt = None;
+        rval
+    }
+
+    /// Returns true if rendering is currently happening inside the body of
+    /// a loop, and therefore that an interrupt can be handled.
+    pub fn in_loop(&self) -> bool {
+        self.loop_depth > 0
+    }
+
+    /// Executes the supplied function as the body of a loop. Any interrupts
+    /// raised while rendering inside the function will be consumed by the
+    /// loop and cannot escape it.
+    pub fn run_in_loop<RvalT, FnT>(&mut self, f: FnT) -> RvalT
+        where FnT : FnOnce(&mut Context) -> RvalT {
+        self.loop_depth += 1;
+        let result = f(self);
+        self.loop_depth -= 1;
+        self.interrupt = None;
+        result
+    }
+
+    /// Creates a new variable scope chained to a parent scope. Variables
+    /// set with `set_local_val` go into the new scope, shadowing any of the
+    /// same name in the scopes below it, until it is removed with
+    /// `pop_scope`. Prefer `run_in_scope` where possible, which can't leave
+    /// a scope behind.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Value, Context};
+    /// let mut ctx = Context::new();
+    /// ctx.set_val("item", Value::str("global"));
+    ///
+    /// ctx.push_scope();
+    /// ctx.set_local_val("item", Value::str("outer"));
+    /// ctx.push_scope();
+    /// ctx.set_local_val("item", Value::str("inner"));
+    /// assert_eq!(ctx.get_val("item"), Some(&Value::str("inner")));
+    ///
+    /// ctx.pop_scope();
+    /// assert_eq!(ctx.get_val("item"), Some(&Value::str("outer")));
+    /// ctx.pop_scope();
+    /// assert_eq!(ctx.get_val("item"), Some(&Value::str("global")));
+    /// ```
+    pub fn push_scope(&mut self) {
+        self.stack.push(Scope::default());
+    }
+
+    /// Removes the scope most recently created with `push_scope`, along
+    /// with every variable set in it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no such scope. The scope that a context is
+    /// created with holds the variables set by `assign_val`, and can't be
+    /// removed.
+    pub fn pop_scope(&mut self) {
+        if self.stack.len() <= 1 {
+            panic!("Pop leaves empty stack")
+        }
+        self.stack.pop();
+    }
+
+    /// Sets up a new stack frame, executes the supplied function and then
+    /// tears the stack frame down before returning the function's result
+    /// to the caller.
+    ///
+    /// # Examples
+    /// ```
+    /// # use liquid::{Value, Context};
+    /// let mut ctx = Context::new();
+    /// ctx.set_val("test", Value::Num(42f32));
+    /// ctx.run_in_scope(|mut stack_frame| {
+    ///   // stack_frame inherits values from its parent context
+    ///   assert_eq!(stack_frame.get_val("test"), Some(&Value::Num(42f32)));
+    ///
+    ///   // but can (optionally) override them
+    ///   stack_frame.set_local_val("test", Value::Num(3.14f32));
+    ///   assert_eq!(stack_frame.get_val("test"), Some(&Value::Num(3.14f32)));
+    /// });
+    /// // the original value is unchanged once the scope exits
+    /// assert_eq!(ctx.get_val("test"), Some(&Value::Num(42f32)));
+    /// ```
+    pub fn run_in_scope<RvalT, FnT>(&mut self, f: FnT) -> RvalT
+        where FnT : FnOnce(&mut Context) -> RvalT {
+        self.push_scope();
+        let result = f(self);
+        self.pop_scope();
+        result
+    }
+
+    /// Internal part of get_val. Walks the scope stack to try and find the
+    /// reqested variable, and failing that checks the global pool.
+    fn get<'a>(&'a self, name: &Symbol) -> Option<&'a Value> {
+        for frame in self.stack.iter().rev() {
+            if let Some(rval) = frame.get(name) {
+                return rval.as_ref();
+            }
+        }
+        self.globals.get(&name[..]).or_else(|| {
+            self.lazy_globals
+                .get(&name[..])
+                .map(|lazy| lazy.value.get_or_init(|| (lazy.provider)(self)))
+        })
+    }
+
+    /// Gets a value from the rendering context. The name value can be a
+    /// path to a value, made up of `.key` lookups into Objects and
+    /// `[index]` lookups into Arrays or Objects. An index may be a number
+    /// (negative numbers count back from the end of an Array), a quoted
+    /// key, or the path of another variable holding either. A value will
+    /// only be returned if every link in the chain resolves.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Value, Context};
+    /// let mut ctx = Context::new();
+    /// ctx.set_val("test", Value::Num(42f32));
+    /// assert_eq!(ctx.get_val("test").unwrap(), &Value::Num(42f32));
+    ///
+    /// ctx.set_val("list", Value::Array(vec![Value::str("a"), Value::str("b")]));
+    /// ctx.set_val("i", Value::Num(1f32));
+    /// assert_eq!(ctx.get_val("list[i]").unwrap(), &Value::str("b"));
+    /// ```
+    pub fn get_val<'b>(&'b self, name: &str) -> Option<&'b Value> {
+        self.get_path(&VariablePath::new(name))
+    }
+
+    /// Gets the value at a path that has already been split into its
+    /// parts, like [get_val](#method.get_val).
+    pub fn get_path<'b>(&'b self, path: &VariablePath) -> Option<&'b Value> {
+        let rval = match path.root {
+            Some(ref root) => self.get(root),
+            None => return None,
+        };
+        self.walk_path(rval, path.parts.iter())
+    }
+
+    /// Gets the value at a path like [get_path](#method.get_path), but only
+    /// if it is one of `globals`, which should come from
+    /// [shared_globals](#method.shared_globals), and isn't hidden by a variable set in a
+    /// scope. The value is borrowed from `globals` rather than the context,
+    /// so the context can still be changed while it is in use.
+    pub fn get_global_path<'v>(&self,
+                               globals: &'v Object,
+                               path: &VariablePath)
+                               -> Option<&'v Value> {
+        let root = match path.root {
+            Some(ref root) => root,
+            None => return None,
+        };
+        if self.stack.iter().any(|frame| frame.contains_key(root)) {
+            return None;
+        }
+        self.walk_path(globals.get(&root[..]), path.parts.iter())
+    }
+
+    /// Follows the rest of a path on from the value of its first part.
+    fn walk_path<'v>(&self, mut rval: Option<&'v Value>, parts: slice::Iter<PathPart>)
+                     -> Option<&'v Value> {
+        // walk the chain of values, as specified by the path
+        for part in parts {
+            let value = match rval {
+                Some(value) => value,
+                None => return None,
+            };
+            rval = match *part {
+                PathPart::Key(ref key) => by_key(value, key),
+                PathPart::Position(n) => by_position(value, n),
+                PathPart::Variable(ref index) => {
+                    match self.get_path(index) {
+                        Some(&Value::Str(ref key)) |
+                        Some(&Value::Safe(ref key)) => by_key(value, key),
+                        Some(&Value::Num(n)) => by_position(value, n),
+                        _ => None,
+                    }
+                }
+            };
+        }
+
+        rval
+    }
+
+    /// Looks up a value like [get_val](#method.get_val), as templates do.
+    /// When a path doesn't lead to a value, the undefined handler is asked
+    /// for one instead, and failing that, it is an error naming the path if
+    /// strict variables are on. A variable that has been assigned `nil` is
+    /// still defined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Value, Context};
+    /// let mut ctx = Context::new();
+    /// ctx.set_val("page", Value::Object(Default::default()));
+    /// assert_eq!(ctx.lookup("page.title").unwrap(), None);
+    ///
+    /// ctx.set_strict_variables(true);
+    /// assert!(ctx.lookup("page.title").is_err());
+    /// ```
+    pub fn lookup<'b>(&'b self, name: &str) -> Result<Option<Cow<'b, Value>>> {
+        self.lookup_path(&VariablePath::new(name))
+    }
+
+    /// Looks up a path that has already been split into its parts, like
+    /// [lookup](#method.lookup).
+    pub fn lookup_path<'b>(&'b self, path: &VariablePath) -> Result<Option<Cow<'b, Value>>> {
+        if let Some(value) = self.get_path(path) {
+            return Ok(Some(Cow::Borrowed(value)));
+        }
+        if self.is_assigned_nil(&path.name) {
+            return Ok(None);
+        }
+        let name = path.name();
+        if name == "now" || name == "today" {
+            let now = DateTime::from_system_time(self.now());
+            let format = if name == "now" { "%Y-%m-%d %H:%M:%S %z" } else { "%Y-%m-%d" };
+            return Ok(Some(Cow::Owned(Value::Str(now.format(format)))));
+        }
+        if let Some(ref handler) = self.undefined_handler {
+            if let Some(value) = try!(handler(name)) {
+                return Ok(Some(Cow::Owned(value)));
+            }
+        }
+        if self.strict_variables {
+            return Err(Error::UndefinedVariable(name.to_owned()));
+        }
+        self.warn(WarningKind::UndefinedVariable(name.to_owned()));
+        Ok(None)
+    }
+
+    /// Sets a function to call whenever a template looks up a variable
+    /// that isn't defined, with the full path that was looked up. It can
+    /// return a value to use instead, `None` to carry on as if it weren't
+    /// there, or an error to fail the render. This is handy for logging
+    /// which variables templates rely on before turning on strict
+    /// variables.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Value, Context};
+    /// let mut ctx = Context::new();
+    /// ctx.set_undefined_handler(|path| Ok(Some(Value::str(&format!("<{}>", path)))));
+    /// assert_eq!(ctx.lookup("user.name").unwrap().unwrap().into_owned(),
+    ///            Value::str("<user.name>"));
+    /// ```
+    pub fn set_undefined_handler<F>(&mut self, handler: F)
+        where F: Fn(&str) -> Result<Option<Value>> + 'static
+    {
+        self.undefined_handler = Some(Arc::new(handler));
+    }
+
+    /// Whether the named variable has been assigned `nil`.
+    fn is_assigned_nil(&self, name: &Symbol) -> bool {
+        for frame in self.stack.iter().rev() {
+            if let Some(val) = frame.get(name) {
+                return val.is_none();
+            }
+        }
+        false
+    }
+
+    /// Makes looking up a variable that isn't defined an error, rather than
+    /// rendering as nothing. Tags that only check whether a variable is
+    /// set, like `{% if user %}`, are affected too.
+    pub fn set_strict_variables(&mut self, strict: bool) {
+        self.strict_variables = strict;
+    }
+
+    pub fn strict_variables(&self) -> bool {
+        self.strict_variables
+    }
+
+    /// Whether using a filter that hasn't been registered fails the render,
+    /// naming the filter. This is on by default; when off, the filter is
+    /// skipped and the value passed through unchanged, as Ruby Liquid does.
+    pub fn set_strict_filters(&mut self, strict: bool) {
+        self.strict_filters = strict;
+    }
+
+    pub fn strict_filters(&self) -> bool {
+        self.strict_filters
+    }
+
+    /// Sets what the built-in arithmetic filters do about dividing by zero
+    /// and about results that are infinite or not a number. This is
+    /// `NumberPolicy::Strict` by default, which makes them errors. See
+    /// [NumberPolicy](enum.NumberPolicy.html).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Context, LiquidOptions, NumberPolicy, Renderable};
+    /// let template = liquid::parse("{{ 1.5 | divided_by: 0 }}", LiquidOptions::default())
+    ///     .unwrap();
+    /// let mut ctx = Context::new();
+    /// assert!(template.render(&mut ctx).is_err());
+    /// ctx.set_number_policy(NumberPolicy::Ruby);
+    /// assert_eq!(template.render(&mut ctx).unwrap(), Some("inf".to_owned()));
+    /// ```
+    pub fn set_number_policy(&mut self, policy: NumberPolicy) {
+        self.number_policy = policy;
+        self.number_filters = filters::number_filters(policy);
+    }
+
+    pub fn number_policy(&self) -> NumberPolicy {
+        self.number_policy
+    }
+
+    /// Sets where the `sample` filter and other random choices get their
+    /// numbers from.
+    pub fn set_random(&mut self, random: Box<RandomSource>) {
+        *locked(&self.random) = random;
+    }
+
+    /// Reseeds the default random source, so that random choices are the
+    /// same from one render to the next.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.set_random(Box::new(SeededRandom::new(seed)));
+    }
+
+    /// Picks a number from `0` up to, but not including, `n`, for tags that
+    /// make random choices.
+    pub fn random_below(&self, n: usize) -> usize {
+        locked(&self.random).below(n)
+    }
+
+    /// Sets the clock that gives the time for the `now` and `today`
+    /// variables and `"now"` in the `date` filter.
+    pub fn set_clock(&mut self, clock: Box<Clock>) {
+        *locked(&self.clock) = clock;
+    }
+
+    /// The current time according to the context's clock.
+    pub fn now(&self) -> SystemTime {
+        locked(&self.clock).now()
+    }
+
+    /// Sets what happens when an output or tag fails to render. This is
+    /// `ErrorMode::Strict` by default, which fails the whole render. See
+    /// [ErrorMode](enum.ErrorMode.html).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Context, ErrorMode, LiquidOptions, Renderable};
+    /// let template = liquid::parse("a{{ 1 | divided_by: 'x' }}b", LiquidOptions::default())
+    ///     .unwrap();
+    /// let mut ctx = Context::new();
+    /// ctx.set_error_mode(ErrorMode::Warn);
+    /// assert_eq!(template.render(&mut ctx).unwrap(), Some("ab".to_owned()));
+    /// assert_eq!(ctx.render_errors().len(), 1);
+    /// ```
+    pub fn set_error_mode(&mut self, mode: ErrorMode) {
+        self.error_mode = mode;
+    }
+
+    pub fn error_mode(&self) -> ErrorMode {
+        self.error_mode
+    }
+
+    /// What has been done by the renders since the context was made or
+    /// last reset. See [RenderStats](struct.RenderStats.html).
+    pub fn stats(&self) -> RenderStats {
+        self.stats.get()
+    }
+
+    /// Adds to the statistics of the context, as tags do while they render.
+    pub fn update_stats<F: FnOnce(&mut RenderStats)>(&self, update: F) {
+        let mut stats = self.stats.get();
+        update(&mut stats);
+        self.stats.set(stats);
+    }
+
+    /// Marks the start of rendering a template with the context, returning
+    /// whether it is the outermost one. Each call that returns `true` must
+    /// be matched by a call to `finish_rendering`.
+    pub fn start_rendering(&mut self) -> bool {
+        !::std::mem::replace(&mut self.rendering, true)
+    }
+
+    /// Marks the end of rendering the outermost template.
+    pub fn finish_rendering(&mut self) {
+        self.rendering = false;
+    }
+
+    /// Whether the output of a node can be written out bit by bit as it
+    /// is rendered. It can't when something might want the whole output of
+    /// a node once it is rendered, or want to replace it if the node fails:
+    /// an instrument, an error handler, or an error mode other than
+    /// `Strict`.
+    pub fn can_stream(&self) -> bool {
+        self.instrument.is_none() && self.error_handler.is_none() &&
+        self.error_mode == ErrorMode::Strict
+    }
+
+    /// The errors from outputs and tags that failed in `ErrorMode::Warn`,
+    /// in the order they happened.
+    pub fn render_errors(&self) -> &[Error] {
+        &self.render_errors
+    }
+
+    /// Takes the errors kept in `ErrorMode::Warn`, leaving none.
+    pub fn take_render_errors(&mut self) -> Vec<Error> {
+        ::std::mem::replace(&mut self.render_errors, vec![])
+    }
+
+    /// Keeps an error as though an output or tag had failed with it in
+    /// `ErrorMode::Warn`, such as for a custom block that carries on past
+    /// a problem of its own.
+    pub fn keep_render_error(&mut self, err: Error) {
+        self.render_errors.push(err);
+    }
+
+    /// Sets a function to call with the error whenever an output or tag
+    /// fails to render, such as to report it. When it returns some text,
+    /// that is rendered in place of the output or tag and the render goes
+    /// on, whatever the error mode. When it returns `None`, the error
+    /// mode decides what happens. Running out of time or going over a
+    /// limit always stops the render without calling it.
+    ///
+    /// The error has the span of the failing markup, but its line and
+    /// column aren't resolved yet.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Context, LiquidOptions, Renderable};
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    ///
+    /// let template = liquid::parse("a{{ 1 | divided_by: 'x' }}b", LiquidOptions::default())
+    ///     .unwrap();
+    /// let reported = Rc::new(RefCell::new(vec![]));
+    /// let log = reported.clone();
+    /// let mut ctx = Context::new();
+    /// ctx.set_error_handler(move |err| {
+    ///     log.borrow_mut().push(err.kind());
+    ///     Some(String::new())
+    /// });
+    /// assert_eq!(template.render(&mut ctx).unwrap(), Some("ab".to_owned()));
+    /// assert_eq!(reported.borrow().len(), 1);
+    /// ```
+    pub fn set_error_handler<F>(&mut self, handler: F)
+        where F: Fn(&Error) -> Option<String> + 'static
+    {
+        self.error_handler = Some(Arc::new(handler));
+    }
+
+    /// Removes the function set with `set_error_handler`.
+    pub fn clear_error_handler(&mut self) {
+        self.error_handler = None;
+    }
+
+    /// Deals with an output or tag that failed to render according to the
+    /// error handler and error mode, returning what to render in its place
+    /// or the error if the render should stop.
+    pub fn recover(&mut self, err: Error) -> Result<Option<String>> {
+        if err.is_timeout() || err.is_limit() {
+            return Err(err);
+        }
+        if let Some(ref handler) = self.error_handler {
+            if let Some(text) = handler(&err) {
+                return Ok(Some(text));
+            }
+        }
+        match self.error_mode {
+            ErrorMode::Strict => Err(err),
+            ErrorMode::Warn => {
+                self.render_errors.push(err);
+                Ok(None)
+            }
+            ErrorMode::Lax => Ok(Some(format!("Liquid error: {}", err.inner()))),
+        }
+    }
+
+    /// Resolves the location of the errors kept since the first `from`
+    /// against the source of the template that raised them.
+    pub fn resolve_render_errors(&mut self, from: usize, source: &str, template: Option<&str>) {
+        let errors: Vec<Error> = self.render_errors.drain(from..).collect();
+        self.render_errors
+            .extend(errors.into_iter().map(|e| e.resolve_location(source, template)));
+    }
+
+    /// Records a problem that doesn't stop the render. It is given the
+    /// location of the output or tag being rendered.
+    pub fn warn(&self, kind: WarningKind) {
+        self.render_warnings.borrow_mut().push(Warning {
+            kind: kind,
+            location: Default::default(),
+        });
+    }
+
+    /// The problems found so far that didn't stop the render, such as
+    /// undefined variables when strict variables are off, unknown filters
+    /// when strict filters are off and deprecated filters.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Context, LiquidOptions, Renderable};
+    /// let template = liquid::parse("Hi {{ name }}", LiquidOptions::default()).unwrap();
+    /// let mut ctx = Context::new();
+    /// assert_eq!(template.render(&mut ctx).unwrap(), Some("Hi ".to_owned()));
+    /// assert_eq!(ctx.render_warnings()[0].to_string(),
+    ///            "line 1, column 4: Undefined variable name");
+    /// ```
+    pub fn render_warnings(&self) -> Vec<Warning> {
+        self.render_warnings.borrow().clone()
+    }
+
+    /// Takes the warnings found so far, leaving none.
+    pub fn take_render_warnings(&mut self) -> Vec<Warning> {
+        ::std::mem::replace(&mut *self.render_warnings.borrow_mut(), vec![])
+    }
+
+    /// Keeps warnings found elsewhere, such as while rendering a section
+    /// of the page with another context, as though they were found by
+    /// this one.
+    pub fn keep_render_warnings(&mut self, warnings: Vec<Warning>) {
+        self.render_warnings.borrow_mut().extend(warnings);
+    }
+
+    /// Makes using the named filter give a `DeprecatedFilter` warning.
+    pub fn deprecate_filter(&mut self, name: &str) {
+        self.deprecated_filters.insert(name.to_owned());
+    }
+
+    pub fn is_deprecated_filter(&self, name: &str) -> bool {
+        self.deprecated_filters.contains(name)
+    }
+
+    /// How many warnings have been recorded, for use with
+    /// `locate_render_warnings`.
+    pub fn render_warning_count(&self) -> usize {
+        self.render_warnings.borrow().len()
+    }
+
+    /// Gives the warnings recorded since the first `from` that don't have
+    /// a location yet the span of the markup being rendered.
+    pub fn locate_render_warnings(&self, from: usize, span: Span) {
+        for warning in self.render_warnings.borrow_mut().iter_mut().skip(from) {
+            if warning.location.span == Span::default() {
+                warning.location.span = span;
+            }
+        }
+    }
+
+    /// Resolves the location of the warnings recorded since the first
+    /// `from` against the source of the template that raised them.
+    pub fn resolve_render_warnings(&self, from: usize, source: &str, template: Option<&str>) {
+        for warning in self.render_warnings.borrow_mut().iter_mut().skip(from) {
+            let location = &mut warning.location;
+            if location.line == 0 && location.span != Span::default() {
+                location.resolve(source);
+            }
+            if location.template.is_none() {
+                location.template = template.map(|t| t.to_owned());
+            }
+        }
+    }
+
+    /// Sets the [Instrument](trait.Instrument.html) to call around the
+    /// rendering of every output, tag and block, or stops calling one
+    /// with `None`.
+    pub fn set_instrument(&mut self, instrument: Option<Arc<Instrument>>) {
+        self.instrument = instrument;
+    }
+
+    pub fn instrument(&self) -> Option<Arc<Instrument>> {
+        self.instrument.clone()
+    }
+
+    /// Turns panics in filters, tags and blocks into `Error::Panic`
+    /// errors holding the panic message, so that a faulty plugin fails
+    /// the render, or just its own markup in a lenient error mode, rather
+    /// than the thread. This is off by default.
+    ///
+    /// The panic is still reported by the panic hook, and the context may
+    /// be left part way through a block, so `reset` it before rendering
+    /// with it again.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Context, Error, LiquidOptions, Renderable, Value};
+    /// let template = liquid::parse("{{ 'x' | explode }}", LiquidOptions::default()).unwrap();
+    /// let mut ctx = Context::new();
+    /// ctx.add_filter("explode", Box::new(|_: &Value, _: &[Value]| panic!("boom")));
+    /// ctx.set_catch_panics(true);
+    /// match *template.render(&mut ctx).unwrap_err().inner() {
+    ///     Error::Panic(ref message) => assert_eq!(message, "in filter 'explode': boom"),
+    ///     ref other => panic!("{}", other),
+    /// }
+    /// ```
+    pub fn set_catch_panics(&mut self, catch: bool) {
+        self.catch_panics = catch;
+    }
+
+    pub fn catch_panics(&self) -> bool {
+        self.catch_panics
+    }
+
+    /// Stops the render with `Error::RenderTimeout` if it is still going
+    /// at the given time, so that a pathological template can't tie up a
+    /// server. The deadline is checked before each element of a block and
+    /// each iteration of a loop, so a single slow filter can still run
+    /// past it.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Sets the deadline to the given time from now.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.deadline = Some(Instant::now() + timeout);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Fails with `Error::RenderTimeout` if the deadline has passed.
+    pub fn check_deadline(&self) -> Result<()> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Error::RenderTimeout),
+            _ => Ok(()),
+        }
+    }
+
+    /// HTML-escapes the result of every `{{ }}` output, so that values
+    /// can't inject markup into the page. Outputs that end with the `raw`
+    /// filter, variables set by `capture` and values made with
+    /// [SafeStr](struct.SafeStr.html) are left as they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Context, LiquidOptions, Renderable};
+    /// let template = liquid::parse("{{ html }} {{ html | raw }}", LiquidOptions::default());
+    /// let mut ctx = Context::new();
+    /// ctx.set_val("html", "<b>");
+    /// ctx.set_autoescape(true);
+    /// assert_eq!(template.unwrap().render(&mut ctx).unwrap(),
+    ///            Some("&lt;b&gt; <b>".to_owned()));
+    /// ```
+    pub fn set_autoescape(&mut self, autoescape: bool) {
+        self.escaper = if autoescape {
+            Some(Arc::new(HtmlEscaper))
+        } else {
+            None
+        };
+    }
+
+    /// Whether outputs are being escaped, for HTML or otherwise.
+    pub fn autoescape(&self) -> bool {
+        self.escaper.is_some()
+    }
 
-        self.evaluate(&values[index])
+    /// Sets the locale and translations for the render, and provides the
+    /// `t` and `l` filters that use them, along with a `date` filter that
+    /// uses the locale's date format. Filters of the same names added to
+    /// the context or registered with the parser are still used instead.
+    /// See [I18n](struct.I18n.html).
+    pub fn set_i18n(&mut self, i18n: I18n) {
+        self.share_i18n(Arc::new(i18n));
     }
 
-    pub fn add_filter(&mut self, name: &str, filter: Box<Filter>) {
-        self.filters.insert(name.to_owned(), filter);
+    /// Sets translations that are shared with other contexts, as
+    /// `set_i18n` does.
+    fn share_i18n(&mut self, i18n: Arc<I18n>) {
+        let t = i18n.clone();
+        self.own_filters.insert("t".to_owned(), Box::new(move |input, args| t.t_filter(input, args)));
+        let l = i18n.clone();
+        self.own_filters.insert("l".to_owned(), Box::new(move |input, args| l.l_filter(input, args)));
+        let date = date_filter(self.clock.clone(), &i18n.format().date_format);
+        self.own_filters.insert("date".to_owned(), date);
+        self.i18n = Some(i18n);
     }
 
-    pub fn get_filter<'b>(&'b self, name: &str) -> Option<&'b Box<Filter>> {
-        self.filters.get(name)
+    pub fn i18n(&self) -> Option<&I18n> {
+        self.i18n.as_ref().map(|i| &**i)
     }
 
-    pub fn interrupted(&self) -> bool {
-        self.interrupt.is_some()
+    /// Changes the current locale, keeping the translations. Does nothing
+    /// if `set_i18n` hasn't been called.
+    pub fn set_locale(&mut self, locale: &str) {
+        let i18n = self.i18n.take().map(|i18n| {
+            let mut i18n = (*i18n).clone();
+            i18n.set_locale(locale);
+            i18n
+        });
+        if let Some(i18n) = i18n {
+            self.set_i18n(i18n);
+        }
     }
 
-    /// Sets the interrupt state. Any previous state is obliterated.
-    pub fn set_interrupt(&mut self, interrupt: Interrupt) {
-        self.interrupt = Some(interrupt);
+    /// Escapes the result of every `{{ }}` output with the given
+    /// [Escaper](trait.Escaper.html), or turns escaping off with `None`.
+    pub fn set_escaper(&mut self, escaper: Option<Arc<Escaper>>) {
+        self.escaper = escaper;
     }
 
-    /// Fetches and clears the interrupt state.
-    pub fn pop_interrupt(&mut self) -> Option<Interrupt> {
-        let rval = self.interrupt.clone();
-        self.interrupt = None;
-        rval
+    pub fn escaper(&self) -> Option<&Escaper> {
+        self.escaper.as_ref().map(|e| &**e)
     }
 
-    /// Creates a new variable scope chained to a parent scope.
-    fn push_scope(&mut self) {
-        self.stack.push(HashMap::new());
+    /// Sets the escaper, returning the one it replaces.
+    pub fn replace_escaper(&mut self, escaper: Option<Arc<Escaper>>) -> Option<Arc<Escaper>> {
+        ::std::mem::replace(&mut self.escaper, escaper)
     }
 
-    /// Removes the topmost stack frame from the local variable stack.
-    ///
-    /// # Panics
-    ///
-    /// This method will panic if popping the topmost frame results in an
-    /// empty stack. Given that a context is created with a top-level stack
-    /// frame already in place, empyting the stack should never happen in a
-    /// well-formed program.
-    fn pop_scope(&mut self) {
-        if let None = self.stack.pop() {
-            panic!("Pop leaves empty stack")
-        };
+    fn evaluate_range_end(&self, t: &Token) -> Result<isize> {
+        match try!(self.evaluate(t)) {
+            Some(Value::Num(n)) => Ok(n as isize),
+            Some(_) => Err(Error::Render(format!("{} is not a number.", t))),
+            None => Err(Error::Render(format!("No such value: {}", t))),
+        }
     }
 
-    /// Sets up a new stack frame, executes the supplied function and then
-    /// tears the stack frame down before returning the function's result
-    /// to the caller.
+    /// Sets a value in the global context. Anything that converts into a
+    /// `Value` can be given, and calls can be chained.
     ///
     /// # Examples
+    ///
     /// ```
     /// # use liquid::{Value, Context};
     /// let mut ctx = Context::new();
-    /// ctx.set_val("test", Value::Num(42f32));
-    /// ctx.run_in_scope(|mut stack_frame| {
-    ///   // stack_frame inherits values from its parent context
-    ///   assert_eq!(stack_frame.get_val("test"), Some(&Value::Num(42f32)));
-    ///
-    ///   // but can (optionally) override them
-    ///   stack_frame.set_local_val("test", Value::Num(3.14f32));
-    ///   assert_eq!(stack_frame.get_val("test"), Some(&Value::Num(3.14f32)));
-    /// });
-    /// // the original value is unchanged once the scope exits
+    /// ctx.set_val("test", Value::Num(42f32))
+    ///    .set_val("name", "world")
+    ///    .set_val("tags", vec!["a", "b"]);
     /// assert_eq!(ctx.get_val("test"), Some(&Value::Num(42f32)));
+    /// assert_eq!(ctx.get_val("name"), Some(&Value::str("world")));
     /// ```
-    pub fn run_in_scope<RvalT, FnT>(&mut self, f: FnT) -> RvalT
-        where FnT : FnOnce(&mut Context) -> RvalT {
-        self.push_scope();
-        let result = f(self);
-        self.pop_scope();
-        result
+    pub fn set_val<V: Into<Value>>(&mut self, name: &str, val: V) -> &mut Context {
+        Arc::make_mut(&mut self.globals).insert(name.to_owned(), val.into());
+        self
     }
 
-    /// Internal part of get_val. Walks the scope stack to try and find the
-    /// reqested variable, and failing that checks the global pool.
-    fn get<'a>(&'a self, name: &str) -> Option<&'a Value> {
-        for frame in self.stack.iter().rev() {
-            if let rval @ Some(_) = frame.get(name) {
-                return rval;
-            }
-        }
-        self.globals.get(name)
+    /// Sets a global whose value is only worked out by calling `provider`
+    /// if and when a template uses it, and then only once. Values set with
+    /// `set_val` take precedence.
+    ///
+    /// The provider can look up other values in the context, but not the
+    /// one it provides.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Value, Context};
+    /// let mut ctx = Context::new();
+    /// ctx.set_val("count", 2);
+    /// ctx.set_lazy_val("sidebar", |ctx| {
+    ///     let count = ctx.get_val("count").cloned().unwrap_or(Value::Num(0f32));
+    ///     Value::str(&format!("{} items", count.to_string()))
+    /// });
+    /// assert_eq!(ctx.get_val("sidebar"), Some(&Value::str("2 items")));
+    /// ```
+    pub fn set_lazy_val<F>(&mut self, name: &str, provider: F) -> &mut Context
+        where F: Fn(&Context) -> Value + 'static
+    {
+        self.lazy_globals.insert(name.to_owned(),
+                                 LazyValue {
+                                     provider: Arc::new(provider),
+                                     value: OnceCell::new(),
+                                 });
+        self
     }
 
-    /// Gets a value from the rendering context. The name value can be a
-    /// dot-separated path to a value. A value will only be returned if
-    /// each link in the chain (excluding the final name) refers to a
-    /// value of type Object.
+    /// Sets a global whose value is fetched without blocking, by the future
+    /// `provider` returns. It is only fetched once a render started with
+    /// [Template::render_async](struct.Template.html#method.render_async)
+    /// turns out to use it, and is then set on the context as `set_val`
+    /// would set it. Other renders don't see it. Only available with the
+    /// `async` feature.
     ///
     /// # Examples
     ///
     /// ```
     /// # use liquid::{Value, Context};
+    /// use std::future;
+    ///
     /// let mut ctx = Context::new();
-    /// ctx.set_val("test", Value::Num(42f32));
-    /// assert_eq!(ctx.get_val("test").unwrap(), &Value::Num(42f32));
+    /// ctx.set_async_val("user", |_ctx| Box::pin(future::ready(Value::str("Ann"))));
+    /// assert_eq!(ctx.get_val("user"), None);
     /// ```
-    pub fn get_val<'b>(&'b self, name: &str) -> Option<&'b Value> {
-        let mut path = name.split('.');
-        let key = path.next().unwrap_or("");
-        let mut rval = self.get(key);
-
-        // walk the chain of Object values, as specified by the path
-        // passed in name
-        for id in path {
-            match rval {
-                Some(&Value::Object(ref x)) => rval = x.get(id),
-                _ => return None,
-            }
-        }
+    #[cfg(feature = "async")]
+    pub fn set_async_val<F>(&mut self, name: &str, provider: F) -> &mut Context
+        where F: Fn(&Context) -> BoxFuture<Value> + 'static
+    {
+        self.async_globals.insert(name.to_owned(), Arc::new(provider));
+        self
+    }
 
-        rval
+    /// Removes a value from the global context, returning it if it was
+    /// set.
+    pub fn remove_val(&mut self, name: &str) -> Option<Value> {
+        Arc::make_mut(&mut self.globals).remove(name)
+    }
+
+    /// The data supplied by the host, without any of the variables created
+    /// while rendering.
+    pub fn globals(&self) -> &Object {
+        &self.globals
     }
 
-    /// Sets a value in the global context.
+    /// Sets a variable for the rest of the render, as `assign` and
+    /// `capture` do. The variable is visible in every scope, and shadows
+    /// any global of the same name without changing it. Assigning `None`
+    /// hides the global altogether.
     ///
     /// # Examples
     ///
     /// ```
     /// # use liquid::{Value, Context};
     /// let mut ctx = Context::new();
-    /// ctx.set_val("test", Value::Num(42f32));
-    /// assert_eq!(ctx.get_val("test"), Some(&Value::Num(42f32)));
+    /// ctx.set_val("title", Value::str("host"));
+    /// ctx.run_in_scope(|scope| scope.assign_val("title", Some(Value::str("template"))));
+    ///
+    /// assert_eq!(ctx.get_val("title"), Some(&Value::str("template")));
+    /// assert_eq!(ctx.globals().get("title"), Some(&Value::str("host")));
     /// ```
-    pub fn set_val(&mut self, name: &str, val: Value) -> Option<Value> {
-        self.globals.insert(name.to_owned(), val)
+    pub fn assign_val(&mut self, name: &str, val: Option<Value>) {
+        self.assign_symbol(&Symbol::from(name), val)
+    }
+
+    /// Sets a variable for the rest of the render like
+    /// [assign_val](#method.assign_val), by a name interned while parsing.
+    pub fn assign_symbol(&mut self, name: &Symbol, val: Option<Value>) {
+        match self.stack.first_mut() {
+            Some(frame) => {
+                frame.insert(name.clone(), val);
+            }
+            None => panic!("Cannot insert into an empty stack"),
+        }
     }
 
     /// Translates a Token to a Value, looking it up in the context if
-    /// necessary
+    /// necessary. `nil` evaluates to no value at all, while `empty` and
+    /// `blank` evaluate to an empty string when used outside of a
+    /// comparison.
     pub fn evaluate(&self, t: &Token) -> Result<Option<Value>> {
+        Ok(try!(self.evaluate_ref(t)).map(Cow::into_owned))
+    }
+
+    /// Evaluates a token like [evaluate](#method.evaluate), but borrows
+    /// the value of a variable from the context instead of copying it,
+    /// which saves copying a large array or object just to look at it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use liquid::{Value, Context, Token};
+    /// # use std::borrow::Cow;
+    /// let mut ctx = Context::new();
+    /// ctx.set_val("list", Value::Array(vec![Value::Num(1f32)]));
+    /// match ctx.evaluate_ref(&Token::Identifier("list".into())).unwrap() {
+    ///     Some(Cow::Borrowed(list)) => assert_eq!(list, ctx.get_val("list").unwrap()),
+    ///     _ => panic!("expected the list to be borrowed"),
+    /// }
+    /// ```
+    pub fn evaluate_ref<'b>(&'b self, t: &Token) -> Result<Option<Cow<'b, Value>>> {
         match t {
-            &NumberLiteral(f)     => Ok(Some(Value::Num(f))),
-            &StringLiteral(ref s) => Ok(Some(Value::Str(s.clone()))),
-            &BooleanLiteral(b)    => Ok(Some(Value::Bool(b))),
-            &Identifier(ref id)   => {
-                Ok(self.get_val(id).cloned())
+            &NumberLiteral(f)     => Ok(Some(Cow::Owned(Value::Num(f)))),
+            &StringLiteral(ref s) => Ok(Some(Cow::Owned(Value::Str(s.to_string())))),
+            &BooleanLiteral(b)    => Ok(Some(Cow::Owned(Value::Bool(b)))),
+            &NilLiteral           => Ok(None),
+            &EmptyLiteral |
+            &BlankLiteral         => Ok(Some(Cow::Owned(Value::str("")))),
+            &Range(ref start, ref stop) => {
+                let start = try!(self.evaluate_range_end(start));
+                let stop = try!(self.evaluate_range_end(stop));
+                let mut range = Vec::new();
+                for x in start..stop {
+                    // a range used as a value has to be made in full, so
+                    // check the deadline every so often while doing so
+                    if x % 4096 == 0 {
+                        try!(self.check_deadline());
+                    }
+                    range.push(Value::Num(x as f32));
+                }
+                Ok(Some(Cow::Owned(Value::Array(range))))
             },
+            &Identifier(ref id)   => self.lookup(id),
             _ => {
                 let msg = format!("Cannot evaluate {}", t);
                 Err(Error::Other(msg))
@@ -224,6 +1756,7 @@ impl Context {
         }
     }
 
+
     /// Sets a value to the rendering context.
     /// Note that it needs to be wrapped in a liquid::Value.
     ///
@@ -249,28 +1782,534 @@ impl Context {
     /// assert_eq!(ctx.get_val("local"), None);
     /// ```
     pub fn set_local_val(&mut self, name: &str, val: Value) -> Option<Value> {
+        self.set_local_symbol(&Symbol::from(name), val)
+    }
+
+    /// Sets a value in the innermost scope like
+    /// [set_local_val](#method.set_local_val), by a name interned while
+    /// parsing, which saves hashing the name on every call.
+    pub fn set_local_symbol(&mut self, name: &Symbol, val: Value) -> Option<Value> {
         match self.stack.last_mut() {
-            Some(frame) => frame.insert(name.to_owned(), val),
+            Some(frame) => frame.insert(name.clone(), Some(val)).and_then(|v| v),
             None => panic!("Cannot insert into an empty stack")
         }
     }
 }
 
+/// The filters a context provides itself, which use its random source and
+/// clock.
+fn own_filters(random: &Arc<Mutex<Box<RandomSource>>>,
+               clock: &Arc<Mutex<Box<Clock>>>)
+               -> HashMap<String, Box<Filter>> {
+    let mut own_filters: HashMap<String, Box<Filter>> = HashMap::new();
+    let source = random.clone();
+    own_filters.insert("sample".to_owned(),
+                       Box::new(move |input, args| {
+                           filters::sample(input, args, &mut **locked(&source))
+                       }));
+    own_filters.insert("date".to_owned(), date_filter(clock.clone(), "%Y-%m-%d"));
+    own_filters
+}
+
+/// Makes a filter for each of `filters` that calls it, for a context to
+/// use filters shared with others.
+fn calling_shared(filters: &Arc<HashMap<String, Box<Filter>>>) -> HashMap<String, Box<Filter>> {
+    filters.keys()
+           .map(|name| {
+               let (filters, key) = (filters.clone(), name.clone());
+               let filter: Box<Filter> = Box::new(move |input, args| filters[&key](input, args));
+               (name.clone(), filter)
+           })
+           .collect()
+}
+
+/// What a snapshot taken with
+/// [Context::with_render_snapshot](struct.Context.html#method.with_render_snapshot)
+/// keeps on top of the globals and settings.
+#[derive(Clone)]
+struct RenderSettings {
+    filters: Arc<HashMap<String, Box<Filter>>>,
+    forked_filters: Vec<Arc<HashMap<String, Box<Filter>>>>,
+    shared_filters: Option<Arc<HashMap<String, Box<Filter>>>>,
+    deprecated_filters: HashSet<String>,
+    random: Arc<Mutex<Box<RandomSource>>>,
+    clock: Arc<Mutex<Box<Clock>>>,
+    deadline: Option<Instant>,
+}
+
+/// The globals and settings of a context, taken with
+/// [Context::snapshot](struct.Context.html#method.snapshot). Snapshots are
+/// `Send` and `Sync`, so one can be handed to other threads to make a
+/// context for each of them.
+#[derive(Clone)]
+pub struct ContextSnapshot {
+    globals: Arc<Object>,
+    strict_variables: bool,
+    strict_filters: bool,
+    number_policy: NumberPolicy,
+    escaper: Option<Arc<Escaper>>,
+    i18n: Option<Arc<I18n>>,
+    error_mode: ErrorMode,
+    catch_panics: bool,
+    instrument: Option<Arc<Instrument>>,
+    render: Option<RenderSettings>,
+}
+
+impl ContextSnapshot {
+    /// Makes a new context with the globals and settings of the snapshot.
+    pub fn context(&self) -> Context {
+        let mut context = Context::with_globals(self.globals.clone());
+        context.strict_variables = self.strict_variables;
+        context.strict_filters = self.strict_filters;
+        context.set_number_policy(self.number_policy);
+        context.escaper = self.escaper.clone();
+        context.error_mode = self.error_mode;
+        context.catch_panics = self.catch_panics;
+        context.instrument = self.instrument.clone();
+        if let Some(ref render) = self.render {
+            context.random = render.random.clone();
+            context.clock = render.clock.clone();
+            context.own_filters = own_filters(&render.random, &render.clock);
+            context.filters = calling_shared(&render.filters);
+            context.forked_filters = render.forked_filters.clone();
+            context.shared_filters = render.shared_filters.clone();
+            context.deprecated_filters = render.deprecated_filters.clone();
+            context.deadline = render.deadline;
+        }
+        if let Some(ref i18n) = self.i18n {
+            context.share_i18n(i18n.clone());
+        }
+        context
+    }
+}
+
+/// The names of the async globals of a context that haven't been fetched.
+#[cfg(feature = "async")]
+pub fn async_val_names(context: &Context) -> Vec<String> {
+    context.async_globals.keys().cloned().collect()
+}
+
+/// Starts fetching the named async global, if the context has one.
+#[cfg(feature = "async")]
+pub fn fetch_async_val(context: &Context, name: &str) -> Option<BoxFuture<Value>> {
+    context.async_globals.get(name).map(|provider| provider(context))
+}
+
+/// Sets a fetched async global as an ordinary one, so that it isn't
+/// fetched again.
+#[cfg(feature = "async")]
+pub fn resolve_async_val(context: &mut Context, name: &str, value: Value) {
+    context.async_globals.remove(name);
+    context.set_val(name, value);
+}
+
+/// Swaps the store used by `{% cache %}` blocks, returning the old one.
+#[cfg(feature = "async")]
+pub fn replace_cache_store(context: &mut Context,
+                           store: Option<Arc<CacheStore>>)
+                           -> Option<Arc<CacheStore>> {
+    ::std::mem::replace(&mut context.cache_store, store)
+}
+
+/// A fork of the context for rendering a template just to find out which
+/// async globals and cached fragments it needs, leaving nothing behind: it
+/// has random numbers of its own, tells no instrument or error handler
+/// about its nodes, and carries on past errors. Each async global that
+/// hasn't been fetched stands in as `false`, and its name is added to
+/// `asked` when the render uses it.
+#[cfg(feature = "async")]
+pub fn trial_fork(context: &mut Context, asked: &Arc<Mutex<Vec<String>>>) -> Context {
+    let mut trial = context.fork();
+    trial.random = Arc::new(Mutex::new(Box::new(SeededRandom::from_time())));
+    trial.own_filters = own_filters(&trial.random, &trial.clock);
+    if let Some(i18n) = trial.i18n.clone() {
+        trial.share_i18n(i18n);
+    }
+    trial.instrument = None;
+    trial.error_handler = None;
+    trial.error_mode = ErrorMode::Lax;
+    let names: Vec<String> = trial.async_globals.drain().map(|(name, _)| name).collect();
+    for name in names {
+        let asked = asked.clone();
+        let recorded = name.clone();
+        trial.set_lazy_val(&name, move |_| {
+            locked(&asked).push(recorded.clone());
+            Value::Bool(false)
+        });
+    }
+    trial
+}
+
+/// Puts together a [Context](struct.Context.html) with its values and
+/// filters.
+///
+/// ## Example
+/// ```
+/// use liquid::{ContextBuilder, Value};
+///
+/// let context = ContextBuilder::new()
+///     .set_val("title", "Hello")
+///     .set_val("count", 3)
+///     .set_val("tags", vec!["a", "b"])
+///     .filter("shout", Box::new(|input, _args| Ok(Value::Str(input.to_string() + "!"))))
+///     .build();
+/// assert_eq!(context.get_val("count"), Some(&Value::Num(3f32)));
+/// ```
+#[derive(Default)]
+pub struct ContextBuilder {
+    values: Object,
+    lazy_values: Vec<(String, Box<Fn(&Context) -> Value>)>,
+    filters: HashMap<String, Box<Filter>>,
+    strict_variables: bool,
+}
+
+impl ContextBuilder {
+    pub fn new() -> ContextBuilder {
+        ContextBuilder::default()
+    }
+
+    pub fn set_val<V: Into<Value>>(mut self, name: &str, val: V) -> ContextBuilder {
+        self.values.insert(name.to_owned(), val.into());
+        self
+    }
+
+    /// See [Context::set_lazy_val](struct.Context.html#method.set_lazy_val).
+    pub fn set_lazy_val<F>(mut self, name: &str, provider: F) -> ContextBuilder
+        where F: Fn(&Context) -> Value + 'static
+    {
+        self.lazy_values.push((name.to_owned(), Box::new(provider)));
+        self
+    }
+
+    pub fn filter(mut self, name: &str, filter: Box<Filter>) -> ContextBuilder {
+        self.filters.insert(name.to_owned(), filter);
+        self
+    }
+
+    /// See [Context::set_strict_variables](struct.Context.html#method.set_strict_variables).
+    pub fn strict_variables(mut self) -> ContextBuilder {
+        self.strict_variables = true;
+        self
+    }
+
+    pub fn build(self) -> Context {
+        let mut context = Context::with_values_and_filters(self.values, self.filters);
+        context.strict_variables = self.strict_variables;
+        for (name, provider) in self.lazy_values {
+            context.lazy_globals.insert(name,
+                                        LazyValue {
+                                            provider: Arc::from(provider),
+                                            value: OnceCell::new(),
+                                        });
+        }
+        context
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::Context;
+    use super::{Context, VariablePath};
     use value::Value;
-    use std::collections::HashMap;
+    use value::Object;
+    use std::rc::Rc;
+    use std::sync::Arc;
 
     #[test]
     fn get_val() {
         let mut ctx = Context::new();
-        let mut post = HashMap::new();
+        let mut post = Object::default();
         post.insert("number".to_owned(), Value::Num(42f32));
         ctx.set_val("post", Value::Object(post));
         assert_eq!(ctx.get_val("post.number").unwrap(), &Value::Num(42f32));
     }
 
+    #[test]
+    fn get_val_indexes_into_arrays_and_objects() {
+        let mut ctx = Context::new();
+        let mut product = Object::default();
+        product.insert("title".to_owned(), Value::str("hat"));
+        product.insert("key with spaces".to_owned(), Value::Num(1f32));
+        let products = Value::Array(vec![Value::str("first"), Value::Object(product)]);
+        let mut settings = Object::default();
+        settings.insert("featured_index".to_owned(), Value::Num(1f32));
+        settings.insert("field".to_owned(), Value::str("title"));
+        ctx.set_val("products", products);
+        ctx.set_val("settings", Value::Object(settings));
+
+        assert_eq!(ctx.get_val("products[0]").unwrap(), &Value::str("first"));
+        assert_eq!(ctx.get_val("products[-2]").unwrap(), &Value::str("first"));
+        assert_eq!(ctx.get_val("products[1].title").unwrap(), &Value::str("hat"));
+        assert_eq!(ctx.get_val("products[1]['title']").unwrap(), &Value::str("hat"));
+        assert_eq!(ctx.get_val("products[1][\"key with spaces\"]").unwrap(),
+                   &Value::Num(1f32));
+        assert_eq!(ctx.get_val("products[settings.featured_index][settings.field]").unwrap(),
+                   &Value::str("hat"));
+        assert_eq!(ctx.get_val("products[2]"), None);
+        assert_eq!(ctx.get_val("products[0].title"), None);
+        assert_eq!(ctx.get_val("products[0"), None);
+    }
+
+    #[test]
+    fn forks_share_globals_and_translations() {
+        use ErrorMode;
+        use i18n::I18n;
+
+        let mut base = Context::new();
+        base.set_val("site", Value::str("example.com"));
+        base.set_i18n(I18n::new("en"));
+        base.set_error_mode(ErrorMode::Lax);
+
+        let fork = base.fork();
+        assert!(Arc::ptr_eq(&base.globals, &fork.globals));
+        assert!(Arc::ptr_eq(base.i18n.as_ref().unwrap(), fork.i18n.as_ref().unwrap()));
+        assert_eq!(fork.error_mode(), ErrorMode::Lax);
+        assert!(fork.get_filter("t").is_some());
+    }
+
+    #[test]
+    fn forks_carry_on_from_their_context() {
+        use ErrorMode;
+        use cache::MemoryCacheStore;
+        use error::Error;
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let mut base = Context::new();
+        base.set_val("site", Value::str("example.com"));
+        base.add_filter("shout", Box::new(|input, _| Ok(Value::Str(input.to_string() + "!"))));
+        let calls = Rc::new(Cell::new(0));
+        let counted = calls.clone();
+        base.set_lazy_val("menu", move |_| {
+            counted.set(counted.get() + 1);
+            Value::str("home")
+        });
+        base.set_undefined_handler(|_| Ok(Some(Value::str("?"))));
+        base.set_error_handler(|_| Some("!".to_owned()));
+        base.set_cache_store(Box::new(MemoryCacheStore::new()));
+        base.push_scope();
+        base.set_local_val("x", Value::Num(1f32));
+        base.set_register("cycle", "a", 2usize);
+        assert_eq!(base.get_val("menu"), Some(&Value::str("home")));
+
+        let mut fork = base.fork();
+        assert_eq!(fork.get_val("x"), Some(&Value::Num(1f32)));
+        assert_eq!(fork.get_register::<usize>("cycle", "a"), Some(&2));
+        assert!(fork.get_filter("shout").is_some());
+        assert!(base.get_filter("shout").is_some());
+        assert!(base.filters.is_empty());
+        assert_eq!(fork.get_val("menu"), Some(&Value::str("home")));
+        assert_eq!(calls.get(), 1);
+        assert_eq!(fork.lookup("nope").unwrap().unwrap().into_owned(), Value::str("?"));
+        assert_eq!(fork.recover(Error::Render("x".to_owned())).unwrap(), Some("!".to_owned()));
+
+        // the scopes and registers of the fork are its own
+        fork.set_local_val("x", Value::Num(2f32));
+        *fork.get_register_mut::<usize>("cycle", "a").unwrap() += 1;
+        assert_eq!(base.get_val("x"), Some(&Value::Num(1f32)));
+        assert_eq!(base.get_register::<usize>("cycle", "a"), Some(&2));
+
+        // but the cache store is shared
+        fork.cache_store().unwrap().set("k", "v".to_owned(), None);
+        assert_eq!(base.cache_store().unwrap().get("k"), Some("v".to_owned()));
+
+        // and a filter added after forking is only in the context it was
+        // added to, while forking again still finds both
+        base.add_filter("whisper", Box::new(|input, _| Ok(input.clone())));
+        assert!(fork.get_filter("whisper").is_none());
+        let again = base.fork();
+        assert!(again.get_filter("shout").is_some() && again.get_filter("whisper").is_some());
+
+        // what earlier renders kept isn't carried over
+        base.clear_error_handler();
+        base.set_error_mode(ErrorMode::Warn);
+        base.recover(Error::Render("x".to_owned())).unwrap();
+        base.update_stats(|stats| stats.nodes += 1);
+        let fork = base.fork();
+        assert_eq!(base.render_errors().len(), 1);
+        assert!(fork.render_errors().is_empty());
+        assert_eq!(fork.stats().nodes, 0);
+    }
+
+    #[test]
+    fn split_paths_follow_the_values_of_their_indexes() {
+        let mut ctx = Context::new();
+        ctx.set_val("list", Value::Array(vec![Value::str("a"), Value::str("b")]));
+        let path = VariablePath::new("list[i]");
+        ctx.set_val("i", Value::Num(0f32));
+        assert_eq!(ctx.get_path(&path), Some(&Value::str("a")));
+        ctx.set_val("i", Value::Num(1f32));
+        assert_eq!(ctx.get_path(&path), Some(&Value::str("b")));
+        assert_eq!(path.name(), "list[i]");
+    }
+
+    #[test]
+    fn indexes_can_hold_brackets() {
+        let mut odd = Object::default();
+        odd.insert("x]y".to_owned(), Value::str("quoted"));
+        let mut ctx = Context::new();
+        ctx.set_val("a", Value::Object(odd));
+        ctx.set_val("list", Value::Array(vec![Value::str("a"), Value::str("b")]));
+        ctx.set_val("b", Value::Array(vec![Value::Num(1f32)]));
+
+        assert_eq!(ctx.get_val("a[\"x]y\"]"), Some(&Value::str("quoted")));
+        assert_eq!(ctx.get_val("a['x]y']"), Some(&Value::str("quoted")));
+        assert_eq!(ctx.get_val("list[b[0]]"), Some(&Value::str("b")));
+        assert_eq!(ctx.get_val("list[b[0]"), None);
+    }
+
+    #[test]
+    fn get_val_with_hyphens_and_unicode() {
+        use parse;
+        use LiquidOptions;
+        use Renderable;
+
+        let mut settings = Object::default();
+        settings.insert("color-scheme-1".to_owned(), Value::str("dark"));
+        settings.insert("1st".to_owned(), Value::str("first"));
+        let mut ctx = Context::new();
+        ctx.set_val("settings", Value::Object(settings));
+        ctx.set_val("prénom", Value::str("Zoë"));
+        ctx.set_val("ready?", Value::Bool(true));
+
+        let text = "{{ settings.color-scheme-1 }} {{ settings['1st'] }} {{ prénom }} {{ ready? }}";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+        assert_eq!(template.render(&mut ctx).unwrap(),
+                   Some("dark first Zoë true".to_owned()));
+    }
+
+    #[test]
+    fn added_filters_take_precedence_over_builtins() {
+        use Renderable;
+        use LiquidOptions;
+        use parse;
+
+        let mut ctx = Context::new();
+        assert!(ctx.get_filter("upcase").is_some());
+        ctx.add_filter("upcase",
+                       Box::new(|input, _args| Ok(Value::str(&format!("<{}>", input.to_string())))));
+        let template = parse("{{ 'a' | upcase }}{{ 'b' | size }}", LiquidOptions::default())
+                           .unwrap();
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("<a>1".to_owned()));
+        assert!(!ctx.filters.contains_key("size"));
+    }
+
+    #[test]
+    fn pushed_scopes_shadow_and_unwind() {
+        let mut ctx = Context::new();
+        ctx.set_val("a", Value::Num(1f32));
+
+        ctx.push_scope();
+        ctx.set_local_val("a", Value::Num(2f32));
+        ctx.assign_val("b", Some(Value::Num(3f32)));
+        ctx.push_scope();
+        assert_eq!(ctx.set_local_val("a", Value::Num(4f32)), None);
+        assert_eq!(ctx.get_val("a"), Some(&Value::Num(4f32)));
+
+        ctx.pop_scope();
+        assert_eq!(ctx.get_val("a"), Some(&Value::Num(2f32)));
+        ctx.pop_scope();
+        assert_eq!(ctx.get_val("a"), Some(&Value::Num(1f32)));
+
+        // assigned values outlive the scope they were set in
+        assert_eq!(ctx.get_val("b"), Some(&Value::Num(3f32)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn popping_the_render_scope_panics() {
+        let mut ctx = Context::new();
+        ctx.push_scope();
+        ctx.pop_scope();
+        ctx.pop_scope();
+    }
+
+    #[test]
+    fn lazy_values_are_worked_out_once_when_used() {
+        use parse;
+        use LiquidOptions;
+        use Renderable;
+        use std::cell::Cell;
+
+        let calls = Rc::new(Cell::new(0));
+        let mut ctx = Context::new();
+        {
+            let calls = calls.clone();
+            ctx.set_lazy_val("sidebar", move |_| {
+                calls.set(calls.get() + 1);
+                Value::str("links")
+            });
+        }
+
+        let unused = parse("{{ title }}", LiquidOptions::default()).unwrap();
+        unused.render(&mut ctx).unwrap();
+        assert_eq!(calls.get(), 0);
+
+        let used = parse("{{ sidebar }} {{ sidebar | upcase }}", LiquidOptions::default()).unwrap();
+        assert_eq!(used.render(&mut ctx).unwrap(), Some("links LINKS".to_owned()));
+        assert_eq!(calls.get(), 1);
+
+        // values set directly take precedence
+        ctx.set_val("sidebar", "set");
+        assert_eq!(ctx.get_val("sidebar"), Some(&Value::str("set")));
+    }
+
+    #[test]
+    fn strict_variables_report_undefined_paths() {
+        use {parse, Renderable, LiquidOptions};
+        use super::ContextBuilder;
+
+        let mut ctx = ContextBuilder::new()
+                          .set_val("page", Value::Object(Default::default()))
+                          .strict_variables()
+                          .build();
+        let render = |text: &str, ctx: &mut Context| {
+            parse(text, LiquidOptions::default()).unwrap().render(ctx)
+        };
+
+        let err = render("ok\n  {{ page.titel }}", &mut ctx).err().unwrap();
+        assert_eq!(err.without_snippet().to_string(),
+                   "line 2, column 3: Rendering error: Undefined variable page.titel");
+        assert!(render("{% if user %}x{% endif %}", &mut ctx).is_err());
+        assert!(render("{% for x in missing %}x{% endfor %}", &mut ctx).is_err());
+
+        // assigning nil defines a variable
+        assert_eq!(render("{% assign a = nil %}[{{ a }}]", &mut ctx).unwrap(),
+                   Some("[]".to_owned()));
+
+        ctx.set_strict_variables(false);
+        assert_eq!(render("[{{ page.titel }}]", &mut ctx).unwrap(), Some("[]".to_owned()));
+    }
+
+    #[test]
+    fn undefined_handler_sees_every_miss() {
+        use {parse, Renderable, LiquidOptions};
+        use error::Error;
+        use std::cell::RefCell;
+
+        let missed = Rc::new(RefCell::new(vec![]));
+        let log = missed.clone();
+        let mut ctx = Context::new();
+        ctx.set_val("known", "k");
+        ctx.set_undefined_handler(move |path| {
+            log.borrow_mut().push(path.to_owned());
+            match path {
+                "fallback" => Ok(Some(Value::str("f"))),
+                "forbidden" => Err(Error::Render("no".to_owned())),
+                _ => Ok(None),
+            }
+        });
+
+        let template = parse("{{ known }}{{ page.title | upcase }}{{ fallback | upcase }}\
+                              {% if nope %}x{% endif %}",
+                             LiquidOptions::default())
+                           .unwrap();
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("kF".to_owned()));
+        assert_eq!(*missed.borrow(), vec!["page.title", "fallback", "nope"]);
+
+        let template = parse("{{ forbidden }}", LiquidOptions::default()).unwrap();
+        assert!(template.render(&mut ctx).is_err());
+    }
+
     #[test]
     fn scoped_variables() {
         let mut ctx = Context::new();
@@ -299,7 +2338,7 @@ mod test {
         use token::Token::StringLiteral;
 
         let ctx = Context::new();
-        let t = StringLiteral("hello".to_owned());
+        let t = StringLiteral("hello".into());
         assert_eq!( ctx.evaluate(&t).unwrap(), Some(Value::str("hello")) );
     }
 
@@ -330,9 +2369,9 @@ mod test {
 
         let mut ctx = Context::new();
         ctx.set_val("var0", Value::Num(42f32));
-        assert_eq!(ctx.evaluate(&Identifier("var0".to_owned())).unwrap(),
+        assert_eq!(ctx.evaluate(&Identifier("var0".into())).unwrap(),
                    Some(Value::Num(42f32)));
-        assert_eq!(ctx.evaluate(&Identifier("nope".to_owned())).unwrap(),
+        assert_eq!(ctx.evaluate(&Identifier("nope".into())).unwrap(),
                    None);
     }
 
@@ -342,5 +2381,151 @@ mod test {
         let ctx = Context::new();
         assert!(ctx.evaluate(&DotDot).is_err());
     }
-}
 
+    #[test]
+    fn seeded_renders_are_reproducible() {
+        use Renderable;
+        use LiquidOptions;
+        use parse;
+
+        let template = parse("{% for i in (0..10) %}{{ letters | sample }}{% endfor %}",
+                             LiquidOptions::default())
+                           .unwrap();
+        let render = |seed| {
+            let mut ctx = Context::new();
+            let letters = "abcdefghij".chars().map(|c| Value::Str(c.to_string())).collect();
+            ctx.set_val("letters", Value::Array(letters));
+            ctx.set_seed(seed);
+            template.render(&mut ctx).unwrap().unwrap()
+        };
+        assert_eq!(render(7), render(7));
+        assert!(render(7) != render(8));
+        assert_eq!(render(7).len(), 10);
+    }
+
+    #[test]
+    fn now_and_today_come_from_the_clock() {
+        use Renderable;
+        use LiquidOptions;
+        use parse;
+        use date::FixedClock;
+        use i18n::{I18n, LocaleFormat};
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let mut ctx = Context::new();
+        ctx.set_clock(Box::new(FixedClock::new(UNIX_EPOCH + Duration::from_secs(1489504166))));
+        let template = parse("{{ now }}|{{ today }}|{{ 'today' | date }}|{{ now | date: '%H:%M' }}",
+                             LiquidOptions::default())
+                           .unwrap();
+        assert_eq!(template.render(&mut ctx).unwrap(),
+                   Some("2017-03-14 15:09:26 +0000|2017-03-14|2017-03-14|15:09".to_owned()));
+
+        let mut i18n = I18n::new("de");
+        i18n.set_format("de",
+                        LocaleFormat { date_format: "%d.%m.%Y".to_owned(), ..Default::default() });
+        ctx.set_i18n(i18n);
+        let template = parse("{{ 'now' | date }}", LiquidOptions::default()).unwrap();
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("14.03.2017".to_owned()));
+
+        ctx.set_val("now", "shadowed");
+        assert_eq!(ctx.lookup("now").unwrap().unwrap().into_owned(), Value::str("shadowed"));
+    }
+
+    #[test]
+    fn reset_clears_render_state() {
+        use Renderable;
+        use LiquidOptions;
+        use parse;
+
+        let mut ctx = Context::new();
+        ctx.set_val("n", 1);
+        let template = parse("{% cycle 'a', 'b' %}{{ x }}{% assign x = n %}",
+                             LiquidOptions::default())
+                           .unwrap();
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("a".to_owned()));
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("b1".to_owned()));
+
+        ctx.reset();
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("a".to_owned()));
+        assert_eq!(ctx.get_val("n"), Some(&Value::Num(1f32)));
+    }
+
+    #[test]
+    fn snapshots_share_globals() {
+        let mut base = Context::new();
+        base.set_val("site", "example.com");
+        base.set_autoescape(true);
+        let snapshot = base.snapshot();
+
+        let mut first = snapshot.context();
+        let second = snapshot.context();
+        assert!(Arc::ptr_eq(&first.globals, &second.globals));
+        assert!(second.autoescape());
+
+        first.set_val("site", "changed");
+        assert_eq!(second.get_val("site"), Some(&Value::str("example.com")));
+        assert_eq!(base.get_val("site"), Some(&Value::str("example.com")));
+    }
+
+    #[test]
+    fn ranges_are_made_within_the_deadline() {
+        use token::Token;
+        use std::time::Instant;
+
+        let range = Token::Range(Box::new(Token::NumberLiteral(0f32)),
+                                 Box::new(Token::NumberLiteral(3f32)));
+        let mut ctx = Context::new();
+        assert_eq!(ctx.evaluate(&range).unwrap(),
+                   Some(Value::Array(vec![Value::Num(0f32), Value::Num(1f32), Value::Num(2f32)])));
+
+        ctx.set_deadline(Some(Instant::now()));
+        assert!(ctx.evaluate(&range).unwrap_err().is_timeout());
+    }
+
+    #[test]
+    fn snapshots_can_be_sent_to_other_threads() {
+        use super::ContextSnapshot;
+        use escape::HtmlEscaper;
+        use std::thread;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ContextSnapshot>();
+
+        let mut base = Context::new();
+        base.set_val("site", "example.com");
+        base.set_escaper(Some(Arc::new(HtmlEscaper)));
+        let snapshot = base.snapshot();
+        let worker = thread::spawn(move || {
+            let ctx = snapshot.context();
+            (ctx.get_val("site").cloned(), ctx.autoescape())
+        });
+        assert_eq!(worker.join().unwrap(), (Some(Value::str("example.com")), true));
+    }
+
+    #[test]
+    fn successful_renders_keep_warnings() {
+        use Renderable;
+        use LiquidOptions;
+        use parse;
+
+        let text = "{{ 'a' | upcase | shout }}\n{% if missing %}{% endif %}{{ 'b' | upcase }}";
+        let template = parse(text, LiquidOptions { template_name: Some("page".to_owned()),
+                                                   ..Default::default() })
+                           .unwrap();
+        let mut ctx = Context::new();
+        ctx.set_strict_filters(false);
+        ctx.deprecate_filter("upcase");
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("A\nB".to_owned()));
+
+        let warnings: Vec<String> = ctx.take_render_warnings()
+                                       .iter()
+                                       .map(|w| w.to_string())
+                                       .collect();
+        assert_eq!(warnings,
+                   vec!["page:1:1: Deprecated filter upcase",
+                        "page:1:1: Unknown filter shout",
+                        "page:2:1: Undefined variable missing",
+                        "page:2:28: Deprecated filter upcase"]);
+        assert!(ctx.render_warnings().is_empty());
+    }
+}
@@ -0,0 +1,391 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Tells the time for the `now` and `today` variables and the `date`
+/// filter. Contexts use the system clock unless given another with
+/// `Context::set_clock`, which lets tests freeze time.
+///
+/// ## Example
+/// ```
+/// use liquid::{FixedClock, Context, LiquidOptions, Renderable};
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// let template = liquid::parse("{{ 'now' | date: '%Y-%m-%d %H:%M' }}",
+///                              LiquidOptions::default()).unwrap();
+/// let mut ctx = Context::new();
+/// ctx.set_clock(Box::new(FixedClock::new(UNIX_EPOCH + Duration::from_secs(86400))));
+/// assert_eq!(template.render(&mut ctx).unwrap(), Some("1970-01-02 00:00".to_owned()));
+/// ```
+pub trait Clock: Send {
+    fn now(&self) -> SystemTime;
+}
+
+/// The time according to the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that is stopped at a given time.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock {
+    time: SystemTime,
+}
+
+impl FixedClock {
+    pub fn new(time: SystemTime) -> FixedClock {
+        FixedClock { time: time }
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.time
+    }
+}
+
+const MONTHS: [&'static str; 12] = ["January", "February", "March", "April", "May", "June",
+                                    "July", "August", "September", "October", "November",
+                                    "December"];
+const DAYS: [&'static str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday",
+                                 "Friday", "Saturday"];
+
+/// A date and time of day, along with the offset from UTC it was given
+/// in. There is no time zone database, so times are only ever shifted by
+/// the offset they came with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// Minutes east of UTC.
+    pub offset: i32,
+}
+
+/// The number of days from 1970-01-01 to the given date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 +
+                      day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date that is the given number of days from 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateTime {
+    /// The UTC time the given number of seconds after 1970-01-01.
+    pub fn from_timestamp(timestamp: i64) -> DateTime {
+        let days = if timestamp >= 0 { timestamp } else { timestamp - 86399 } / 86400;
+        let seconds = (timestamp - days * 86400) as u32;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: seconds / 3600,
+            minute: seconds / 60 % 60,
+            second: seconds % 60,
+            offset: 0,
+        }
+    }
+
+    pub fn from_system_time(time: SystemTime) -> DateTime {
+        let timestamp = match time.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64),
+        };
+        DateTime::from_timestamp(timestamp)
+    }
+
+    /// Parses dates like `2017-03-14`, optionally followed by a time like
+    /// `15:09` or `15:09:26` after a space or a `T`, and then optionally
+    /// by an offset like `Z`, `+0100` or `-05:30`.
+    pub fn parse(text: &str) -> Option<DateTime> {
+        DateTime::try_parse(text).ok()
+    }
+
+    /// Parses a date like `parse` does, saying which part of the text is
+    /// wrong when it can't.
+    pub fn try_parse(text: &str) -> Result<DateTime, String> {
+        fn number(text: &str, digits: usize) -> Option<u32> {
+            let bytes = text.as_bytes();
+            if bytes.len() < digits || !bytes[..digits].iter().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            text[..digits].parse().ok()
+        }
+
+        let bad_date = || "expected a date like 2017-03-14".to_owned();
+        let bad_time = || "expected a time like 15:09 or 15:09:26 after the date".to_owned();
+
+        let text = text.trim();
+        let negative = text.starts_with('-');
+        let text = if negative { &text[1..] } else { text };
+        let year_len = text.find('-').unwrap_or(0);
+        if year_len < 4 || text.len() < year_len + 6 {
+            return Err(bad_date());
+        }
+        let year = try!(number(text, year_len).ok_or_else(&bad_date)) as i64;
+        let year = if negative { -year } else { year };
+        let rest = &text[year_len..];
+        if rest.as_bytes()[3] != b'-' {
+            return Err(bad_date());
+        }
+        let month = try!(number(&rest[1..], 2).ok_or_else(&bad_date));
+        let day = try!(number(&rest[4..], 2).ok_or_else(&bad_date));
+        if month < 1 || month > 12 {
+            return Err(format!("there is no month {}", month));
+        }
+        if day < 1 || day > days_in_month(year, month) {
+            return Err(format!("there is no day {} in {} {}",
+                               day,
+                               MONTHS[month as usize - 1],
+                               year));
+        }
+        let mut date = DateTime {
+            year: year,
+            month: month,
+            day: day,
+            hour: 0,
+            minute: 0,
+            second: 0,
+            offset: 0,
+        };
+
+        let mut rest = &rest[6..];
+        if rest.starts_with(' ') || rest.starts_with('T') {
+            rest = &rest[1..];
+            date.hour = try!(number(rest, 2).ok_or_else(&bad_time));
+            if rest.as_bytes().get(2) != Some(&b':') {
+                return Err(bad_time());
+            }
+            date.minute = try!(number(&rest[3..], 2).ok_or_else(&bad_time));
+            rest = &rest[5..];
+            if rest.starts_with(':') {
+                date.second = try!(number(&rest[1..], 2).ok_or_else(&bad_time));
+                rest = &rest[3..];
+            }
+            if date.hour > 23 || date.minute > 59 || date.second > 60 {
+                return Err(format!("there is no time {:02}:{:02}:{:02}",
+                                   date.hour,
+                                   date.minute,
+                                   date.second));
+            }
+        }
+
+        let rest = rest.trim_left();
+        if rest.is_empty() || rest == "Z" || rest == "UTC" {
+            return Ok(date);
+        }
+        let bad_offset = || format!("expected an offset like Z, +0100 or -05:30, found '{}'", rest);
+        let sign = match rest.as_bytes()[0] {
+            b'+' => 1,
+            b'-' => -1,
+            _ => return Err(bad_offset()),
+        };
+        let digits = rest[1..].replace(':', "");
+        if digits.len() != 4 {
+            return Err(bad_offset());
+        }
+        let hours = try!(number(&digits, 2).ok_or_else(&bad_offset)) as i32;
+        let minutes = try!(number(&digits[2..], 2).ok_or_else(&bad_offset)) as i32;
+        date.offset = sign * (hours * 60 + minutes);
+        Ok(date)
+    }
+
+    /// The number of seconds since 1970-01-01 UTC.
+    pub fn timestamp(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * 86400 +
+        (self.hour * 3600 + self.minute * 60 + self.second) as i64 -
+        self.offset as i64 * 60
+    }
+
+    /// The day of the week, from 0 for Sunday.
+    pub fn weekday(&self) -> u32 {
+        // 1970-01-01 was a Thursday
+        ((days_from_civil(self.year, self.month, self.day) % 7 + 11) % 7) as u32
+    }
+
+    pub fn day_of_year(&self) -> u32 {
+        (days_from_civil(self.year, self.month, self.day) -
+         days_from_civil(self.year, 1, 1) + 1) as u32
+    }
+
+    /// Writes the date in `strftime` notation. The usual specifiers for
+    /// dates, times and offsets are understood; any others are written as
+    /// they are.
+    pub fn format(&self, format: &str) -> String {
+        match self.write(format, false) {
+            Ok(out) => out,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    /// Writes the date like `format` does, but fails on specifiers it
+    /// doesn't understand, naming the first one.
+    pub fn try_format(&self, format: &str) -> Result<String, String> {
+        self.write(format, true)
+    }
+
+    fn write(&self, format: &str, strict: bool) -> Result<String, String> {
+        let mut out = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            let hour12 = match self.hour % 12 {
+                0 => 12,
+                h => h,
+            };
+            match chars.next() {
+                Some('Y') => out.push_str(&self.year.to_string()),
+                Some('C') => out.push_str(&format!("{:02}", self.year / 100)),
+                Some('y') => out.push_str(&format!("{:02}", self.year % 100)),
+                Some('m') => out.push_str(&format!("{:02}", self.month)),
+                Some('B') => out.push_str(MONTHS[self.month as usize - 1]),
+                Some('b') | Some('h') => out.push_str(&MONTHS[self.month as usize - 1][..3]),
+                Some('d') => out.push_str(&format!("{:02}", self.day)),
+                Some('e') => out.push_str(&format!("{:2}", self.day)),
+                Some('j') => out.push_str(&format!("{:03}", self.day_of_year())),
+                Some('A') => out.push_str(DAYS[self.weekday() as usize]),
+                Some('a') => out.push_str(&DAYS[self.weekday() as usize][..3]),
+                Some('w') => out.push_str(&self.weekday().to_string()),
+                Some('u') => {
+                    out.push_str(&match self.weekday() {
+                                     0 => 7,
+                                     d => d,
+                                 }
+                                 .to_string())
+                }
+                Some('H') => out.push_str(&format!("{:02}", self.hour)),
+                Some('k') => out.push_str(&format!("{:2}", self.hour)),
+                Some('I') => out.push_str(&format!("{:02}", hour12)),
+                Some('l') => out.push_str(&format!("{:2}", hour12)),
+                Some('M') => out.push_str(&format!("{:02}", self.minute)),
+                Some('S') => out.push_str(&format!("{:02}", self.second)),
+                Some('p') => out.push_str(if self.hour < 12 { "AM" } else { "PM" }),
+                Some('P') => out.push_str(if self.hour < 12 { "am" } else { "pm" }),
+                Some('s') => out.push_str(&self.timestamp().to_string()),
+                Some('z') => {
+                    let sign = if self.offset < 0 { '-' } else { '+' };
+                    let offset = self.offset.abs();
+                    out.push_str(&format!("{}{:02}{:02}", sign, offset / 60, offset % 60))
+                }
+                Some('F') => out.push_str(&self.format("%Y-%m-%d")),
+                Some('T') => out.push_str(&self.format("%H:%M:%S")),
+                Some('D') => out.push_str(&self.format("%m/%d/%y")),
+                Some('R') => out.push_str(&self.format("%H:%M")),
+                Some('%') => out.push('%'),
+                Some(other) if strict => {
+                    return Err(format!("unknown directive '%{}' in '{}'", other, format));
+                }
+                None if strict => return Err(format!("'{}' ends with a lone '%'", format)),
+                Some(other) => {
+                    out.push('%');
+                    out.push(other);
+                }
+                None => out.push('%'),
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DateTime;
+
+    #[test]
+    fn timestamps_round_trip() {
+        for &timestamp in &[0i64, 86399, 951782400, 1489504166, -1, -86401, 4102444800] {
+            assert_eq!(DateTime::from_timestamp(timestamp).timestamp(), timestamp);
+        }
+        let leap_day = DateTime::from_timestamp(951782400);
+        assert_eq!((leap_day.year, leap_day.month, leap_day.day), (2000, 2, 29));
+        let before = DateTime::from_timestamp(-1);
+        assert_eq!(before.format("%F %T"), "1969-12-31 23:59:59");
+    }
+
+    #[test]
+    fn parses_dates_times_and_offsets() {
+        let date = DateTime::parse("2017-03-14").unwrap();
+        assert_eq!(date.format("%F %T %z"), "2017-03-14 00:00:00 +0000");
+        let date = DateTime::parse("2017-03-14T15:09:26Z").unwrap();
+        assert_eq!(date.format("%F %T %z"), "2017-03-14 15:09:26 +0000");
+        let date = DateTime::parse("2017-03-14 15:09 -05:30").unwrap();
+        assert_eq!(date.format("%F %T %z"), "2017-03-14 15:09:00 -0530");
+        assert_eq!(date.timestamp(), 1489504140 + 5 * 3600 + 30 * 60);
+
+        for text in &["", "2017", "2017-3-14", "2017-02-30", "2017-03-14 25:00", "2017-03-14 x"] {
+            assert!(DateTime::parse(text).is_none(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn failures_say_what_is_wrong() {
+        assert_eq!(DateTime::try_parse("2017-02-30").unwrap_err(),
+                   "there is no day 30 in February 2017");
+        assert_eq!(DateTime::try_parse("2017-13-01").unwrap_err(), "there is no month 13");
+        assert_eq!(DateTime::try_parse("2017-03-14 25:00").unwrap_err(),
+                   "there is no time 25:00:00");
+        assert_eq!(DateTime::try_parse("2017-03-14 x").unwrap_err(),
+                   "expected a time like 15:09 or 15:09:26 after the date");
+        assert_eq!(DateTime::try_parse("2017-03-14T15:09 CET").unwrap_err(),
+                   "expected an offset like Z, +0100 or -05:30, found 'CET'");
+        assert_eq!(DateTime::try_parse("14/03/2017").unwrap_err(),
+                   "expected a date like 2017-03-14");
+
+        let date = DateTime::from_timestamp(0);
+        assert_eq!(date.try_format("%Y %Q").unwrap_err(), "unknown directive '%Q' in '%Y %Q'");
+        assert_eq!(date.try_format("100%").unwrap_err(), "'100%' ends with a lone '%'");
+        assert_eq!(date.try_format("%F").unwrap(), "1970-01-01");
+    }
+
+    #[test]
+    fn formats_strftime_specifiers() {
+        let date = DateTime::parse("2017-03-05 09:07:02").unwrap();
+        assert_eq!(date.format("%a %A %b %B %d %e %j %y %C"),
+                   "Sun Sunday Mar March 05  5 064 17 20");
+        assert_eq!(date.format("%H %I %l %M %S %p %P %u %w"),
+                   "09 09  9 07 02 AM am 7 0");
+        assert_eq!(date.format("%D %R 100%% %Q"), "03/05/17 09:07 100% %Q");
+    }
+}
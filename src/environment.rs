@@ -0,0 +1,349 @@
+use Renderable;
+use builder::Parser;
+use context::Context;
+use template::Template;
+use partials::PartialCache;
+use escape::{Escaper, HtmlEscaper};
+use error::Result;
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A set of templates known by name, all parsed with the tags, blocks and
+/// filters of a single [Parser](struct.Parser.html). The templates can
+/// `include`, `extends` and `layout` one another by name, whatever order
+/// they were added in.
+///
+/// Templates are parsed the first time they are asked for. Partials are
+/// only parsed once however many templates include them, and are kept
+/// until their source changes. Partials loaded from outside the
+/// environment, such as from files, can't be kept track of this way, so
+/// call `invalidate` when one of those changes.
+///
+/// ## Example
+/// ```
+/// use liquid::{Environment, ParserBuilder, Context, Value};
+///
+/// let mut env = Environment::new(ParserBuilder::new().build());
+/// env.add_template("page", "{% layout 'base' %}Hello, {{ name }}!")
+///    .add_template("base", "<body>{{ content_for_layout }}</body>");
+///
+/// let mut context = Context::new();
+/// context.set_val("name", Value::str("world"));
+/// let output = env.render("page", &mut context);
+/// assert_eq!(output.unwrap(), Some("<body>Hello, world!</body>".to_owned()));
+/// ```
+pub struct Environment {
+    parser: Parser,
+    parsed: RwLock<HashMap<String, Arc<Template>>>,
+    strict_variables: Option<bool>,
+    strict_filters: Option<bool>,
+    /// The escaper for every render, or `None` to leave it to the context.
+    escaper: Option<Option<Arc<Escaper>>>,
+}
+
+impl Environment {
+    pub fn new(parser: Parser) -> Environment {
+        let mut parser = parser;
+        if parser.options().partial_cache.is_none() {
+            parser.options_mut().partial_cache = Some(PartialCache::new());
+        }
+        Environment {
+            parser: parser,
+            parsed: RwLock::new(HashMap::new()),
+            strict_variables: None,
+            strict_filters: None,
+            escaper: None,
+        }
+    }
+
+    /// Adds a template under the given name, replacing any template
+    /// already added under that name.
+    pub fn add_template(&mut self, name: &str, source: &str) -> &mut Environment {
+        self.parser.add_template(name, source);
+        self.invalidate(name);
+        self
+    }
+
+    /// Drops the named template or partial, along with everything parsed
+    /// from it, so that it is loaded and parsed again when next used.
+    pub fn invalidate(&self, name: &str) {
+        if let Some(ref cache) = self.parser.options().partial_cache {
+            cache.invalidate(name);
+        }
+        // the top-level templates don't record what they include, but
+        // their partials are still kept, so they are cheap to parse again
+        self.parsed_mut().clear();
+    }
+
+    /// Drops every parsed template and partial.
+    pub fn invalidate_all(&self) {
+        if let Some(ref cache) = self.parser.options().partial_cache {
+            cache.clear();
+        }
+        self.parsed_mut().clear();
+    }
+
+    /// Whether a template has been added under the given name.
+    pub fn has_template(&self, name: &str) -> bool {
+        self.parser.options().templates.contains_key(name)
+    }
+
+    /// Returns the named template, parsing it if it hasn't been already.
+    pub fn get_template(&self, name: &str) -> Result<Arc<Template>> {
+        if let Some(template) = self.parsed().get(name) {
+            return Ok(template.clone());
+        }
+        let template = Arc::new(try!(self.parser.parse_template(name)));
+        self.parsed_mut().insert(name.to_owned(), template.clone());
+        Ok(template)
+    }
+
+    fn parsed(&self) -> RwLockReadGuard<HashMap<String, Arc<Template>>> {
+        self.parsed.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn parsed_mut(&self) -> RwLockWriteGuard<HashMap<String, Arc<Template>>> {
+        self.parsed.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Makes every render through the environment treat undefined
+    /// variables as errors, or not, whatever the context says. See
+    /// [Context::set_strict_variables](struct.Context.html#method.set_strict_variables).
+    pub fn set_strict_variables(&mut self, strict: bool) -> &mut Environment {
+        self.strict_variables = Some(strict);
+        self
+    }
+
+    /// Makes every render through the environment treat unknown filters
+    /// as errors, or not, whatever the context says. See
+    /// [Context::set_strict_filters](struct.Context.html#method.set_strict_filters).
+    pub fn set_strict_filters(&mut self, strict: bool) -> &mut Environment {
+        self.strict_filters = Some(strict);
+        self
+    }
+
+    /// Makes every render through the environment HTML-escape its outputs,
+    /// or not, whatever the context says. See
+    /// [Context::set_autoescape](struct.Context.html#method.set_autoescape).
+    pub fn set_autoescape(&mut self, autoescape: bool) -> &mut Environment {
+        let escaper = if autoescape {
+            Some(Arc::new(HtmlEscaper) as Arc<Escaper>)
+        } else {
+            None
+        };
+        self.set_escaper(escaper)
+    }
+
+    /// Makes every render through the environment escape its outputs with
+    /// the given [Escaper](trait.Escaper.html), or not at all with `None`,
+    /// whatever the context says.
+    pub fn set_escaper(&mut self, escaper: Option<Arc<Escaper>>) -> &mut Environment {
+        self.escaper = Some(escaper);
+        self
+    }
+
+    /// Renders the named template. The settings of the environment only
+    /// apply for the length of the render.
+    pub fn render(&self, name: &str, context: &mut Context) -> Result<Option<String>> {
+        let template = try!(self.get_template(name));
+
+        let strict_variables = context.strict_variables();
+        let strict_filters = context.strict_filters();
+        context.set_strict_variables(self.strict_variables.unwrap_or(strict_variables));
+        context.set_strict_filters(self.strict_filters.unwrap_or(strict_filters));
+        let escaper = match self.escaper {
+            Some(ref escaper) => Some(context.replace_escaper(escaper.clone())),
+            None => None,
+        };
+        let result = template.render(context);
+        context.set_strict_variables(strict_variables);
+        context.set_strict_filters(strict_filters);
+        if let Some(escaper) = escaper {
+            context.set_escaper(escaper);
+        }
+
+        result
+    }
+
+    /// The parser the templates are parsed with.
+    pub fn parser(&self) -> &Parser {
+        &self.parser
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Environment;
+    use builder::ParserBuilder;
+    use context::Context;
+    use value::Value;
+    use filters::FilterResult;
+
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn exclaim(input: &Value, _args: &[Value]) -> FilterResult {
+        Ok(Value::Str(input.to_string() + "!"))
+    }
+
+    #[test]
+    fn templates_refer_to_each_other_by_name() {
+        let mut env = Environment::new(ParserBuilder::new()
+                                           .filter("exclaim", Box::new(exclaim))
+                                           .build());
+        env.add_template("page",
+                          "{% extends 'base' %}{% block body %}{% include 'greeting' %}{% endblock %}")
+           .add_template("base", "<{% block body %}{% endblock %}>")
+           .add_template("greeting", "{{ 'hi' | exclaim }}");
+
+        assert_eq!(env.render("page", &mut Context::new()).unwrap(),
+                   Some("<hi!>".to_owned()));
+    }
+
+    #[test]
+    fn named_templates_come_before_files() {
+        let mut env = Environment::new(ParserBuilder::new()
+                                           .file_system(PathBuf::from("tests/fixtures/input"))
+                                           .build());
+        env.add_template("a", "{% include 'example.txt' %}|{% include 'b' %}")
+           .add_template("b", "b");
+        assert_eq!(env.render("a", &mut Context::new()).unwrap(),
+                   Some("5 wot wot\n|b".to_owned()));
+
+        env.add_template("example.txt", "named");
+        assert_eq!(env.render("a", &mut Context::new()).unwrap(),
+                   Some("named|b".to_owned()));
+    }
+
+    #[test]
+    fn templates_are_parsed_once() {
+        let mut env = Environment::new(ParserBuilder::new().build());
+        env.add_template("a", "a");
+        let first = env.get_template("a").unwrap();
+        assert!(Arc::ptr_eq(&first, &env.get_template("a").unwrap()));
+
+        env.add_template("b", "b");
+        assert!(!Arc::ptr_eq(&first, &env.get_template("a").unwrap()));
+    }
+
+    #[test]
+    fn partials_are_parsed_once_until_they_change() {
+        let mut env = Environment::new(ParserBuilder::new().build());
+        env.add_template("a", "a{% include 'shared' %}")
+           .add_template("b", "b{% include 'shared' %}")
+           .add_template("shared", "[{% include 'inner' %}]")
+           .add_template("inner", "1")
+           .add_template("other", "o");
+        assert_eq!(env.render("a", &mut Context::new()).unwrap(), Some("a[1]".to_owned()));
+        assert_eq!(env.render("b", &mut Context::new()).unwrap(), Some("b[1]".to_owned()));
+
+        let cache = env.parser().options().partial_cache.as_ref().unwrap();
+        assert_eq!(cache.len(), 2);
+        let shared = cache.get("shared", "[{% include 'inner' %}]").unwrap();
+
+        // unrelated changes leave the partials alone
+        env.add_template("other", "p");
+        let cache = env.parser().options().partial_cache.as_ref().unwrap();
+        assert!(Arc::ptr_eq(&shared,
+                            &cache.get("shared", "[{% include 'inner' %}]").unwrap()));
+
+        // changing a nested partial drops the partials that include it
+        env.add_template("inner", "2");
+        assert!(env.parser().options().partial_cache.as_ref().unwrap().is_empty());
+        assert_eq!(env.render("b", &mut Context::new()).unwrap(), Some("b[2]".to_owned()));
+    }
+
+    #[test]
+    fn strictness_applies_to_every_render() {
+        let mut env = Environment::new(ParserBuilder::new().build());
+        env.add_template("a", "[{{ 'x' | nope }}]");
+        let err = env.render("a", &mut Context::new()).err().unwrap();
+        assert_eq!(err.template_name(), Some("a"));
+        assert_eq!(err.to_string(),
+                   "a:1:2: Rendering error: Unknown filter 'nope'\n\
+                    [{{ 'x' | nope }}]\n \
+                    ^~~~~~~~~~~~~~~~");
+
+        env.set_strict_filters(false);
+        let mut context = Context::new();
+        assert_eq!(env.render("a", &mut context).unwrap(), Some("[x]".to_owned()));
+        assert!(context.strict_filters());
+
+        env.add_template("b", "[{{ missing }}]");
+        assert_eq!(env.render("b", &mut Context::new()).unwrap(), Some("[]".to_owned()));
+        env.set_strict_variables(true);
+        let err = env.render("b", &mut Context::new()).err().unwrap();
+        assert_eq!(err.inner().to_string(), "Rendering error: Undefined variable missing");
+    }
+
+    #[test]
+    fn autoescaping_covers_partials() {
+        let mut env = Environment::new(ParserBuilder::new().build());
+        env.add_template("page", "<p>{{ text }}</p>{% include 'footer' %}")
+           .add_template("footer", "<i>{{ text }}|{{ text | raw }}</i>")
+           .set_autoescape(true);
+
+        let mut context = Context::new();
+        context.set_val("text", "Tom & \"Jerry\" <3");
+        assert_eq!(env.render("page", &mut context).unwrap(),
+                   Some(concat!("<p>Tom &amp; &quot;Jerry&quot; &lt;3</p>",
+                                "<i>Tom &amp; &quot;Jerry&quot; &lt;3|Tom & \"Jerry\" <3</i>")
+                            .to_owned()));
+    }
+
+    #[test]
+    fn escapers_can_be_chosen_per_environment() {
+        use escape::CsvEscaper;
+
+        let mut env = Environment::new(ParserBuilder::new().build());
+        env.add_template("row", "{{ name }},{{ note }}")
+           .set_escaper(Some(Arc::new(CsvEscaper)));
+
+        let mut context = Context::new();
+        context.set_val("name", "Ann").set_val("note", "says \"hi\", twice");
+        assert_eq!(env.render("row", &mut context).unwrap(),
+                   Some("Ann,\"says \"\"hi\"\", twice\"".to_owned()));
+        assert!(!context.autoescape());
+    }
+
+    #[test]
+    fn errors_name_the_template() {
+        let mut env = Environment::new(ParserBuilder::new().build());
+        env.add_template("broken", "ok\n{% if %}{% endif %}");
+        assert!(!env.has_template("missing"));
+        assert!(env.render("missing", &mut Context::new()).is_err());
+        let err = env.get_template("broken").err().unwrap();
+        assert!(err.to_string().starts_with("broken:2:1: "), "{}", err);
+    }
+
+    #[test]
+    fn environments_can_be_shared_between_threads() {
+        use LiquidOptions;
+        use builder::Parser;
+        use std::thread;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Environment>();
+        assert_send_sync::<Parser>();
+        assert_send_sync::<LiquidOptions>();
+
+        let mut env = Environment::new(ParserBuilder::new().build());
+        env.add_template("page", "[{% include 'name' %}]")
+           .add_template("name", "{{ name }}");
+        let env = Arc::new(env);
+        let threads: Vec<_> = (0..4)
+                                  .map(|i| {
+                                      let env = env.clone();
+                                      thread::spawn(move || {
+                                          let mut context = Context::new();
+                                          context.set_val("name", Value::Num(i as f32));
+                                          env.render("page", &mut context).unwrap()
+                                      })
+                                  })
+                                  .collect();
+        for (i, thread) in threads.into_iter().enumerate() {
+            assert_eq!(thread.join().unwrap(), Some(format!("[{}]", i)));
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use token::Token;
+use lexer::Span;
 
 use std::result;
 use std::error;
 use std::fmt;
 use std::io;
+use std::panic;
 
 use filters::FilterError;
 
@@ -15,9 +17,130 @@ pub enum Error {
     Lexer(String),
     Parser(String),
     Render(String),
+    /// A filter that isn't known was used while strict filters are on.
+    UnknownFilter(String),
+    /// A variable that isn't defined was used while strict variables are
+    /// on.
+    UndefinedVariable(String),
     Filter(FilterError),
+    /// A filter failed while rendering an output. Holds the name of the
+    /// filter and where it is in the output's chain of filters, from 1.
+    FilterCall(String, usize, FilterError),
     Other(String),
     Io(io::Error),
+    /// Writing the output of a render failed. Unlike `Io`, which is for
+    /// reading templates, this never comes from the template itself.
+    Write(io::Error),
+    Limit(String),
+    /// The deadline set with `Context::set_deadline` passed before the
+    /// render finished.
+    RenderTimeout,
+    /// A filter or tag panicked while `Context::set_catch_panics` was on.
+    /// Holds the panic message.
+    Panic(String),
+    Located(Location, Box<Error>),
+}
+
+/// The category of an error, for programs that need to tell errors apart
+/// without reading their messages. New kinds may be added, but the code of
+/// an existing kind won't change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// The template couldn't be split into tokens.
+    Syntax,
+    /// The template's markup doesn't make sense, such as an unknown tag
+    /// or a block without its end.
+    Parse,
+    UnknownFilter,
+    UndefinedVariable,
+    /// A filter was given a value of the wrong type.
+    TypeMismatch,
+    /// A filter was given the wrong arguments.
+    InvalidArgument,
+    /// A number was divided by zero or became too large.
+    Arithmetic,
+    LimitExceeded,
+    Timeout,
+    /// A template or partial couldn't be read.
+    Io,
+    /// The output couldn't be written.
+    Write,
+    /// A filter or tag panicked.
+    Panic,
+    /// Any other failure while rendering.
+    Render,
+    Other,
+}
+
+impl ErrorKind {
+    /// A short, stable name for the kind, such as `unknown_filter`.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ErrorKind::Syntax => "syntax",
+            ErrorKind::Parse => "parse",
+            ErrorKind::UnknownFilter => "unknown_filter",
+            ErrorKind::UndefinedVariable => "undefined_variable",
+            ErrorKind::TypeMismatch => "type_mismatch",
+            ErrorKind::InvalidArgument => "invalid_argument",
+            ErrorKind::Arithmetic => "arithmetic",
+            ErrorKind::LimitExceeded => "limit_exceeded",
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Io => "io",
+            ErrorKind::Write => "write",
+            ErrorKind::Panic => "panic",
+            ErrorKind::Render => "render",
+            ErrorKind::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Translates error messages, so that template errors can be shown to the
+/// people writing templates in their own language. Give one to
+/// `Error::localize`.
+///
+/// ## Example
+/// ```
+/// use liquid::{Error, ErrorKind, ErrorTranslator, LiquidOptions, Renderable, Context};
+///
+/// struct German;
+///
+/// impl ErrorTranslator for German {
+///     fn translate(&self, kind: ErrorKind, params: &[String]) -> Option<String> {
+///         match kind {
+///             ErrorKind::UnknownFilter => Some(format!("Unbekannter Filter '{}'", params[0])),
+///             _ => None,
+///         }
+///     }
+/// }
+///
+/// let template = liquid::parse("{{ 'x' | nope }}", LiquidOptions::default()).unwrap();
+/// let err = template.render(&mut Context::new()).unwrap_err().without_snippet();
+/// assert_eq!(err.localize(&German), "line 1, column 1: Unbekannter Filter 'nope'");
+/// ```
+pub trait ErrorTranslator {
+    /// The message for an error of the given kind, with the parameters
+    /// from `Error::parameters`, or `None` to keep the English message.
+    fn translate(&self, kind: ErrorKind, params: &[String]) -> Option<String>;
+}
+
+/// Where in a template an error occurred. `line` and `column` are 1-based,
+/// and are zero until the location has been resolved against the source.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    pub template: Option<String>,
+    pub span: Span,
+    pub line: usize,
+    pub column: usize,
+
+    /// The line of the template the error happened on, shown with a caret
+    /// under the offending markup when the error is displayed.
+    pub snippet: Option<String>,
 }
 
 impl Error {
@@ -28,6 +151,211 @@ impl Error {
     pub fn renderer<T>(msg: &str) -> Result<T> {
         Err(Error::Render(msg.to_owned()))
     }
+
+    /// Attaches the span of the offending markup to an error. Errors that
+    /// already carry a location keep it, so the innermost span wins.
+    pub fn located(self, span: Span) -> Error {
+        match self {
+            Error::Located(..) => self,
+            err => Error::Located(Location { span: span, ..Default::default() }, Box::new(err)),
+        }
+    }
+
+    /// What sort of error this is, wherever it happened.
+    pub fn kind(&self) -> ErrorKind {
+        match *self.inner() {
+            Error::Lexer(_) => ErrorKind::Syntax,
+            Error::Parser(_) => ErrorKind::Parse,
+            Error::Render(_) => ErrorKind::Render,
+            Error::UnknownFilter(_) => ErrorKind::UnknownFilter,
+            Error::UndefinedVariable(_) => ErrorKind::UndefinedVariable,
+            Error::Filter(ref err) |
+            Error::FilterCall(_, _, ref err) => {
+                match *err {
+                    FilterError::InvalidType(_) => ErrorKind::TypeMismatch,
+                    FilterError::InvalidArgumentCount(_) |
+                    FilterError::InvalidArgument(..) => ErrorKind::InvalidArgument,
+                    FilterError::Arithmetic(_) => ErrorKind::Arithmetic,
+                }
+            }
+            Error::Other(_) => ErrorKind::Other,
+            Error::Io(_) => ErrorKind::Io,
+            Error::Write(_) => ErrorKind::Write,
+            Error::Limit(_) => ErrorKind::LimitExceeded,
+            Error::RenderTimeout => ErrorKind::Timeout,
+            Error::Panic(_) => ErrorKind::Panic,
+            Error::Located(..) => unreachable!(),
+        }
+    }
+
+    /// The details that go into the message of the error, for translating
+    /// it: the name of the filter or variable for `UnknownFilter` and
+    /// `UndefinedVariable`; the name, position and message of the filter
+    /// error for `FilterCall`; nothing for `RenderTimeout`; and the
+    /// message for everything else.
+    pub fn parameters(&self) -> Vec<String> {
+        match *self.inner() {
+            Error::Lexer(ref err) |
+            Error::Parser(ref err) |
+            Error::Render(ref err) |
+            Error::Other(ref err) |
+            Error::Limit(ref err) |
+            Error::Panic(ref err) |
+            Error::UnknownFilter(ref err) |
+            Error::UndefinedVariable(ref err) => vec![err.clone()],
+            Error::Filter(ref err) => vec![err.to_string()],
+            Error::FilterCall(ref name, position, ref err) => {
+                vec![name.clone(), position.to_string(), err.to_string()]
+            }
+            Error::Io(ref err) |
+            Error::Write(ref err) => vec![err.to_string()],
+            Error::RenderTimeout => vec![],
+            Error::Located(..) => unreachable!(),
+        }
+    }
+
+    /// The error written out like `to_string` does, with the message
+    /// translated when the translator knows how to.
+    pub fn localize(&self, translator: &ErrorTranslator) -> String {
+        match *self {
+            Error::Located(ref location, ref err) => {
+                let mut out = format!("{}: {}", location, err.localize(translator));
+                if let Some(ref snippet) = location.snippet {
+                    out.push('\n');
+                    out.push_str(&location.underlined(snippet));
+                }
+                out
+            }
+            ref err => {
+                translator.translate(err.kind(), &err.parameters())
+                          .unwrap_or_else(|| err.to_string())
+            }
+        }
+    }
+
+    /// Where in which template the error happened, when that is known.
+    pub fn location(&self) -> Option<&Location> {
+        match *self {
+            Error::Located(ref location, _) => Some(location),
+            _ => None,
+        }
+    }
+
+    /// The name of the template the error happened in, when it has one.
+    pub fn template_name(&self) -> Option<&str> {
+        self.location().and_then(|l| l.template.as_ref()).map(|t| &t[..])
+    }
+
+    /// The span of the markup the error happened in.
+    pub fn span(&self) -> Option<Span> {
+        self.location().map(|l| l.span)
+    }
+
+    /// The line and column the error happened at, once the location has
+    /// been resolved against the template source.
+    pub fn line_column(&self) -> Option<(usize, usize)> {
+        match self.location() {
+            Some(location) if location.line > 0 => Some((location.line, location.column)),
+            _ => None,
+        }
+    }
+
+    /// The error itself, without its location.
+    pub fn inner(&self) -> &Error {
+        match *self {
+            Error::Located(_, ref err) => err.inner(),
+            ref err => err,
+        }
+    }
+
+    /// Drops the line of source shown under the error, for when the error
+    /// is read by a program rather than a person.
+    pub fn without_snippet(self) -> Error {
+        match self {
+            Error::Located(mut location, err) => {
+                location.snippet = None;
+                Error::Located(location, err)
+            }
+            err => err,
+        }
+    }
+
+    /// Whether this error is from a template going over one of the
+    /// configured `Limits`. These errors are never recovered from.
+    pub fn is_limit(&self) -> bool {
+        match *self {
+            Error::Limit(_) => true,
+            Error::Located(_, ref err) => err.is_limit(),
+            _ => false,
+        }
+    }
+
+    /// Whether this error is from a render running past its deadline.
+    pub fn is_timeout(&self) -> bool {
+        match *self {
+            Error::RenderTimeout => true,
+            Error::Located(_, ref err) => err.is_timeout(),
+            _ => false,
+        }
+    }
+
+    /// Converts the byte offset of a located error into a line and column
+    /// within `source`, and records the template name if none is set yet.
+    pub fn resolve_location(self, source: &str, template: Option<&str>) -> Error {
+        match self {
+            Error::Located(mut location, err) => {
+                if location.line == 0 {
+                    location.resolve(source);
+                }
+                if location.template.is_none() {
+                    location.template = template.map(|t| t.to_owned());
+                }
+                Error::Located(location, err)
+            }
+            err => err,
+        }
+    }
+}
+
+/// Runs `f`, turning a panic into an error holding the panic message.
+pub fn catch_panic<T, F>(f: F) -> result::Result<T, String>
+    where F: FnOnce() -> T
+{
+    panic::catch_unwind(panic::AssertUnwindSafe(f)).map_err(|payload| {
+        match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => {
+                match payload.downcast::<&'static str>() {
+                    Ok(message) => (*message).to_owned(),
+                    Err(_) => "unknown panic".to_owned(),
+                }
+            }
+        }
+    })
+}
+
+impl Location {
+    /// Works out the line and column of the start of the span within
+    /// `source`, and keeps the line as the snippet.
+    pub fn resolve(&mut self, source: &str) {
+        let before = &source[..self.span.start];
+        self.line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        self.column = before[line_start..].chars().count() + 1;
+        let line_end = source[line_start..].find('\n').map_or(source.len(), |i| line_start + i);
+        self.snippet = Some(source[line_start..line_end].trim_right_matches('\r').to_owned());
+    }
+
+    /// The snippet with a line under it marking the span, like
+    /// `{{ x | fo }}` over `^~~~~~~~~~~`.
+    fn underlined(&self, snippet: &str) -> String {
+        let marked = snippet.chars().count().saturating_sub(self.column - 1);
+        let width = (self.span.end - self.span.start).min(marked).max(1);
+        format!("{}\n{}^{}",
+                snippet,
+                " ".repeat(self.column - 1),
+                "~".repeat(width - 1))
+    }
 }
 
 impl From<String> for Error {
@@ -54,9 +382,29 @@ impl fmt::Display for Error {
             Error::Lexer(ref err) => write!(f, "Syntax error: {}", err),
             Error::Parser(ref err) => write!(f, "Parsing error: {}", err),
             Error::Render(ref err) => write!(f, "Rendering error: {}", err),
+            Error::UnknownFilter(ref name) => {
+                write!(f, "Rendering error: Unknown filter '{}'", name)
+            }
+            Error::UndefinedVariable(ref name) => {
+                write!(f, "Rendering error: Undefined variable {}", name)
+            }
             Error::Filter(ref err) => write!(f, "Filtering error: {}", err),
+            Error::FilterCall(ref name, position, ref err) => {
+                write!(f, "Filtering error in filter #{} ({}): {}", position, name, err)
+            }
             Error::Other(ref err) => write!(f, "Error: {}", err),
             Error::Io(ref err) => write!(f, "Io::Error: {}", err),
+            Error::Write(ref err) => write!(f, "Could not write output: {}", err),
+            Error::Limit(ref err) => write!(f, "Limit exceeded: {}", err),
+            Error::RenderTimeout => write!(f, "Render timeout: the deadline has passed"),
+            Error::Panic(ref err) => write!(f, "Panicked while rendering: {}", err),
+            Error::Located(ref location, ref err) => {
+                try!(write!(f, "{}: {}", location, err));
+                match location.snippet {
+                    Some(ref snippet) => write!(f, "\n{}", location.underlined(snippet)),
+                    None => Ok(()),
+                }
+            }
         }
     }
 }
@@ -67,15 +415,140 @@ impl error::Error for Error {
             Error::Lexer(ref err) |
             Error::Parser(ref err) |
             Error::Render(ref err) |
-            Error::Other(ref err) => err,
-            Error::Filter(ref err) => err.description(),
-            Error::Io(ref err) => err.description(),
+            Error::Other(ref err) |
+            Error::Panic(ref err) |
+            Error::Limit(ref err) => err,
+            Error::UnknownFilter(_) => "unknown filter",
+            Error::UndefinedVariable(_) => "undefined variable",
+            Error::Filter(ref err) |
+            Error::FilterCall(_, _, ref err) => err.description(),
+            Error::Io(ref err) |
+            Error::Write(ref err) => err.description(),
+            Error::RenderTimeout => "the deadline has passed",
+            Error::Located(_, ref err) => err.description(),
         }
     }
 
-    fn cause(&self) -> Option<&error::Error> {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
         match *self {
+            // the location is shown with the error it wraps, which is
+            // already part of the message
+            Error::Located(_, ref err) => err.source(),
+            Error::Io(ref err) |
+            Error::Write(ref err) => Some(err),
+            Error::Filter(ref err) |
+            Error::FilterCall(_, _, ref err) => Some(err),
             _ => None,
         }
     }
 }
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.template, self.line) {
+            (_, 0) => write!(f, "byte {}", self.span.start),
+            (&Some(ref name), _) => write!(f, "{}:{}:{}", name, self.line, self.column),
+            (&None, _) => write!(f, "line {}, column {}", self.line, self.column),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use lexer::Span;
+
+    #[test]
+    fn resolves_line_and_column() {
+        let source = "first\nsecond\n  {% oops %}";
+        let err = Error::Parser("bad".to_owned()).located(Span::new(15, 25));
+        assert_eq!(err.resolve_location(source, None).to_string(),
+                   "line 3, column 3: Parsing error: bad\n  {% oops %}\n  ^~~~~~~~~~");
+    }
+
+    #[test]
+    fn located_errors_expose_their_location() {
+        let err = Error::Render("oops".to_owned())
+                      .located(Span::new(2, 4))
+                      .resolve_location("a\nbcd", Some("page.liquid"));
+        assert_eq!(err.template_name(), Some("page.liquid"));
+        assert_eq!(err.span(), Some(Span::new(2, 4)));
+        assert_eq!(err.line_column(), Some((2, 1)));
+        assert_eq!(err.inner().to_string(), "Rendering error: oops");
+
+        let err = Error::Render("oops".to_owned());
+        assert!(err.location().is_none());
+        assert_eq!(err.line_column(), None);
+    }
+
+    #[test]
+    fn sources_are_chained() {
+        use std::error::Error as StdError;
+        use std::io;
+        use filters::FilterError;
+
+        let err = Error::from(io::Error::new(io::ErrorKind::NotFound, "gone"))
+                      .located(Span::new(0, 1));
+        let io = err.source().unwrap();
+        assert_eq!(io.to_string(), "gone");
+        assert!(io.source().is_none());
+
+        let err = Error::FilterCall("plus".to_owned(),
+                                    1,
+                                    FilterError::InvalidType("Num expected".to_owned()));
+        assert_eq!(err.source().unwrap().to_string(), "Invalid type : Num expected");
+        assert!(Error::Render("x".to_owned()).source().is_none());
+        assert!(Error::Render("x".to_owned()).located(Span::new(0, 1)).source().is_none());
+    }
+
+    #[test]
+    fn kinds_see_through_locations() {
+        use super::ErrorKind;
+        use filters::FilterError;
+
+        let err = Error::UnknownFilter("nope".to_owned()).located(Span::new(0, 1));
+        assert_eq!(err.kind(), ErrorKind::UnknownFilter);
+        assert_eq!(err.kind().code(), "unknown_filter");
+        assert_eq!(Error::FilterCall("plus".to_owned(),
+                                     1,
+                                     FilterError::InvalidType("Num expected".to_owned()))
+                       .kind(),
+                   ErrorKind::TypeMismatch);
+        assert_eq!(Error::RenderTimeout.kind(), ErrorKind::Timeout);
+        assert_eq!(Error::Lexer("x".to_owned()).kind(), ErrorKind::Syntax);
+    }
+
+    #[test]
+    fn localized_errors_keep_their_location() {
+        use super::{ErrorKind, ErrorTranslator};
+
+        struct Shouting;
+
+        impl ErrorTranslator for Shouting {
+            fn translate(&self, kind: ErrorKind, params: &[String]) -> Option<String> {
+                match kind {
+                    ErrorKind::Render => Some(params[0].to_uppercase()),
+                    _ => None,
+                }
+            }
+        }
+
+        let err = Error::Render("oops".to_owned())
+                      .located(Span::new(2, 4))
+                      .resolve_location("a\nbcd", None);
+        assert_eq!(err.localize(&Shouting), "line 2, column 1: OOPS\nbcd\n^~");
+        let err = Error::Parser("bad".to_owned());
+        assert_eq!(err.localize(&Shouting), err.to_string());
+    }
+
+    #[test]
+    fn innermost_location_wins() {
+        let err = Error::Parser("bad".to_owned())
+                      .located(Span::new(6, 7))
+                      .located(Span::new(0, 1));
+        assert_eq!(err.resolve_location("a\nb\nc\nd", Some("page.liquid"))
+                      .without_snippet()
+                      .to_string(),
+                   "page.liquid:4:1: Parsing error: bad");
+    }
+}
@@ -0,0 +1,129 @@
+/// Makes the result of a `{{ }}` output safe to include in a particular
+/// kind of document. Set one for a render with `Context::set_escaper`, or
+/// for every render through an `Environment` with
+/// `Environment::set_escaper`. Outputs whose value is marked safe, by the
+/// `raw` filter, by `capture` or with [SafeStr](struct.SafeStr.html),
+/// aren't escaped.
+///
+/// ## Example
+/// ```
+/// use liquid::{Escaper, Context, LiquidOptions, Renderable};
+/// use std::sync::Arc;
+///
+/// struct Brackets;
+///
+/// impl Escaper for Brackets {
+///     fn escape(&self, text: &str) -> String {
+///         text.replace("[", "\\[").replace("]", "\\]")
+///     }
+/// }
+///
+/// let template = liquid::parse("[{{ link }}]", LiquidOptions::default()).unwrap();
+/// let mut ctx = Context::new();
+/// ctx.set_val("link", "[x]");
+/// ctx.set_escaper(Some(Arc::new(Brackets)));
+/// assert_eq!(template.render(&mut ctx).unwrap(), Some("[\\[x\\]]".to_owned()));
+/// ```
+pub trait Escaper: Send + Sync {
+    fn escape(&self, text: &str) -> String;
+}
+
+/// Escapes text for HTML element content and quoted attribute values.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HtmlEscaper;
+
+impl Escaper for HtmlEscaper {
+    fn escape(&self, text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '<' => escaped.push_str("&lt;"),
+                '>' => escaped.push_str("&gt;"),
+                '"' => escaped.push_str("&quot;"),
+                '\'' => escaped.push_str("&#39;"),
+                c => escaped.push(c),
+            }
+        }
+        escaped
+    }
+}
+
+/// Escapes text for the inside of a JSON string, between the quotes.
+/// `<`, `>` and `&` are escaped as well, so the JSON can be embedded in a
+/// `<script>` element.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonEscaper;
+
+impl Escaper for JsonEscaper {
+    fn escape(&self, text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\t' => escaped.push_str("\\t"),
+                c if c < ' ' || c == '<' || c == '>' || c == '&' => {
+                    escaped.push_str(&format!("\\u{:04x}", c as u32))
+                }
+                c => escaped.push(c),
+            }
+        }
+        escaped
+    }
+}
+
+/// Quotes text as a single argument for a POSIX shell.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShellEscaper;
+
+impl Escaper for ShellEscaper {
+    fn escape(&self, text: &str) -> String {
+        format!("'{}'", text.replace('\'', "'\\''"))
+    }
+}
+
+/// Quotes text as a single CSV field, when it needs quoting.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CsvEscaper;
+
+impl Escaper for CsvEscaper {
+    fn escape(&self, text: &str) -> String {
+        if text.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        } else {
+            text.to_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Escaper, HtmlEscaper, JsonEscaper, ShellEscaper, CsvEscaper};
+
+    #[test]
+    fn html() {
+        assert_eq!(HtmlEscaper.escape("<a href=\"x\">Tom & 'Jerry'</a>"),
+                   "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;");
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(JsonEscaper.escape("say \"hi\"\\\n</script>\u{1}"),
+                   "say \\\"hi\\\"\\\\\\n\\u003c/script\\u003e\\u0001");
+    }
+
+    #[test]
+    fn shell() {
+        assert_eq!(ShellEscaper.escape("it's; rm -rf"), "'it'\\''s; rm -rf'");
+        assert_eq!(ShellEscaper.escape(""), "''");
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(CsvEscaper.escape("plain"), "plain");
+        assert_eq!(CsvEscaper.escape("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+}
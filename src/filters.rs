@@ -1,8 +1,11 @@
 use std::fmt;
 use std::error::Error;
+use std::collections::HashMap;
 
 use value::Value;
 use value::Value::*;
+use random::RandomSource;
+use date::DateTime;
 
 use self::FilterError::*;
 
@@ -11,6 +14,8 @@ pub enum FilterError {
     InvalidType(String),
     InvalidArgumentCount(String),
     InvalidArgument(u16, String), // (position, "expected / given ")
+    /// Dividing by zero, or a result that isn't a finite number.
+    Arithmetic(String),
 }
 
 impl FilterError {
@@ -27,6 +32,7 @@ impl fmt::Display for FilterError {
             InvalidArgument(ref pos, ref e) => {
                 write!(f, "Invalid argument given at position {} : {}", pos, e)
             }
+            Arithmetic(ref e) => write!(f, "Arithmetic error : {}", e),
         }
     }
 }
@@ -36,17 +42,21 @@ impl Error for FilterError {
         match *self {
             InvalidType(ref e) |
             InvalidArgumentCount(ref e) |
-            InvalidArgument(_, ref e) => e,
+            InvalidArgument(_, ref e) |
+            Arithmetic(ref e) => e,
         }
     }
 }
 
 pub type FilterResult = Result<Value, FilterError>;
-pub type Filter = Fn(&Value, &[Value]) -> FilterResult;
+
+/// A filter. Filters are shared by every template parsed with them, which
+/// may be rendered from several threads, so they must be `Send` and `Sync`.
+pub type Filter = Fn(&Value, &[Value]) -> FilterResult + Send + Sync;
 
 pub fn size(input: &Value, _args: &[Value]) -> FilterResult {
     match *input {
-        Str(ref x) => Ok(Num(x.len() as f32)),
+        Str(ref x) | Safe(ref x) => Ok(Num(x.len() as f32)),
         Array(ref x) => Ok(Num(x.len() as f32)),
         Object(ref x) => Ok(Num(x.len() as f32)),
         _ => Err(InvalidType("String, Array or Object expected".to_owned())),
@@ -55,75 +65,106 @@ pub fn size(input: &Value, _args: &[Value]) -> FilterResult {
 
 pub fn upcase(input: &Value, _args: &[Value]) -> FilterResult {
     match *input {
-        Str(ref s) => Ok(Str(s.to_uppercase())),
+        Str(ref s) | Safe(ref s) => Ok(Str(s.to_uppercase())),
         _ => Err(InvalidType("String expected".to_owned())),
     }
 }
 
 pub fn minus(input: &Value, args: &[Value]) -> FilterResult {
-
-    let num = match *input {
-        Num(n) => n,
-        _ => return Err(InvalidType("Num expected".to_owned())),
-    };
-    match args.first() {
-        Some(&Num(x)) => Ok(Num(num - x)),
-        _ => Err(InvalidArgument(0, "Num expected".to_owned())),
-    }
+    binary(input, args, NumberPolicy::Strict, |a, b| a - b)
 }
 
 pub fn plus(input: &Value, args: &[Value]) -> FilterResult {
-
-    let num = match *input {
-        Num(n) => n,
-        _ => return Err(InvalidType("Num expected".to_owned())),
-    };
-    match args.first() {
-        Some(&Num(x)) => Ok(Num(num + x)),
-        _ => Err(InvalidArgument(0, "Num expected".to_owned())),
-    }
+    binary(input, args, NumberPolicy::Strict, |a, b| a + b)
 }
 
 pub fn times(input: &Value, args: &[Value]) -> FilterResult {
+    binary(input, args, NumberPolicy::Strict, |a, b| a * b)
+}
 
-    let num = match *input {
-        Num(n) => n,
-        _ => return Err(InvalidType("Num expected".to_owned())),
-    };
-    match args.first() {
-        Some(&Num(x)) => Ok(Num(num * x)),
-        _ => Err(InvalidArgument(0, "Num expected".to_owned())),
+pub fn divided_by(input: &Value, args: &[Value]) -> FilterResult {
+    divide(input, args, NumberPolicy::Strict)
+}
+
+pub fn floor(input: &Value, _args: &[Value]) -> FilterResult {
+    unary(input, NumberPolicy::Strict, f32::floor)
+}
+
+pub fn ceil(input: &Value, _args: &[Value]) -> FilterResult {
+    unary(input, NumberPolicy::Strict, f32::ceil)
+}
+
+pub fn round(input: &Value, _args: &[Value]) -> FilterResult {
+    unary(input, NumberPolicy::Strict, f32::round)
+}
+
+/// What the arithmetic filters do about dividing by zero and about numbers
+/// that aren't finite, which would otherwise be passed silently from one
+/// filter to the next. Set with `Context::set_number_policy`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NumberPolicy {
+    /// Dividing by zero is an error, as is any result that is infinite or
+    /// not a number, such as from overflowing or from an input that was
+    /// already infinite.
+    Strict,
+    /// Like Ruby Liquid: dividing a whole number by zero is an error, but
+    /// other results are passed on as they are, so `1.5 | divided_by: 0`
+    /// is infinite.
+    Ruby,
+}
+
+impl Default for NumberPolicy {
+    fn default() -> NumberPolicy {
+        NumberPolicy::Strict
     }
 }
 
-pub fn divided_by(input: &Value, args: &[Value]) -> FilterResult {
+fn finite(n: f32, policy: NumberPolicy) -> FilterResult {
+    if policy == NumberPolicy::Strict && !n.is_finite() {
+        let problem = if n.is_nan() {
+            "Result is not a number"
+        } else {
+            "Result is too large"
+        };
+        return Err(Arithmetic(problem.to_owned()));
+    }
+    Ok(Num(n))
+}
+
+fn operands(input: &Value, args: &[Value]) -> Result<(f32, f32), FilterError> {
+    if args.len() != 1 {
+        return Err(InvalidArgumentCount(format!("expected 1, {} given", args.len())));
+    }
     let num = match *input {
         Num(n) => n,
         _ => return Err(InvalidType("Num expected".to_owned())),
     };
     match args.first() {
-        Some(&Num(x)) => Ok(Num((num / x).floor())),
+        Some(&Num(x)) => Ok((num, x)),
         _ => Err(InvalidArgument(0, "Num expected".to_owned())),
     }
 }
 
-pub fn floor(input: &Value, _args: &[Value]) -> FilterResult {
-    match *input {
-        Num(n) => Ok(Num(n.floor())),
-        _ => Err(InvalidType("Num expected".to_owned())),
-    }
+fn binary<F>(input: &Value, args: &[Value], policy: NumberPolicy, op: F) -> FilterResult
+    where F: Fn(f32, f32) -> f32
+{
+    let (a, b) = try!(operands(input, args));
+    finite(op(a, b), policy)
 }
 
-pub fn ceil(input: &Value, _args: &[Value]) -> FilterResult {
-    match *input {
-        Num(n) => Ok(Num(n.ceil())),
-        _ => Err(InvalidType("Num expected".to_owned())),
+fn divide(input: &Value, args: &[Value], policy: NumberPolicy) -> FilterResult {
+    let (a, b) = try!(operands(input, args));
+    if b == 0f32 && (policy == NumberPolicy::Strict || a.fract() == 0f32) {
+        return Err(Arithmetic("Division by zero".to_owned()));
     }
+    finite((a / b).floor(), policy)
 }
 
-pub fn round(input: &Value, _args: &[Value]) -> FilterResult {
+fn unary<F>(input: &Value, policy: NumberPolicy, op: F) -> FilterResult
+    where F: Fn(f32) -> f32
+{
     match *input {
-        Num(n) => Ok(Num(n.round())),
+        Num(n) => finite(op(n), policy),
         _ => Err(InvalidType("Num expected".to_owned())),
     }
 }
@@ -133,7 +174,7 @@ pub fn replace(input: &Value, args: &[Value]) -> FilterResult {
         return Err(InvalidArgumentCount(format!("expected 2, {} given", args.len())));
     }
     match *input {
-        Str(ref x) => {
+        Str(ref x) | Safe(ref x) => {
             let arg1 = match args[0] {
                 Str(ref a) => a,
                 _ => return Err(InvalidArgument(0, "Str expected".to_owned())),
@@ -148,6 +189,153 @@ pub fn replace(input: &Value, args: &[Value]) -> FilterResult {
     }
 }
 
+/// Marks its input as already safe to include in the output, so that it
+/// isn't escaped when auto-escaping is on. Numbers and booleans, which
+/// never need escaping, are passed through untouched.
+pub fn raw(input: &Value, _args: &[Value]) -> FilterResult {
+    match *input {
+        Num(_) | Bool(_) | Safe(_) => Ok(input.clone()),
+        _ => Ok(Safe(input.to_string())),
+    }
+}
+
+/// Picks an element of an array at random. Unlike the other built-in
+/// filters this needs a random source, so each context registers its own
+/// `sample` using the source set with `Context::set_random`.
+pub fn sample(input: &Value, _args: &[Value], random: &mut RandomSource) -> FilterResult {
+    match *input {
+        Array(ref x) if x.is_empty() => Ok(Str("".to_owned())),
+        Array(ref x) => Ok(x[random.below(x.len())].clone()),
+        _ => Err(InvalidType("Array expected".to_owned())),
+    }
+}
+
+/// Formats a date in `strftime` notation, or in `default_format` when no
+/// format is given. The input may be a date like `2017-03-14 15:09:26`, a
+/// number of seconds since 1970, or `"now"` or `"today"` for the given
+/// current time. Like `sample`, each context registers its own `date` so
+/// that it can use the context's clock.
+pub fn date(input: &Value, args: &[Value], now: &DateTime, default_format: &str) -> FilterResult {
+    let format = match args.first() {
+        Some(&Str(ref format)) => Some(&format[..]),
+        Some(_) => return Err(InvalidArgument(0, "String expected".to_owned())),
+        None => None,
+    };
+    let date = match *input {
+        Str(ref s) | Safe(ref s) if s == "now" || s == "today" => *now,
+        Str(ref s) | Safe(ref s) => {
+            match DateTime::try_parse(s) {
+                Ok(date) => date,
+                Err(why) => {
+                    return Err(InvalidType(format!("Date expected, but '{}' can't be read: {}",
+                                                   s,
+                                                   why)))
+                }
+            }
+        }
+        Num(n) => DateTime::from_timestamp(n as i64),
+        _ => return Err(InvalidType("Date expected".to_owned())),
+    };
+    // the default format comes from the host rather than the template,
+    // so it is written leniently
+    match format {
+        Some(format) => date.try_format(format).map(Str).map_err(|why| InvalidArgument(0, why)),
+        None => Ok(Str(date.format(default_format))),
+    }
+}
+
+/// The built-in filters, with the names of the arguments each one takes.
+const SIGNATURES: &'static [(&'static str, &'static [&'static str])] =
+    &[("size", &[]),
+      ("upcase", &[]),
+      ("minus", &["number"]),
+      ("plus", &["number"]),
+      ("times", &["number"]),
+      ("divided_by", &["number"]),
+      ("ceil", &[]),
+      ("floor", &[]),
+      ("round", &[]),
+      ("replace", &["search", "replacement"]),
+      ("raw", &[]),
+      ("sample", &[]),
+      ("date", &["format"])];
+
+/// The number of arguments the named built-in filter takes.
+pub fn arity(name: &str) -> Option<usize> {
+    SIGNATURES.iter().find(|&&(n, _)| n == name).map(|&(_, params)| params.len())
+}
+
+/// How the named built-in filter is called, such as
+/// `replace: <search>, <replacement>`.
+pub fn signature(name: &str) -> Option<String> {
+    SIGNATURES.iter().find(|&&(n, _)| n == name).map(|&(name, params)| {
+        let params: Vec<String> = params.iter().map(|p| format!("<{}>", p)).collect();
+        if params.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}: {}", name, params.join(", "))
+        }
+    })
+}
+
+lazy_static! {
+    // The built-in filters, boxed once and borrowed by every context.
+    static ref BUILTINS: HashMap<String, Box<Filter>> = {
+        let mut filters: HashMap<String, Box<Filter>> = HashMap::new();
+        filters.insert("size".to_owned(), Box::new(size));
+        filters.insert("upcase".to_owned(), Box::new(upcase));
+        filters.insert("minus".to_owned(), Box::new(minus));
+        filters.insert("plus".to_owned(), Box::new(plus));
+        filters.insert("times".to_owned(), Box::new(times));
+        filters.insert("divided_by".to_owned(), Box::new(divided_by));
+        filters.insert("ceil".to_owned(), Box::new(ceil));
+        filters.insert("floor".to_owned(), Box::new(floor));
+        filters.insert("round".to_owned(), Box::new(round));
+        filters.insert("replace".to_owned(), Box::new(replace));
+        filters.insert("raw".to_owned(), Box::new(raw));
+        filters
+    };
+}
+
+lazy_static! {
+    // The arithmetic filters for `NumberPolicy::Ruby`, which contexts with
+    // that policy use in place of the built-in ones.
+    static ref RUBY_MATH: HashMap<String, Box<Filter>> = {
+        let ruby = NumberPolicy::Ruby;
+        let mut filters: HashMap<String, Box<Filter>> = HashMap::new();
+        filters.insert("minus".to_owned(),
+                       Box::new(move |input, args| binary(input, args, ruby, |a, b| a - b)));
+        filters.insert("plus".to_owned(),
+                       Box::new(move |input, args| binary(input, args, ruby, |a, b| a + b)));
+        filters.insert("times".to_owned(),
+                       Box::new(move |input, args| binary(input, args, ruby, |a, b| a * b)));
+        filters.insert("divided_by".to_owned(),
+                       Box::new(move |input, args| divide(input, args, ruby)));
+        filters.insert("ceil".to_owned(), Box::new(move |input, _| unary(input, ruby, f32::ceil)));
+        filters.insert("floor".to_owned(),
+                       Box::new(move |input, _| unary(input, ruby, f32::floor)));
+        filters.insert("round".to_owned(),
+                       Box::new(move |input, _| unary(input, ruby, f32::round)));
+        filters
+    };
+}
+
+/// The arithmetic filters that behave as the given policy says, to be
+/// looked up before the built-in ones, or `None` if the built-in ones
+/// already do.
+pub fn number_filters(policy: NumberPolicy) -> Option<&'static HashMap<String, Box<Filter>>> {
+    match policy {
+        NumberPolicy::Strict => None,
+        NumberPolicy::Ruby => Some(&RUBY_MATH),
+    }
+}
+
+/// The registry of built-in filters, which contexts fall back to when a
+/// filter hasn't been registered with them or with the parser.
+pub fn builtin_filters() -> &'static HashMap<String, Box<Filter>> {
+    &BUILTINS
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -213,6 +401,33 @@ mod tests {
         assert!(divided_by(&Num(2.5), &[]).is_err());
     }
 
+    #[test]
+    fn non_finite_numbers_follow_the_policy() {
+        assert_eq!(divided_by(&Num(1f32), &[Num(0f32)]).unwrap_err().to_string(),
+                   "Arithmetic error : Division by zero");
+        assert!(times(&Num(1e30), &[Num(1e30)]).is_err());
+        assert!(floor(&Num(::std::f32::NAN), &[]).is_err());
+
+        let ruby = number_filters(NumberPolicy::Ruby).unwrap();
+        assert!(ruby["divided_by"](&Num(1f32), &[Num(0f32)]).is_err());
+        assert_eq!(ruby["divided_by"](&Num(1.5), &[Num(0f32)]).unwrap(),
+                   Num(::std::f32::INFINITY));
+        assert_eq!(ruby["times"](&Num(1e30), &[Num(1e30)]).unwrap(),
+                   Num(::std::f32::INFINITY));
+        assert_eq!(ruby["plus"](&Num(1f32), &[Num(2f32)]).unwrap(), Num(3f32));
+        assert!(number_filters(NumberPolicy::Strict).is_none());
+    }
+
+    #[test]
+    fn signatures_name_the_arguments() {
+        assert_eq!(signature("replace"),
+                   Some("replace: <search>, <replacement>".to_owned()));
+        assert_eq!(signature("size"), Some("size".to_owned()));
+        assert_eq!(signature("nope"), None);
+        assert_eq!(arity("date"), Some(1));
+        assert!(plus(&Num(1f32), &[Num(1f32), Num(2f32)]).is_err());
+    }
+
     #[test]
     fn unit_floor() {
         assert_eq!(unit!(floor, Num(1.1f32), &[]), Num(1f32));
@@ -241,4 +456,30 @@ mod tests {
                    tos!("foofoo"));
     }
 
+    #[test]
+    fn unit_raw() {
+        assert_eq!(unit!(raw, tos!("<b>")), tos!("<b>"));
+        assert_eq!(unit!(raw, Num(1f32)), Num(1f32));
+    }
+
+    #[test]
+    fn unit_date() {
+        use date::DateTime;
+
+        let now = DateTime::from_timestamp(1489504166);
+        assert_eq!(date(&tos!("now"), &[tos!("%F %T")], &now, "%F").unwrap(),
+                   tos!("2017-03-14 15:09:26"));
+        assert_eq!(date(&tos!("2000-02-29"), &[tos!("%b %e, %Y")], &now, "%F").unwrap(),
+                   tos!("Feb 29, 2000"));
+        assert_eq!(date(&Num(86400f32), &[], &now, "%F").unwrap(), tos!("1970-01-02"));
+        assert!(date(&tos!("yesterday"), &[], &now, "%F").is_err());
+        assert!(date(&tos!("now"), &[Num(1f32)], &now, "%F").is_err());
+        assert_eq!(date(&tos!("2017-02-30"), &[], &now, "%F").unwrap_err().to_string(),
+                   "Invalid type : Date expected, but '2017-02-30' can't be read: there is no \
+                    day 30 in February 2017");
+        assert_eq!(date(&tos!("now"), &[tos!("%Y-%q")], &now, "%F").unwrap_err().to_string(),
+                   "Invalid argument given at position 0 : unknown directive '%q' in '%Y-%q'");
+        assert_eq!(date(&tos!("now"), &[], &now, "%Y %q").unwrap(), tos!("2017 %q"));
+    }
+
 }
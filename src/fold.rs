@@ -0,0 +1,187 @@
+use Renderable;
+use Transform;
+use context::Context;
+use filters::Filter;
+use output::{Output, VarOrVal};
+use parser;
+use value::Value;
+use token::Token::{self, Identifier, Pipe, Colon, StringLiteral, NumberLiteral, BooleanLiteral,
+                   NilLiteral, EmptyLiteral, BlankLiteral};
+use lexer::Element::Expression;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Filters whose result can change from one render to the next, as they
+/// depend on the clock, a random choice or the locale of the context.
+const IMPURE_FILTERS: &'static [&'static str] = &["date", "sample", "t", "l"];
+
+/// Whether an output only depends on literals and pure filters, so that it
+/// renders the same way every time. Filter arguments are always literals,
+/// so this comes down to the value being filtered, the filters used, and
+/// making sure that the only other identifiers are the keys of named
+/// arguments.
+fn is_constant(tokens: &[Token]) -> bool {
+    match tokens[0] {
+        StringLiteral(_) | NumberLiteral(_) | BooleanLiteral(_) | NilLiteral | EmptyLiteral |
+        BlankLiteral => {}
+        _ => return false,
+    }
+
+    tokens.iter().enumerate().all(|(i, t)| {
+        match *t {
+            Identifier(ref name) if tokens[i - 1] == Pipe => !IMPURE_FILTERS.contains(&&**name),
+            Identifier(_) => tokens.get(i + 1) == Some(&Colon),
+            _ => true,
+        }
+    })
+}
+
+/// Creates a [Transform](type.Transform.html) that works out the value of
+/// outputs made up only of literals, like `{{ 'hello' | upcase }}` or
+/// `{{ 3 | plus: 4 }}`, once while parsing, and replaces them with outputs
+/// of that value. The value is still escaped when the template is
+/// rendered with an escaper, unless it is marked safe.
+///
+/// The built-in filters are always available, along with any in `filters`,
+/// which should be the same filters the template is rendered with and
+/// give the same result for the same input every time. Outputs that use
+/// any other filter, that use `date`, `sample`, `t` or `l`, whose results
+/// depend on the context, or that fail to render, are left as they are so
+/// that they behave just as they would have otherwise.
+///
+/// ```
+/// use liquid::{LiquidOptions, Renderable, Context};
+///
+/// let mut options = LiquidOptions::default();
+/// options.register_transform(liquid::constant_folder(None));
+///
+/// let template = liquid::parse("{{ 'hello' | upcase }}", options).unwrap();
+/// let output = template.render(&mut Context::new());
+/// assert_eq!(output.unwrap(), Some("HELLO".to_owned()));
+/// ```
+pub fn constant_folder(filters: Option<Arc<HashMap<String, Box<Filter>>>>) -> Box<Transform> {
+    Box::new(move |element, node, state| {
+        let output = match *element {
+            Expression(ref tokens, _, _) if is_constant(tokens) => {
+                match parser::output(tokens, state.symbols()) {
+                    Ok(output) => output,
+                    Err(_) => return Ok(node),
+                }
+            }
+            _ => return Ok(node),
+        };
+
+        let mut context = Context::new();
+        context.replace_shared_filters(filters.clone());
+        let value = match output.value(&context) {
+            Ok(value) => value.map_or(Value::str(""), Cow::into_owned),
+            Err(_) => return Ok(node),
+        };
+        Ok(Box::new(Output::new(VarOrVal::Val(value), vec![])) as Box<Renderable>)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{constant_folder, is_constant};
+    use LiquidOptions;
+    use Renderable;
+    use parse;
+    use context::Context;
+    use lexer::tokenize;
+    use lexer::Element::Expression;
+    use token::Token;
+    use value::Value;
+    use filters::Filter;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn tokens(text: &str) -> Vec<Token<'static>> {
+        match tokenize(&format!("{{{{ {} }}}}", text)).unwrap()[0] {
+            Expression(ref tokens, _, _) => tokens.iter().cloned().map(Token::into_owned).collect(),
+            _ => panic!("not an output"),
+        }
+    }
+
+    #[test]
+    fn finds_constant_outputs() {
+        for text in &["'a'", "3 | plus: 4", "'a' | replace: 'a', 'b' | upcase", "true", "nil"] {
+            assert!(is_constant(&tokens(text)), "{}", text);
+        }
+        for text in &["a", "a | upcase", "'a' | append: b", "(1..3) | size", "'a' if b",
+                      "'now' | date: '%Y'", "'a,b' | split: ',' | sample", "'k' | t"] {
+            assert!(!is_constant(&tokens(text)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn folds_literal_filter_chains() {
+        let mut options = LiquidOptions::default();
+        options.register_transform(constant_folder(None));
+        let template = parse("{{ 3 | plus: 4 }} {{ 'hi' | upcase }} {{ x | upcase }}", options)
+                           .unwrap();
+
+        let mut context = Context::new();
+        context.set_val("x", Value::str("x"));
+        assert_eq!(template.render(&mut context).unwrap(), Some("7 HI X".to_owned()));
+    }
+
+    #[test]
+    fn leaves_outputs_that_depend_on_the_context() {
+        use date::FixedClock;
+        use std::time::UNIX_EPOCH;
+
+        let mut options = LiquidOptions::default();
+        options.register_transform(constant_folder(None));
+        let template = parse("{{ 'now' | date: '%Y' }}", options).unwrap();
+
+        let mut context = Context::new();
+        context.set_clock(Box::new(FixedClock::new(UNIX_EPOCH)));
+        assert_eq!(template.render(&mut context).unwrap(), Some("1970".to_owned()));
+    }
+
+    #[test]
+    fn folded_outputs_are_still_escaped() {
+        let mut options = LiquidOptions::default();
+        options.register_transform(constant_folder(None));
+        let template = parse("{{ '<b>' }} {{ '<i>' | raw }} {{ '<a>' | upcase }}", options)
+                           .unwrap();
+
+        let mut context = Context::new();
+        context.set_autoescape(true);
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("&lt;b&gt; <i> &lt;A&gt;".to_owned()));
+    }
+
+    #[test]
+    fn folds_with_the_given_filters() {
+        let mut filters: HashMap<String, Box<Filter>> = HashMap::new();
+        filters.insert("shout".to_owned(), Box::new(|_, _| Ok(Value::str("A!"))));
+        let mut options = LiquidOptions::default();
+        options.register_transform(constant_folder(Some(Arc::new(filters))));
+
+        // the filter isn't around when rendering, so this only works if the
+        // output was folded away
+        let template = parse("{{ 'a' | shout }}", options).unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(), Some("A!".to_owned()));
+    }
+
+    #[test]
+    fn leaves_failing_outputs_alone() {
+        let mut options = LiquidOptions::default();
+        options.register_transform(constant_folder(None));
+        let template = parse("{{ 'a' | shout }}", options).unwrap();
+
+        let mut context = Context::new();
+        context.add_filter("shout", Box::new(|_, _| Ok(Value::str("A!"))));
+        assert_eq!(template.render(&mut context).unwrap(), Some("A!".to_owned()));
+
+        let mut options = LiquidOptions::default();
+        options.register_transform(constant_folder(None));
+        let template = parse("{{ 'a' | plus: 1 }}", options).unwrap();
+        assert!(template.render(&mut Context::new()).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::hash::BuildHasher;
+#[cfg(feature = "fast-hash")]
+use std::hash::Hasher;
+#[cfg(not(feature = "fast-hash"))]
+use std::collections::hash_map::{DefaultHasher, RandomState};
+
+/// The 64-bit FNV-1a hash. It is much quicker than the standard library's
+/// SipHash on short keys like variable names, but gives no protection
+/// against keys chosen to collide, so it is only used with the `fast-hash`
+/// feature.
+#[cfg(feature = "fast-hash")]
+pub struct FnvHasher(u64);
+
+#[cfg(feature = "fast-hash")]
+impl Default for FnvHasher {
+    fn default() -> FnvHasher {
+        FnvHasher(0xcbf29ce484222325)
+    }
+}
+
+#[cfg(feature = "fast-hash")]
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// The hasher of the maps in a `Value::Object` and of the globals of a
+/// `Context`. This is the standard library's SipHash, with keys chosen at
+/// random for each map, unless the `fast-hash` feature is on, when it is
+/// FNV instead. The type is the same either way, so turning the feature on
+/// doesn't change the types of the public API.
+#[derive(Clone, Default)]
+pub struct ObjectHasher {
+    #[cfg(not(feature = "fast-hash"))]
+    keys: RandomState,
+}
+
+#[cfg(not(feature = "fast-hash"))]
+impl BuildHasher for ObjectHasher {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        self.keys.build_hasher()
+    }
+}
+
+#[cfg(feature = "fast-hash")]
+impl BuildHasher for ObjectHasher {
+    type Hasher = FnvHasher;
+
+    fn build_hasher(&self) -> FnvHasher {
+        FnvHasher::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ObjectHasher;
+
+    use std::hash::{BuildHasher, Hash, Hasher};
+
+    #[test]
+    fn a_map_hashes_the_same_key_the_same_way() {
+        let hasher = ObjectHasher::default();
+        let hash = |key: &str| {
+            let mut state = hasher.build_hasher();
+            key.hash(&mut state);
+            state.finish()
+        };
+        assert_eq!(hash("title"), hash("title"));
+        assert!(hash("title") != hash("titles"));
+    }
+
+    #[cfg(feature = "fast-hash")]
+    #[test]
+    fn fnv_1a() {
+        use super::FnvHasher;
+
+        let hash = |bytes: &[u8]| {
+            let mut hasher = FnvHasher::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf29ce484222325);
+        assert_eq!(hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(hash(b"foobar"), 0x85944171f73967e8);
+    }
+}
@@ -0,0 +1,328 @@
+use value::{Value, Object};
+use filters::{FilterResult, FilterError};
+
+use std::collections::HashMap;
+
+/// How numbers and dates are written in a locale.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocaleFormat {
+    pub decimal_separator: String,
+
+    /// Put between each group of three digits in the whole part of a
+    /// number. Empty for no grouping.
+    pub group_separator: String,
+
+    /// The format used by the `date` filter when it isn't given one, in
+    /// `strftime` notation.
+    pub date_format: String,
+}
+
+impl Default for LocaleFormat {
+    fn default() -> LocaleFormat {
+        LocaleFormat {
+            decimal_separator: ".".to_owned(),
+            group_separator: ",".to_owned(),
+            date_format: "%Y-%m-%d".to_owned(),
+        }
+    }
+}
+
+/// Replaces each `%{name}` in `text` with the matching value from `args`,
+/// in a single pass, so that placeholders within the values are left as
+/// they are. Placeholders without a value are kept.
+fn interpolate(text: &str, args: &Object) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("%{") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.find('}').and_then(|end| args.get(&after[..end]).map(|v| (end, v)));
+        match value {
+            Some((end, value)) => {
+                result.push_str(&value.to_string());
+                rest = &after[end + 1..];
+            }
+            None => {
+                result.push_str("%{");
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// The current locale for a render, along with the translations and
+/// formats of every locale known to the host. Give one to a context with
+/// `Context::set_i18n`, which makes the `t` filter translate keys and the
+/// `l` filter format numbers for the current locale.
+///
+/// Lookups go through a chain of locales: the current one, then its
+/// language without the region (`pt` for `pt-BR`), then each of the
+/// fallbacks in turn.
+///
+/// ## Example
+/// ```
+/// use liquid::{I18n, Context, LiquidOptions, Object, Renderable, Value};
+///
+/// let mut greetings = Object::default();
+/// greetings.insert("hello".to_owned(), Value::str("Olá, %{name}!"));
+/// let mut pt = Object::default();
+/// pt.insert("greetings".to_owned(), Value::Object(greetings));
+///
+/// let mut i18n = I18n::new("pt-BR");
+/// i18n.add_translations("pt", &Value::Object(pt));
+///
+/// let mut ctx = Context::new();
+/// ctx.set_i18n(i18n);
+/// let template = liquid::parse("{{ 'greetings.hello' | t: name: 'Ana' }}",
+///                              LiquidOptions::default()).unwrap();
+/// assert_eq!(template.render(&mut ctx).unwrap(), Some("Olá, Ana!".to_owned()));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct I18n {
+    locale: String,
+    fallbacks: Vec<String>,
+    translations: HashMap<String, HashMap<String, String>>,
+    formats: HashMap<String, LocaleFormat>,
+}
+
+impl I18n {
+    pub fn new(locale: &str) -> I18n {
+        I18n { locale: locale.to_owned(), ..Default::default() }
+    }
+
+    pub fn locale(&self) -> &str {
+        &self.locale
+    }
+
+    pub fn set_locale(&mut self, locale: &str) {
+        self.locale = locale.to_owned();
+    }
+
+    /// Sets the locales to try, in order, when a translation or format
+    /// isn't found for the current locale.
+    pub fn set_fallbacks(&mut self, fallbacks: Vec<String>) {
+        self.fallbacks = fallbacks;
+    }
+
+    /// Adds a single translation.
+    pub fn add_translation(&mut self, locale: &str, key: &str, text: &str) {
+        self.translations
+            .entry(locale.to_owned())
+            .or_insert_with(HashMap::new)
+            .insert(key.to_owned(), text.to_owned());
+    }
+
+    /// Adds a table of translations, such as one loaded from a YAML or
+    /// JSON file and converted to a `Value`. Nested objects give dotted
+    /// keys, so `{"nav": {"home": "Home"}}` is looked up as `nav.home`.
+    pub fn add_translations(&mut self, locale: &str, table: &Value) {
+        let mut flat = vec![];
+        flatten("", table, &mut flat);
+        for (key, text) in flat {
+            self.add_translation(locale, &key, &text);
+        }
+    }
+
+    /// Sets how numbers and dates are written in a locale.
+    pub fn set_format(&mut self, locale: &str, format: LocaleFormat) {
+        self.formats.insert(locale.to_owned(), format);
+    }
+
+    /// The locales to look in, in order.
+    fn chain(&self) -> Vec<&str> {
+        let mut chain = vec![&self.locale[..]];
+        if let Some(i) = self.locale.find(|c| c == '-' || c == '_') {
+            chain.push(&self.locale[..i]);
+        }
+        chain.extend(self.fallbacks.iter().map(|f| &f[..]));
+        chain
+    }
+
+    /// Looks up the translation of a key, replacing each `%{name}` in it
+    /// with the matching value from `args`.
+    pub fn translate(&self, key: &str, args: &Object) -> Option<String> {
+        let text = self.chain()
+                       .into_iter()
+                       .filter_map(|locale| self.translations.get(locale))
+                       .filter_map(|table| table.get(key))
+                       .next();
+        text.map(|text| interpolate(text, args))
+    }
+
+    /// How numbers and dates are written in the current locale.
+    pub fn format(&self) -> LocaleFormat {
+        self.chain()
+            .into_iter()
+            .filter_map(|locale| self.formats.get(locale))
+            .next()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Writes a number the way the current locale does.
+    pub fn format_number(&self, n: f32) -> String {
+        let format = self.format();
+        let text = n.abs().to_string();
+        let (whole, fraction) = match text.find('.') {
+            Some(i) => (&text[..i], Some(&text[i + 1..])),
+            None => (&text[..], None),
+        };
+
+        let mut grouped = String::new();
+        for (i, c) in whole.chars().enumerate() {
+            if i > 0 && (whole.len() - i) % 3 == 0 {
+                grouped.push_str(&format.group_separator);
+            }
+            grouped.push(c);
+        }
+        if n < 0f32 {
+            grouped.insert(0, '-');
+        }
+        if let Some(fraction) = fraction {
+            grouped.push_str(&format.decimal_separator);
+            grouped.push_str(fraction);
+        }
+        grouped
+    }
+
+    /// The `t` filter: translates its input, taking any `name: value`
+    /// arguments as values for the placeholders. Keys without a
+    /// translation are output as they are.
+    pub fn t_filter(&self, input: &Value, args: &[Value]) -> FilterResult {
+        let key = match *input {
+            Value::Str(ref key) | Value::Safe(ref key) => key,
+            _ => return FilterError::invalid_type("String expected"),
+        };
+        let no_args = Object::default();
+        let named = match args.last() {
+            Some(&Value::Object(ref named)) => named,
+            _ => &no_args,
+        };
+        Ok(Value::Str(self.translate(key, named).unwrap_or(key.clone())))
+    }
+
+    /// The `l` filter: writes numbers the way the current locale does,
+    /// and passes anything else through.
+    pub fn l_filter(&self, input: &Value, _args: &[Value]) -> FilterResult {
+        match *input {
+            Value::Num(n) => Ok(Value::Str(self.format_number(n))),
+            ref other => Ok(other.clone()),
+        }
+    }
+}
+
+fn flatten(prefix: &str, value: &Value, out: &mut Vec<(String, String)>) {
+    match *value {
+        Value::Object(ref fields) => {
+            for (key, value) in fields {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&key, value, out);
+            }
+        }
+        ref other => out.push((prefix.to_owned(), other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{I18n, LocaleFormat};
+    use Renderable;
+    use LiquidOptions;
+    use parse;
+    use context::Context;
+    use value::Value;
+
+    use value::Object;
+
+    fn i18n() -> I18n {
+        let mut i18n = I18n::new("de-AT");
+        i18n.set_fallbacks(vec!["en".to_owned()]);
+        i18n.add_translation("en", "hello", "Hello");
+        i18n.add_translation("en", "bye", "Bye, %{name}");
+        i18n.add_translation("de", "hello", "Hallo");
+        i18n.add_translation("de-AT", "hello", "Servus");
+        i18n.set_format("de",
+                        LocaleFormat {
+                            decimal_separator: ",".to_owned(),
+                            group_separator: ".".to_owned(),
+                            ..Default::default()
+                        });
+        i18n
+    }
+
+    #[test]
+    fn translations_fall_back_along_the_chain() {
+        let mut i18n = i18n();
+        let none = Object::default();
+        assert_eq!(i18n.translate("hello", &none), Some("Servus".to_owned()));
+        i18n.set_locale("de-DE");
+        assert_eq!(i18n.translate("hello", &none), Some("Hallo".to_owned()));
+        assert_eq!(i18n.translate("bye", &none), Some("Bye, %{name}".to_owned()));
+        assert_eq!(i18n.translate("missing", &none), None);
+    }
+
+    #[test]
+    fn values_are_not_read_for_placeholders() {
+        let mut i18n = I18n::new("en");
+        i18n.add_translation("en", "pair", "%{a} and %{b} %{c}");
+        let mut args = Object::default();
+        args.insert("a".to_owned(), Value::str("%{b}"));
+        args.insert("b".to_owned(), Value::str("%{a}"));
+        assert_eq!(i18n.translate("pair", &args),
+                   Some("%{b} and %{a} %{c}".to_owned()));
+    }
+
+    #[test]
+    fn nested_tables_give_dotted_keys() {
+        let mut nav = Object::default();
+        nav.insert("home".to_owned(), Value::str("Start"));
+        let mut table = Object::default();
+        table.insert("nav".to_owned(), Value::Object(nav));
+
+        let mut i18n = I18n::new("de");
+        i18n.add_translations("de", &Value::Object(table));
+        assert_eq!(i18n.translate("nav.home", &Object::default()),
+                   Some("Start".to_owned()));
+    }
+
+    #[test]
+    fn numbers_use_the_locale_format() {
+        let mut i18n = i18n();
+        assert_eq!(i18n.format_number(1234567.5), "1.234.567,5");
+        assert_eq!(i18n.format_number(-1234.5), "-1.234,5");
+        assert_eq!(i18n.format_number(12f32), "12");
+        i18n.set_locale("en");
+        assert_eq!(i18n.format_number(1234.5), "1,234.5");
+    }
+
+    #[test]
+    fn filters_use_the_current_locale() {
+        let mut ctx = Context::new();
+        ctx.set_i18n(i18n());
+        let template = parse("{{ 'hello' | t }} {{ 'bye' | t: name: 'Jo' }} {{ 'x' | t }} \
+                              {{ 1234.5 | l }}",
+                             LiquidOptions::default())
+                           .unwrap();
+        assert_eq!(template.render(&mut ctx).unwrap(),
+                   Some("Servus Bye, Jo x 1.234,5".to_owned()));
+
+        ctx.set_locale("en");
+        assert_eq!(template.render(&mut ctx).unwrap(),
+                   Some("Hello Bye, Jo x 1,234.5".to_owned()));
+    }
+
+    #[test]
+    fn locale_filters_give_way_to_the_hosts() {
+        let mut ctx = Context::new();
+        ctx.add_filter("date", Box::new(|_, _| Ok(Value::str("host date"))));
+        ctx.set_i18n(i18n());
+        let template = parse("{{ 'now' | date }}", LiquidOptions::default()).unwrap();
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("host date".to_owned()));
+    }
+}
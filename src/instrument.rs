@@ -0,0 +1,56 @@
+use lexer::Span;
+use error::Result;
+
+/// What a piece of markup in a template is.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NodeKind {
+    /// A `{{ }}` output.
+    Output,
+    /// A tag, such as `assign`, by name.
+    Tag(String),
+    /// A block, such as `for`, by name.
+    Block(String),
+}
+
+/// Called around the rendering of every output, tag and block, with what
+/// kind of node it is and where its markup is in the template. Give one to
+/// a context with `Context::set_instrument` to profile or trace renders,
+/// or to stop them early by returning an error from `before`.
+///
+/// Spans are within the source of the template the node was parsed from,
+/// which for partials is the partial rather than the template including it.
+///
+/// ## Example
+/// ```
+/// use liquid::{Instrument, NodeKind, Span, Context, Error, LiquidOptions, Renderable};
+/// use std::sync::{Arc, Mutex};
+///
+/// #[derive(Default)]
+/// struct Trace(Mutex<Vec<String>>);
+///
+/// impl Instrument for Trace {
+///     fn before(&self, kind: &NodeKind, span: Span) -> Result<(), Error> {
+///         self.0.lock().unwrap().push(format!("{:?} at {}", kind, span.start));
+///         Ok(())
+///     }
+/// }
+///
+/// let template = liquid::parse("a{% if true %}{{ 1 }}{% endif %}",
+///                              LiquidOptions::default()).unwrap();
+/// let trace = Arc::new(Trace::default());
+/// let mut ctx = Context::new();
+/// ctx.set_instrument(Some(trace.clone()));
+/// template.render(&mut ctx).unwrap();
+/// assert_eq!(*trace.0.lock().unwrap(), vec!["Block(\"if\") at 1", "Output at 14"]);
+/// ```
+pub trait Instrument: Send + Sync {
+    /// Called before a node is rendered. Returning an error stops the
+    /// render with that error.
+    fn before(&self, _kind: &NodeKind, _span: Span) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once a node has been rendered, with what it rendered to.
+    fn after(&self, _kind: &NodeKind, _span: Span, _result: &Result<Option<String>>) {}
+}
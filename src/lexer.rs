@@ -2,64 +2,425 @@ use token::Token;
 use token::Token::*;
 use token::ComparisonOperator::*;
 use self::Element::*;
-use regex::Regex;
+use regex::{Regex, quote};
 use error::{Error, Result};
+use {LiquidOptions, ParseState, WhitespaceMode};
+
+use std::borrow::Cow;
+use std::io::{BufRead, BufReader, Read};
+
+/// A range of bytes within the source of a template.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span {
+            start: start,
+            end: end,
+        }
+    }
+}
 
 #[derive(Clone, Debug, PartialEq)]
-pub enum Element {
-    Expression(Vec<Token>, String),
-    Tag(Vec<Token>, String),
-    Raw(String),
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Element<'a> {
+    Expression(Vec<Token<'a>>, Cow<'a, str>, Span),
+    Tag(Vec<Token<'a>>, Cow<'a, str>, Span),
+    Raw(Cow<'a, str>),
+}
+
+impl<'a> Element<'a> {
+    /// Converts an element that may borrow from the template source into
+    /// one that owns its text.
+    pub fn into_owned(self) -> Element<'static> {
+        let tokens = |tokens: Vec<Token<'a>>| tokens.into_iter().map(Token::into_owned).collect();
+        match self {
+            Expression(t, raw, span) => Expression(tokens(t), Cow::Owned(raw.into_owned()), span),
+            Tag(t, raw, span) => Tag(tokens(t), Cow::Owned(raw.into_owned()), span),
+            Raw(text) => Raw(Cow::Owned(text.into_owned())),
+        }
+    }
+}
+
+/// The pairs of delimiters that mark up outputs (`{{ }}`) and tags
+/// (`{% %}`) in a template.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delimiters {
+    pub output_start: String,
+    pub output_end: String,
+    pub tag_start: String,
+    pub tag_end: String,
+}
+
+impl Default for Delimiters {
+    fn default() -> Delimiters {
+        Delimiters {
+            output_start: "{{".to_owned(),
+            output_end: "}}".to_owned(),
+            tag_start: "{%".to_owned(),
+            tag_end: "%}".to_owned(),
+        }
+    }
+}
+
+/// The compiled patterns for recognising markup with a given set of
+/// delimiters.
+struct Markup {
+    delimiters: Delimiters,
+    whitespace: WhitespaceMode,
+    markup: Regex,
+    expression: Regex,
+    tag: Regex,
+}
+
+impl Markup {
+    fn new(delimiters: &Delimiters, whitespace: WhitespaceMode) -> Result<Markup> {
+        let (oo, oc) = (quote(&delimiters.output_start), quote(&delimiters.output_end));
+        let (to, tc) = (quote(&delimiters.tag_start), quote(&delimiters.tag_end));
+        // Ruby lets markup span several lines
+        let flags = match whitespace {
+            WhitespaceMode::Standard => "",
+            WhitespaceMode::Ruby => "(?s)",
+        };
+        let regex = |pattern: String| {
+            Regex::new(&(flags.to_owned() + &pattern))
+                .map_err(|e| Error::Lexer(format!("invalid delimiters: {}", e)))
+        };
+
+        Ok(Markup {
+            delimiters: delimiters.clone(),
+            whitespace: whitespace,
+            markup: try!(regex(format!("{}.*?{}|{}.*?{}", to, tc, oo, oc))),
+            expression: try!(regex(format!("{}-?(.*?)-?{}", oo, oc))),
+            tag: try!(regex(format!("{}-?(.*?)-?{}", to, tc))),
+        })
+    }
+
+    fn is_markup(&self, block: &str) -> bool {
+        block.starts_with(&self.delimiters.output_start) ||
+        block.starts_with(&self.delimiters.tag_start)
+    }
+
+    /// Does the block start with a whitespace control marker, e.g. `{{-`?
+    fn trims_left(&self, block: &str) -> bool {
+        let d = &self.delimiters;
+        block.starts_with(&(d.output_start.clone() + "-")) ||
+        block.starts_with(&(d.tag_start.clone() + "-"))
+    }
+
+    /// Does the block end with a whitespace control marker, e.g. `-}}`?
+    fn trims_right(&self, block: &str) -> bool {
+        let d = &self.delimiters;
+        block.ends_with(&("-".to_owned() + &d.output_end)) ||
+        block.ends_with(&("-".to_owned() + &d.tag_end))
+    }
+
+    /// Whether whitespace control strips the given character.
+    fn is_space(&self, c: char) -> bool {
+        match self.whitespace {
+            WhitespaceMode::Standard => c.is_whitespace(),
+            // the characters stripped by Ruby's `strip`
+            WhitespaceMode::Ruby => c == ' ' || ('\t' <= c && c <= '\r') || c == '\0',
+        }
+    }
 }
 
 lazy_static! {
-    static ref MARKUP: Regex = Regex::new("\\{%.*?%\\}|\\{\\{.*?\\}\\}").unwrap();
+    static ref DEFAULT_MARKUP: Markup = Markup::new(&Delimiters::default(),
+                                                    WhitespaceMode::Standard)
+                                            .unwrap();
+    static ref RUBY_MARKUP: Markup = Markup::new(&Delimiters::default(), WhitespaceMode::Ruby)
+                                         .unwrap();
 }
 
-fn split_blocks(text: &str) -> Vec<&str> {
+/// Calls `f` with the markup patterns for the given options, compiling them
+/// only if the defaults can't be used.
+fn with_markup<T, F>(options: &LiquidOptions, f: F) -> Result<T>
+    where F: FnOnce(&Markup) -> Result<T>
+{
+    if options.delimiters != Delimiters::default() {
+        return f(&try!(Markup::new(&options.delimiters, options.whitespace)));
+    }
+    match options.whitespace {
+        WhitespaceMode::Standard => f(&DEFAULT_MARKUP),
+        WhitespaceMode::Ruby => f(&RUBY_MARKUP),
+    }
+}
+
+/// Splits the text into markup and raw text, along with the span of the
+/// source occupied by each.
+fn split_markup<'a>(text: &'a str, markup: &Markup) -> Vec<(&'a str, Span)> {
     let mut tokens = vec![];
     let mut current = 0;
-    for (begin, end) in MARKUP.find_iter(text) {
+    for (begin, end) in markup.markup.find_iter(text) {
         match &text[current..begin] {
             "" => {}
-            t => tokens.push(t),
+            t => tokens.push((t, Span::new(current, begin))),
         }
-        tokens.push(&text[begin..end]);
+        tokens.push((&text[begin..end], Span::new(begin, end)));
         current = end;
     }
     match &text[current..text.len()] {
         "" => {}
-        t => tokens.push(t),
+        t => tokens.push((t, Span::new(current, text.len()))),
     }
     tokens
 }
 
-lazy_static! {
-    static ref EXPRESSION: Regex = Regex::new("\\{\\{(.*?)\\}\\}").unwrap();
-    static ref TAG: Regex = Regex::new("\\{%(.*?)%\\}").unwrap();
+/// Cuts text down to the given byte range, without copying it if it is
+/// borrowed from the source.
+fn keep_range(text: &mut Cow<str>, start: usize, end: usize) {
+    match *text {
+        Cow::Borrowed(s) => *text = Cow::Borrowed(&s[start..end]),
+        Cow::Owned(ref mut s) => {
+            s.truncate(end);
+            s.drain(..start);
+        }
+    }
+}
+
+/// Removes any trailing whitespace from the last element, if it is raw
+/// text. Used to implement `{{-` and `{%-` whitespace control.
+fn trim_previous(blocks: &mut Vec<Element>, markup: &Markup) {
+    let empty = match blocks.last_mut() {
+        Some(&mut Raw(ref mut text)) => {
+            let len = text.trim_right_matches(|c| markup.is_space(c)).len();
+            keep_range(text, 0, len);
+            text.is_empty()
+        }
+        _ => false,
+    };
+    if empty {
+        blocks.pop();
+    }
 }
 
 pub fn tokenize(text: &str) -> Result<Vec<Element>> {
+    tokenize_markup(text, &DEFAULT_MARKUP, &|e, _, span| Err(e.located(span)))
+}
+
+/// Handles markup that can't be tokenized, given the error, the markup and
+/// its span. It returns the text to emit in place of the markup, if any.
+type Recover<'r> = Fn(Error, &str, Span) -> Result<Option<String>> + 'r;
+
+/// Splits the text into elements. Markup whose contents can't be
+/// tokenized is handed to `recover`.
+fn tokenize_markup<'a>(text: &'a str,
+                       markup: &Markup,
+                       recover: &Recover)
+                       -> Result<Vec<Element<'a>>> {
     let mut blocks = vec![];
+    try!(Tokenizer::new(markup).feed(&mut blocks, text, recover));
+    Ok(blocks)
+}
+
+/// Turns template source into elements, which borrow their text from the
+/// source wherever possible. The source may be fed in pieces, so long as no
+/// piece ends part way through some markup.
+struct Tokenizer<'m> {
+    markup: &'m Markup,
+    trim_next: bool,
+    offset: usize,
+}
 
-    for block in split_blocks(text) {
-        if let Some(caps) = TAG.captures(block) {
-            blocks.push(Tag(try!(granularize(caps.at(1).unwrap_or(""))),
-                            block.to_owned()));
-        } else if let Some(caps) = EXPRESSION.captures(block) {
-            blocks.push(Expression(try!(granularize(caps.at(1).unwrap_or(""))),
-                                   block.to_owned()));
-        } else {
-            blocks.push(Raw(block.to_owned()));
+impl<'m> Tokenizer<'m> {
+    fn new(markup: &'m Markup) -> Tokenizer<'m> {
+        Tokenizer {
+            markup: markup,
+            trim_next: false,
+            offset: 0,
         }
     }
 
+    fn feed<'a>(&mut self,
+                blocks: &mut Vec<Element<'a>>,
+                text: &'a str,
+                recover: &Recover)
+                -> Result<()> {
+        let markup = self.markup;
+        for (block, span) in split_markup(text, markup) {
+            let span = Span::new(span.start + self.offset, span.end + self.offset);
+            let is_markup = markup.is_markup(block);
+            if is_markup && markup.trims_left(block) {
+                trim_previous(blocks, markup);
+            }
+
+            let trim_this = self.trim_next;
+            self.trim_next = is_markup && markup.trims_right(block);
+
+            if let Some(caps) = markup.tag.captures(block) {
+                let content = caps.at(1).unwrap_or("");
+                if content.trim_left().starts_with('#') {
+                    // inline comments can contain arbitrary text, so we don't
+                    // try to tokenize their contents.
+                    blocks.push(Tag(vec![Identifier("#".into())], block.into(), span));
+                } else {
+                    match granularize(content) {
+                        Ok(tokens) => blocks.push(Tag(tokens, block.into(), span)),
+                        Err(e) => {
+                            if let Some(text) = try!(recover(e, block, span)) {
+                                push_text(blocks, text.into());
+                            }
+                        }
+                    }
+                }
+            } else if let Some(caps) = markup.expression.captures(block) {
+                match granularize(caps.at(1).unwrap_or("")) {
+                    // an empty output renders nothing, just as in Ruby
+                    Ok(ref tokens) if tokens.is_empty() => {}
+                    Ok(tokens) => blocks.push(Expression(tokens, block.into(), span)),
+                    Err(e) => {
+                        if let Some(text) = try!(recover(e, block, span)) {
+                            push_text(blocks, text.into());
+                        }
+                    }
+                }
+            } else {
+                let text = if trim_this {
+                    block.trim_left_matches(|c| markup.is_space(c))
+                } else {
+                    block
+                };
+                // raw text may be split between pieces, in which case any
+                // whitespace trimming carries over to the next piece.
+                self.trim_next = trim_this && text.is_empty();
+                push_text(blocks, text.into());
+            }
+        }
+        self.offset += text.len();
+        Ok(())
+    }
+}
+
+/// Adds raw text, merging it with any raw text just before it.
+fn push_text<'a>(blocks: &mut Vec<Element<'a>>, text: Cow<'a, str>) {
+    match blocks.last_mut() {
+        Some(&mut Raw(ref mut previous)) => {
+            previous.to_mut().push_str(&text);
+            return;
+        }
+        _ => {}
+    }
+    if !text.is_empty() {
+        blocks.push(Raw(text));
+    }
+}
+
+/// Tokenizes a template, applying any lexer-level settings from the
+/// supplied options.
+pub fn tokenize_with_options<'a>(text: &'a str, state: &ParseState) -> Result<Vec<Element<'a>>> {
+    let options = state.options();
+    try!(options.check_size(text.len()));
+    let recover = |e, raw: &str, span| state.recover_markup(e, raw, span);
+    let mut blocks = try!(with_markup(options, |markup| tokenize_markup(text, markup, &recover)));
+    if options.trim_blocks && options.whitespace == WhitespaceMode::Standard {
+        trim_blocks(&mut blocks);
+    }
     Ok(blocks)
 }
 
+/// Tokenizes a template read from a stream, a line at a time, so that the
+/// whole source never has to be held in memory. Markup can't span lines,
+/// so this gives the same result as `tokenize_with_options`. The exception
+/// is `WhitespaceMode::Ruby`, where markup can span lines and the whole
+/// source is read up front.
+pub fn tokenize_reader<R: Read>(reader: R, state: &ParseState) -> Result<Vec<Element<'static>>> {
+    let options = state.options();
+    // read no more than is needed to tell that the template is too big
+    let max_read = options.limits.max_bytes.map_or(u64::max_value(), |max| max as u64 + 1);
+    let mut reader = BufReader::new(reader.take(max_read));
+
+    // markup can span lines in Ruby mode, so the source can't be split up
+    if options.whitespace == WhitespaceMode::Ruby {
+        let mut text = String::new();
+        try!(reader.read_to_string(&mut text));
+        let blocks = try!(tokenize_with_options(&text, state));
+        return Ok(blocks.into_iter().map(Element::into_owned).collect());
+    }
+
+    let recover = |e, raw: &str, span| state.recover_markup(e, raw, span);
+    with_markup(options, |markup| {
+        let mut tokenizer = Tokenizer::new(markup);
+        let mut line = String::new();
+        let mut blocks = vec![];
+        while try!(reader.read_line(&mut line)) > 0 {
+            // raw text at the end of the last line may still be trimmed or
+            // added to by this one
+            let mut pending = match blocks.last() {
+                Some(&Raw(_)) => vec![blocks.pop().unwrap()],
+                _ => vec![],
+            };
+            try!(tokenizer.feed(&mut pending, &line, &recover));
+            blocks.extend(pending.into_iter().map(Element::into_owned));
+            try!(options.check_size(tokenizer.offset));
+            line.clear();
+        }
+
+        if options.trim_blocks {
+            trim_blocks(&mut blocks);
+        }
+        Ok(blocks)
+    })
+}
+
+/// Removes the first newline following each `{% %}` tag.
+fn trim_blocks(blocks: &mut Vec<Element>) {
+    let mut after_tag = false;
+    for block in blocks.iter_mut() {
+        if let Raw(ref mut text) = *block {
+            if after_tag {
+                let len = text.len();
+                if text.starts_with('\n') {
+                    keep_range(text, 1, len);
+                } else if text.starts_with("\r\n") {
+                    keep_range(text, 2, len);
+                }
+            }
+        }
+        after_tag = match *block {
+            Tag(..) => true,
+            _ => false,
+        };
+    }
+    blocks.retain(|b| {
+        match *b {
+            Raw(ref text) => !text.is_empty(),
+            _ => true,
+        }
+    });
+}
+
+// Variable names follow the same rules as in Ruby Liquid. A name starts
+// with a letter or an underscore, and carries on with letters, digits,
+// underscores and hyphens, optionally ending in a single question mark.
+// Letters and digits include any in unicode, so `prénom` and `color-scheme-1`
+// are both names, as is `a-1`; put spaces around a minus sign to mean it.
+//
+// Properties are added with a dot followed by a name, which may also start
+// with a digit (`a.0`), or with an index in square brackets. Anything else,
+// such as a key containing spaces or punctuation, has to be given as an
+// index: `page["key with spaces"]`.
+//
+// Parts can also be joined with slashes, so that tags like
+// `include_relative` can take unquoted file names such as `sub/page.html`.
+const NAME: &'static str = r"[\pL_][\w-]*\??";
+const PATH: &'static str = r"(\.[\w-]+\??|/[\w-]+|\[[^\[\]]*\])*";
+
 lazy_static! {
-    static ref SPLIT: Regex = Regex::new(
-        r"\s+|[\|:,\[\]\(\)\?-]|\.\.|={1,2}|!=|<=|>=|[<>]").unwrap();
+    // String literals and variable paths such as `a.b[0]["c d"]` are
+    // matched first, so that they're kept whole even if they contain
+    // whitespace or other separators. A path has to start on a word
+    // boundary, so that malformed numbers like `12abc` stay in one piece.
+    static ref SPLIT: Regex = Regex::new(&format!(
+        r#"'([^'\\]|\\.)*'|"([^"\\]|\\.)*"|\b{}{}|{}"#,
+        NAME,
+        PATH,
+        r"\s+|[\|:,\[\]\(\)\?-]|\.\.|={1,2}|!=|<=|>=|[<>]")).unwrap();
 }
 
 fn split_atom(block: &str) -> Vec<&str> {
@@ -78,17 +439,58 @@ fn split_atom(block: &str) -> Vec<&str> {
 }
 
 lazy_static! {
-    static ref IDENTIFIER: Regex = Regex::new(r"[a-zA-Z_][\w-]*\??").unwrap();
-    static ref SINGLE_STRING_LITERAL: Regex = Regex::new(r"'[^']*'").unwrap();
-    static ref DOUBLE_STRING_LITERAL: Regex = Regex::new("\"[^\"]*\"").unwrap();
-    static ref NUMBER_LITERAL: Regex = Regex::new(r"^-?\d+(\.\d+)?$").unwrap();
-    static ref BOOLEAN_LITERAL: Regex = Regex::new(r"^true|false$").unwrap();
+    static ref IDENTIFIER: Regex = Regex::new(&format!("^{}{}$", NAME, PATH)).unwrap();
+    static ref SINGLE_STRING_LITERAL: Regex = Regex::new(r"^'([^'\\]|\\.)*'$").unwrap();
+    static ref DOUBLE_STRING_LITERAL: Regex = Regex::new(r#"^"([^"\\]|\\.)*"$"#).unwrap();
+    static ref NUMBER_LITERAL: Regex = Regex::new(r"^-?(\d+(\.\d+)?|\.\d+)$").unwrap();
+    static ref NUMBER_LIKE: Regex = Regex::new(r"^-?\.?\d").unwrap();
+    static ref BOOLEAN_LITERAL: Regex = Regex::new(r"^(true|false)$").unwrap();
+}
+
+/// Resolves the `\\`, `\'` and `\"` escapes in the body of a string
+/// literal. Any other backslash is kept as-is. The text is only copied if
+/// there are escapes to resolve.
+fn unescape(text: &str) -> Cow<str> {
+    if !text.contains('\\') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                match chars.next() {
+                    Some(e @ '\\') | Some(e @ '\'') | Some(e @ '"') => result.push(e),
+                    Some(e) => {
+                        result.push('\\');
+                        result.push(e);
+                    }
+                    None => result.push('\\'),
+                }
+            }
+            c => result.push(c),
+        }
+    }
+    Cow::Owned(result)
+}
+
+fn parse_number(text: &str) -> Result<f32> {
+    text.parse::<f32>().map_err(|_| Error::Lexer(format!("{} is not a valid number", text)))
 }
 
 fn granularize(block: &str) -> Result<Vec<Token>> {
     let mut result = vec![];
+    let mut atoms = split_atom(block).into_iter().peekable();
+
+    while let Some(el) = atoms.next() {
+        // a dash directly in front of a number is a unary minus
+        if el == "-" && atoms.peek().map_or(false, |x| NUMBER_LITERAL.is_match(x)) {
+            let n = atoms.next().unwrap();
+            result.push(NumberLiteral(-try!(parse_number(n))));
+            continue;
+        }
 
-    for el in split_atom(block) {
         result.push(match &*el.trim() {
             "" => continue,
 
@@ -112,17 +514,21 @@ fn granularize(block: &str) -> Result<Vec<Token>> {
             ">" => Comparison(GreaterThan),
             "contains" => Comparison(Contains),
             ".." => DotDot,
+            "nil" => NilLiteral,
+            "empty" => EmptyLiteral,
+            "blank" => BlankLiteral,
 
             x if SINGLE_STRING_LITERAL.is_match(x) || DOUBLE_STRING_LITERAL.is_match(x) => {
-                StringLiteral(x[1..x.len() - 1].to_owned())
+                StringLiteral(unescape(&x[1..x.len() - 1]))
             }
-            x if NUMBER_LITERAL.is_match(x) => {
-                NumberLiteral(x.parse::<f32>().expect(&format!("Could not parse {:?} as float", x)))
+            x if NUMBER_LITERAL.is_match(x) => NumberLiteral(try!(parse_number(x))),
+            x if NUMBER_LIKE.is_match(x) => {
+                return Err(Error::Lexer(format!("{} is not a valid number", x)))
             }
             x if BOOLEAN_LITERAL.is_match(x) => {
                 BooleanLiteral(x.parse::<bool>().expect(&format!("Could not parse {:?} as bool", x)))
             }
-            x if IDENTIFIER.is_match(x) => Identifier(x.to_owned()),
+            x if IDENTIFIER.is_match(x) => Identifier(x.into()),
             x => return Err(Error::Lexer(format!("{} is not a valid identifier", x))),
         });
     }
@@ -131,132 +537,326 @@ fn granularize(block: &str) -> Result<Vec<Token>> {
 }
 
 #[test]
-fn test_split_blocks() {
-    assert_eq!(split_blocks("asdlkjfn\n{{askdljfbalkjsdbf}} asdjlfb"),
-               vec!["asdlkjfn\n", "{{askdljfbalkjsdbf}}", " asdjlfb"]);
-    assert_eq!(split_blocks("asdlkjfn\n{%askdljfbalkjsdbf%} asdjlfb"),
-               vec!["asdlkjfn\n", "{%askdljfbalkjsdbf%}", " asdjlfb"]);
+fn test_split_markup() {
+    assert_eq!(split_markup("asdlkjfn\n{{askdljfbalkjsdbf}} asdjlfb", &DEFAULT_MARKUP),
+               vec![("asdlkjfn\n", Span::new(0, 9)),
+                    ("{{askdljfbalkjsdbf}}", Span::new(9, 29)),
+                    (" asdjlfb", Span::new(29, 37))]);
+    assert_eq!(split_markup("asdlkjfn\n{%askdljfbalkjsdbf%} asdjlfb", &DEFAULT_MARKUP),
+               vec![("asdlkjfn\n", Span::new(0, 9)),
+                    ("{%askdljfbalkjsdbf%}", Span::new(9, 29)),
+                    (" asdjlfb", Span::new(29, 37))]);
 }
 
 #[test]
 fn test_split_atom() {
     assert_eq!(split_atom("truc | arg:val"),
-               vec!["truc", " ", "", "|", "", " ", "arg", ":", "val"]);
+               vec!["", "truc", "", " ", "", "|", "", " ", "", "arg", "", ":", "", "val", ""]);
     assert_eq!(split_atom("truc | filter:arg1,arg2"),
-               vec!["truc", " ", "", "|", "", " ", "filter", ":", "arg1", ",", "arg2"]);
+               vec!["", "truc", "", " ", "", "|", "", " ", "", "filter", "", ":", "", "arg1", "",
+                    ",", "", "arg2", ""]);
 }
 
 #[test]
 fn test_tokenize() {
     assert_eq!(tokenize("{{hello 'world'}}").unwrap(),
-               vec![Expression(vec![Identifier("hello".to_owned()),
-                                    StringLiteral("world".to_owned())],
-                               "{{hello 'world'}}".to_owned())]);
+               vec![Expression(vec![Identifier("hello".into()),
+                                    StringLiteral("world".into())],
+                               "{{hello 'world'}}".into(),
+                               Span::new(0, 17))]);
     assert_eq!(tokenize("{{hello.world}}").unwrap(),
-               vec![Expression(vec![Identifier("hello.world".to_owned())],
-                               "{{hello.world}}".to_owned())]);
+               vec![Expression(vec![Identifier("hello.world".into())],
+                               "{{hello.world}}".into(),
+                               Span::new(0, 15))]);
     assert_eq!(tokenize("{{ hello 'world' }}").unwrap(),
-               vec![Expression(vec![Identifier("hello".to_owned()),
-                                    StringLiteral("world".to_owned())],
-                               "{{ hello 'world' }}".to_owned())]);
+               vec![Expression(vec![Identifier("hello".into()),
+                                    StringLiteral("world".into())],
+                               "{{ hello 'world' }}".into(),
+                               Span::new(0, 19))]);
     assert_eq!(tokenize("{{   hello   'world'    }}").unwrap(),
-               vec![Expression(vec![Identifier("hello".to_owned()),
-                                    StringLiteral("world".to_owned())],
-                               "{{   hello   'world'    }}".to_owned())]);
+               vec![Expression(vec![Identifier("hello".into()),
+                                    StringLiteral("world".into())],
+                               "{{   hello   'world'    }}".into(),
+                               Span::new(0, 26))]);
     assert_eq!(tokenize("wat\n{{hello 'world'}} test").unwrap(),
-               vec![Raw("wat\n".to_owned()),
-                    Expression(vec![Identifier("hello".to_owned()),
-                                    StringLiteral("world".to_owned())],
-                               "{{hello 'world'}}".to_owned()),
-                    Raw(" test".to_owned())]);
+               vec![Raw("wat\n".into()),
+                    Expression(vec![Identifier("hello".into()),
+                                    StringLiteral("world".into())],
+                               "{{hello 'world'}}".into(),
+                               Span::new(4, 21)),
+                    Raw(" test".into())]);
+    assert_eq!(tokenize("{% # it's a note: {{ x | y }} %}").unwrap(),
+               vec![Tag(vec![Identifier("#".into())],
+                        "{% # it's a note: {{ x | y }} %}".into(),
+                        Span::new(0, 32))]);
+}
+
+#[test]
+fn test_whitespace_control() {
+    assert_eq!(tokenize("a \n {{- hello -}} \n b").unwrap(),
+               vec![Raw("a".into()),
+                    Expression(vec![Identifier("hello".into())],
+                               "{{- hello -}}".into(),
+                               Span::new(4, 17)),
+                    Raw("b".into())]);
+    assert_eq!(tokenize("a \n {%- hello %} \n b").unwrap(),
+               vec![Raw("a".into()),
+                    Tag(vec![Identifier("hello".into())], "{%- hello %}".into(),
+                    Span::new(4, 16)),
+                    Raw(" \n b".into())]);
+    assert_eq!(tokenize("a \n {% hello -%} \n b").unwrap(),
+               vec![Raw("a \n ".into()),
+                    Tag(vec![Identifier("hello".into())], "{% hello -%}".into(),
+                    Span::new(4, 16)),
+                    Raw("b".into())]);
+
+    // whitespace-only text between trimmed tags disappears entirely
+    assert_eq!(tokenize("{{ a -}} \n\t {%- b %}").unwrap(),
+               vec![Expression(vec![Identifier("a".into())], "{{ a -}}".into(),
+               Span::new(0, 8)),
+                    Tag(vec![Identifier("b".into())], "{%- b %}".into(),
+                    Span::new(12, 20))]);
+}
+
+#[test]
+fn test_trim_blocks() {
+    let text = "{% if a %}\nyes\n{% endif %}\r\n{{ b }}\nc";
+    let options = LiquidOptions { trim_blocks: true, ..Default::default() };
+    assert_eq!(tokenize_with_options(text, &ParseState::new(&options)).unwrap(),
+               vec![Tag(vec![Identifier("if".into()), Identifier("a".into())],
+                        "{% if a %}".into(),
+                        Span::new(0, 10)),
+                    Raw("yes\n".into()),
+                    Tag(vec![Identifier("endif".into())], "{% endif %}".into(),
+                    Span::new(15, 26)),
+                    Expression(vec![Identifier("b".into())], "{{ b }}".into(),
+                    Span::new(28, 35)),
+                    Raw("\nc".into())]);
+
+    // trimming is off by default
+    assert_eq!(tokenize_with_options(text, &ParseState::new(&LiquidOptions::default())).unwrap(),
+               tokenize(text).unwrap());
+}
+
+#[test]
+fn test_ruby_whitespace() {
+    let text = "a\u{a0} {{- b\n}}\u{3000}";
+    let options = LiquidOptions { whitespace: WhitespaceMode::Ruby, ..Default::default() };
+    assert_eq!(tokenize_with_options(text, &ParseState::new(&options)).unwrap(),
+               vec![Raw("a\u{a0}".into()),
+                    Expression(vec![Identifier("b".into())], "{{- b\n}}".into(),
+                    Span::new(4, 12)),
+                    Raw("\u{3000}".into())]);
+
+    // otherwise, markup has to be on one line and any whitespace is trimmed
+    assert_eq!(tokenize("a\u{a0} {{- b }}").unwrap(),
+               vec![Raw("a".into()),
+                    Expression(vec![Identifier("b".into())], "{{- b }}".into(),
+                    Span::new(4, 12))]);
+    assert_eq!(tokenize("{{ b\n}}").unwrap(), vec![Raw("{{ b\n}}".into())]);
+
+    // the whole source is read when markup can span lines
+    let read = tokenize_reader("x\n{{ b\n}}".as_bytes(), &ParseState::new(&options)).unwrap();
+    assert_eq!(read[1],
+               Expression(vec![Identifier("b".into())], "{{ b\n}}".into(), Span::new(2, 9)));
+}
+
+#[test]
+fn test_empty_output() {
+    assert_eq!(tokenize("a{{ }}b{{}}").unwrap(), vec![Raw("ab".into())]);
+}
+
+#[test]
+fn test_custom_delimiters() {
+    let options = LiquidOptions {
+        delimiters: Delimiters {
+            output_start: "<<<".to_owned(),
+            output_end: ">>>".to_owned(),
+            tag_start: "<%".to_owned(),
+            tag_end: "%>".to_owned(),
+        },
+        ..Default::default()
+    };
+    let state = ParseState::new(&options);
+    assert_eq!(tokenize_with_options("{{ a }} <% if b %><<<- c ->>> <% endif %>", &state)
+                   .unwrap(),
+               vec![Raw("{{ a }} ".into()),
+                    Tag(vec![Identifier("if".into()), Identifier("b".into())],
+                        "<% if b %>".into(),
+                        Span::new(8, 18)),
+                    Expression(vec![Identifier("c".into())], "<<<- c ->>>".into(),
+                    Span::new(18, 29)),
+                    Tag(vec![Identifier("endif".into())], "<% endif %>".into(),
+                    Span::new(30, 41))]);
+}
+
+#[test]
+fn test_tokens_borrow_from_source() {
+    fn borrowed(c: &Cow<str>) -> bool {
+        match *c {
+            Cow::Borrowed(_) => true,
+            Cow::Owned(_) => false,
+        }
+    }
+
+    let elements = tokenize("text {{ name | append: 'plain', 'esc\\'d' }}").unwrap();
+    assert_eq!(elements.len(), 2);
+    match (&elements[0], &elements[1]) {
+        (&Raw(ref text), &Expression(ref tokens, ref raw, _)) => {
+            assert!(borrowed(text) && borrowed(raw));
+            match (&tokens[0], &tokens[4], &tokens[6]) {
+                (&Identifier(ref name), &StringLiteral(ref plain), &StringLiteral(ref escaped)) => {
+                    assert!(borrowed(name) && borrowed(plain));
+                    // unescaping is the only thing that needs a copy
+                    assert!(!borrowed(escaped));
+                    assert_eq!(escaped, "esc'd");
+                }
+                _ => panic!("unexpected tokens {:?}", tokens),
+            }
+        }
+        _ => panic!("unexpected elements {:?}", elements),
+    }
+}
+
+#[test]
+fn test_identifiers() {
+    for name in &["color-scheme-1", "available?", "a-1", "prénom", "_x.0.first", "café[\"a b\"].ü",
+                  "sub/page.html"] {
+        assert_eq!(granularize(name).unwrap(), vec![Identifier((*name).into())]);
+    }
+    assert_eq!(granularize("a - 1 ? b").unwrap(),
+               vec![Identifier("a".into()), Dash, NumberLiteral(1f32), Question,
+                    Identifier("b".into())]);
+    assert_eq!(granularize("x | minus:-1").unwrap(),
+               vec![Identifier("x".into()), Pipe, Identifier("minus".into()), Colon,
+                    NumberLiteral(-1f32)]);
+    for bad in &["a$b", "a#b", "a%b"] {
+        assert!(granularize(bad).is_err(), "{} should not be an identifier", bad);
+    }
 }
 
 #[test]
 fn test_granularize() {
     assert_eq!(granularize("test | me").unwrap(),
-               vec![Identifier("test".to_owned()), Pipe, Identifier("me".to_owned())]);
+               vec![Identifier("test".into()), Pipe, Identifier("me".into())]);
     assert_eq!(granularize("test .. me").unwrap(),
-               vec![Identifier("test".to_owned()), DotDot, Identifier("me".to_owned())]);
+               vec![Identifier("test".into()), DotDot, Identifier("me".into())]);
     assert_eq!(granularize("test : me").unwrap(),
-               vec![Identifier("test".to_owned()), Colon, Identifier("me".to_owned())]);
+               vec![Identifier("test".into()), Colon, Identifier("me".into())]);
     assert_eq!(granularize("test , me").unwrap(),
-               vec![Identifier("test".to_owned()), Comma, Identifier("me".to_owned())]);
+               vec![Identifier("test".into()), Comma, Identifier("me".into())]);
     assert_eq!(granularize("test [ me").unwrap(),
-               vec![Identifier("test".to_owned()), OpenSquare, Identifier("me".to_owned())]);
+               vec![Identifier("test".into()), OpenSquare, Identifier("me".into())]);
     assert_eq!(granularize("test ] me").unwrap(),
-               vec![Identifier("test".to_owned()), CloseSquare, Identifier("me".to_owned())]);
+               vec![Identifier("test".into()), CloseSquare, Identifier("me".into())]);
     assert_eq!(granularize("test ( me").unwrap(),
-               vec![Identifier("test".to_owned()), OpenRound, Identifier("me".to_owned())]);
+               vec![Identifier("test".into()), OpenRound, Identifier("me".into())]);
     assert_eq!(granularize("test ) me").unwrap(),
-               vec![Identifier("test".to_owned()), CloseRound, Identifier("me".to_owned())]);
+               vec![Identifier("test".into()), CloseRound, Identifier("me".into())]);
     assert_eq!(granularize("test ? me").unwrap(),
-               vec![Identifier("test".to_owned()), Question, Identifier("me".to_owned())]);
+               vec![Identifier("test".into()), Question, Identifier("me".into())]);
     assert_eq!(granularize("test - me").unwrap(),
-               vec![Identifier("test".to_owned()), Dash, Identifier("me".to_owned())]);
+               vec![Identifier("test".into()), Dash, Identifier("me".into())]);
     assert_eq!(granularize("test me").unwrap(),
-               vec![Identifier("test".to_owned()), Identifier("me".to_owned())]);
+               vec![Identifier("test".into()), Identifier("me".into())]);
+    assert_eq!(granularize("x | plus: -1 | times: .5, -0.25, 2.0").unwrap(),
+               vec![Identifier("x".into()),
+                    Pipe,
+                    Identifier("plus".into()),
+                    Colon,
+                    NumberLiteral(-1f32),
+                    Pipe,
+                    Identifier("times".into()),
+                    Colon,
+                    NumberLiteral(0.5f32),
+                    Comma,
+                    NumberLiteral(-0.25f32),
+                    Comma,
+                    NumberLiteral(2f32)]);
+    assert_eq!(granularize("(-3..-1)").unwrap(),
+               vec![OpenRound, NumberLiteral(-3f32), DotDot, NumberLiteral(-1f32), CloseRound]);
+    assert_eq!(granularize("a - 1").unwrap(),
+               vec![Identifier("a".into()), Dash, NumberLiteral(1f32)]);
+    for bad in &["1.2.3", "12abc", "1.", ".5.5"] {
+        match granularize(bad) {
+            Err(Error::Lexer(msg)) => assert_eq!(msg, format!("{} is not a valid number", bad)),
+            x => panic!("{} should not be a valid number, got {:?}", bad, x),
+        }
+    }
+    assert_eq!(granularize(r#"'it\'s' "say \"hi\"" 'a\\b' "c\d" 'x y'"#).unwrap(),
+               vec![StringLiteral("it's".into()),
+                    StringLiteral("say \"hi\"".into()),
+                    StringLiteral("a\\b".into()),
+                    StringLiteral("c\\d".into()),
+                    StringLiteral("x y".into())]);
+    assert_eq!(granularize("a.b[0][\"key with spaces\"] | f: c[d.e]").unwrap(),
+               vec![Identifier("a.b[0][\"key with spaces\"]".into()),
+                    Pipe,
+                    Identifier("f".into()),
+                    Colon,
+                    Identifier("c[d.e]".into())]);
     assert_eq!(granularize("test = me").unwrap(),
-           vec![Identifier("test".to_owned()),
+           vec![Identifier("test".into()),
                 Assignment,
-                Identifier("me".to_owned())]);
+                Identifier("me".into())]);
     assert_eq!(granularize("test == me").unwrap(),
-               vec![Identifier("test".to_owned()),
+               vec![Identifier("test".into()),
                     Comparison(Equals),
-                    Identifier("me".to_owned())]);
+                    Identifier("me".into())]);
     assert_eq!(granularize("test >= me").unwrap(),
-               vec![Identifier("test".to_owned()),
+               vec![Identifier("test".into()),
                     Comparison(GreaterThanEquals),
-                    Identifier("me".to_owned())]);
+                    Identifier("me".into())]);
     assert_eq!(granularize("test > me").unwrap(),
-               vec![Identifier("test".to_owned()),
+               vec![Identifier("test".into()),
                     Comparison(GreaterThan),
-                    Identifier("me".to_owned())]);
+                    Identifier("me".into())]);
     assert_eq!(granularize("test < me").unwrap(),
-               vec![Identifier("test".to_owned()),
+               vec![Identifier("test".into()),
                     Comparison(LessThan),
-                    Identifier("me".to_owned())]);
+                    Identifier("me".into())]);
     assert_eq!(granularize("test != me").unwrap(),
-               vec![Identifier("test".to_owned()),
+               vec![Identifier("test".into()),
                     Comparison(NotEquals),
-                    Identifier("me".to_owned())]);
+                    Identifier("me".into())]);
     assert_eq!(granularize("test <= me").unwrap(),
-               vec![Identifier("test".to_owned()),
+               vec![Identifier("test".into()),
                     Comparison(LessThanEquals),
-                    Identifier("me".to_owned())]);
+                    Identifier("me".into())]);
     assert_eq!(granularize("test.me").unwrap(),
-               vec![Identifier("test.me".to_owned())]);
+               vec![Identifier("test.me".into())]);
     assert_eq!(granularize("'test' == \"me\"").unwrap(),
-               vec![StringLiteral("test".to_owned()),
+               vec![StringLiteral("test".into()),
                     Comparison(Equals),
-                    StringLiteral("me".to_owned())]);
+                    StringLiteral("me".into())]);
     assert_eq!(granularize("test | me:arg").unwrap(),
-               vec![Identifier("test".to_owned()),
+               vec![Identifier("test".into()),
                     Pipe,
-                    Identifier("me".to_owned()),
+                    Identifier("me".into()),
                     Colon,
-                    Identifier("arg".to_owned())]);
+                    Identifier("arg".into())]);
     assert_eq!(granularize("test | me:arg1,arg2").unwrap(),
-               vec![Identifier("test".to_owned()),
+               vec![Identifier("test".into()),
                     Pipe,
-                    Identifier("me".to_owned()),
+                    Identifier("me".into()),
                     Colon,
-                    Identifier("arg1".to_owned()),
+                    Identifier("arg1".into()),
                     Comma,
-                    Identifier("arg2".to_owned())]);
+                    Identifier("arg2".into())]);
     assert_eq!(granularize("test | me : arg1, arg2").unwrap(),
-               vec![Identifier("test".to_owned()),
+               vec![Identifier("test".into()),
                     Pipe,
-                    Identifier("me".to_owned()),
+                    Identifier("me".into()),
                     Colon,
-                    Identifier("arg1".to_owned()),
+                    Identifier("arg1".into()),
                     Comma,
-                    Identifier("arg2".to_owned())]);
+                    Identifier("arg2".into())]);
     assert_eq!(granularize("multiply 5 3").unwrap(),
-               vec![Identifier("multiply".to_owned()), NumberLiteral(5f32), NumberLiteral(3f32)]);
+               vec![Identifier("multiply".into()), NumberLiteral(5f32), NumberLiteral(3f32)]);
     assert_eq!(granularize("for i in (1..5)").unwrap(),
-               vec![Identifier("for".to_owned()),
-                    Identifier("i".to_owned()),
-                    Identifier("in".to_owned()),
+               vec![Identifier("for".into()),
+                    Identifier("i".into()),
+                    Identifier("in".into()),
                     OpenRound,
                     NumberLiteral(1f32),
                     DotDot,
@@ -39,28 +39,71 @@
 #[macro_use]
 extern crate lazy_static;
 extern crate regex;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate bincode;
 
 use std::collections::HashMap;
 use lexer::Element;
-use tags::{assign_tag, cycle_tag, include_tag, break_tag, continue_tag,
-           comment_block, raw_block, for_block, if_block, unless_block, capture_block};
+use tags::{assign_tag, cycle_tag, include_tag, include_relative_tag, break_tag, continue_tag,
+           comment_block, inline_comment_tag, doc_block, raw_block, for_block, if_block, unless_block, capture_block,
+           case_block, block_block, extends_block, cache_block, layout_block, content_for_block,
+           yield_tag};
 use std::default::Default;
+use std::fmt;
+use std::result;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::path::PathBuf;
 use error::Result;
 
-pub use value::Value;
-pub use context::Context;
+pub use value::{Value, SafeStr, Object};
+pub use hash::ObjectHasher;
+pub use context::{Context, ContextBuilder, ContextSnapshot, VariablePath};
 pub use template::Template;
-pub use error::Error;
-pub use filters::{FilterResult, FilterError};
+pub use node::Node;
+pub use error::{Error, ErrorKind, ErrorTranslator, Location};
+pub use filters::{Filter, FilterResult, FilterError, NumberPolicy};
 pub use token::Token;
+pub use lexer::{Delimiters, Span};
+pub use cache::{CacheStore, MemoryCacheStore};
+pub use builder::{Parser, ParserBuilder};
+pub use parse_state::ParseState;
+pub use environment::Environment;
+pub use i18n::{I18n, LocaleFormat};
+pub use escape::{Escaper, HtmlEscaper, JsonEscaper, ShellEscaper, CsvEscaper};
+pub use partials::{PartialSource, FileSystemSource, PartialCache, TemplateCache};
+pub use random::{RandomSource, SeededRandom};
+pub use date::{Clock, SystemClock, FixedClock, DateTime};
+pub use instrument::{Instrument, NodeKind};
+pub use visitor::{Visitor, walk};
+pub use fold::constant_folder;
+pub use validate::{Warning, WarningKind};
+pub use symbol::{Symbol, SymbolTable};
+pub use stats::RenderStats;
+#[cfg(feature = "async")]
+pub use async_render::{AsyncPartialSource, AsyncCacheStore, BoxFuture, ParseAsync, RenderAsync};
+#[cfg(feature = "serde")]
+pub use ser::{to_value, to_object};
 
 pub mod lexer;
 pub mod parser;
 
 mod token;
+mod builder;
+mod parse_state;
+mod environment;
+mod escape;
+mod i18n;
+mod partials;
+mod random;
+mod date;
+mod instrument;
+mod cache;
 mod error;
 mod template;
+mod node;
 mod output;
 mod text;
 mod tags;
@@ -68,11 +111,30 @@ mod filters;
 mod value;
 mod variable;
 mod context;
+mod visitor;
+mod fold;
+mod located;
+mod validate;
+mod symbol;
+mod stats;
+mod hash;
+#[cfg(feature = "async")]
+mod async_render;
+#[cfg(feature = "serde")]
+mod ser;
 
 /// The ErrorMode to use.
-/// This currently does not have an effect, until
-/// ErrorModes are properly implemented.
-#[derive(Clone, Copy)]
+///
+/// When parsing, in `Lax` mode malformed markup is emitted as literal
+/// text, with a warning, rather than failing the whole template. `Strict`
+/// and `Warn` currently behave the same way, stopping at the first error.
+///
+/// When rendering, set with `Context::set_error_mode`, `Strict` stops at
+/// the first error, `Warn` renders the failing output or tag as nothing
+/// and keeps the error in the context, and `Lax` renders
+/// `Liquid error: ...` in its place, like Ruby Liquid. Running out of
+/// time or going over a limit always stops the render.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ErrorMode {
     Strict,
     Warn,
@@ -85,12 +147,50 @@ impl Default for ErrorMode {
     }
 }
 
+/// Describes what the parser does when it encounters a `{% tag %}` that
+/// has not been registered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnknownTagMode {
+    /// Fail the whole parse with an error.
+    Error,
+    /// Emit the tag verbatim into the output, and record a warning.
+    Emit,
+    /// Drop the tag from the output, and record a warning.
+    Drop,
+}
+
+impl Default for UnknownTagMode {
+    fn default() -> UnknownTagMode {
+        UnknownTagMode::Error
+    }
+}
+
+/// Describes how closely the handling of whitespace and markup boundaries
+/// follows Ruby Liquid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WhitespaceMode {
+    /// Markup has to fit on a single line, and whitespace control strips
+    /// any unicode whitespace.
+    Standard,
+    /// Gives the same output as Ruby Liquid, byte for byte. Markup may span
+    /// several lines, whitespace control only strips the ASCII whitespace
+    /// that Ruby's `strip` does, and `trim_blocks` has no effect.
+    Ruby,
+}
+
+impl Default for WhitespaceMode {
+    fn default() -> WhitespaceMode {
+        WhitespaceMode::Standard
+    }
+}
+
 /// A trait for creating custom tags. This is a simple type alias for a function.
 ///
 /// This function will be called whenever the parser encounters a tag and returns
 /// a new [Renderable](trait.Renderable.html) based on its parameters. The received parameters
 /// specify the name of the tag, the argument [Tokens](lexer/enum.Token.html) passed to
-/// the tag and the global [LiquidOptions](struct.LiquidOptions.html).
+/// the tag and the [ParseState](struct.ParseState.html) of the parse under way, which
+/// holds the [LiquidOptions](struct.LiquidOptions.html) being parsed with.
 ///
 /// ## Minimal Example
 /// ```
@@ -114,19 +214,143 @@ impl Default for ErrorMode {
 /// let output = template.render(&mut data);
 /// assert_eq!(output.unwrap(), Some("Hello World!".to_owned()));
 /// ```
-pub type Tag = Fn(&str, &[Token], &LiquidOptions) -> Result<Box<Renderable>>;
+pub type Tag = Fn(&str, &[Token], &ParseState) -> Result<Box<Renderable>> + Send + Sync;
 
 /// A trait for creating custom custom block-size tags (`{% if something %}{% endif %}`). This is a simple type alias for a function.
 ///
 /// This function will be called whenever the parser encounters a block and returns
 /// a new `Renderable` based on its parameters. The received parameters specify the name
 /// of the block, the argument [Tokens](lexer/enum.Token.html) passed to
-/// the block, a Vec of all [Elements](lexer/enum.Element.html) inside the block and the global [LiquidOptions](struct.LiquidOptions.html).
-pub type Block = Fn(&str, &[Token], Vec<Element>, &LiquidOptions) -> Result<Box<Renderable>>;
+/// the block, a Vec of all [Elements](lexer/enum.Element.html) inside the block and the
+/// [ParseState](struct.ParseState.html) of the parse under way.
+pub type Block = Fn(&str, &[Token], Vec<Element>, &ParseState) -> Result<Box<Renderable>> +
+                 Send + Sync;
+
+/// A trait for creating custom blocks whose body has already been parsed. This is
+/// a simple type alias for a function.
+///
+/// This works just like a [Block](type.Block.html), except that instead of the raw
+/// [Elements](lexer/enum.Element.html) inside the block, the function receives the
+/// body as a ready-to-render [Template](struct.Template.html).
+///
+/// ## Minimal Example
+/// ```
+/// # use liquid::{Renderable, LiquidOptions, Context, Error, Template};
+///
+/// struct Shout {
+///     body: Template,
+/// }
+///
+/// impl Renderable for Shout {
+///     fn render(&self, context: &mut Context) -> Result<Option<String>, Error>{
+///         let body = try!(self.body.render(context)).unwrap_or(String::new());
+///         Ok(Some(body.to_uppercase()))
+///     }
+/// }
+///
+/// let mut options : LiquidOptions = Default::default();
+/// options.register_parsed_block("shout", Box::new(|_block_name, _arguments, body, _options| {
+///      Ok(Box::new(Shout { body: body }))
+/// }));
+///
+/// let template = liquid::parse("{% shout %}hello {{name}}{% endshout %}", options).unwrap();
+/// let mut data = Context::new();
+/// data.set_val("name", liquid::Value::str("world"));
+/// let output = template.render(&mut data);
+/// assert_eq!(output.unwrap(), Some("HELLO WORLD".to_owned()));
+/// ```
+pub type ParsedBlock = Fn(&str, &[Token], Template, &ParseState) -> Result<Box<Renderable>> +
+                       Send + Sync;
+
+/// A pass over the nodes of a template, run as each one is parsed. This is a
+/// simple type alias for a function.
+///
+/// The function receives the [Element](lexer/enum.Element.html) a node was
+/// parsed from and the node itself, and returns the node to use in its
+/// place: either the same node, a wrapper around it, or something else
+/// entirely. Blocks are passed to transforms after their contents, so a
+/// transform sees every node at every level of nesting.
+///
+/// ## Minimal Example
+/// ```
+/// # use liquid::{Renderable, LiquidOptions, Context, Error};
+///
+/// struct Brackets(Box<Renderable>);
+///
+/// impl Renderable for Brackets {
+///     fn render(&self, context: &mut Context) -> Result<Option<String>, Error> {
+///         let inner = try!(self.0.render(context)).unwrap_or(String::new());
+///         Ok(Some(format!("[{}]", inner)))
+///     }
+/// }
+///
+/// let mut options : LiquidOptions = Default::default();
+/// options.register_transform(Box::new(|element, node, _options| {
+///     match *element {
+///         liquid::lexer::Element::Expression(..) => Ok(Box::new(Brackets(node))),
+///         _ => Ok(node),
+///     }
+/// }));
+///
+/// let template = liquid::parse("a {{ 'b' }} c", options).unwrap();
+/// let output = template.render(&mut Context::new());
+/// assert_eq!(output.unwrap(), Some("a [b] c".to_owned()));
+/// ```
+pub type Transform = Fn(&Element, Box<Renderable>, &ParseState) -> Result<Box<Renderable>> +
+                     Send + Sync;
 
 /// Any object (tag/block) that can be rendered by liquid must implement this trait.
-pub trait Renderable {
+///
+/// Renderables must be `Send` and `Sync`, so that a parsed template can be
+/// shared between threads in an `Arc` and rendered from each of them with
+/// its own `Context`. Anything a tag needs to change while rendering
+/// belongs in the context.
+pub trait Renderable: Send + Sync {
     fn render(&self, context: &mut Context) -> Result<Option<String>>;
+
+    /// Renders into `out` rather than returning the output, so that blocks
+    /// such as `for` can write out each part as soon as it is rendered
+    /// instead of holding all of it. The default renders the whole element
+    /// with `render` and then writes it.
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        match try!(self.render(context)) {
+            Some(ref x) => template::write(out, x),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the documentation attached to this element, if it is a
+    /// `{% doc %}` block.
+    fn documentation(&self) -> Option<&str> {
+        None
+    }
+
+    /// Moves the element out into the variant of `Node` made for it, for
+    /// the built-in tags, blocks and text, which the parser holds without
+    /// a trait object. Anything else stays where it is and gives `None`.
+    #[doc(hidden)]
+    fn take_node(&mut self, _kind: NodeKind, _span: Span) -> Option<Node> {
+        None
+    }
+}
+
+/// Limits on the templates that will be parsed, to stop a hostile template
+/// from exhausting the stack or memory before rendering even starts. Each
+/// limit is off when `None`. Partials count towards the limits of the
+/// template that includes them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// The largest template, in bytes.
+    pub max_bytes: Option<usize>,
+
+    /// How deeply blocks and partials may be nested.
+    pub max_depth: Option<usize>,
+
+    /// The most outputs, tags, blocks and runs of text in a template.
+    pub max_nodes: Option<usize>,
+
+    /// How deeply partials may include one another.
+    pub max_include_depth: Option<usize>,
 }
 
 #[derive(Default)]
@@ -134,7 +358,54 @@ pub struct LiquidOptions {
     pub blocks: HashMap<String, Box<Block>>,
     pub tags: HashMap<String, Box<Tag>>,
     pub file_system: Option<PathBuf>,
+
+    /// Template sources that `include`, `extends` and `layout` look up by
+    /// name before trying `file_system`.
+    pub templates: HashMap<String, String>,
+
+    /// Further places to look for partials by name, tried in order after
+    /// `templates` and before `file_system`. See
+    /// [PartialSource](trait.PartialSource.html).
+    pub partials: Vec<Box<PartialSource>>,
+
+    /// Keeps partials once parsed, so that each is only parsed once
+    /// however many templates include it. Off when `None`.
+    pub partial_cache: Option<PartialCache>,
     pub error_mode: ErrorMode,
+    pub unknown_tags: UnknownTagMode,
+
+    /// Allows parentheses to group conditions in `if` and `unless` tags.
+    /// This is an extension to standard Liquid, and is off by default.
+    pub grouped_conditions: bool,
+
+    /// Allows outputs of the form `{{ a if condition else b }}`. This is an
+    /// extension to standard Liquid, and is off by default.
+    pub inline_conditionals: bool,
+
+    /// Strips the first newline after every `{% %}` tag, so that block
+    /// tags on their own lines don't leave blank lines in the output.
+    pub trim_blocks: bool,
+
+    /// How closely whitespace handling follows Ruby Liquid.
+    pub whitespace: WhitespaceMode,
+
+    /// The delimiters used to mark up outputs and tags.
+    pub delimiters: Delimiters,
+
+    /// The name of the template being parsed, used when reporting the
+    /// location of parse errors.
+    pub template_name: Option<String>,
+
+    /// Keeps parsing past malformed markup, collecting every error rather
+    /// than stopping at the first one. See `parse_all`.
+    pub collect_errors: bool,
+
+    /// Passes run over every node as it is parsed, in the order they were
+    /// registered. See [Transform](type.Transform.html).
+    pub transforms: Vec<Box<Transform>>,
+
+    /// Limits on the size and complexity of templates.
+    pub limits: Limits,
 }
 
 impl LiquidOptions {
@@ -147,26 +418,79 @@ impl LiquidOptions {
     }
 
     /// Registers all known tags and blocks in an existing options
-    /// struct
+    /// struct. Tags and blocks that have already been registered under
+    /// the same name are left untouched, so user-supplied implementations
+    /// always take precedence over the built-in ones.
     pub fn register_known_blocks(&mut self) {
-        self.register_tag("assign",   Box::new(assign_tag));
-        self.register_tag("break",    Box::new(break_tag));
-        self.register_tag("continue", Box::new(continue_tag));
-        self.register_tag("cycle",    Box::new(cycle_tag));
-        self.register_tag("include",  Box::new(include_tag));
+        self.register_default_tag("assign",   Box::new(assign_tag));
+        self.register_default_tag("break",    Box::new(break_tag));
+        self.register_default_tag("continue", Box::new(continue_tag));
+        self.register_default_tag("cycle",    Box::new(cycle_tag));
+        self.register_default_tag("include",  Box::new(include_tag));
+        self.register_default_tag("include_relative", Box::new(include_relative_tag));
+        self.register_default_tag("#",        Box::new(inline_comment_tag));
+        self.register_default_tag("yield",    Box::new(yield_tag));
+
+        self.register_default_block("raw",     Box::new(raw_block));
+        self.register_default_block("if",      Box::new(if_block));
+        self.register_default_block("unless",  Box::new(unless_block));
+        self.register_default_block("for",     Box::new(for_block));
+        self.register_default_block("comment", Box::new(comment_block));
+        self.register_default_block("doc",     Box::new(doc_block));
+        self.register_default_block("capture", Box::new(capture_block));
+        self.register_default_block("case",    Box::new(case_block));
+        self.register_default_block("cache",   Box::new(cache_block));
+        self.register_default_block("block",   Box::new(block_block));
+        self.register_default_block("extends", Box::new(extends_block));
+        self.register_default_block("layout",  Box::new(layout_block));
+        self.register_default_block("content_for", Box::new(content_for_block));
+    }
+
+    fn register_default_block(&mut self, name: &str, block: Box<Block>) {
+        self.blocks.entry(name.to_owned()).or_insert(block);
+    }
 
-        self.register_block("raw",     Box::new(raw_block));
-        self.register_block("if",      Box::new(if_block));
-        self.register_block("unless",  Box::new(unless_block));
-        self.register_block("for",     Box::new(for_block));
-        self.register_block("comment", Box::new(comment_block));
-        self.register_block("capture", Box::new(capture_block));
+    fn register_default_tag(&mut self, name: &str, tag: Box<Tag>) {
+        self.tags.entry(name.to_owned()).or_insert(tag);
     }
 
+    /// Checks the size of a template's source against `limits`.
+    pub fn check_size(&self, bytes: usize) -> Result<()> {
+        match self.limits.max_bytes {
+            Some(max) if bytes > max => {
+                Err(Error::Limit(format!("template is over the limit of {} bytes", max)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Registers a custom block under the given name, replacing any block
+    /// previously registered under that name.
     pub fn register_block(&mut self, name: &str, block: Box<Block>) {
         self.blocks.insert(name.to_owned(), block);
     }
 
+    /// Registers a custom block that receives its body pre-parsed, replacing
+    /// any block previously registered under that name.
+    pub fn register_parsed_block(&mut self, name: &str, block: Box<ParsedBlock>) {
+        self.register_block(name, Box::new(move |block_name: &str,
+                                                 arguments: &[Token],
+                                                 elements: Vec<Element>,
+                                                 state: &ParseState| {
+            let body = Template::new(try!(parser::parse(&elements, state)));
+            block(block_name, arguments, body, state)
+        }));
+    }
+
+    /// Adds a pass to run over every node as it is parsed, after any that
+    /// have already been registered.
+    pub fn register_transform(&mut self, transform: Box<Transform>) {
+        self.transforms.push(transform);
+    }
+
+    /// Registers a custom tag under the given name, replacing any tag
+    /// previously registered under that name. The tag can then be used as
+    /// either `{% name args %}` or `{{ name args }}` in a template.
     pub fn register_tag(&mut self, name: &str, tag: Box<Tag>) {
         self.tags.insert(name.to_owned(), tag);
     }
@@ -189,7 +513,114 @@ impl LiquidOptions {
 pub fn parse(text: &str, options: LiquidOptions) -> Result<Template> {
     let mut options = options;
     options.register_known_blocks();
+    parse_template(text, &options)
+}
+
+fn parse_template(text: &str, options: &LiquidOptions) -> Result<Template> {
+    parse_named_template(text, options.template_name.as_ref().map(|n| &n[..]), options)
+}
+
+fn parse_named_template(text: &str, name: Option<&str>, options: &LiquidOptions) -> Result<Template> {
+    parse_in_state(text, name, &ParseState::new(options))
+}
+
+fn parse_in_state(text: &str, name: Option<&str>, state: &ParseState) -> Result<Template> {
+    let text: Arc<str> = Arc::from(text);
+    let elements = state.with_source(&text, || {
+        lexer::tokenize_with_options(&text, state)
+            .and_then(|tokens| parser::parse(&tokens, state))
+            .map_err(|e| e.resolve_location(&text, name))
+    });
+    let mut template = try!(finish_template(elements, state));
+    template.set_shared_origin(text, name);
+    Ok(template)
+}
+
+/// Locks state that is never left half changed while it is locked, so a
+/// panic elsewhere while it was doesn't stop it from being used.
+fn locked<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Wraps up parsed elements as a template, along with any warnings
+/// recorded while parsing them.
+fn finish_template(elements: Result<Vec<Node>>, state: &ParseState) -> Result<Template> {
+    let warnings = state.take_warnings();
+    let mut template = Template::new(try!(elements));
+    template.warnings = warnings;
+    Ok(template)
+}
+
+/// Parses a liquid template like `parse`, but carries on past malformed
+/// markup so that every error in the template is reported at once, as an
+/// editor or linter would want.
+///
+/// ```
+/// use liquid::LiquidOptions;
+///
+/// let text = "{% if %}a{% endif %}{{ 'ok' }}{% for %}b{% endfor %}";
+/// let errors = liquid::parse_all(text, LiquidOptions::default()).err().unwrap();
+/// assert_eq!(errors.len(), 2);
+/// ```
+pub fn parse_all(text: &str, options: LiquidOptions) -> result::Result<Template, Vec<Error>> {
+    let mut options = options;
+    options.register_known_blocks();
+    options.collect_errors = true;
+
+    let text: Arc<str> = Arc::from(text);
+    let state = ParseState::new(&options);
+    let elements = state.with_source(&text, || parse_elements(&text, &state));
+    let name = options.template_name.as_ref().map(|n| &n[..]);
+    let errors: Vec<Error> = state.take_errors(0)
+                                  .into_iter()
+                                  .map(|e| e.resolve_location(&text, name))
+                                  .collect();
+    match elements {
+        Err(err) => Err(vec![err]),
+        Ok(_) if !errors.is_empty() => Err(errors),
+        Ok(elements) => {
+            let mut template = Template::new(elements);
+            template.warnings = state.take_warnings();
+            template.set_shared_origin(text.clone(), name);
+            Ok(template)
+        }
+    }
+}
+
+/// Walks the structure of a liquid template with a
+/// [Visitor](trait.Visitor.html), without building anything that can be
+/// rendered. This is handy for tools that want to inspect templates, such
+/// as finding every variable a template refers to.
+///
+/// ```
+/// use liquid::{LiquidOptions, Span, Token, Visitor};
+///
+/// struct Outputs(Vec<String>);
+///
+/// impl Visitor for Outputs {
+///     fn visit_output(&mut self, tokens: &[Token], _span: Span) {
+///         self.0.push(tokens[0].to_string());
+///     }
+/// }
+///
+/// let mut outputs = Outputs(vec![]);
+/// let text = "{{ title }}{% for item in items %}{{ item.name }}{% endfor %}";
+/// liquid::visit(text, LiquidOptions::default(), &mut outputs).unwrap();
+/// assert_eq!(outputs.0, vec!["title", "item.name"]);
+/// ```
+pub fn visit<V: Visitor>(text: &str, options: LiquidOptions, visitor: &mut V) -> Result<()> {
+    let mut options = options;
+    options.register_known_blocks();
+    let name = options.template_name.as_ref().map(|n| &n[..]);
+    let elements = try!(lexer::tokenize_with_options(&text, &ParseState::new(&options))
+                            .map_err(|e| e.resolve_location(text, name)));
+    walk(&elements, &options, visitor);
+    Ok(())
+}
 
-    let tokens = try!(lexer::tokenize(&text));
-    parser::parse(&tokens, &options).map(Template::new)
+fn parse_elements(text: &str, state: &ParseState) -> Result<Vec<Node>> {
+    let name = state.options().template_name.as_ref().map(|n| &n[..]);
+    lexer::tokenize_with_options(&text, state)
+        .and_then(|tokens| parser::parse(&tokens, state))
+        .map_err(|e| e.resolve_location(text, name))
 }
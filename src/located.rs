@@ -0,0 +1,306 @@
+use Renderable;
+use context::Context;
+use lexer::Span;
+use instrument::NodeKind;
+use error::{Error, Result, catch_panic};
+use node::Node;
+use template;
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Wraps a node parsed from a piece of markup, so that any error it raises
+/// while rendering records where that markup is in the template. This is
+/// also where the context's instrument hears about each node.
+///
+/// The node is a trait object unless given a type, as it is for outputs
+/// and the built-in tags and blocks.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Located<N = Box<Renderable>> {
+    node: N,
+    kind: NodeKind,
+    span: Span,
+}
+
+// lets a located node hold a tag or block made by its parser
+impl Renderable for Box<Renderable> {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        (**self).render(context)
+    }
+
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        (**self).render_fmt(out, context)
+    }
+
+    fn documentation(&self) -> Option<&str> {
+        (**self).documentation()
+    }
+
+    fn take_node(&mut self, kind: NodeKind, span: Span) -> Option<Node> {
+        (**self).take_node(kind, span)
+    }
+}
+
+// lets a template hold a node that isn't one of the built-in kinds, while
+// staying cheap to clone
+impl Renderable for Arc<Renderable> {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        (**self).render(context)
+    }
+
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        (**self).render_fmt(out, context)
+    }
+
+    fn documentation(&self) -> Option<&str> {
+        (**self).documentation()
+    }
+}
+
+impl<N: Renderable> Renderable for Located<N> {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        context.update_stats(|stats| stats.nodes += 1);
+        let instrument = context.instrument();
+        if let Some(ref instrument) = instrument {
+            try!(instrument.before(&self.kind, self.span).map_err(|e| e.located(self.span)));
+        }
+        let warned = context.render_warning_count();
+        let result = if context.catch_panics() {
+            let node = &self.node;
+            match catch_panic(|| node.render(context)) {
+                Ok(result) => result,
+                Err(message) => Err(Error::Panic(message)),
+            }
+        } else {
+            self.node.render(context)
+        };
+        context.locate_render_warnings(warned, self.span);
+        if let Some(ref instrument) = instrument {
+            instrument.after(&self.kind, self.span, &result);
+        }
+        match result {
+            Ok(output) => Ok(output),
+            Err(e) => context.recover(e.located(self.span)),
+        }
+    }
+
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        if !context.can_stream() {
+            return match try!(self.render(context)) {
+                Some(ref x) => template::write(out, x),
+                None => Ok(()),
+            };
+        }
+        context.update_stats(|stats| stats.nodes += 1);
+        let warned = context.render_warning_count();
+        let result = if context.catch_panics() {
+            let node = &self.node;
+            match catch_panic(|| node.render_fmt(out, context)) {
+                Ok(result) => result,
+                Err(message) => Err(Error::Panic(message)),
+            }
+        } else {
+            self.node.render_fmt(out, context)
+        };
+        context.locate_render_warnings(warned, self.span);
+        result.map_err(|e| e.located(self.span))
+    }
+
+    fn documentation(&self) -> Option<&str> {
+        self.node.documentation()
+    }
+}
+
+impl<N: Renderable> Located<N> {
+    pub fn new(node: N, kind: NodeKind, span: Span) -> Located<N> {
+        Located {
+            node: node,
+            kind: kind,
+            span: span,
+        }
+    }
+
+    /// The node itself, without where it came from.
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use Renderable;
+    use LiquidOptions;
+    use ParseState;
+    use parse;
+    use context::Context;
+
+    #[test]
+    fn render_errors_report_their_markup() {
+        let text = "{{ 'a' }}\n{% for x in (1..3) %}\n  {{ x | plus: 'one' }}{% endfor %}";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+        let err = template.render(&mut Context::new()).err().unwrap();
+        assert_eq!(err.without_snippet().to_string(),
+                   "line 3, column 3: Filtering error in filter #1 (plus): Invalid argument \
+                    given at position 0 : Num expected");
+
+        let template = parse("{{ 1 | plus: 1 | upcase }}", LiquidOptions::default()).unwrap();
+        let err = template.render(&mut Context::new()).err().unwrap();
+        assert_eq!(err.inner().to_string(),
+                   "Filtering error in filter #2 (upcase): Invalid type : String expected");
+    }
+
+    #[test]
+    fn error_modes_decide_whether_renders_carry_on() {
+        use ErrorMode;
+
+        let text = "a{{ 1 | plus: 'x' }}b\n{% for i in (0..2) %}{{ i | upcase }}{% endfor %}c";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let mut ctx = Context::new();
+        assert!(template.render(&mut ctx).is_err());
+
+        ctx.set_error_mode(ErrorMode::Warn);
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("ab\nc".to_owned()));
+        let errors: Vec<String> = ctx.take_render_errors()
+                                     .into_iter()
+                                     .map(|e| e.without_snippet().to_string())
+                                     .collect();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("line 1, column 2: Filtering error in filter #1 (plus)"));
+        assert!(errors[2].starts_with("line 2, column 22: Filtering error in filter #1 (upcase)"));
+
+        ctx.set_error_mode(ErrorMode::Lax);
+        assert_eq!(template.render(&mut ctx).unwrap(),
+                   Some("aLiquid error: Filtering error in filter #1 (plus): Invalid argument \
+                         given at position 0 : Num expectedb\n\
+                         Liquid error: Filtering error in filter #1 (upcase): Invalid type : \
+                         String expected\
+                         Liquid error: Filtering error in filter #1 (upcase): Invalid type : \
+                         String expectedc"
+                            .to_owned()));
+        assert!(ctx.render_errors().is_empty());
+    }
+
+    #[test]
+    fn panicking_tags_can_be_caught() {
+        use ErrorMode;
+        use error::{Error, Result};
+        use token::Token;
+
+        struct Explode;
+
+        impl Renderable for Explode {
+            fn render(&self, _context: &mut Context) -> Result<Option<String>> {
+                panic!("tag {} exploded", 1)
+            }
+        }
+
+        fn explode(_tag: &str,
+                   _arguments: &[Token],
+                   _state: &ParseState)
+                   -> Result<Box<Renderable>> {
+            Ok(Box::new(Explode))
+        }
+
+        let mut options = LiquidOptions::default();
+        options.tags.insert("explode".to_owned(), Box::new(explode));
+        let template = parse("a{% explode %}b", options).unwrap();
+
+        let mut ctx = Context::new();
+        ctx.set_catch_panics(true);
+        match *template.render(&mut ctx).unwrap_err().inner() {
+            Error::Panic(ref message) => assert_eq!(message, "tag 1 exploded"),
+            ref other => panic!("{}", other),
+        }
+
+        ctx.set_error_mode(ErrorMode::Warn);
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("ab".to_owned()));
+    }
+
+    #[test]
+    fn error_handlers_come_before_the_error_mode() {
+        use ErrorMode;
+        use ErrorKind;
+
+        let template = parse("a{{ 1 | plus: 'x' }}b{{ 'x' | nope }}c",
+                             LiquidOptions::default())
+                           .unwrap();
+        let mut ctx = Context::new();
+        ctx.set_error_mode(ErrorMode::Warn);
+        ctx.set_error_handler(|err| match err.kind() {
+            ErrorKind::InvalidArgument => Some("?".to_owned()),
+            _ => None,
+        });
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("a?bc".to_owned()));
+        let errors = ctx.take_render_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind(), ErrorKind::UnknownFilter);
+
+        ctx.clear_error_handler();
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("abc".to_owned()));
+        assert_eq!(ctx.take_render_errors().len(), 2);
+    }
+
+    #[test]
+    fn render_errors_in_partials_report_the_partial() {
+        let options = LiquidOptions {
+            file_system: Some(::std::path::PathBuf::from("tests/fixtures/input")),
+            template_name: Some("page".to_owned()),
+            ..Default::default()
+        };
+        let template = parse("x\n{% include 'render_error.txt' %}", options).unwrap();
+        let err = template.render(&mut Context::new()).err().unwrap();
+        assert!(err.to_string().starts_with("tests/fixtures/input/render_error.txt:2:1: "),
+                "{}",
+                err);
+    }
+
+    #[test]
+    fn instruments_see_every_node_and_can_stop_the_render() {
+        use instrument::{Instrument, NodeKind};
+        use lexer::Span;
+        use error::{Error, Result};
+        use std::sync::{Arc, Mutex};
+
+        struct Budget {
+            left: Mutex<usize>,
+            rendered: Mutex<Vec<(NodeKind, String)>>,
+        }
+
+        impl Instrument for Budget {
+            fn before(&self, _kind: &NodeKind, _span: Span) -> Result<()> {
+                let mut left = self.left.lock().unwrap();
+                if *left == 0 {
+                    return Err(Error::Render("over budget".to_owned()));
+                }
+                *left -= 1;
+                Ok(())
+            }
+
+            fn after(&self, kind: &NodeKind, _span: Span, result: &Result<Option<String>>) {
+                if let Ok(ref output) = *result {
+                    let output = output.clone().unwrap_or_default();
+                    self.rendered.lock().unwrap().push((kind.clone(), output));
+                }
+            }
+        }
+
+        let text = "{% assign x = 1 %}{% for i in (0..3) %}{{ i }}{% endfor %}";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+        let budget = Arc::new(Budget { left: Mutex::new(10), rendered: Mutex::new(vec![]) });
+        let mut ctx = Context::new();
+        ctx.set_instrument(Some(budget.clone()));
+        assert_eq!(template.render(&mut ctx).unwrap(), Some("012".to_owned()));
+        assert_eq!(budget.rendered.lock().unwrap()[0],
+                   (NodeKind::Tag("assign".to_owned()), "".to_owned()));
+        assert_eq!(budget.rendered.lock().unwrap()[1], (NodeKind::Output, "0".to_owned()));
+        assert_eq!(budget.rendered.lock().unwrap()[4],
+                   (NodeKind::Block("for".to_owned()), "012".to_owned()));
+
+        *budget.left.lock().unwrap() = 3;
+        let err = template.render(&mut ctx).err().unwrap();
+        assert_eq!(err.without_snippet().to_string(),
+                   "line 1, column 40: Rendering error: over budget");
+    }
+}
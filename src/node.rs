@@ -0,0 +1,204 @@
+use Renderable;
+use context::Context;
+use error::Result;
+use located::Located;
+use output::Output;
+use tags::{TagNode, BlockNode};
+use text::Text;
+
+use std::fmt;
+use std::sync::Arc;
+
+/// One element of a parsed template. Text, outputs and the built-in tags
+/// and blocks are held by kind, so that rendering them needs no allocation
+/// or virtual call of their own, and so that a template made of them can
+/// be cloned and inspected. Anything else, such as a tag registered by the
+/// user or a node returned by a transform, is kept as a `Custom` trait
+/// object, which is shared rather than copied when the template is cloned
+/// and can't be serialized.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Node {
+    Text(Text),
+    Output(Located<Output>),
+    Tag(Located<TagNode>),
+    Block(Located<BlockNode>),
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Custom(Arc<Renderable>),
+}
+
+impl Renderable for Node {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        match *self {
+            Node::Text(ref x) => x.render(context),
+            Node::Output(ref x) => x.render(context),
+            Node::Tag(ref x) => x.render(context),
+            Node::Block(ref x) => x.render(context),
+            Node::Custom(ref x) => x.render(context),
+        }
+    }
+
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        match *self {
+            Node::Text(ref x) => x.render_fmt(out, context),
+            Node::Output(ref x) => x.render_fmt(out, context),
+            Node::Tag(ref x) => x.render_fmt(out, context),
+            Node::Block(ref x) => x.render_fmt(out, context),
+            Node::Custom(ref x) => x.render_fmt(out, context),
+        }
+    }
+
+    fn documentation(&self) -> Option<&str> {
+        match *self {
+            Node::Text(ref x) => x.documentation(),
+            Node::Output(ref x) => x.documentation(),
+            Node::Tag(ref x) => x.documentation(),
+            Node::Block(ref x) => x.documentation(),
+            Node::Custom(ref x) => x.documentation(),
+        }
+    }
+}
+
+impl Node {
+    /// Boxes the node up as a trait object, for the APIs that hand nodes
+    /// around one at a time, such as transforms.
+    pub fn into_renderable(self) -> Box<Renderable> {
+        match self {
+            Node::Text(x) => Box::new(x),
+            Node::Output(x) => Box::new(x),
+            Node::Tag(x) => Box::new(x),
+            Node::Block(x) => Box::new(x),
+            Node::Custom(x) => Box::new(x),
+        }
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Node::Text(ref x) => f.debug_tuple("Text").field(x).finish(),
+            Node::Output(ref x) => f.debug_tuple("Output").field(x).finish(),
+            Node::Tag(ref x) => f.debug_tuple("Tag").field(x).finish(),
+            Node::Block(ref x) => f.debug_tuple("Block").field(x).finish(),
+            Node::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+impl From<Box<Renderable>> for Node {
+    fn from(node: Box<Renderable>) -> Node {
+        Node::Custom(Arc::from(node))
+    }
+}
+
+impl From<Text> for Node {
+    fn from(text: Text) -> Node {
+        Node::Text(text)
+    }
+}
+
+impl From<Located<Output>> for Node {
+    fn from(output: Located<Output>) -> Node {
+        Node::Output(output)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Node;
+    use LiquidOptions;
+    use ParseState;
+    use parse;
+
+    #[test]
+    fn nodes_are_held_by_kind() {
+        let template = parse("a{{ b }}{% assign c = 1 %}{% if c %}d{% endif %}",
+                             LiquidOptions::default())
+                           .unwrap();
+        assert_eq!(template.elements.len(), 4);
+        match (&template.elements[0],
+               &template.elements[1],
+               &template.elements[2],
+               &template.elements[3]) {
+            (&Node::Text(_), &Node::Output(_), &Node::Tag(_), &Node::Block(_)) => {}
+            _ => panic!("expected text, an output, a tag and a block"),
+        }
+    }
+
+    #[test]
+    fn cloned_templates_render_the_same() {
+        use Renderable;
+        use context::Context;
+
+        let text = "{% for i in (1..3) %}{{ i }}{% if i == 2 %}!{% elsif i == 1 %}?{% endif %}\
+                    {% endfor %}{{ 'a' if true }}";
+        let options = LiquidOptions { inline_conditionals: true, ..Default::default() };
+        let template = parse(text, options).unwrap();
+        let copy = template.clone();
+        drop(template);
+        assert_eq!(copy.render(&mut Context::new()).unwrap(), Some("1?2!a".to_owned()));
+    }
+
+    #[test]
+    fn nodes_can_be_printed() {
+        let template = parse("{% assign title = 'x' %}{{ title }}", LiquidOptions::default())
+                           .unwrap();
+        let printed = format!("{:?}", template);
+        assert!(printed.contains("Tag(Located { node: Assign(Assign { dst: \"title\""),
+                "{}",
+                printed);
+        assert!(printed.contains("Output(Located { node: Output"), "{}", printed);
+    }
+
+    #[test]
+    fn registered_tags_are_custom() {
+        use Renderable;
+        use context::Context;
+        use error::Result;
+        use token::Token;
+
+        struct Hello;
+
+        impl Renderable for Hello {
+            fn render(&self, _context: &mut Context) -> Result<Option<String>> {
+                Ok(Some("hello".to_owned()))
+            }
+        }
+
+        fn hello(_tag: &str, _arguments: &[Token], _state: &ParseState)
+                 -> Result<Box<Renderable>> {
+            Ok(Box::new(Hello))
+        }
+
+        let mut options = LiquidOptions::default();
+        options.tags.insert("hello".to_owned(), Box::new(hello));
+        let template = parse("{% hello %}", options).unwrap();
+        match template.elements[0] {
+            Node::Custom(_) => {}
+            _ => panic!("expected a custom node"),
+        }
+        assert_eq!(format!("{:?}", template.elements[0]), "Custom(..)");
+        let copy = template.clone();
+        assert_eq!(copy.render(&mut Context::new()).unwrap(), Some("hello".to_owned()));
+    }
+
+    #[test]
+    fn transformed_outputs_are_custom() {
+        use Renderable;
+        use context::Context;
+        use error::Result;
+        use lexer::Element;
+
+        let mut options = LiquidOptions::default();
+        options.register_transform(Box::new(|_: &Element, node: Box<Renderable>, _: &ParseState|
+                                                -> Result<Box<Renderable>> { Ok(node) }));
+        let template = parse("{{ 1 | plus: 'x' }}", options).unwrap();
+        match template.elements[0] {
+            Node::Custom(_) => {}
+            _ => panic!("expected a custom node"),
+        }
+        // it still says where it failed
+        let err = template.render(&mut Context::new()).unwrap_err().without_snippet();
+        assert!(err.to_string().starts_with("line 1, column 1:"), "{}", err);
+    }
+}
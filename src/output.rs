@@ -2,29 +2,86 @@ use Renderable;
 use context::Context;
 use value::Value;
 use variable::Variable;
-use error::{Error, Result};
+use token::Token;
+use error::{Error, Result, catch_panic};
+use validate::WarningKind;
+use filters::FilterError;
+use symbol::Symbol;
 
-#[derive(Debug)]
+use std::borrow::Cow;
+use std::ops::Deref;
+
+/// A filter as it is called in a template. Its arguments are literals, so
+/// they are worked out once while parsing and lent to the filter on every
+/// call, without being copied or collected into a new list.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FilterPrototype {
-    name: String,
-    arguments: Vec<Value>,
+    name: Symbol,
+    arguments: Arguments,
+}
+
+/// The arguments of a filter. Nearly every filter takes two arguments or
+/// fewer, so those are kept inline rather than in a list of their own.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum Arguments {
+    None,
+    One([Value; 1]),
+    Two([Value; 2]),
+    Many(Box<[Value]>),
+}
+
+impl From<Vec<Value>> for Arguments {
+    fn from(arguments: Vec<Value>) -> Arguments {
+        if arguments.len() > 2 {
+            return Arguments::Many(arguments.into_boxed_slice());
+        }
+        let mut arguments = arguments.into_iter();
+        match (arguments.next(), arguments.next()) {
+            (None, _) => Arguments::None,
+            (Some(first), None) => Arguments::One([first]),
+            (Some(first), Some(second)) => Arguments::Two([first, second]),
+        }
+    }
+}
+
+impl Deref for Arguments {
+    type Target = [Value];
+
+    fn deref(&self) -> &[Value] {
+        match *self {
+            Arguments::None => &[],
+            Arguments::One(ref x) => x,
+            Arguments::Two(ref x) => x,
+            Arguments::Many(ref x) => x,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum VarOrVal {
     Var(Variable),
     Val(Value),
+    Range(Token<'static>),
 }
 
 impl FilterPrototype {
     pub fn new(name: &str, arguments: Vec<Value>) -> FilterPrototype {
+        FilterPrototype::from_symbol(Symbol::from(name), arguments)
+    }
+
+    pub fn from_symbol(name: Symbol, arguments: Vec<Value>) -> FilterPrototype {
         FilterPrototype {
-            name: name.to_owned(),
-            arguments: arguments,
+            name: name,
+            arguments: Arguments::from(arguments),
         }
     }
 }
 
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Output {
     entry: VarOrVal,
     filters: Vec<FilterPrototype>,
@@ -32,37 +89,105 @@ pub struct Output {
 
 impl Renderable for Output {
     fn render(&self, context: &mut Context) -> Result<Option<String>> {
-        let mut entry = match self.entry {
-            VarOrVal::Val(ref x) => try!(x.render(context)).unwrap_or("".to_owned()),
-            VarOrVal::Var(ref x) => try!(x.render(context)).unwrap_or("".to_owned()),
+        let value = try!(self.value(context));
+        let (entry, safe) = match value {
+            Some(ref value) => (value.to_string(), value.is_safe()),
+            None => ("".to_owned(), false),
         };
-        let filter_entry: Option<&Value> = match self.entry {
-            VarOrVal::Val(ref x) => Some(x),
-            VarOrVal::Var(ref x) => context.get_val(&*x.name()),
+        match context.escaper() {
+            Some(escaper) if !safe => Ok(Some(escaper.escape(&entry))),
+            _ => Ok(Some(entry)),
+        }
+    }
+}
+
+impl Output {
+    pub fn new(entry: VarOrVal, filters: Vec<FilterPrototype>) -> Output {
+        Output {
+            entry: entry,
+            filters: filters,
+        }
+    }
+
+    /// Works out the value of the output, with its filters applied, but
+    /// not yet escaped.
+    pub fn value<'c>(&'c self, context: &'c Context) -> Result<Option<Cow<'c, Value>>> {
+        let filter_entry: Option<Cow<Value>> = match self.entry {
+            VarOrVal::Val(ref x) => Some(Cow::Borrowed(x)),
+            VarOrVal::Var(ref x) => try!(context.lookup_path(x.path())),
+            VarOrVal::Range(ref range) => try!(context.evaluate(range)).map(Cow::Owned),
         };
-        for filter in &self.filters {
+        let mut entry = None;
+        let empty = Value::Str("".to_owned());
+        for (i, filter) in self.filters.iter().enumerate() {
             let f = match context.get_filter(&filter.name) {
                 Some(x) => x,
+                None if !context.strict_filters() => {
+                    context.warn(WarningKind::UnknownFilter(filter.name.to_string()));
+                    continue;
+                }
                 None => {
-                    return Err(Error::Render(format!("Filter {} not implemented", &filter.name)))
+                    return Err(Error::UnknownFilter(filter.name.to_string()))
+                }
+            };
+            context.update_stats(|stats| stats.filters += 1);
+            if context.is_deprecated_filter(&filter.name) {
+                context.warn(WarningKind::DeprecatedFilter(filter.name.to_string()));
+            }
+            let input = filter_entry.as_ref().map_or(&empty, |v| &**v);
+            let fresult = if context.catch_panics() {
+                match catch_panic(|| f(input, &filter.arguments)) {
+                    Ok(result) => result,
+                    Err(message) => {
+                        return Err(Error::Panic(format!("in filter '{}': {}", filter.name, message)))
+                    }
                 }
+            } else {
+                f(input, &filter.arguments)
             };
-            let fresult = f(&filter_entry.unwrap_or(&Value::Str("".to_owned())),
-                            &filter.arguments);
             entry = match fresult {
-                Ok(s) => s.to_string(),
-                Err(e) => return Err(Error::Filter(e)),
+                Ok(value) => Some(value),
+                Err(FilterError::InvalidArgumentCount(msg)) => {
+                    let msg = match context.filter_signature(&filter.name) {
+                        Some(signature) => format!("{} (usage: {})", msg, signature),
+                        None => msg,
+                    };
+                    let err = FilterError::InvalidArgumentCount(msg);
+                    return Err(Error::FilterCall(filter.name.to_string(), i + 1, err));
+                }
+                Err(e) => return Err(Error::FilterCall(filter.name.to_string(), i + 1, e)),
             };
         }
-        Ok(Some(entry))
+        Ok(match entry {
+            Some(value) => Some(Cow::Owned(value)),
+            None => filter_entry,
+        })
     }
 }
 
-impl Output {
-    pub fn new(entry: VarOrVal, filters: Vec<FilterPrototype>) -> Output {
-        Output {
-            entry: entry,
-            filters: filters,
+#[cfg(test)]
+mod test {
+    use super::Arguments;
+    use value::Value;
+
+    #[test]
+    fn few_arguments_are_kept_inline() {
+        let args = |n: usize| {
+            Arguments::from((0..n).map(|i| Value::Num(i as f32)).collect::<Vec<_>>())
+        };
+        match args(0) {
+            Arguments::None => {}
+            x => panic!("{:?}", x),
+        }
+        match args(2) {
+            Arguments::Two(_) => {}
+            x => panic!("{:?}", x),
+        }
+        match args(3) {
+            Arguments::Many(_) => {}
+            x => panic!("{:?}", x),
         }
+        assert_eq!(&*args(2), &[Value::Num(0f32), Value::Num(1f32)][..]);
+        assert_eq!(args(3).len(), 3);
     }
 }
@@ -0,0 +1,351 @@
+use {LiquidOptions, ErrorMode, Renderable};
+use lexer::{Element, Span};
+use error::{Error, Result};
+use symbol::SymbolTable;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// What a single parse has found and where it has got to, along with the
+/// [LiquidOptions](struct.LiquidOptions.html) it parses with. One is made
+/// for each template parsed and handed to every tag, block and transform
+/// along the way, partials included, so that the options themselves never
+/// change while parsing and can be shared by any number of parses at once.
+///
+/// ## Example
+/// ```
+/// use liquid::{LiquidOptions, ParseState};
+/// use liquid::lexer::tokenize_with_options;
+///
+/// let options = LiquidOptions::with_known_blocks();
+/// let state = ParseState::new(&options);
+/// let elements = tokenize_with_options("{{ greeting }}!", &state).unwrap();
+/// let nodes = liquid::parser::parse(&elements, &state).unwrap();
+/// assert_eq!(nodes.len(), 2);
+/// assert!(state.warnings().is_empty());
+/// ```
+pub struct ParseState<'a> {
+    options: &'a LiquidOptions,
+
+    /// The errors collected so far when `collect_errors` is set.
+    errors: RefCell<Vec<Error>>,
+
+    /// Non-fatal problems found so far, such as unknown tags that were
+    /// passed through rather than treated as errors.
+    warnings: RefCell<Vec<String>>,
+
+    /// How deeply nested the parser currently is, checked against the
+    /// limits of the options.
+    depth: Cell<usize>,
+
+    /// How many nodes have been parsed so far, checked against the limits
+    /// of the options.
+    nodes: Cell<usize>,
+
+    /// The names of the partials currently being parsed, outermost first,
+    /// used to catch partials that include themselves.
+    includes: RefCell<Vec<String>>,
+
+    /// The files of the partials currently being parsed that were read
+    /// from one, innermost last, which `include_relative` resolves its
+    /// paths against.
+    include_paths: RefCell<Vec<PathBuf>>,
+
+    /// The names included by each of the partials currently being parsed
+    /// for the partial cache, innermost last, so that the cache can tell
+    /// which partials to drop when one of them changes.
+    cached_includes: RefCell<Vec<Vec<String>>>,
+
+    /// The source of the template currently being parsed, which its text
+    /// nodes share rather than each keeping a copy of their text.
+    source: RefCell<Option<Arc<str>>>,
+
+    /// The variable and filter names seen so far, so that each name is
+    /// only kept once however many times the template uses it.
+    symbols: SymbolTable,
+
+    /// The sources of partials loaded before the parse began, such as by
+    /// `Parser::parse_async`, by name.
+    loaded_partials: HashMap<String, String>,
+}
+
+impl<'a> ParseState<'a> {
+    pub fn new(options: &'a LiquidOptions) -> ParseState<'a> {
+        ParseState {
+            options: options,
+            errors: RefCell::new(vec![]),
+            warnings: RefCell::new(vec![]),
+            depth: Cell::new(0),
+            nodes: Cell::new(0),
+            includes: RefCell::new(vec![]),
+            include_paths: RefCell::new(vec![]),
+            cached_includes: RefCell::new(vec![]),
+            source: RefCell::new(None),
+            symbols: SymbolTable::new(),
+            loaded_partials: HashMap::new(),
+        }
+    }
+
+    /// The options being parsed with.
+    pub fn options(&self) -> &'a LiquidOptions {
+        self.options
+    }
+
+    /// The names interned by this parse.
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Makes the source of a partial loaded ahead of the parse available
+    /// to `include` and friends. These are tried after the templates and
+    /// partial sources of the options, and before the file system.
+    pub fn add_partial(&mut self, name: &str, source: String) {
+        self.loaded_partials.insert(name.to_owned(), source);
+    }
+
+    /// The source of a partial loaded ahead of the parse, if there is one
+    /// by that name.
+    pub fn loaded_partial(&self, name: &str) -> Option<&str> {
+        self.loaded_partials.get(name).map(|s| &s[..])
+    }
+
+    /// Runs `parse` with `source` as the text that text nodes share,
+    /// putting back the previous source afterwards so that partials parsed
+    /// along the way leave the template including them as it was.
+    pub fn with_source<T, F: FnOnce() -> T>(&self, source: &Arc<str>, parse: F) -> T {
+        let previous = self.source.replace(Some(source.clone()));
+        let result = parse();
+        *self.source.borrow_mut() = previous;
+        result
+    }
+
+    /// The source of the template currently being parsed, if it is known.
+    pub fn source(&self) -> Option<Arc<str>> {
+        self.source.borrow().clone()
+    }
+
+    /// Records a non-fatal problem found while parsing.
+    pub fn warn(&self, warning: String) {
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    /// The warnings recorded so far.
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.borrow().clone()
+    }
+
+    /// Takes the warnings recorded so far, leaving none.
+    pub fn take_warnings(&self) -> Vec<String> {
+        self.warnings.borrow_mut().drain(..).collect()
+    }
+
+    /// How many warnings have been recorded so far.
+    pub fn warning_count(&self) -> usize {
+        self.warnings.borrow().len()
+    }
+
+    /// How many errors have been collected so far.
+    pub fn error_count(&self) -> usize {
+        self.errors.borrow().len()
+    }
+
+    /// Takes the errors collected since there were `from` of them, leaving
+    /// the ones before.
+    pub fn take_errors(&self, from: usize) -> Vec<Error> {
+        self.errors.borrow_mut().drain(from..).collect()
+    }
+
+    /// Locates the errors collected since there were `from` of them within
+    /// the given source, such as that of a partial they were found in.
+    pub fn resolve_errors(&self, from: usize, text: &str, name: Option<&str>) {
+        let mut errors = self.errors.borrow_mut();
+        let resolved: Vec<Error> = errors.drain(from..)
+                                         .map(|e| e.resolve_location(text, name))
+                                         .collect();
+        errors.extend(resolved);
+    }
+
+    /// Handles an error in a single piece of markup. When collecting
+    /// errors it is recorded so that parsing can carry on; otherwise it
+    /// is returned as-is.
+    pub fn recover(&self, err: Error) -> Result<()> {
+        if self.options.collect_errors && !err.is_limit() {
+            self.errors.borrow_mut().push(err);
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+
+    /// Handles an error in a single piece of markup, returning the text to
+    /// emit in its place, if any. In `Lax` mode the markup is emitted
+    /// verbatim and a warning recorded; otherwise the error is located and
+    /// passed on to `recover`.
+    pub fn recover_markup(&self, err: Error, raw: &str, span: Span) -> Result<Option<String>> {
+        match self.options.error_mode {
+            ErrorMode::Lax if !err.is_limit() => {
+                self.warn(format!("Malformed markup {} emitted verbatim ({})", raw, err));
+                Ok(Some(raw.to_owned()))
+            }
+            _ => self.recover(err.located(span)).map(|_| None),
+        }
+    }
+
+    /// Runs the registered transforms over a freshly parsed node.
+    pub fn transform(&self, element: &Element, node: Box<Renderable>) -> Result<Box<Renderable>> {
+        let mut node = node;
+        for transform in &self.options.transforms {
+            node = try!(transform(element, node, self));
+        }
+        Ok(node)
+    }
+
+    /// Runs `parse` one level deeper, failing if that goes past the
+    /// deepest nesting the options allow.
+    pub fn nested<T, F: FnOnce() -> Result<T>>(&self, parse: F) -> Result<T> {
+        let depth = self.depth.get();
+        if let Some(max) = self.options.limits.max_depth {
+            if depth > max {
+                return Err(Error::Limit(format!("blocks are nested more than {} deep", max)));
+            }
+        }
+
+        self.depth.set(depth + 1);
+        let result = parse();
+        self.depth.set(depth);
+        result
+    }
+
+    /// Counts a node towards the limit on the number of nodes in a template.
+    pub fn count_node(&self) -> Result<()> {
+        let nodes = self.nodes.get() + 1;
+        self.nodes.set(nodes);
+        match self.options.limits.max_nodes {
+            Some(max) if nodes > max => {
+                Err(Error::Limit(format!("template has more than {} nodes", max)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs `parse` with the named partial marked as being parsed, failing
+    /// if it is already being parsed further out or if partials would be
+    /// nested deeper than the options allow.
+    pub fn including<T, F: FnOnce() -> Result<T>>(&self, name: &str, parse: F) -> Result<T> {
+        {
+            let includes = self.includes.borrow();
+            if includes.iter().any(|n| n == name) {
+                let mut chain = includes.clone();
+                chain.push(name.to_owned());
+                return Err(Error::Parser(format!("Include cycle: {}", chain.join(" -> "))));
+            }
+            if let Some(max) = self.options.limits.max_include_depth {
+                if includes.len() >= max {
+                    return Err(Error::Limit(format!("partials are included more than {} deep",
+                                                    max)));
+                }
+            }
+        }
+
+        self.includes.borrow_mut().push(name.to_owned());
+        let result = parse();
+        self.includes.borrow_mut().pop();
+        result
+    }
+
+    /// Runs `parse` with `path` as the file of the partial being parsed,
+    /// if it was read from one.
+    pub fn in_file<T, F: FnOnce() -> T>(&self, path: Option<PathBuf>, parse: F) -> T {
+        let pushed = path.is_some();
+        if let Some(path) = path {
+            self.include_paths.borrow_mut().push(path);
+        }
+        let result = parse();
+        if pushed {
+            self.include_paths.borrow_mut().pop();
+        }
+        result
+    }
+
+    /// The file of the innermost partial being parsed that was read from
+    /// one, if any.
+    pub fn current_file(&self) -> Option<PathBuf> {
+        self.include_paths.borrow().last().cloned()
+    }
+
+    /// Records that the partial being parsed for the cache, if any,
+    /// includes the named partial.
+    pub fn record_include(&self, name: &str) {
+        if let Some(includes) = self.cached_includes.borrow_mut().last_mut() {
+            includes.push(name.to_owned());
+        }
+    }
+
+    /// Runs `parse` for a partial that is to be cached, returning its
+    /// result along with the names of the partials it included.
+    pub fn recording_includes<T, F: FnOnce() -> T>(&self, parse: F) -> (T, Vec<String>) {
+        self.cached_includes.borrow_mut().push(vec![]);
+        let result = parse();
+        let includes = self.cached_includes.borrow_mut().pop().unwrap_or_default();
+        (result, includes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ParseState;
+    use LiquidOptions;
+    use Limits;
+    use error::Error;
+
+    #[test]
+    fn nesting_is_limited() {
+        let mut options = LiquidOptions::default();
+        options.limits = Limits { max_depth: Some(1), ..Limits::default() };
+        let state = ParseState::new(&options);
+
+        assert!(state.nested(|| state.nested(|| Ok(()))).is_ok());
+        let err = state.nested(|| state.nested(|| state.nested(|| Ok(())))).err().unwrap();
+        assert!(err.is_limit(), "{}", err);
+        // the depth is put back as each level is left
+        assert!(state.nested(|| Ok(())).is_ok());
+    }
+
+    #[test]
+    fn partials_including_themselves_are_caught() {
+        let options = LiquidOptions::default();
+        let state = ParseState::new(&options);
+        let err = state.including("a", || state.including("b", || state.including("a", || Ok(()))))
+                       .err()
+                       .unwrap();
+        assert_eq!(err.to_string(), "Parsing error: Include cycle: a -> b -> a");
+        assert!(state.including("a", || Ok(())).is_ok());
+    }
+
+    #[test]
+    fn errors_are_only_collected_when_asked() {
+        let mut options = LiquidOptions::default();
+        let state = ParseState::new(&options);
+        assert!(state.recover(Error::Parser("x".to_owned())).is_err());
+        assert_eq!(state.error_count(), 0);
+
+        options.collect_errors = true;
+        let state = ParseState::new(&options);
+        assert!(state.recover(Error::Parser("x".to_owned())).is_ok());
+        assert!(state.recover(Error::Limit("too big".to_owned())).is_err());
+        assert_eq!(state.take_errors(0).len(), 1);
+    }
+
+    #[test]
+    fn states_of_separate_parses_are_separate() {
+        let options = LiquidOptions::with_known_blocks();
+        let first = ParseState::new(&options);
+        let second = ParseState::new(&options);
+        first.warn("first".to_owned());
+        first.count_node().unwrap();
+        assert!(second.warnings().is_empty());
+        assert_eq!(second.nodes.get(), 0);
+    }
+}
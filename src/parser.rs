@@ -1,53 +1,164 @@
 use Renderable;
 use LiquidOptions;
-use value::Value;
+use ParseState;
+use UnknownTagMode;
+use value::{Value, Object};
 use variable::Variable;
 use text::Text;
+use located::Located;
+use node::Node;
+use instrument::NodeKind;
 use output::{Output, FilterPrototype, VarOrVal};
-use token::Token::{self, Identifier, Colon, Comma, Pipe, StringLiteral, NumberLiteral};
+use token::Token::{self, Identifier, Colon, Comma, Pipe, StringLiteral, NumberLiteral,
+                   BooleanLiteral, NilLiteral, EmptyLiteral, BlankLiteral, OpenRound,
+                   CloseRound, DotDot, Range};
 use lexer::Element::{self, Expression, Tag, Raw};
+use lexer::Span;
 use error::{Error, Result};
+use symbol::SymbolTable;
+use tags::inline_conditional;
 
 use std::slice::Iter;
 use std::collections::HashSet;
 use std::iter::FromIterator;
+use std::sync::Arc;
 
-pub fn parse(elements: &[Element], options: &LiquidOptions) -> Result<Vec<Box<Renderable>>> {
-    let mut ret = vec![];
+pub fn parse(elements: &[Element], state: &ParseState) -> Result<Vec<Node>> {
+    state.nested(|| parse_nodes(elements, state))
+}
+
+/// What kind of node the markup with the given tokens parsed into.
+fn node_kind(tokens: &[Token], options: &LiquidOptions) -> NodeKind {
+    match tokens[0] {
+        Identifier(ref x) if options.tags.contains_key(&x[..]) => NodeKind::Tag(x.to_string()),
+        Identifier(ref x) if options.blocks.contains_key(&x[..]) => NodeKind::Block(x.to_string()),
+        _ => NodeKind::Output,
+    }
+}
+
+/// Puts a node parsed from markup in the variant for its kind. The
+/// built-in tags and blocks move themselves into theirs; anything else,
+/// including an output that a transform may have replaced, is kept as a
+/// custom node.
+fn located(node: Box<Renderable>, kind: NodeKind, span: Span) -> Node {
+    let mut node = node;
+    match node.take_node(kind.clone(), span) {
+        Some(node) => node,
+        None => Node::Custom(Arc::new(Located::new(node, kind, span))),
+    }
+}
+
+/// Whether an output is an inline conditional, like `{{ a if b }}`.
+fn is_inline_conditional(tokens: &[Token], options: &LiquidOptions) -> bool {
+    options.inline_conditionals && tokens.contains(&Identifier("if".into()))
+}
+
+fn parse_nodes(elements: &[Element], state: &ParseState) -> Result<Vec<Node>> {
+    let options = state.options();
+    let mut ret: Vec<Node> = vec![];
     let mut iter = elements.iter();
     let mut token = iter.next();
-    while token.is_some() {
-        match *token.unwrap() {
-            Expression(ref tokens, _) => ret.push(try!(parse_expression(tokens, options))),
-            Tag(ref tokens, _) => ret.push(try!(parse_tag(&mut iter, tokens, options))),
-            Raw(ref x) => ret.push(Box::new(Text::new(&x))),
+    while let Some(element) = token {
+        try!(state.count_node());
+        match *element {
+            Expression(ref tokens, ref raw, span) => {
+                let kind = node_kind(tokens, options);
+                let node = if kind == NodeKind::Output && options.transforms.is_empty() &&
+                              !is_inline_conditional(tokens, options) {
+                    output(tokens, state.symbols())
+                        .map(|x| Node::Output(Located::new(x, kind, span)))
+                } else {
+                    parse_expression(tokens, state)
+                        .and_then(|x| state.transform(element, x))
+                        .map(|x| located(x, kind, span))
+                };
+                match node {
+                    Ok(node) => ret.push(node),
+                    Err(e) => {
+                        if let Some(text) = try!(state.recover_markup(e, raw, span)) {
+                            ret.push(Node::Text(Text::new(&text)));
+                        }
+                    }
+                }
+            }
+            Tag(ref tokens, ref raw, span) => {
+                // kept so that the body of a malformed block can be parsed
+                // as if the block wasn't there, when it is emitted verbatim
+                let body = iter.clone();
+                match parse_tag(&mut iter, tokens, raw, state)
+                          .and_then(|x| state.transform(element, x)) {
+                    Ok(x) => ret.push(located(x, node_kind(tokens, options), span)),
+                    Err(e) => {
+                        if let Some(text) = try!(state.recover_markup(e, raw, span)) {
+                            ret.push(Node::Text(Text::new(&text)));
+                            iter = body;
+                        }
+                    }
+                }
+            }
+            Raw(ref x) if options.transforms.is_empty() => {
+                ret.push(Node::Text(text_node(x, state)))
+            }
+            Raw(ref x) => {
+                let text = Box::new(text_node(x, state));
+                ret.push(Node::from(try!(state.transform(element, text))))
+            }
         }
         token = iter.next();
     }
     Ok(ret)
 }
 
+/// Makes a text node that shares the source being parsed, if there is one.
+fn text_node(text: &str, state: &ParseState) -> Text {
+    match state.source() {
+        Some(ref source) => Text::in_source(source, text),
+        None => Text::new(text),
+    }
+}
+
 // creates an expression, which wraps everything that gets rendered
-fn parse_expression(tokens: &[Token], options: &LiquidOptions) -> Result<Box<Renderable>> {
+fn parse_expression(tokens: &[Token], state: &ParseState) -> Result<Box<Renderable>> {
+    let options = state.options();
     match tokens[0] {
-        Identifier(ref x) if options.tags.contains_key(&x.to_owned()) => {
-            options.tags.get(x).unwrap()(&x, &tokens[1..], options)
+        Identifier(ref x) if options.tags.contains_key(&x[..]) => {
+            options.tags.get(&x[..]).unwrap()(&x, &tokens[1..], state)
+        }
+        _ if is_inline_conditional(tokens, options) => {
+            inline_conditional(tokens, state)
         }
-        _ => parse_output(tokens),
+        _ => Ok(Box::new(try!(output(tokens, state.symbols())))),
     }
 }
 
 // creates an output, basically a wrapper around values, variables and filters
-fn parse_output(tokens: &[Token]) -> Result<Box<Renderable>> {
+pub fn parse_output(tokens: &[Token]) -> Result<Box<Renderable>> {
+    Ok(Box::new(try!(output(tokens, &SymbolTable::new()))))
+}
+
+/// Creates an output from its tokens, like `parse_output`, without boxing
+/// it, interning its names in `symbols`.
+pub fn output(tokens: &[Token], symbols: &SymbolTable) -> Result<Output> {
+    let mut iter = tokens.iter().peekable();
     let entry = match tokens[0] {
-        Identifier(ref x) => VarOrVal::Var(Variable::new(&x)),
-        StringLiteral(ref x) => VarOrVal::Val(Value::Str(x.to_owned())),
+        OpenRound => {
+            iter.next();
+            let (start, stop) = try!(consume_range(&mut iter));
+            VarOrVal::Range(Range(Box::new(start), Box::new(stop)))
+        }
+        Identifier(ref x) => VarOrVal::Var(Variable::from_symbol(symbols.intern(x))),
+        StringLiteral(ref x) => VarOrVal::Val(Value::Str(x.to_string())),
+        NumberLiteral(x) => VarOrVal::Val(Value::Num(x)),
+        BooleanLiteral(x) => VarOrVal::Val(Value::Bool(x)),
+        NilLiteral | EmptyLiteral | BlankLiteral => VarOrVal::Val(Value::str("")),
         ref x => return Err(Error::Parser(format!("parse_output: {:?} not implemented", x))),
     };
 
+    if tokens[0] != OpenRound {
+        iter.next();
+    }
+
     let mut filters = vec![];
-    let mut iter = tokens.iter().peekable();
-    iter.next();
 
     while iter.peek() != None {
         if iter.next().unwrap() != &Pipe {
@@ -64,7 +175,7 @@ fn parse_output(tokens: &[Token]) -> Result<Box<Renderable>> {
 
         match iter.peek() {
             Some(&&Pipe) | None => {
-                filters.push(FilterPrototype::new(&name, args));
+                filters.push(FilterPrototype::from_symbol(symbols.intern(name), args));
                 continue;
             }
             _ => (),
@@ -76,21 +187,44 @@ fn parse_output(tokens: &[Token]) -> Result<Box<Renderable>> {
 
         iter.next(); // skip colon
 
+        // like Ruby Liquid, any `key: value` arguments are gathered into an
+        // Object that is passed to the filter after the positional ones
+        let mut named = Object::default();
         while iter.peek() != None && iter.peek().unwrap() != &&Pipe {
             match iter.next().unwrap() {
                 &Comma => continue, // next argument
-                &StringLiteral(ref x) => args.push(Value::Str(x.to_owned())),
-                &NumberLiteral(x) => args.push(Value::Num(x)),
-                ref x => {
-                    return Err(Error::Parser(format!("parse_output: {:?} not implemented", x)))
+                &Identifier(ref key) if iter.peek() == Some(&&Colon) => {
+                    iter.next();
+                    let value = match iter.next() {
+                        Some(t) => try!(literal_value(t)),
+                        None => return Error::parser("value", None),
+                    };
+                    named.insert(key.to_string(), value);
                 }
+                x => args.push(try!(literal_value(x))),
             }
         }
+        if !named.is_empty() {
+            args.push(Value::Object(named));
+        }
 
-        filters.push(FilterPrototype::new(&name, args));
+        filters.push(FilterPrototype::from_symbol(symbols.intern(name), args));
     }
 
-    Ok(Box::new(Output::new(entry, filters)))
+    Ok(Output::new(entry, filters))
+}
+
+/// Converts a literal filter argument into a Value.
+fn literal_value(token: &Token) -> Result<Value> {
+    match *token {
+        StringLiteral(ref x) => Ok(Value::Str(x.to_string())),
+        NumberLiteral(x) => Ok(Value::Num(x)),
+        BooleanLiteral(x) => Ok(Value::Bool(x)),
+        // there is no nil Value, so nil arguments are passed as an empty
+        // string
+        NilLiteral | EmptyLiteral | BlankLiteral => Ok(Value::str("")),
+        ref x => Err(Error::Parser(format!("parse_output: {:?} not implemented", x))),
+    }
 }
 
 // a tag can be either a single-element tag or a block, which can contain other
@@ -99,17 +233,19 @@ fn parse_output(tokens: &[Token]) -> Result<Box<Renderable>> {
 // renderable expressions
 fn parse_tag(iter: &mut Iter<Element>,
              tokens: &[Token],
-             options: &LiquidOptions)
+             raw: &str,
+             state: &ParseState)
              -> Result<Box<Renderable>> {
+    let options = state.options();
     let tag = &tokens[0];
     match *tag {
         // is a tag
-        Identifier(ref x) if options.tags.contains_key(x) => {
-            options.tags.get(x).unwrap()(&x, &tokens[1..], options)
+        Identifier(ref x) if options.tags.contains_key(&x[..]) => {
+            options.tags.get(&x[..]).unwrap()(&x, &tokens[1..], state)
         }
 
         // is a block
-        Identifier(ref x) if options.blocks.contains_key(x) => {
+        Identifier(ref x) if options.blocks.contains_key(&x[..]) => {
             // Collect all the inner elements of this block until we find a
             // matching "end<blockname>" tag. Note that there may be nested blocks
             // of the same type (and hence have the same closing delimiter) *inside*
@@ -120,11 +256,11 @@ fn parse_tag(iter: &mut Iter<Element>,
             // The whole nesting count machinery below is to ensure we only stop
             // collecting elements when we have an un-nested closing tag.
 
-            let end_tag = Identifier("end".to_owned() + &x);
+            let end_tag = Identifier(format!("end{}", x).into());
             let mut children = vec![];
             let mut nesting_depth = 0;
             for t in iter {
-                if let &Tag(ref tokens, _) = t {
+                if let &Tag(ref tokens, _, _) = t {
                     match tokens[0] {
                         ref n if n == tag => {
                             nesting_depth += 1;
@@ -140,10 +276,24 @@ fn parse_tag(iter: &mut Iter<Element>,
                 };
                 children.push(t.clone())
             }
-            options.blocks.get(x).unwrap()(&x, &tokens[1..], children, options)
+            options.blocks.get(&x[..]).unwrap()(&x, &tokens[1..], children, state)
         }
 
-        ref x => Err(Error::Parser(format!("parse_tag: {:?} not implemented", x))),
+        ref x => {
+            match options.unknown_tags {
+                UnknownTagMode::Error => {
+                    Err(Error::Parser(format!("parse_tag: {:?} not implemented", x)))
+                }
+                UnknownTagMode::Emit => {
+                    state.warn(format!("Unknown tag {} emitted verbatim", x));
+                    Ok(Box::new(Text::new(raw)))
+                }
+                UnknownTagMode::Drop => {
+                    state.warn(format!("Unknown tag {} dropped", x));
+                    Ok(Box::new(Text::new("")))
+                }
+            }
+        }
     }
 }
 
@@ -157,23 +307,59 @@ pub fn expect(tokens: &mut Iter<Token>, expected: Token) -> Result<()> {
 }
 
 /// Extracts a token from the token stream that can be used to express a
-/// value. For our purposes, this is either a string literal, number literal
-/// or an identifier that might refer to a variable.
-pub fn consume_value_token(tokens: &mut Iter<Token>) -> Result<Token> {
+/// value. For our purposes, this is either a literal (string, number,
+/// boolean, `nil`, `empty` or `blank`) or an identifier that might refer to
+/// a variable. The token is returned owned, ready to be kept in the parsed
+/// template.
+pub fn consume_value_token(tokens: &mut Iter<Token>) -> Result<Token<'static>> {
     match tokens.next() {
+        Some(&OpenRound) => {
+            let (start, stop) = try!(consume_range(tokens));
+            Ok(Range(Box::new(start), Box::new(stop)))
+        }
         Some(t) => value_token(t.clone()),
         None => Error::parser("string | number | identifier", None)
     }
 }
 
+/// Extracts the endpoints of a range literal such as `(1..n)` from the
+/// token stream, which should be positioned just after the opening
+/// parenthesis. Each endpoint is either a number or a variable.
+pub fn consume_range<'a, 's: 'a, I>(tokens: &mut I) -> Result<(Token<'static>, Token<'static>)>
+    where I: Iterator<Item = &'a Token<'s>>
+{
+    let start = try!(range_end_point(tokens.next()));
+    match tokens.next() {
+        Some(&DotDot) => {}
+        x => return Error::parser("..", x),
+    }
+    let stop = try!(range_end_point(tokens.next()));
+    match tokens.next() {
+        Some(&CloseRound) => Ok((start, stop)),
+        x => Error::parser(")", x),
+    }
+}
+
+fn range_end_point(token: Option<&Token>) -> Result<Token<'static>> {
+    match token {
+        Some(t @ &NumberLiteral(_)) |
+        Some(t @ &Identifier(_)) => Ok(t.clone().into_owned()),
+        x => Error::parser("number | Identifier", x),
+    }
+}
+
 /// Recognises a value token, returning an error if a non-value token
 /// is presented.
-pub fn value_token(t: Token) -> Result<Token> {
+pub fn value_token(t: Token) -> Result<Token<'static>> {
     match t {
         v @ StringLiteral(_) |
         v @ NumberLiteral(_) |
+        v @ BooleanLiteral(_) |
+        v @ NilLiteral |
+        v @ EmptyLiteral |
+        v @ BlankLiteral |
         v @ Identifier(_) => {
-            Ok(v)
+            Ok(v.into_owned())
         },
         x => {
             Error::parser("string | number | identifier", Some(&x))
@@ -181,11 +367,54 @@ pub fn value_token(t: Token) -> Result<Token> {
     }
 }
 
+/// The arguments to a tag, split into positional values and `key: value`
+/// pairs.
+#[derive(Debug, PartialEq)]
+pub struct Arguments {
+    pub positional: Vec<Token<'static>>,
+    pub named: Vec<(String, Token<'static>)>,
+}
+
+impl Arguments {
+    /// Looks up the value of a named argument.
+    pub fn get(&self, name: &str) -> Option<&Token<'static>> {
+        self.named.iter().find(|&&(ref key, _)| key == name).map(|&(_, ref value)| value)
+    }
+}
+
+/// Parses a tag's argument list, such as `a, 'b', limit: 2 offset: n`, into
+/// positional values and named `key: value` pairs. Commas between arguments
+/// are optional.
+pub fn parse_arguments(tokens: &[Token]) -> Result<Arguments> {
+    let mut args = Arguments {
+        positional: vec![],
+        named: vec![],
+    };
+    let mut iter = tokens.iter();
+
+    while !iter.as_slice().is_empty() {
+        let ahead = iter.as_slice();
+        match (&ahead[0], ahead.get(1)) {
+            (&Comma, _) => {
+                iter.next();
+            }
+            (&Identifier(ref key), Some(&Colon)) => {
+                iter.next();
+                iter.next();
+                args.named.push((key.to_string(), try!(consume_value_token(&mut iter))));
+            }
+            _ => args.positional.push(try!(consume_value_token(&mut iter))),
+        }
+    }
+
+    Ok(args)
+}
+
 /// Describes the optional trailing part of a block split.
 pub struct BlockSplit<'a> {
     pub delimiter: String,
-    pub args: &'a [Token],
-    pub trailing: &'a [Element]
+    pub args: &'a [Token<'a>],
+    pub trailing: &'a [Element<'a>]
 }
 
 /// A sub-block aware splitter that will only split the token stream
@@ -195,31 +424,31 @@ pub struct BlockSplit<'a> {
 /// Returns a slice contaiing all elements before the delimiter, and
 /// an optional BlockSplit struct describing the delimiter and
 /// trailing elements.
-pub fn split_block<'a>(tokens: &'a[Element],
+pub fn split_block<'a>(tokens: &'a[Element<'a>],
                        delimiters: &[&str],
                        options: &LiquidOptions) ->
-                            (&'a[Element], Option<BlockSplit<'a>>) {
+                            (&'a[Element<'a>], Option<BlockSplit<'a>>) {
     // construct a fast-lookup cache of the delimiters, as we're going to be
     // consulting the delimiter list a *lot*.
     let delims : HashSet<&str> = HashSet::from_iter(delimiters.iter().map(|x|*x));
     let mut stack : Vec<String> = Vec::new();
 
     for (i, t) in tokens.iter().enumerate() {
-        if let Tag(ref args, _) = *t {
+        if let Tag(ref args, _, _) = *t {
             match args[0] {
-                Identifier(ref name) if options.blocks.contains_key(name) => {
+                Identifier(ref name) if options.blocks.contains_key(&name[..]) => {
                     stack.push("end".to_owned() + name);
                 },
 
-                Identifier(ref name) if Some(name) == stack.last() => {
+                Identifier(ref name) if stack.last().map_or(false, |end| *name == end[..]) => {
                     stack.pop();
                 },
 
                 Identifier(ref name) if stack.is_empty() &&
-                                        delims.contains(name.as_str()) => {
+                                        delims.contains(&name[..]) => {
                     let leading = &tokens[0..i];
                     let split = BlockSplit {
-                        delimiter: name.clone(),
+                        delimiter: name.to_string(),
                         args: args,
                         trailing: &tokens[i..]
                     };
@@ -247,6 +476,194 @@ mod test {
         assert!(expect(&mut tokens, Comma).is_err());
     }
 
+    #[test]
+    fn unknown_tags_are_errors_by_default() {
+        use parse;
+        use LiquidOptions;
+
+        assert!(parse("{% form %}body{% endform %}", LiquidOptions::default()).is_err());
+    }
+
+    #[test]
+    fn errors_report_line_and_column() {
+        use parse;
+        use LiquidOptions;
+
+        let text = "first line\nsecond {{ x }}\n  {% if %}yes{% endif %}";
+        let err = parse(text, LiquidOptions::default()).err().unwrap();
+        assert_eq!(err.without_snippet().to_string(),
+                   "line 3, column 3: Parsing error: Expected string | number | identifier, found None");
+
+        let options = LiquidOptions {
+            template_name: Some("page.liquid".to_owned()),
+            ..Default::default()
+        };
+        let err = parse("{{ x }}\n{% for %}", options).err().unwrap();
+        assert!(err.to_string().starts_with("page.liquid:2:1: "));
+    }
+
+    #[test]
+    fn parse_all_collects_every_error() {
+        use parse_all;
+        use LiquidOptions;
+
+        let text = concat!("{{ @ }}\n",
+                           "{% if x %}{% for %}{% endfor %}{% endif %}\n",
+                           "{% if %}{% endif %}");
+        let errors = parse_all(text, LiquidOptions::default()).err().unwrap();
+        let messages: Vec<String> = errors.into_iter()
+                                          .map(|e| e.without_snippet().to_string())
+                                          .collect();
+        assert_eq!(messages,
+                   vec!["line 1, column 1: Syntax error: @ is not a valid identifier",
+                        "line 2, column 11: Parsing error: Expected Identifier, found None",
+                        "line 3, column 1: Parsing error: Expected string | number | \
+                         identifier, found None"]);
+
+        assert!(parse_all("{{ 'fine' }}", LiquidOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn unknown_tags_can_be_emitted() {
+        use parse;
+        use LiquidOptions;
+        use UnknownTagMode;
+        use Renderable;
+        use context::Context;
+
+        let options = LiquidOptions { unknown_tags: UnknownTagMode::Emit, ..Default::default() };
+        let template = parse("{% form 'x' %}{{ 'body' }}{%endform%}", options).unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(),
+                   Some("{% form 'x' %}body{%endform%}".to_owned()));
+        assert_eq!(template.warnings.len(), 2);
+    }
+
+    #[test]
+    fn unknown_tags_can_be_dropped() {
+        use parse;
+        use LiquidOptions;
+        use UnknownTagMode;
+        use Renderable;
+        use context::Context;
+
+        let options = LiquidOptions { unknown_tags: UnknownTagMode::Drop, ..Default::default() };
+        let template = parse("a{% paginate x by 5 %}b{% endpaginate %}c", options).unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(),
+                   Some("abc".to_owned()));
+        assert_eq!(template.warnings,
+                   vec!["Unknown tag paginate dropped".to_owned(),
+                        "Unknown tag endpaginate dropped".to_owned()]);
+    }
+
+    #[test]
+    fn lax_mode_emits_malformed_markup() {
+        use parse;
+        use LiquidOptions;
+        use ErrorMode;
+        use Renderable;
+        use context::Context;
+
+        let options = LiquidOptions { error_mode: ErrorMode::Lax, ..Default::default() };
+        let text = "a {% if %}b {{ 'c' }}{% endif %} {{ @ }} {{ 'd' | upcase }}";
+        let template = parse(text, options).unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(),
+                   Some("a {% if %}b c{% endif %} {{ @ }} D".to_owned()));
+        assert_eq!(template.warnings,
+                   vec!["Malformed markup {{ @ }} emitted verbatim (Syntax error: @ is not a \
+                         valid identifier)",
+                        "Malformed markup {% if %} emitted verbatim (Parsing error: Expected \
+                         string | number | identifier, found None)",
+                        "Malformed markup {% endif %} emitted verbatim (Parsing error: \
+                         parse_tag: Identifier(\"endif\") not implemented)"]);
+    }
+
+    #[test]
+    fn limits_are_enforced() {
+        use {parse, parse_all};
+        use LiquidOptions;
+        use ErrorMode;
+        use Limits;
+
+        fn options(limits: Limits) -> LiquidOptions {
+            LiquidOptions { limits: limits, ..Default::default() }
+        }
+
+        let text = "{% if a %}{% if b %}{% if c %}x{% endif %}{% endif %}{% endif %}";
+        let depth = Limits { max_depth: Some(3), ..Default::default() };
+        assert!(parse(text, options(depth)).is_ok());
+        let depth = Limits { max_depth: Some(2), ..Default::default() };
+        let err = parse(text, options(depth)).err().unwrap();
+        assert!(err.is_limit());
+        assert!(err.without_snippet()
+                   .to_string()
+                   .ends_with("Limit exceeded: blocks are nested more than 2 deep"));
+
+        let nodes = Limits { max_nodes: Some(4), ..Default::default() };
+        assert!(parse(text, options(nodes)).is_ok());
+        let nodes = Limits { max_nodes: Some(3), ..Default::default() };
+        assert!(parse(text, options(nodes)).err().unwrap().is_limit());
+
+        let bytes = Limits { max_bytes: Some(text.len()), ..Default::default() };
+        assert!(parse(text, options(bytes)).is_ok());
+        let bytes = Limits { max_bytes: Some(text.len() - 1), ..Default::default() };
+        assert!(parse(text, options(bytes)).err().unwrap().is_limit());
+
+        // limits can't be recovered from
+        let errors = parse_all(text, options(depth)).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].is_limit());
+        let mut lax = options(depth);
+        lax.error_mode = ErrorMode::Lax;
+        assert!(parse(text, lax).err().unwrap().is_limit());
+    }
+
+    #[test]
+    fn transforms_replace_nodes() {
+        use parse;
+        use LiquidOptions;
+        use Renderable;
+        use context::Context;
+        use error::Result;
+        use lexer::Element;
+        use token::Token::StringLiteral;
+
+        struct Marked(Box<Renderable>);
+
+        impl Renderable for Marked {
+            fn render(&self, context: &mut Context) -> Result<Option<String>> {
+                let inner = try!(self.0.render(context)).unwrap_or("".to_owned());
+                Ok(Some(format!("<{}>", inner)))
+            }
+        }
+
+        let mut options = LiquidOptions::default();
+        // point includes of one partial at another
+        options.register_transform(Box::new(|element, node, state| {
+            match *element {
+                Element::Tag(ref tokens, _, _) if tokens.get(1) == Some(&StringLiteral("include_with_val.txt".into())) => {
+                    let new_path = [StringLiteral("theme_layout.txt".into())];
+                    state.options().tags.get("include").unwrap()("include", &new_path, state)
+                }
+                _ => Ok(node),
+            }
+        }));
+        // and wrap every tag, including the blocks nested inside others
+        options.register_transform(Box::new(|element, node, _state| {
+            match *element {
+                Element::Tag(..) => Ok(Box::new(Marked(node))),
+                _ => Ok(node),
+            }
+        }));
+        options.file_system = Some(::std::path::PathBuf::from("tests/fixtures/input"));
+
+        let text = "{% if true %}a{% if true %}b{% endif %}{% endif %}|{% include 'include_with_val.txt' %}";
+        let template = parse(text, options).unwrap();
+        let mut context = Context::new();
+        context.set_val("content_for_layout", ::value::Value::str("x"));
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("<a<b>>|<<html>x</html>\n>".to_owned()));
+    }
+
     #[test]
     fn token_split_handles_nonmatching_stream() {
         use lexer::tokenize;
@@ -268,11 +685,60 @@ mod test {
     }
 
 
+    #[test]
+    fn parse_arguments_splits_named_arguments() {
+        use super::parse_arguments;
+        use lexer::tokenize;
+        use lexer::Element::Tag;
+        use token::Token::{Identifier, StringLiteral, NumberLiteral, Colon};
+
+        let tokens = match tokenize("{% t a, 'b' limit: 2, sort: key c %}").unwrap()[0] {
+            Tag(ref tokens, _, _) => tokens[1..].to_vec(),
+            _ => panic!("expected a tag"),
+        };
+        let args = parse_arguments(&tokens).unwrap();
+        assert_eq!(args.positional,
+                   vec![Identifier("a".into()),
+                        StringLiteral("b".into()),
+                        Identifier("c".into())]);
+        assert_eq!(args.get("limit"), Some(&NumberLiteral(2f32)));
+        assert_eq!(args.get("sort"), Some(&Identifier("key".into())));
+        assert_eq!(args.get("missing"), None);
+
+        assert!(parse_arguments(&[Identifier("a".into()), Colon]).is_err());
+    }
+
+    #[test]
+    fn named_filter_arguments_are_passed_as_an_object() {
+        use parse;
+        use LiquidOptions;
+        use Renderable;
+        use context::Context;
+        use value::Value;
+        use filters::FilterResult;
+        use value::Object;
+
+        fn describe(input: &Value, args: &[Value]) -> FilterResult {
+            Ok(Value::Str(format!("{}{:?}", input.to_string(), args)))
+        }
+
+        let template = parse("{{ 'x' | describe: 1, sep: '-' }}", LiquidOptions::default())
+                           .unwrap();
+        let mut context = Context::new();
+        context.add_filter("describe", Box::new(describe));
+
+        let mut named = Object::default();
+        named.insert("sep".to_owned(), Value::str("-"));
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some(format!("x{:?}", vec![Value::Num(1f32), Value::Object(named)])));
+    }
+
     #[test]
     fn token_split_honours_nesting() {
         use lexer::tokenize;
         use token::Token::Identifier;
         use lexer::Element::{Tag, Raw};
+        use lexer::Span;
         use super::split_block;
         use LiquidOptions;
 
@@ -300,10 +766,10 @@ mod test {
         match trailing {
             Some(split) => {
                 assert_eq!(split.delimiter, "else");
-                assert_eq!(split.args, &[Identifier("else".to_owned())]);
+                assert_eq!(split.args, &[Identifier("else".into())]);
                 assert_eq!(split.trailing, &[
-                    Tag(vec![Identifier("else".to_owned())], "{% else %}".to_owned()),
-                    Raw("trailing tags".to_owned())]);
+                    Tag(vec![Identifier("else".into())], "{% else %}".into(), Span::new(140, 150)),
+                    Raw("trailing tags".into())]);
             },
             None => panic!("split failed")
         }
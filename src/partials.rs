@@ -0,0 +1,328 @@
+use template::Template;
+use locked;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Somewhere that `include`, `extends` and `layout` can load partials from
+/// by name, such as a directory, a map in memory or a database. Register
+/// one with `ParserBuilder::partials`, or by adding it to
+/// `LiquidOptions::partials`.
+///
+/// ## Example
+/// ```
+/// use liquid::{PartialSource, ParserBuilder, Renderable, Context};
+/// use std::borrow::Cow;
+///
+/// struct Shouting;
+///
+/// impl PartialSource for Shouting {
+///     fn get(&self, name: &str) -> Option<Cow<str>> {
+///         Some(Cow::Owned(name.to_uppercase()))
+///     }
+/// }
+///
+/// let parser = ParserBuilder::new().partials(Box::new(Shouting)).build();
+/// let template = parser.parse("{% include 'hello' %}").unwrap();
+/// let output = template.render(&mut Context::new());
+/// assert_eq!(output.unwrap(), Some("HELLO".to_owned()));
+/// ```
+pub trait PartialSource: Send + Sync {
+    /// Returns the source of the named partial, or `None` if there is no
+    /// partial by that name.
+    fn get(&self, name: &str) -> Option<Cow<str>>;
+}
+
+/// Partials kept in memory, keyed by name.
+impl PartialSource for HashMap<String, String> {
+    fn get(&self, name: &str) -> Option<Cow<str>> {
+        HashMap::get(self, name).map(|s| Cow::Borrowed(&s[..]))
+    }
+}
+
+/// Loads partials from files, treating their names as paths relative to a
+/// root directory. Files that can't be read are treated as missing.
+#[derive(Clone, Debug)]
+pub struct FileSystemSource {
+    root: PathBuf,
+}
+
+impl FileSystemSource {
+    pub fn new(root: PathBuf) -> FileSystemSource {
+        FileSystemSource { root: root }
+    }
+}
+
+impl PartialSource for FileSystemSource {
+    fn get(&self, name: &str) -> Option<Cow<str>> {
+        let mut content = String::new();
+        match File::open(self.root.join(name)).and_then(|mut f| f.read_to_string(&mut content)) {
+            Ok(_) => Some(Cow::Owned(content)),
+            Err(_) => None,
+        }
+    }
+}
+
+/// A partial that has already been parsed, along with the names of the
+/// partials parsed as part of it.
+struct CachedPartial {
+    source: String,
+    template: Arc<Template>,
+    includes: Vec<String>,
+}
+
+/// Keeps partials once they have been parsed, so that a partial included
+/// from many templates is only parsed once. Entries are keyed by name and
+/// only reused while the source they were parsed from stays the same.
+///
+/// A partial that includes other partials is parsed along with them, so
+/// when the source of one of those changes, call `invalidate` with its
+/// name to drop every entry that was built from it.
+#[derive(Default)]
+pub struct PartialCache {
+    entries: Mutex<HashMap<String, CachedPartial>>,
+}
+
+impl PartialCache {
+    pub fn new() -> PartialCache {
+        PartialCache::default()
+    }
+
+    /// Looks up a partial that has been parsed from the given source
+    /// before.
+    pub fn get(&self, name: &str, source: &str) -> Option<Arc<Template>> {
+        match locked(&self.entries).get(name) {
+            Some(entry) if entry.source == source => Some(entry.template.clone()),
+            _ => None,
+        }
+    }
+
+    /// Keeps a partial parsed from the given source, along with the names
+    /// of the partials that were parsed as part of it.
+    pub fn insert(&self,
+                  name: &str,
+                  source: &str,
+                  template: Arc<Template>,
+                  includes: Vec<String>) {
+        locked(&self.entries).insert(name.to_owned(),
+                                     CachedPartial {
+                                         source: source.to_owned(),
+                                         template: template,
+                                         includes: includes,
+                                     });
+    }
+
+    /// Drops the named partial, along with every partial that included
+    /// it, directly or otherwise.
+    pub fn invalidate(&self, name: &str) {
+        let mut entries = locked(&self.entries);
+        let mut dropped = vec![name.to_owned()];
+        while let Some(name) = dropped.pop() {
+            entries.remove(&name);
+            dropped.extend(entries.iter()
+                                  .filter(|&(_, e)| e.includes.contains(&name))
+                                  .map(|(n, _)| n.clone())
+                                  .collect::<Vec<_>>());
+        }
+    }
+
+    /// Drops every partial.
+    pub fn clear(&self) {
+        locked(&self.entries).clear();
+    }
+
+    /// How many partials are being kept.
+    pub fn len(&self) -> usize {
+        locked(&self.entries).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Keeps templates parsed by [Parser::parse_cached](struct.Parser.html#method.parse_cached),
+/// keyed by their source, so that an application that is handed the same
+/// template text again and again only parses it once. At most `capacity`
+/// templates are kept; when another is added, the one used least recently
+/// is dropped. A cache can be shared between threads.
+pub struct TemplateCache {
+    capacity: usize,
+    entries: Mutex<CacheEntries>,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    templates: HashMap<Arc<str>, CachedTemplate>,
+    /// Counts uses of the cache, to tell which entry was used least recently.
+    uses: u64,
+}
+
+struct CachedTemplate {
+    template: Arc<Template>,
+    last_used: u64,
+}
+
+impl CacheEntries {
+    fn next_use(&mut self) -> u64 {
+        self.uses += 1;
+        self.uses
+    }
+}
+
+impl TemplateCache {
+    pub fn new(capacity: usize) -> TemplateCache {
+        TemplateCache {
+            capacity: capacity,
+            entries: Mutex::new(CacheEntries::default()),
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<CacheEntries> {
+        locked(&self.entries)
+    }
+
+    /// Looks up the template parsed from the given source, if it is kept.
+    pub fn get(&self, source: &str) -> Option<Arc<Template>> {
+        let mut entries = self.entries();
+        let used = entries.next_use();
+        match entries.templates.get_mut(source) {
+            Some(entry) => {
+                entry.last_used = used;
+                Some(entry.template.clone())
+            }
+            None => None,
+        }
+    }
+
+    /// Keeps the template parsed from the given source, dropping the least
+    /// recently used template if the cache is full.
+    pub fn insert(&self, source: &str, template: Arc<Template>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries();
+        let used = entries.next_use();
+        if entries.templates.len() >= self.capacity && !entries.templates.contains_key(source) {
+            let oldest = entries.templates
+                                .iter()
+                                .min_by_key(|&(_, e)| e.last_used)
+                                .map(|(source, _)| source.clone());
+            if let Some(oldest) = oldest {
+                entries.templates.remove(&oldest);
+            }
+        }
+        entries.templates.insert(Arc::from(source),
+                                 CachedTemplate {
+                                     template: template,
+                                     last_used: used,
+                                 });
+    }
+
+    /// Drops every template.
+    pub fn clear(&self) {
+        self.entries().templates.clear();
+    }
+
+    /// How many templates are being kept.
+    pub fn len(&self) -> usize {
+        self.entries().templates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The most templates that will be kept.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PartialSource, FileSystemSource, TemplateCache};
+    use Renderable;
+    use LiquidOptions;
+    use parse;
+    use context::Context;
+
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn sources_are_tried_in_order() {
+        let mut first = HashMap::new();
+        first.insert("a".to_owned(), "first a".to_owned());
+        let mut second = HashMap::new();
+        second.insert("a".to_owned(), "second a".to_owned());
+        second.insert("b".to_owned(), "second b".to_owned());
+
+        let mut options = LiquidOptions::default();
+        options.partials.push(Box::new(first));
+        options.partials.push(Box::new(second));
+        options.partials.push(Box::new(FileSystemSource::new(PathBuf::from("tests/fixtures/input"))));
+
+        let template = parse("{% include 'a' %}|{% include 'b' %}|{% include 'example.txt' %}",
+                             options)
+                           .unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(),
+                   Some("first a|second b|5 wot wot\n".to_owned()));
+    }
+
+    #[test]
+    fn missing_partials_are_errors() {
+        let source = FileSystemSource::new(PathBuf::from("tests/fixtures/input"));
+        assert!(source.get("no_such_file.txt").is_none());
+
+        let mut options = LiquidOptions::default();
+        options.partials.push(Box::new(source));
+        assert!(parse("{% include 'no_such_file.txt' %}", options).is_err());
+    }
+
+    #[test]
+    fn template_cache_drops_the_least_recently_used() {
+        let cache = TemplateCache::new(2);
+        let template = |text| Arc::new(parse(text, LiquidOptions::default()).unwrap());
+        cache.insert("a", template("a"));
+        cache.insert("b", template("b"));
+        assert!(cache.get("a").is_some());
+        cache.insert("c", template("c"));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+    }
+
+    #[test]
+    fn partials_are_only_reused_for_the_same_source() {
+        use super::PartialCache;
+
+        let cache = PartialCache::new();
+        let template = Arc::new(parse("a", LiquidOptions::default()).unwrap());
+        cache.insert("p", "a", template.clone(), vec![]);
+        assert!(Arc::ptr_eq(&cache.get("p", "a").unwrap(), &template));
+        assert!(cache.get("p", "b").is_none());
+    }
+
+    #[test]
+    fn template_cache_is_shared_between_threads() {
+        use std::thread;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<TemplateCache>();
+
+        let cache = Arc::new(TemplateCache::new(2));
+        let template = Arc::new(parse("a", LiquidOptions::default()).unwrap());
+        cache.insert("a", template.clone());
+        let shared = cache.clone();
+        let found = thread::spawn(move || shared.get("a")).join().unwrap();
+        assert!(Arc::ptr_eq(&found.unwrap(), &template));
+        assert!(cache.get("b").is_none());
+    }
+}
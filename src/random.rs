@@ -0,0 +1,91 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where filters and tags that make random choices, such as `sample`, get
+/// their random numbers from. Give a context one with
+/// `Context::set_random`, or seed the default one with `Context::set_seed`
+/// to make renders reproducible.
+///
+/// ## Example
+/// ```
+/// use liquid::{RandomSource, Context, LiquidOptions, Renderable, Value};
+///
+/// struct AlwaysLast;
+///
+/// impl RandomSource for AlwaysLast {
+///     fn next_u64(&mut self) -> u64 {
+///         u64::max_value()
+///     }
+/// }
+///
+/// let template = liquid::parse("{{ items | sample }}", LiquidOptions::default()).unwrap();
+/// let mut ctx = Context::new();
+/// ctx.set_val("items", Value::Array(vec![Value::str("a"), Value::str("b")]));
+/// ctx.set_random(Box::new(AlwaysLast));
+/// assert_eq!(template.render(&mut ctx).unwrap(), Some("b".to_owned()));
+/// ```
+pub trait RandomSource: Send {
+    fn next_u64(&mut self) -> u64;
+
+    /// Picks a number from `0` up to, but not including, `n`, which must
+    /// not be zero.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// A small, fast generator (xorshift64*) that always gives the same
+/// numbers for the same seed. Not suitable for anything secret.
+#[derive(Clone, Debug)]
+pub struct SeededRandom {
+    state: u64,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> SeededRandom {
+        // mix the seed so that nearby seeds start far apart, keeping the
+        // state away from zero, which the generator can't leave
+        let state = (seed ^ 0x9E37_79B9_7F4A_7C15).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        SeededRandom { state: if state == 0 { 0x9E37_79B9_7F4A_7C15 } else { state } }
+    }
+
+    /// Seeds a generator from the current time.
+    pub fn from_time() -> SeededRandom {
+        let seed = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs() ^ (elapsed.subsec_nanos() as u64) << 32,
+            Err(_) => 0,
+        };
+        SeededRandom::new(seed)
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RandomSource, SeededRandom};
+
+    #[test]
+    fn same_seed_same_numbers() {
+        let mut a = SeededRandom::new(42);
+        let mut b = SeededRandom::new(42);
+        let mut c = SeededRandom::new(43);
+        let from_a: Vec<u64> = (0..5).map(|_| a.next_u64()).collect();
+        let from_b: Vec<u64> = (0..5).map(|_| b.next_u64()).collect();
+        let from_c: Vec<u64> = (0..5).map(|_| c.next_u64()).collect();
+        assert_eq!(from_a, from_b);
+        assert!(from_a != from_c);
+    }
+
+    #[test]
+    fn below_stays_in_range() {
+        let mut random = SeededRandom::new(0);
+        assert!((0..100).all(|_| random.below(3) < 3));
+    }
+}
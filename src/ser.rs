@@ -0,0 +1,630 @@
+use context::Context;
+use error::{Error, Result};
+use value::{Value, Object};
+
+use serde::ser::{self, Serialize, Impossible};
+
+use std::fmt::Display;
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Error {
+        Error::Other(msg.to_string())
+    }
+}
+
+/// Converts anything that implements `Serialize` into a `Value`.
+///
+/// There is no nil `Value`, so struct fields and map entries that are
+/// `None` or `()` are left out altogether, which templates treat the same
+/// way. Elsewhere, such as in a list, they become an empty string.
+///
+/// ```
+/// #[macro_use]
+/// extern crate serde;
+/// extern crate liquid;
+///
+/// use liquid::Value;
+///
+/// #[derive(Serialize)]
+/// struct Post {
+///     title: String,
+///     tags: Vec<String>,
+/// }
+///
+/// # fn main() {
+/// let post = Post { title: "Hello".to_owned(), tags: vec!["intro".to_owned()] };
+/// let value = liquid::to_value(&post).unwrap();
+/// match value {
+///     Value::Object(ref fields) => assert_eq!(fields["title"], Value::str("Hello")),
+///     _ => panic!("expected an object"),
+/// }
+/// # }
+/// ```
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value> {
+    value.serialize(ValueSerializer).map(|v| v.unwrap_or(Value::str("")))
+}
+
+/// Converts a struct or map that implements `Serialize` into the fields of
+/// an object, ready to be used as the values of a `Context`. See
+/// [to_value](fn.to_value.html).
+pub fn to_object<T: Serialize + ?Sized>(value: &T) -> Result<Object> {
+    match try!(to_value(value)) {
+        Value::Object(fields) => Ok(fields),
+        other => Err(Error::Other(format!("Expected an object, found {:?}", other))),
+    }
+}
+
+impl Context {
+    /// Creates a context whose values are the fields of a struct or map
+    /// that implements `Serialize`. See [to_value](fn.to_value.html).
+    pub fn from_serialize<T: Serialize + ?Sized>(value: &T) -> Result<Context> {
+        Ok(Context::with_values(try!(to_object(value))))
+    }
+}
+
+/// Serializes into a `Value`, or into nothing for `None` and `()`.
+struct ValueSerializer;
+
+fn num(n: f64) -> Result<Option<Value>> {
+    Ok(Some(Value::Num(n as f32)))
+}
+
+fn tagged(variant: &str, value: Value) -> Value {
+    let mut fields = Object::default();
+    fields.insert(variant.to_owned(), value);
+    Value::Object(fields)
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeArray;
+    type SerializeMap = SerializeObject;
+    type SerializeStruct = SerializeObject;
+    type SerializeStructVariant = SerializeObject;
+
+    fn serialize_bool(self, v: bool) -> Result<Option<Value>> {
+        Ok(Some(Value::Bool(v)))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Option<Value>> {
+        num(v as f64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Option<Value>> {
+        num(v as f64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Option<Value>> {
+        num(v as f64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Option<Value>> {
+        num(v as f64)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Option<Value>> {
+        num(v as f64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Option<Value>> {
+        num(v as f64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Option<Value>> {
+        num(v as f64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Option<Value>> {
+        num(v as f64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Option<Value>> {
+        num(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Option<Value>> {
+        num(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<Option<Value>> {
+        Ok(Some(Value::Str(v.to_string())))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Option<Value>> {
+        Ok(Some(Value::str(v)))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Option<Value>> {
+        Ok(Some(Value::Array(v.iter().map(|&b| Value::Num(b as f32)).collect())))
+    }
+
+    fn serialize_none(self) -> Result<Option<Value>> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Option<Value>> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Option<Value>> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Option<Value>> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(self,
+                              _name: &'static str,
+                              _index: u32,
+                              variant: &'static str)
+                              -> Result<Option<Value>> {
+        Ok(Some(Value::str(variant)))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self,
+                                                       _name: &'static str,
+                                                       value: &T)
+                                                       -> Result<Option<Value>> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self,
+                                                        _name: &'static str,
+                                                        _index: u32,
+                                                        variant: &'static str,
+                                                        value: &T)
+                                                        -> Result<Option<Value>> {
+        Ok(Some(tagged(variant, try!(to_value(value)))))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray> {
+        Ok(SerializeArray {
+            variant: None,
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SerializeArray> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self,
+                               _name: &'static str,
+                               _index: u32,
+                               variant: &'static str,
+                               len: usize)
+                               -> Result<SerializeArray> {
+        Ok(SerializeArray {
+            variant: Some(variant),
+            items: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeObject> {
+        Ok(SerializeObject {
+            variant: None,
+            fields: Object::default(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeObject> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(self,
+                                _name: &'static str,
+                                _index: u32,
+                                variant: &'static str,
+                                _len: usize)
+                                -> Result<SerializeObject> {
+        Ok(SerializeObject {
+            variant: Some(variant),
+            fields: Object::default(),
+            key: None,
+        })
+    }
+}
+
+struct SerializeArray {
+    variant: Option<&'static str>,
+    items: Vec<Value>,
+}
+
+impl SerializeArray {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.items.push(try!(to_value(value)));
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<Value>> {
+        let array = Value::Array(self.items);
+        Ok(Some(match self.variant {
+            Some(variant) => tagged(variant, array),
+            None => array,
+        }))
+    }
+}
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Option<Value>> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Option<Value>> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Option<Value>> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeArray {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Option<Value>> {
+        self.finish()
+    }
+}
+
+struct SerializeObject {
+    variant: Option<&'static str>,
+    fields: Object,
+    key: Option<String>,
+}
+
+impl SerializeObject {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<()> {
+        if let Some(value) = try!(value.serialize(ValueSerializer)) {
+            self.fields.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<Value>> {
+        let object = Value::Object(self.fields);
+        Ok(Some(match self.variant {
+            Some(variant) => tagged(variant, object),
+            None => object,
+        }))
+    }
+}
+
+impl ser::SerializeMap for SerializeObject {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(try!(key.serialize(KeySerializer)));
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = try!(self.key
+                           .take()
+                           .ok_or(Error::Other("Map value given without a key".to_owned())));
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Option<Value>> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeObject {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self,
+                                              key: &'static str,
+                                              value: &T)
+                                              -> Result<()> {
+        self.insert(key.to_owned(), value)
+    }
+
+    fn end(self) -> Result<Option<Value>> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeObject {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self,
+                                              key: &'static str,
+                                              value: &T)
+                                              -> Result<()> {
+        self.insert(key.to_owned(), value)
+    }
+
+    fn end(self) -> Result<Option<Value>> {
+        self.finish()
+    }
+}
+
+/// Serializes the keys of maps, which have to be strings, numbers,
+/// booleans or characters.
+struct KeySerializer;
+
+fn key_error() -> Error {
+    Error::Other("Map keys must be strings, numbers or booleans".to_owned())
+}
+
+impl ser::Serializer for KeySerializer {
+    type Ok = String;
+    type Error = Error;
+
+    type SerializeSeq = Impossible<String, Error>;
+    type SerializeTuple = Impossible<String, Error>;
+    type SerializeTupleStruct = Impossible<String, Error>;
+    type SerializeTupleVariant = Impossible<String, Error>;
+    type SerializeMap = Impossible<String, Error>;
+    type SerializeStruct = Impossible<String, Error>;
+    type SerializeStructVariant = Impossible<String, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_char(self, v: char) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<String> {
+        Ok(v.to_owned())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<String> {
+        Err(key_error())
+    }
+
+    fn serialize_none(self) -> Result<String> {
+        Err(key_error())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<String> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<String> {
+        Err(key_error())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String> {
+        Err(key_error())
+    }
+
+    fn serialize_unit_variant(self,
+                              _name: &'static str,
+                              _index: u32,
+                              variant: &'static str)
+                              -> Result<String> {
+        Ok(variant.to_owned())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self,
+                                                       _name: &'static str,
+                                                       value: &T)
+                                                       -> Result<String> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self,
+                                                        _name: &'static str,
+                                                        _index: u32,
+                                                        _variant: &'static str,
+                                                        _value: &T)
+                                                        -> Result<String> {
+        Err(key_error())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple_struct(self,
+                              _name: &'static str,
+                              _len: usize)
+                              -> Result<Self::SerializeTupleStruct> {
+        Err(key_error())
+    }
+
+    fn serialize_tuple_variant(self,
+                               _name: &'static str,
+                               _index: u32,
+                               _variant: &'static str,
+                               _len: usize)
+                               -> Result<Self::SerializeTupleVariant> {
+        Err(key_error())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(key_error())
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(key_error())
+    }
+
+    fn serialize_struct_variant(self,
+                                _name: &'static str,
+                                _index: u32,
+                                _variant: &'static str,
+                                _len: usize)
+                                -> Result<Self::SerializeStructVariant> {
+        Err(key_error())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_value, to_object};
+    use Renderable;
+    use LiquidOptions;
+    use parse;
+    use context::Context;
+    use value::Value;
+
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Serialize)]
+    struct Author {
+        name: String,
+        email: Option<String>,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Status {
+        Draft,
+        Published,
+    }
+
+    #[derive(Serialize)]
+    struct Post {
+        title: &'static str,
+        views: u32,
+        featured: bool,
+        tags: Vec<&'static str>,
+        author: Author,
+        status: Status,
+        #[serde(rename = "read-time")]
+        read_time: f64,
+    }
+
+    fn post() -> Post {
+        Post {
+            title: "Hello",
+            views: 12,
+            featured: true,
+            tags: vec!["a", "b"],
+            author: Author {
+                name: "Ann".to_owned(),
+                email: None,
+            },
+            status: Status::Published,
+            read_time: 2.5,
+        }
+    }
+
+    #[test]
+    fn renders_serialized_structs() {
+        let text = concat!("{{ title }} ({{ views }}, {{ featured }}, {{ status }}, {{ read-time }}) ",
+                           "{% for t in tags %}#{{ t }}{% endfor %} ",
+                           "by {{ author.name }}{% if author.email %} <{{ author.email }}>{% endif %}");
+        let template = parse(text, LiquidOptions::default()).unwrap();
+        let mut context = Context::from_serialize(&post()).unwrap();
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("Hello (12, true, published, 2.5) #a#b by Ann".to_owned()));
+    }
+
+    #[test]
+    fn leaves_out_missing_fields() {
+        let author = to_object(&post().author).unwrap();
+        assert_eq!(author.get("name"), Some(&Value::str("Ann")));
+        assert!(!author.contains_key("email"));
+
+        let list = to_value(&vec![Some(1), None]).unwrap();
+        assert_eq!(list, Value::Array(vec![Value::Num(1f32), Value::str("")]));
+    }
+
+    #[test]
+    fn converts_map_keys() {
+        let mut map = BTreeMap::new();
+        map.insert(1, "one");
+        let object = to_object(&map).unwrap();
+        assert_eq!(object.get("1"), Some(&Value::str("one")));
+
+        let mut bad = HashMap::new();
+        bad.insert(vec![1], "list");
+        assert!(to_object(&bad).is_err());
+        assert!(to_object(&vec![1]).is_err());
+        let _ = Status::Draft;
+    }
+}
@@ -0,0 +1,39 @@
+/// Counts of what went on during a render, kept by the
+/// [Context](struct.Context.html) and read back with `stats` once the
+/// render is done. They add up over every render with the context until it
+/// is `reset`, which is handy for tracking how much work pages take as
+/// templates change.
+///
+/// ## Example
+/// ```
+/// use liquid::{Context, LiquidOptions, Renderable};
+///
+/// let template = liquid::parse("{{ 'a' | upcase }} {{ 2 | plus: 1 }}",
+///                              LiquidOptions::default()).unwrap();
+/// let mut context = Context::new();
+/// template.render(&mut context).unwrap();
+///
+/// let stats = context.stats();
+/// assert_eq!(stats.nodes, 2);
+/// assert_eq!(stats.filters, 2);
+/// assert_eq!(stats.bytes, 3);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RenderStats {
+    /// Outputs, tags and blocks rendered, counting each time one inside a
+    /// loop is rendered.
+    pub nodes: usize,
+
+    /// Filters called.
+    pub filters: usize,
+
+    /// Bytes of output written by top-level templates.
+    pub bytes: usize,
+
+    /// Partials rendered by `include` and `include_relative`.
+    pub includes: usize,
+
+    /// Fragments the `cache` block found in its store rather than
+    /// rendering.
+    pub cache_hits: usize,
+}
@@ -0,0 +1,231 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+#[cfg(feature = "fast-hash")]
+use hash::FnvHasher;
+#[cfg(not(feature = "fast-hash"))]
+use std::collections::hash_map::RandomState;
+#[cfg(not(feature = "fast-hash"))]
+use std::hash::BuildHasher;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+#[cfg(not(feature = "fast-hash"))]
+lazy_static! {
+    /// The keys used to hash every symbol, chosen afresh for each process
+    /// so that names can't be picked to collide.
+    static ref SYMBOL_KEYS: RandomState = RandomState::new();
+}
+
+/// A name from a template, such as the name of a variable or a filter.
+/// The name is hashed once, when the symbol is made, and the scope stack
+/// of a `Context` is keyed by symbols, so looking a variable up there
+/// never hashes its name again. Names are interned while parsing, so that
+/// the same name used throughout a template shares a single copy, and
+/// comparing two uses of it only compares pointers.
+///
+/// # Examples
+///
+/// ```
+/// # use liquid::Symbol;
+/// let name = Symbol::from("title");
+/// assert_eq!(&*name, "title");
+/// assert_eq!(name, Symbol::from("title"));
+/// ```
+#[derive(Clone)]
+pub struct Symbol {
+    name: Arc<str>,
+    hash: u64,
+}
+
+impl Symbol {
+    /// The name, as it was written.
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Hashes a name for a symbol, with the quick FNV hash under the
+/// `fast-hash` feature.
+#[cfg(feature = "fast-hash")]
+fn hash_name(name: &str) -> u64 {
+    let mut hasher = FnvHasher::default();
+    name.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(not(feature = "fast-hash"))]
+fn hash_name(name: &str) -> u64 {
+    let mut hasher = SYMBOL_KEYS.build_hasher();
+    name.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<'a> From<&'a str> for Symbol {
+    fn from(name: &'a str) -> Symbol {
+        Symbol {
+            hash: hash_name(name),
+            name: Arc::from(name),
+        }
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Symbol {
+        Symbol {
+            hash: hash_name(&name),
+            name: Arc::from(name),
+        }
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl AsRef<str> for Symbol {
+    fn as_ref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        self.hash == other.hash && (Arc::ptr_eq(&self.name, &other.name) || self.name == other.name)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.name, f)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&*self.name, f)
+    }
+}
+
+// a symbol is stored as its name, and hashed again when it is read back
+#[cfg(feature = "serde")]
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Symbol, D::Error> {
+        String::deserialize(deserializer).map(Symbol::from)
+    }
+}
+
+/// The hasher for maps keyed by symbols, which takes the hash each symbol
+/// already has rather than hashing its name again.
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        // only symbols are meant to be hashed with this, but anything else
+        // still gets a usable hash
+        for byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ *byte as u64;
+        }
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+}
+
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+
+/// The names interned by a single parse. Each name is only kept once, and
+/// the table is dropped along with the parse, so it doesn't grow as more
+/// templates are parsed.
+///
+/// # Examples
+///
+/// ```
+/// # use liquid::SymbolTable;
+/// let symbols = SymbolTable::new();
+/// let first = symbols.intern("title");
+/// assert_eq!(first, symbols.intern("title"));
+/// assert_eq!(symbols.len(), 1);
+/// ```
+#[derive(Default)]
+pub struct SymbolTable {
+    symbols: RefCell<HashMap<Box<str>, Symbol>>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    /// The symbol for `name`, the same one each time it is asked for.
+    pub fn intern(&self, name: &str) -> Symbol {
+        if let Some(symbol) = self.symbols.borrow().get(name) {
+            return symbol.clone();
+        }
+        let symbol = Symbol::from(name);
+        self.symbols.borrow_mut().insert(Box::from(name), symbol.clone());
+        symbol
+    }
+
+    /// How many different names have been interned.
+    pub fn len(&self) -> usize {
+        self.symbols.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.borrow().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Symbol, SymbolMap, SymbolTable};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn interned_names_are_shared() {
+        let symbols = SymbolTable::new();
+        let first = symbols.intern("title");
+        let second = symbols.intern("title");
+        assert!(Arc::ptr_eq(&first.name, &second.name));
+        assert_eq!(symbols.len(), 1);
+    }
+
+    #[test]
+    fn symbols_made_separately_find_each_other() {
+        let mut map = SymbolMap::default();
+        map.insert(SymbolTable::new().intern("title"), 1);
+        assert_eq!(map.get(&Symbol::from("title")), Some(&1));
+        assert_eq!(map.get(&Symbol::from("titles")), None);
+    }
+}
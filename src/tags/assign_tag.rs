@@ -1,52 +1,49 @@
 use Renderable;
 use context::Context;
-use LiquidOptions;
-use parser::expect;
-use token::Token::{self, Identifier, Assignment, StringLiteral, NumberLiteral, BooleanLiteral};
+use ParseState;
+use parser::{expect, consume_value_token};
+use token::Token::{self, Identifier, Assignment, NilLiteral};
 use error::{Error, Result};
+use symbol::Symbol;
+use tags::TagNode;
 
-struct Assign {
-    dst: String,
-    src: Token
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Assign {
+    dst: Symbol,
+    src: Token<'static>
 }
 
 impl Renderable for Assign {
     fn render(&self, context: &mut Context) -> Result<Option<String>> {
-        let value = match try!(context.evaluate(&self.src)) {
-            Some(v) => v,
+        match try!(context.evaluate(&self.src)) {
+            Some(v) => context.assign_symbol(&self.dst, Some(v)),
+            None if self.src == NilLiteral => context.assign_symbol(&self.dst, None),
             None => return Error::renderer(
                 &format!("No such value {:?}", self.src))
         };
 
-        context.set_val(&self.dst, value);
         Ok(None)
     }
 }
 
 pub fn assign_tag(_tag_name: &str,
                   arguments: &[Token],
-                  _options: &LiquidOptions) -> Result<Box<Renderable>> {
+                  state: &ParseState) -> Result<Box<Renderable>> {
     let mut args = arguments.iter();
     let dst = match args.next() {
-        Some(&Identifier(ref id)) => id.clone(),
+        Some(&Identifier(ref id)) => state.symbols().intern(id),
         x => return Error::parser("Identifier", x)
     };
 
     try!(expect(&mut args, Assignment));
 
-    let src = match args.next() {
-        x @ Some(&Identifier(_)) |
-        x @ Some(&StringLiteral(_)) |
-        x @ Some(&NumberLiteral(_)) |
-        x @ Some(&BooleanLiteral(_)) => { x.unwrap().clone() },
-        x @ Some(_) | x @ None =>
-            return Error::parser("Identifier | String | Number | Boolean", x)
-    };
+    let src = try!(consume_value_token(&mut args));
 
-    Ok(Box::new(Assign {
+    Ok(Box::new(TagNode::Assign(Assign {
         dst: dst,
         src: src
-    }))
+    })))
 }
 
 #[cfg(test)]
@@ -95,4 +92,55 @@ mod test {
             assert_eq!(output.unwrap(), Some("<p>Freestyle!</p>".to_string()));
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn assignments_do_not_change_globals() {
+        use value::Object;
+        use std::sync::Arc;
+
+        let text = "{{ a }}{% assign a = 'mine' %}{{ a }}{% capture b %}x{% endcapture %}{{ b }}";
+        let template = parse(text, Default::default()).unwrap();
+
+        let mut globals = Object::default();
+        globals.insert("a".to_owned(), Value::str("host"));
+        globals.insert("b".to_owned(), Value::str("host"));
+        let globals = Arc::new(globals);
+
+        for _ in 0..2 {
+            let mut context = Context::with_globals(globals.clone());
+            assert_eq!(template.render(&mut context).unwrap(), Some("hostminex".to_owned()));
+            assert_eq!(context.globals().get("a"), Some(&Value::str("host")));
+        }
+        assert_eq!(globals.get("b"), Some(&Value::str("host")));
+
+        // assigning nil hides the global rather than removing it
+        let template = parse("{% assign a = nil %}[{{ a }}]", Default::default()).unwrap();
+        let mut context = Context::with_globals(globals.clone());
+        assert_eq!(template.render(&mut context).unwrap(), Some("[]".to_owned()));
+        assert_eq!(context.globals().get("a"), Some(&Value::str("host")));
+    }
+
+    #[test]
+    fn assigning_ranges() {
+        let text = "{% assign r = (2..n) %}{{ r | size }}: {{ r }}; {{ (1..3) }}";
+        let template = parse(text, Default::default()).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("n", Value::Num(5f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("3: 2, 3, 4; 1, 2".to_owned()));
+    }
+
+    #[test]
+    fn assigning_keywords() {
+        let text = concat!(
+            "{% assign a = 'x' %}{% assign a = nil %}{% if a == nil %}nil{% endif %}",
+            "{% assign b = empty %}[{{ b }}]",
+            "{% assign c = false %}{% unless c %}false{% endunless %}");
+        let template = parse(text, Default::default()).unwrap();
+
+        let mut context = Context::new();
+        assert_eq!(template.render(&mut context).unwrap(), Some("nil[]false".to_owned()));
+        assert_eq!(context.get_val("a"), None);
+        assert_eq!(context.get_val("b"), Some(&Value::str("")));
+    }
+}
@@ -0,0 +1,123 @@
+use Renderable;
+use context::Context;
+use ParseState;
+use template::Template;
+use token::Token::{self, Comma, Colon, Identifier, NumberLiteral};
+use parser::{parse, expect, consume_value_token};
+use lexer::Element;
+use error::{Error, Result};
+use tags::BlockNode;
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Cache {
+    key: Token<'static>,
+    ttl: Option<u64>,
+    template: Template,
+}
+
+impl Renderable for Cache {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        if context.cache_store().is_none() {
+            return self.template.render(context);
+        }
+
+        let key = match try!(context.evaluate(&self.key)) {
+            Some(k) => k.to_string(),
+            None => return Error::renderer(&format!("No such value {}", self.key)),
+        };
+
+        if let Some(cached) = context.cache_store().and_then(|store| store.get(&key)) {
+            context.update_stats(|stats| stats.cache_hits += 1);
+            return Ok(Some(cached));
+        }
+
+        let output = try!(self.template.render(context)).unwrap_or("".to_owned());
+        if let Some(store) = context.cache_store() {
+            store.set(&key, output.clone(), self.ttl);
+        }
+        Ok(Some(output))
+    }
+}
+
+pub fn cache_block(_tag_name: &str,
+                   arguments: &[Token],
+                   tokens: Vec<Element>,
+                   state: &ParseState)
+                   -> Result<Box<Renderable>> {
+    let mut args = arguments.iter();
+    let key = try!(consume_value_token(&mut args));
+
+    let mut ttl = None;
+    while let Some(token) = args.next() {
+        if *token != Comma {
+            return Error::parser(",", Some(token));
+        }
+        match args.next() {
+            Some(&Identifier(ref attr)) if attr == "ttl" => {
+                try!(expect(&mut args, Colon));
+                ttl = match args.next() {
+                    Some(&NumberLiteral(n)) if n >= 0f32 => Some(n as u64),
+                    x => return Error::parser("positive number", x),
+                };
+            }
+            x => return Error::parser("ttl", x),
+        }
+    }
+
+    Ok(Box::new(BlockNode::Cache(Cache {
+        key: key,
+        ttl: ttl,
+        template: Template::new(try!(parse(&tokens, state))),
+    })))
+}
+
+#[cfg(test)]
+mod test {
+    use context::Context;
+    use Renderable;
+    use parse;
+    use value::Value;
+    use cache::MemoryCacheStore;
+    use LiquidOptions;
+
+    #[test]
+    fn renders_body_without_store() {
+        let text = "{% cache 'key' %}{{ x }}{% endcache %}";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("x", Value::Num(1f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("1".to_owned()));
+        context.set_val("x", Value::Num(2f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("2".to_owned()));
+    }
+
+    #[test]
+    fn uses_cached_output() {
+        let text = "{% cache name, ttl: 300 %}{{ x }}{% endcache %}";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let mut context = Context::new();
+        context.set_cache_store(Box::new(MemoryCacheStore::new()));
+        context.set_val("name", Value::str("a"));
+        context.set_val("x", Value::Num(1f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("1".to_owned()));
+
+        // the body is not re-rendered while the entry is cached...
+        context.set_val("x", Value::Num(2f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("1".to_owned()));
+
+        // ...but a different key gets its own entry
+        context.set_val("name", Value::str("b"));
+        assert_eq!(template.render(&mut context).unwrap(), Some("2".to_owned()));
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        assert!(parse("{% cache %}x{% endcache %}", LiquidOptions::default()).is_err());
+        assert!(parse("{% cache 'k' ttl: 3 %}x{% endcache %}", LiquidOptions::default()).is_err());
+        assert!(parse("{% cache 'k', ttl 3 %}x{% endcache %}", LiquidOptions::default()).is_err());
+        assert!(parse("{% cache 'k', expiry: 3 %}x{% endcache %}", LiquidOptions::default()).is_err());
+    }
+}
@@ -1,15 +1,19 @@
 use context::Context;
 use error::{Error, Result};
 use lexer::Element;
-use LiquidOptions;
+use ParseState;
 use Renderable;
 use template::Template;
 use token::Token::{self, Identifier};
 use value::Value;
+use symbol::Symbol;
 use parser::parse;
+use tags::BlockNode;
 
-struct Capture {
-    id: String,
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Capture {
+    id: Symbol,
     template: Template
 }
 
@@ -21,7 +25,8 @@ impl Renderable for Capture {
             Err(x) => return Err(x)
         };
 
-        context.set_val(&self.id, Value::Str(output));
+        // anything in the output that needed escaping has been already
+        context.assign_symbol(&self.id, Some(Value::Safe(output)));
         Ok(None)
     }
 }
@@ -29,11 +34,11 @@ impl Renderable for Capture {
 pub fn capture_block(_tag_name: &str,
                      arguments: &[Token],
                      tokens: Vec<Element>,
-                     options: &LiquidOptions)
+                     state: &ParseState)
                      -> Result<Box<Renderable>> {
     let mut args = arguments.iter();
     let id = match args.next() {
-        Some(&Identifier(ref x)) => x.clone(),
+        Some(&Identifier(ref x)) => state.symbols().intern(x),
         x @ Some(_) | x @ None => {
             return Error::parser("Identifier", x)
         }
@@ -44,12 +49,12 @@ pub fn capture_block(_tag_name: &str,
         return Error::parser("%}", t)
     };
 
-    let t = Template::new(try!(parse(&tokens, options)));
+    let t = Template::new(try!(parse(&tokens, state)));
 
-    Ok(Box::new(Capture {
+    Ok(Box::new(BlockNode::Capture(Capture {
         id: id,
         template: t
-    }))
+    })))
 }
 
 #[cfg(test)]
@@ -87,4 +92,16 @@ mod test {
             "{% endcapture %}" );
         assert!(parse(text, LiquidOptions::default()).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn captures_are_not_escaped_twice() {
+        let text = "{% capture x %}<{{ v }}>{% endcapture %}{{ x }}|{{ x | upcase }}";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let mut ctx = Context::new();
+        ctx.set_autoescape(true);
+        ctx.set_val("v", Value::str("a&b"));
+        assert_eq!(template.render(&mut ctx).unwrap(),
+                   Some("<a&amp;b>|&lt;A&amp;AMP;B&gt;".to_owned()));
+    }
+}
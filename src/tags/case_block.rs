@@ -0,0 +1,204 @@
+use Renderable;
+use context::Context;
+use ParseState;
+use template::Template;
+use token::Token::{self, Comma, Identifier};
+use parser::{parse, split_block, consume_value_token, BlockSplit};
+use lexer::Element;
+use error::{Error, Result};
+use value::Value;
+use tags::BlockNode;
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct CaseOption {
+    values: Vec<Token<'static>>,
+    template: Template,
+}
+
+impl CaseOption {
+    fn matches(&self, value: Option<&Value>, context: &Context) -> Result<bool> {
+        for v in &self.values {
+            if try!(context.evaluate_ref(v)).as_ref().map(|v| &**v) == value {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Case {
+    target: Token<'static>,
+    cases: Vec<CaseOption>,
+    else_block: Option<Template>,
+}
+
+impl Renderable for Case {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        let matched = {
+            let value = try!(context.evaluate_ref(&self.target));
+            let value = value.as_ref().map(|v| &**v);
+            let mut matched = None;
+            for case in &self.cases {
+                if try!(case.matches(value, context)) {
+                    matched = Some(case);
+                    break;
+                }
+            }
+            matched
+        };
+        if let Some(case) = matched {
+            return case.template.render(context);
+        }
+
+        match self.else_block {
+            Some(ref t) => t.render(context),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Parses the values of a `when` clause. Multiple values may be separated
+/// by either commas or `or`, and the clause matches if any of them do.
+fn parse_when_values(arguments: &[Token]) -> Result<Vec<Token<'static>>> {
+    let mut args = arguments.iter();
+    let mut values = vec![try!(consume_value_token(&mut args))];
+
+    loop {
+        match args.next() {
+            Some(&Comma) => {}
+            Some(&Identifier(ref x)) if x == "or" => {}
+            None => break,
+            x => return Error::parser("Comma | or", x),
+        }
+        values.push(try!(consume_value_token(&mut args)));
+    }
+
+    Ok(values)
+}
+
+pub fn case_block(_tag_name: &str,
+                  arguments: &[Token],
+                  tokens: Vec<Element>,
+                  state: &ParseState)
+                  -> Result<Box<Renderable>> {
+    let mut args = arguments.iter();
+    let target = try!(consume_value_token(&mut args));
+
+    if let t @ Some(_) = args.next() {
+        return Error::parser("%}", t);
+    };
+
+    let delimiters = ["when", "else"];
+    let mut cases = vec![];
+    let mut else_block = None;
+
+    // anything before the first `when` is ignored
+    let (_, mut split) = split_block(&tokens, &delimiters, state.options());
+
+    while let Some(BlockSplit { delimiter, args, trailing }) = split {
+        let (body, next) = split_block(&trailing[1..], &delimiters, state.options());
+        let template = Template::new(try!(parse(body, state)));
+
+        if else_block.is_some() {
+            return Err(Error::Parser(format!("Unexpected {} after else in case", delimiter)));
+        }
+
+        if delimiter == "when" {
+            cases.push(CaseOption {
+                values: try!(parse_when_values(&args[1..])),
+                template: template,
+            });
+        } else {
+            else_block = Some(template);
+        }
+
+        split = next;
+    }
+
+    Ok(Box::new(BlockNode::Case(Case {
+        target: target,
+        cases: cases,
+        else_block: else_block,
+    })))
+}
+
+#[cfg(test)]
+mod test {
+    use context::Context;
+    use Renderable;
+    use parse;
+    use value::Value;
+    use LiquidOptions;
+
+    #[test]
+    fn test_case() {
+        let text = concat!(
+            "{% case x %}",
+            "{% when 2 %}two",
+            "{% when 3 %}three",
+            "{% else %}other",
+            "{% endcase %}");
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("x", Value::Num(2f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("two".to_owned()));
+
+        context.set_val("x", Value::Num(3f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("three".to_owned()));
+
+        context.set_val("x", Value::Num(4f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("other".to_owned()));
+    }
+
+    #[test]
+    fn multiple_values_per_when() {
+        let text = concat!(
+            "{% case x %}",
+            "{% when 'a', 'b' %}first",
+            "{% when 'c' or 'd' or y %}second",
+            "{% endcase %}");
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("y", Value::str("e"));
+
+        for &(input, expected) in &[("a", "first"),
+                                    ("b", "first"),
+                                    ("c", "second"),
+                                    ("d", "second"),
+                                    ("e", "second"),
+                                    ("f", "")] {
+            context.set_val("x", Value::str(input));
+            assert_eq!(template.render(&mut context).unwrap(),
+                       Some(expected.to_owned()));
+        }
+    }
+
+    #[test]
+    fn nested_case_blocks() {
+        let text = concat!(
+            "{% case x %}",
+            "{% when 1 %}{% case y %}{% when 1 %}1-1{% else %}1-?{% endcase %}",
+            "{% else %}?",
+            "{% endcase %}");
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("x", Value::Num(1f32));
+        context.set_val("y", Value::Num(2f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("1-?".to_owned()));
+    }
+
+    #[test]
+    fn malformed_when_is_an_error() {
+        let text = "{% case x %}{% when 1 2 %}bad{% endcase %}";
+        assert!(parse(text, LiquidOptions::default()).is_err());
+
+        let text = "{% case x %}{% else %}a{% when 1 %}b{% endcase %}";
+        assert!(parse(text, LiquidOptions::default()).is_err());
+    }
+}
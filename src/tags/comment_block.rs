@@ -1,11 +1,14 @@
 use Renderable;
 use context::Context;
-use LiquidOptions;
+use ParseState;
 use token::Token;
-use lexer::Element;
+use lexer::Element::{self, Expression, Tag, Raw};
 use error::Result;
+use tags::{TagNode, BlockNode};
 
-struct Comment;
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Comment;
 
 impl Renderable for Comment {
     fn render(&self, _context: &mut Context) -> Result<Option<String>> {
@@ -16,26 +19,104 @@ impl Renderable for Comment {
 pub fn comment_block(_tag_name: &str,
                      _arguments: &[Token],
                      _tokens: Vec<Element>,
-                     _options: &LiquidOptions)
+                     _state: &ParseState)
                      -> Result<Box<Renderable>> {
-    Ok(Box::new(Comment))
+    Ok(Box::new(BlockNode::Comment(Comment)))
+}
+
+/// A comment whose contents are kept so that tools can extract them.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Doc {
+    text: String,
+}
+
+impl Renderable for Doc {
+    fn render(&self, _context: &mut Context) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn documentation(&self) -> Option<&str> {
+        Some(&self.text)
+    }
+}
+
+/// Parses a `{% doc %}` block. Like a comment, nothing inside it is
+/// rendered, but its raw text is retained.
+pub fn doc_block(_tag_name: &str,
+                 _arguments: &[Token],
+                 tokens: Vec<Element>,
+                 _state: &ParseState)
+                 -> Result<Box<Renderable>> {
+    let text = tokens.iter()
+                     .map(|t| {
+                         match *t {
+                             Expression(_, ref text, _) |
+                             Tag(_, ref text, _) |
+                             Raw(ref text) => &text[..],
+                         }
+                     })
+                     .collect::<Vec<&str>>()
+                     .concat();
+    Ok(Box::new(BlockNode::Doc(Doc { text: text.trim().to_owned() })))
+}
+
+/// Parses an inline comment, `{% # like this %}`.
+pub fn inline_comment_tag(_tag_name: &str,
+                          _arguments: &[Token],
+                          _state: &ParseState)
+                          -> Result<Box<Renderable>> {
+    Ok(Box::new(TagNode::Comment(Comment)))
 }
 
 #[cfg(test)]
 mod test {
     use LiquidOptions;
+    use ParseState;
     use super::comment_block;
     use std::default::Default;
     use lexer::Element::Expression;
+    use lexer::Span;
 
     #[test]
     fn test_comment() {
         let options: LiquidOptions = Default::default();
         let comment = comment_block("comment",
                                     &[],
-                                    vec![Expression(vec![], "This is a test".to_string())],
-                                    &options);
+                                    vec![Expression(vec![], "This is a test".into(), Span::default())],
+                                    &ParseState::new(&options));
         assert_eq!(comment.unwrap().render(&mut Default::default()).unwrap(),
                    None);
     }
+
+    #[test]
+    fn test_doc() {
+        use parse;
+        use Renderable;
+        use context::Context;
+
+        let text = concat!(
+            "{% doc %}\n",
+            "  Renders a {{ product }} card.\n",
+            "  @param product\n",
+            "{% enddoc %}",
+            "card");
+        let template = parse(text, LiquidOptions::default()).unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(),
+                   Some("card".to_owned()));
+        assert_eq!(template.docs(),
+                   vec!["Renders a {{ product }} card.\n  @param product"]);
+    }
+
+    #[test]
+    fn test_inline_comment() {
+        use parse;
+        use Renderable;
+        use context::Context;
+
+        let text = "a{% # this isn't rendered %}b{%#neither is this%}c";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(),
+                   Some("abc".to_owned()));
+    }
 }
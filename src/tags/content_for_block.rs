@@ -0,0 +1,135 @@
+use Renderable;
+use context::Context;
+use ParseState;
+use template::Template;
+use token::Token::{self, Identifier, StringLiteral};
+use parser::parse;
+use lexer::Element;
+use error::{Error, Result};
+use tags::{TagNode, BlockNode};
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ContentFor {
+    name: String,
+    body: Template,
+}
+
+impl Renderable for ContentFor {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        let content = try!(self.body.render(context)).unwrap_or_default();
+        context.register_or_insert_with("content_for", &self.name, String::new)
+               .push_str(&content);
+        Ok(None)
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Yield {
+    name: Option<String>,
+}
+
+impl Renderable for Yield {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        match self.name {
+            Some(ref name) => Ok(Some(context.content_for(name).unwrap_or("").to_owned())),
+            None => {
+                let body = context.get_val("content_for_layout").map(|v| v.to_string());
+                Ok(Some(body.unwrap_or_default()))
+            }
+        }
+    }
+}
+
+/// Parses the name of a section, which may be quoted or not.
+fn section_name(arguments: &[Token]) -> Result<Option<String>> {
+    let mut args = arguments.iter();
+    let name = match args.next() {
+        Some(&StringLiteral(ref name)) |
+        Some(&Identifier(ref name)) => Some(name.to_string()),
+        None => None,
+        x => return Error::parser("String Literal | Identifier", x),
+    };
+
+    if let t @ Some(_) = args.next() {
+        return Error::parser("%}", t);
+    };
+    Ok(name)
+}
+
+/// Parses a `{% content_for 'name' %}` block, which renders nothing where
+/// it is but adds what its body renders to the named section, for a
+/// layout to place with `{% yield 'name' %}`. Using the same name again
+/// adds to the end of the section.
+pub fn content_for_block(_tag_name: &str,
+                         arguments: &[Token],
+                         tokens: Vec<Element>,
+                         state: &ParseState)
+                         -> Result<Box<Renderable>> {
+    let name = match try!(section_name(arguments)) {
+        Some(name) => name,
+        None => return Error::parser("String Literal | Identifier", None),
+    };
+
+    Ok(Box::new(BlockNode::ContentFor(ContentFor {
+        name: name,
+        body: Template::new(try!(parse(&tokens, state))),
+    })))
+}
+
+/// Parses a `{% yield 'name' %}` tag, which renders the named section
+/// filled in by `content_for` blocks, or nothing if there is no such
+/// section. A bare `{% yield %}` renders the page body inside a layout,
+/// like `{{ content_for_layout }}`.
+pub fn yield_tag(_tag_name: &str,
+                 arguments: &[Token],
+                 _state: &ParseState)
+                 -> Result<Box<Renderable>> {
+    Ok(Box::new(TagNode::Yield(Yield { name: try!(section_name(arguments)) })))
+}
+
+#[cfg(test)]
+mod test {
+    use context::Context;
+    use Renderable;
+    use parse;
+    use LiquidOptions;
+
+    fn options() -> LiquidOptions {
+        let mut options = LiquidOptions::default();
+        options.templates.insert("base".to_owned(),
+                                 "<head>{% yield 'head' %}</head><body>{% yield %}</body>"
+                                     .to_owned());
+        options
+    }
+
+    #[test]
+    fn layouts_yield_sections() {
+        let text = concat!("{% layout 'base' %}",
+                           "{% content_for 'head' %}<title>{{ title }}</title>{% endcontent_for %}",
+                           "Hello",
+                           "{% content_for head %}<meta>{% endcontent_for %}");
+        let template = parse(text, options()).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("title", "Home");
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("<head><title>Home</title><meta></head><body>Hello</body>".to_owned()));
+        assert_eq!(context.content_for("head"), Some("<title>Home</title><meta>"));
+    }
+
+    #[test]
+    fn missing_sections_are_empty() {
+        let template = parse("{% layout 'base' %}Hello", options()).unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(),
+                   Some("<head></head><body>Hello</body>".to_owned()));
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert!(parse("{% content_for %}x{% endcontent_for %}", options()).is_err());
+        assert!(parse("{% content_for 'a' 'b' %}x{% endcontent_for %}", options()).is_err());
+        assert!(parse("{% yield 'a' 'b' %}", options()).is_err());
+    }
+}
@@ -1,13 +1,16 @@
 use Renderable;
 use context::Context;
-use LiquidOptions;
+use ParseState;
 use token::Token::{self, Comma, Colon};
 use error::{Error, Result};
 use parser::{consume_value_token, value_token};
+use tags::TagNode;
 
-struct Cycle {
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Cycle {
     name: String,
-    values: Vec<Token>
+    values: Vec<Token<'static>>
 }
 
 impl Renderable for Cycle {
@@ -19,7 +22,7 @@ impl Renderable for Cycle {
 
 /// Internal implementation of cycle, to allow easier testing.
 fn parse_cycle(arguments: &[Token],
-               _options: &LiquidOptions) -> Result<Cycle> {
+               _state: &ParseState) -> Result<Cycle> {
     let mut args = arguments.iter();
     let mut values = Vec::new();
     let mut name = String::new();
@@ -69,9 +72,9 @@ fn parse_cycle(arguments: &[Token],
 
 pub fn cycle_tag(_tag_name: &str,
                  arguments: &[Token],
-                 options: &LiquidOptions) -> Result<Box<Renderable>> {
-    parse_cycle(arguments, options)
-        .map(|opt| Box::new(opt) as Box<Renderable>)
+                 state: &ParseState) -> Result<Box<Renderable>> {
+    parse_cycle(arguments, state)
+        .map(|opt| Box::new(TagNode::Cycle(opt)) as Box<Renderable>)
 }
 
 #[cfg(test)]
@@ -82,13 +85,14 @@ mod test {
         use super::parse_cycle;
         use token::Token::{StringLiteral, Identifier, Comma};
         use LiquidOptions;
-
-        let tokens = vec![Identifier("this".to_owned()), Comma,
-                          StringLiteral("cycle".to_owned()), Comma,
-                          Identifier("has".to_owned()), Comma,
-                          Identifier("no".to_owned()), Comma,
-                          Identifier("name".to_owned())];
-        let cycle = parse_cycle(&tokens[..], &LiquidOptions::default()).unwrap();
+    use ParseState;
+
+        let tokens = vec![Identifier("this".into()), Comma,
+                          StringLiteral("cycle".into()), Comma,
+                          Identifier("has".into()), Comma,
+                          Identifier("no".into()), Comma,
+                          Identifier("name".into())];
+        let cycle = parse_cycle(&tokens[..], &ParseState::new(&LiquidOptions::default())).unwrap();
         assert_eq!("thiscyclehasnoname", cycle.name);
     }
 
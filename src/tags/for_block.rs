@@ -1,23 +1,33 @@
 use Renderable;
-use context::{Context, Interrupt};
-use LiquidOptions;
+use context::{Context, Interrupt, VariablePath};
+use ParseState;
 use lexer::Element;
-use token::Token::{self, Identifier, OpenRound, CloseRound, NumberLiteral, DotDot, Colon};
-use parser::{parse, expect, split_block};
+use token::Token::{self, Identifier, OpenRound, NumberLiteral, Colon};
+use parser::{parse, expect, split_block, consume_range};
 use template::Template;
-use value::Value;
+use value::{Value, Object};
 use error::{Error, Result};
+use symbol::Symbol;
+use tags::BlockNode;
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp;
+use std::fmt;
 use std::slice::Iter;
 
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 enum Range {
-    Array (String),
-    Counted (Token, Token)
+    Array (VariablePath),
+    Counted (Token<'static>, Token<'static>)
 }
 
-struct For {
-    var_name: String,
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct For {
+    var_name: Symbol,
+    /// The name of the `for_loop` variable, interned along with the others.
+    loop_name: Symbol,
     range: Range,
     item_template: Template,
     else_template: Option<Template>,
@@ -26,13 +36,6 @@ struct For {
     reversed: bool
 }
 
-fn get_array(context: &Context, array_id: &str) -> Result<Vec<Value>> {
-    match context.get_val(array_id) {
-        Some(&Value::Array(ref x)) => Ok(x.clone()),
-        x => Err(Error::Render(format!("Tried to iterate over {:?}, which is not supported.", x))),
-    }
-}
-
 fn token_as_int(token: &Token, context: &Context) -> Result<isize> {
     let value = match try!(context.evaluate(token)) {
         Some(Value::Num(ref n)) => *n,
@@ -43,46 +46,69 @@ fn token_as_int(token: &Token, context: &Context) -> Result<isize> {
     Ok(value as isize)
 }
 
-impl Renderable for For {
-    fn render(&self, context: &mut Context) -> Result<Option<String>> {
-        let mut range = match self.range {
-            Range::Array(ref array_id) => {
-                try!(get_array(context, array_id))
-            },
-
-            Range::Counted(ref start_token, ref stop_token) => {
-                let start = try!(token_as_int(start_token, context));
-                let stop = try!(token_as_int(stop_token, context));
-                (start..stop).map(|x| Value::Num(x as f32)).collect()
-            }
-        };
-
+impl For {
+    /// Where the items the loop visits start and end, out of `len`.
+    fn window(&self, len: usize) -> (usize, usize) {
+        let start = cmp::min(self.offset, len);
         let end = match self.limit {
-            Some(n) => self.offset + n,
-            None => range.len()
+            Some(n) => cmp::min(start.saturating_add(n), len),
+            None => len
         };
+        (start, end)
+    }
 
-        let slice = &mut range[self.offset .. end];
-        if self.reversed {
-            slice.reverse();
-        };
+    /// Gets the items of the array the loop goes over that it visits. An
+    /// array in `globals` is borrowed from there, as the loop changes the
+    /// context while it runs; only the visited items of any other array
+    /// are copied.
+    fn get_array<'a>(&self,
+                     context: &Context,
+                     globals: &'a Object,
+                     path: &VariablePath)
+                     -> Result<Cow<'a, [Value]>> {
+        if let Some(value) = context.get_global_path(globals, path) {
+            return match *value {
+                Value::Array(ref x) => {
+                    let (start, end) = self.window(x.len());
+                    Ok(Cow::Borrowed(&x[start..end]))
+                }
+                _ => Err(not_iterable(Some(value))),
+            };
+        }
+        match try!(context.lookup_path(path)) {
+            Some(Cow::Borrowed(&Value::Array(ref x))) => {
+                let (start, end) = self.window(x.len());
+                Ok(Cow::Owned(x[start..end].to_vec()))
+            }
+            Some(Cow::Owned(Value::Array(mut x))) => {
+                let (start, end) = self.window(x.len());
+                x.truncate(end);
+                x.drain(..start);
+                Ok(Cow::Owned(x))
+            }
+            x => Err(not_iterable(x.as_ref().map(|x| &**x))),
+        }
+    }
 
-        match slice.len() {
+    fn render_items<I>(&self, items: I, out: &mut fmt::Write, context: &mut Context) -> Result<()>
+        where I: ExactSizeIterator<Item = Value>
+    {
+        match items.len() {
             0 => {
                 if let Some(ref t) = self.else_template {
-                    t.render(context)
+                    t.render_fmt(out, context)
                 } else {
-                    Ok(None)
+                    Ok(())
                 }
             },
 
             range_len => {
-                let mut ret = String::default();
-                context.run_in_scope(|mut scope| {
-                    let mut helper_vars : HashMap<String, Value> = HashMap::new();
+                context.run_in_scope(|scope| scope.run_in_loop(|mut scope| {
+                    let mut helper_vars = Object::default();
                     helper_vars.insert("length".to_owned(), Value::Num(range_len as f32));
 
-                    for (i, v) in slice.iter().enumerate() {
+                    for (i, v) in items.enumerate() {
+                        try!(scope.check_deadline());
                         helper_vars.insert("index0".to_owned(), Value::Num(i as f32));
                         helper_vars.insert("index".to_owned(), Value::Num((i + 1) as f32));
                         helper_vars.insert("rindex0".to_owned(), Value::Num((range_len - i - 1) as f32));
@@ -90,10 +116,9 @@ impl Renderable for For {
                         helper_vars.insert("first".to_owned(), Value::Bool(i == 0));
                         helper_vars.insert("last".to_owned(), Value::Bool(i == (range_len-1)));
 
-                        scope.set_local_val("for_loop", Value::Object(helper_vars.clone()));
-                        scope.set_local_val(&self.var_name, v.clone());
-                        let inner = try!(self.item_template.render(&mut scope)).unwrap_or("".to_owned());
-                        ret = ret + &inner;
+                        scope.set_local_symbol(&self.loop_name, Value::Object(helper_vars.clone()));
+                        scope.set_local_symbol(&self.var_name, v);
+                        try!(self.item_template.render_fmt(out, &mut scope));
 
                         // given that we're at the end of the loop body
                         // already, dealing with a `continue` signal is just
@@ -104,8 +129,48 @@ impl Renderable for For {
                         }
                     }
 
-                    Ok(Some(ret))
-                })
+                    Ok(())
+                }))
+            }
+        }
+    }
+}
+
+fn not_iterable(value: Option<&Value>) -> Error {
+    Error::Render(format!("Tried to iterate over {:?}, which is not supported.", value))
+}
+
+impl Renderable for For {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        let mut ret = String::default();
+        try!(self.render_fmt(&mut ret, context));
+        Ok(Some(ret))
+    }
+
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        match self.range {
+            Range::Array(ref path) => {
+                let globals = context.shared_globals();
+                let items = try!(self.get_array(context, &globals, path));
+                if self.reversed {
+                    self.render_items(items.iter().rev().cloned(), out, context)
+                } else {
+                    self.render_items(items.iter().cloned(), out, context)
+                }
+            },
+
+            // numbers are only made as the loop gets to them, so a huge
+            // range still stops at the deadline
+            Range::Counted(ref start_token, ref stop_token) => {
+                let start = try!(token_as_int(start_token, context));
+                let stop = try!(token_as_int(stop_token, context));
+                let (first, end) = self.window(cmp::max(stop - start, 0) as usize);
+                let numbers = start + first as isize..start + end as isize;
+                if self.reversed {
+                    self.render_items(numbers.rev().map(|x| Value::Num(x as f32)), out, context)
+                } else {
+                    self.render_items(numbers.map(|x| Value::Num(x as f32)), out, context)
+                }
             }
         }
     }
@@ -121,40 +186,26 @@ fn int_attr(args: &mut Iter<Token>) -> Result<Option<usize>> {
     }
 }
 
-fn range_end_point(args: &mut Iter<Token>) -> Result<Token> {
-    let t = match args.next() {
-        Some(id @ &NumberLiteral(_)) |
-        Some(id @ &Identifier(_)) => id.clone(),
-        x => return Error::parser("number | Identifier", x)
-    };
-    Ok(t)
-}
-
 pub fn for_block(_tag_name: &str,
                  arguments: &[Token],
                  tokens: Vec<Element>,
-                 options: &LiquidOptions)
+                 state: &ParseState)
                  -> Result<Box<Renderable>> {
     let mut args = arguments.iter();
     let var_name = match args.next() {
-        Some(&Identifier(ref x)) => x.clone(),
+        Some(&Identifier(ref x)) => state.symbols().intern(x),
         x => return Error::parser("Identifier", x)
     };
 
-    try!(expect(&mut args, Identifier("in".to_owned())));
+    try!(expect(&mut args, Identifier("in".into())));
 
     let range = match args.next() {
-        Some(&Identifier(ref x)) => Range::Array(x.clone()),
+        Some(&Identifier(ref x)) => {
+            Range::Array(VariablePath::from_symbol(state.symbols().intern(x)))
+        }
         Some(&OpenRound) => {
             // this might be a range, let's try and see
-            let start = try!(range_end_point(&mut args));
-
-            try!(expect(&mut args, DotDot));
-
-            let stop = try!(range_end_point(&mut args));
-
-            try!(expect(&mut args, CloseRound));
-
+            let (start, stop) = try!(consume_range(&mut args));
             Range::Counted (start, stop)
         },
         x => return Error::parser("Identifier or (", x),
@@ -181,26 +232,27 @@ pub fn for_block(_tag_name: &str,
         }
     }
 
-    let (leading, trailing) = split_block(&tokens, &["else"], options);
-    let item_template = Template::new(try!(parse(leading, options)));
+    let (leading, trailing) = split_block(&tokens, &["else"], state.options());
+    let item_template = Template::new(try!(parse(leading, state)));
 
     let else_template = match trailing {
         Some(split) => {
-            let parsed = try!(parse(&split.trailing[1..], options));
+            let parsed = try!(parse(&split.trailing[1..], state));
             Some(Template::new(parsed))
         },
         None => None
     };
 
-    Ok(Box::new(For {
+    Ok(Box::new(BlockNode::For(For {
         var_name: var_name,
+        loop_name: state.symbols().intern("for_loop"),
         range: range,
         item_template: item_template,
         else_template: else_template,
         limit: limit,
         offset: offset,
         reversed: reversed
-    }))
+    })))
 }
 
 #[cfg(test)]
@@ -208,6 +260,7 @@ mod test{
     use super::for_block;
     use parse;
     use LiquidOptions;
+    use ParseState;
     use Renderable;
     use token::Token::{Identifier, OpenRound, CloseRound, NumberLiteral, DotDot};
     use lexer::tokenize;
@@ -219,11 +272,11 @@ mod test{
     fn loop_over_array() {
         let options: LiquidOptions = Default::default();
         let for_tag = for_block("for",
-                                &[Identifier("name".to_owned()),
-                                  Identifier("in".to_owned()),
-                                  Identifier("array".to_owned())],
+                                &[Identifier("name".into()),
+                                  Identifier("in".into()),
+                                  Identifier("array".into())],
                                 tokenize("test {{name}} ").unwrap(),
-                                &options);
+                                &ParseState::new(&options));
 
         let mut data: Context = Default::default();
         data.set_val("array",
@@ -240,15 +293,15 @@ mod test{
     fn loop_over_range_literals() {
         let options: LiquidOptions = Default::default();
         let for_tag = for_block("for",
-                                &[Identifier("name".to_owned()),
-                                  Identifier("in".to_owned()),
+                                &[Identifier("name".into()),
+                                  Identifier("in".into()),
                                   OpenRound,
                                   NumberLiteral(42f32),
                                   DotDot,
                                   NumberLiteral(46f32),
                                   CloseRound],
                                 tokenize("#{{for_loop.index}} test {{name}} | ").unwrap(),
-                                &options);
+                                &ParseState::new(&options));
 
         let mut data: Context = Default::default();
         assert_eq!(for_tag.unwrap().render(&mut data).unwrap(),
@@ -336,6 +389,37 @@ mod test{
         assert_eq!(output.unwrap(), Some("".to_string()));
     }
 
+    #[test]
+    fn whitespace_control() {
+        let text = concat!(
+            "<ul>\n",
+            "{%- for i in (1..4) %}\n",
+            "  <li>{{ i }}</li>\n",
+            "{%- endfor %}\n",
+            "</ul>");
+        let template = parse(text, Default::default()).unwrap();
+        let mut context = Context::new();
+        let output = template.render(&mut context);
+        assert_eq!(output.unwrap(),
+                   Some("<ul>\n  <li>1</li>\n  <li>2</li>\n  <li>3</li>\n</ul>".to_string()));
+    }
+
+    #[test]
+    fn trim_blocks() {
+        let text = concat!(
+            "<ul>\n",
+            "{% for i in (1..4) %}\n",
+            "  <li>{{ i }}</li>\n",
+            "{% endfor %}\n",
+            "</ul>");
+        let options = LiquidOptions { trim_blocks: true, ..Default::default() };
+        let template = parse(text, options).unwrap();
+        let mut context = Context::new();
+        let output = template.render(&mut context);
+        assert_eq!(output.unwrap(),
+                   Some("<ul>\n  <li>1</li>\n  <li>2</li>\n  <li>3</li>\n</ul>".to_string()));
+    }
+
     #[test]
     fn limited_loop() {
         let text = concat!(
@@ -396,6 +480,33 @@ mod test{
         assert_eq!(output.unwrap(), Some("6 5 4 3 2 ".to_string()));
     }
 
+    #[test]
+    fn slices_past_the_end_are_empty() {
+        let text = concat!(
+            "{% for i in list offset:5 %}{{ i }}{% else %}none{% endfor %} ",
+            "{% for i in list offset:2 limit:5 %}{{ i }}{% endfor %} ",
+            "{% for i in (1..4) offset:9 %}{{ i }}{% else %}none{% endfor %}");
+        let template = parse(text, Default::default()).unwrap();
+        let mut context = Context::new();
+        context.set_val("list", vec![1, 2, 3]);
+        let output = template.render(&mut context);
+        assert_eq!(output.unwrap(), Some("none 3 none".to_string()));
+    }
+
+    #[test]
+    fn loops_over_globals_and_the_variables_hiding_them() {
+        let text = concat!(
+            "{% for i in list %}{% assign last = i %}{{ i }}{% endfor %} {{ last }} ",
+            "{% assign list = letters %}",
+            "{% for i in list reversed %}{{ i }}{% endfor %}");
+        let template = parse(text, Default::default()).unwrap();
+        let mut context = Context::new();
+        context.set_val("list", vec![1, 2, 3]);
+        context.set_val("letters", vec!["a", "b"]);
+        let output = template.render(&mut context);
+        assert_eq!(output.unwrap(), Some("123 3 ba".to_string()));
+    }
+
     #[test]
     fn empty_loop_invokes_else_template() {
         let text = concat!(
@@ -415,8 +526,8 @@ mod test{
     fn loop_variables() {
         let options: LiquidOptions = Default::default();
         let for_tag = for_block("for",
-                                &[Identifier("v".to_owned()),
-                                  Identifier("in".to_owned()),
+                                &[Identifier("v".into()),
+                                  Identifier("in".into()),
                                   OpenRound,
                                   NumberLiteral(100f32),
                                   DotDot,
@@ -431,7 +542,7 @@ mod test{
                                          "value: {{v}}, ",
                                          "first: {{for_loop.first}}, ",
                                          "last: {{for_loop.last}}\n")).unwrap(),
-                                &options);
+                                &ParseState::new(&options));
 
         let mut data: Context = Default::default();
         assert_eq!(for_tag.unwrap().render(&mut data).unwrap(),
@@ -449,11 +560,11 @@ mod test{
 
         let options: LiquidOptions = Default::default();
         let for_tag = for_block("for",
-                                &[Identifier("name".to_owned()),
-                                  Identifier("in".to_owned()),
-                                  Identifier("array".to_owned())],
+                                &[Identifier("name".into()),
+                                  Identifier("in".into()),
+                                  Identifier("array".into())],
                                 tokenize("test {{name | shout}} ").unwrap(),
-                                &options);
+                                &ParseState::new(&options));
 
         let mut data: Context = Default::default();
         data.add_filter("shout", Box::new(|input, _args| {
@@ -472,4 +583,33 @@ mod test{
         assert_eq!(for_tag.unwrap().render(&mut data).unwrap(),
                    Some("test ALPHA test BETA test GAMMA ".to_owned()));
     }
+
+    #[test]
+    fn loops_stop_at_the_deadline() {
+        use std::time::{Duration, Instant};
+
+        let text = concat!(
+            "{% for i in (1..1000) %}",
+            "{% for j in (1..1000) %}.{% endfor %}",
+            "{% endfor %}");
+        let template = parse(text, Default::default()).unwrap();
+
+        let mut context = Context::new();
+        context.set_timeout(Duration::from_millis(10));
+        let err = template.render(&mut context).err().unwrap();
+        assert!(err.is_timeout(), "{}", err);
+
+        let template = parse("{% for i in (1..2000000000) %}{% endfor %}",
+                             Default::default()).unwrap();
+        let mut context = Context::new();
+        context.set_timeout(Duration::from_millis(10));
+        let err = template.render(&mut context).err().unwrap();
+        assert!(err.is_timeout(), "{}", err);
+
+        let mut context = Context::new();
+        context.set_deadline(Some(Instant::now() + Duration::from_secs(3600)));
+        let template = parse("{% for i in (1..4) %}{{ i }}{% endfor %}",
+                             Default::default()).unwrap();
+        assert_eq!(template.render(&mut context).unwrap(), Some("123".to_owned()));
+    }
 }
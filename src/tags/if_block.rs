@@ -1,46 +1,163 @@
 use Renderable;
 use context::Context;
+use output::Output;
 use template::Template;
-use LiquidOptions;
-use token::Token::{self, Comparison};
+use ParseState;
+use token::Token::{self, Comparison, Identifier, OpenRound, CloseRound, DotDot, NilLiteral,
+                   EmptyLiteral, BlankLiteral};
 use token::ComparisonOperator::{self, Equals, NotEquals, LessThan, GreaterThan, LessThanEquals,
                                 GreaterThanEquals, Contains};
-use parser::{parse, split_block, consume_value_token};
+use parser::{parse, output, split_block, consume_value_token, expect};
 use lexer::Element;
 use error::{Error, Result};
+use value::Value;
+use tags::{TagNode, BlockNode};
 
-struct Condition {
-    lh: Token,
+use std::fmt;
+use std::slice::Iter;
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct BinaryCondition {
+    lh: Token<'static>,
     comparison: ComparisonOperator,
-    rh: Token
+    rh: Token<'static>
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum Condition {
+    Comparison(BinaryCondition),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
 }
 
-struct Conditional {
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Conditional {
     condition: Condition,
     mode: bool,
     if_true: Template,
-    if_false: Option<Template>,
+    if_false: Option<Otherwise>,
 }
 
-impl Conditional {
-    fn compare(&self, context: &Context) -> Result<bool> {
-        let a = try!(context.evaluate(&self.condition.lh));
-        let b = try!(context.evaluate(&self.condition.rh));
+/// What a conditional renders when its condition doesn't hold: either an
+/// `else` branch, or the conditional of an `elsif`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+enum Otherwise {
+    Else(Template),
+    Elsif(Box<Conditional>),
+}
+
+/// An output of the form `{{ a if condition else b }}`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InlineConditional {
+    condition: Condition,
+    if_true: Output,
+    if_false: Option<Output>,
+}
+
+/// Implements the `contains` operator: strings contain substrings, arrays
+/// contain elements and objects contain keys.
+fn contains_check(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (&Value::Array(ref x), y) => x.contains(y),
+        (&Value::Object(ref x), y) => y.as_str().map_or(false, |y| x.contains_key(y)),
+        (x, y) => {
+            match (x.as_str(), y.as_str()) {
+                (Some(x), Some(y)) => x.contains(y),
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Checks a value against one of the `nil`, `empty` and `blank` keywords.
+/// `empty` matches empty strings, arrays and objects, while `blank` also
+/// matches missing values, `false` and strings of only whitespace.
+fn keyword_check(keyword: &Token, value: Option<&Value>) -> bool {
+    match (keyword, value) {
+        (&NilLiteral, None) => true,
+        (&EmptyLiteral, Some(&Value::Str(ref x))) |
+        (&EmptyLiteral, Some(&Value::Safe(ref x))) => x.is_empty(),
+        (&BlankLiteral, Some(&Value::Str(ref x))) |
+        (&BlankLiteral, Some(&Value::Safe(ref x))) => x.trim().is_empty(),
+        (&EmptyLiteral, Some(&Value::Array(ref x))) |
+        (&BlankLiteral, Some(&Value::Array(ref x))) => x.is_empty(),
+        (&EmptyLiteral, Some(&Value::Object(ref x))) |
+        (&BlankLiteral, Some(&Value::Object(ref x))) => x.is_empty(),
+        (&BlankLiteral, None) |
+        (&BlankLiteral, Some(&Value::Bool(false))) => true,
+        _ => false,
+    }
+}
+
+fn is_keyword(t: &Token) -> bool {
+    match *t {
+        NilLiteral | EmptyLiteral | BlankLiteral => true,
+        _ => false,
+    }
+}
+
+impl BinaryCondition {
+    fn evaluate(&self, context: &Context) -> Result<bool> {
+        // comparisons against keywords test a property of the other side,
+        // rather than comparing two values
+        let keyword = match (&self.lh, &self.rh) {
+            (k, other) if is_keyword(k) => Some((k, other)),
+            (other, k) if is_keyword(k) => Some((k, other)),
+            _ => None,
+        };
+        if let Some((k, other)) = keyword {
+            let value = try!(context.evaluate_ref(other));
+            let matched = keyword_check(k, value.as_ref().map(|v| &**v));
+            return Ok(match self.comparison {
+                Equals => matched,
+                NotEquals => !matched,
+                _ => false,
+            });
+        }
+
+        let a = try!(context.evaluate_ref(&self.lh));
+        let b = try!(context.evaluate_ref(&self.rh));
 
         if a == None || b == None {
             return Ok(false);
         }
 
-        let result = match self.condition.comparison {
+        let result = match self.comparison {
             Equals => a == b,
             NotEquals => a != b,
             LessThan => a < b,
             GreaterThan => a > b,
             LessThanEquals => a <= b,
             GreaterThanEquals => a >= b,
-            Contains => false, // TODO!!!
+            Contains => contains_check(a.as_ref().unwrap(), b.as_ref().unwrap()),
         };
 
+        Ok(result)
+    }
+}
+
+impl Condition {
+    fn evaluate(&self, context: &Context) -> Result<bool> {
+        match *self {
+            Condition::Comparison(ref c) => c.evaluate(context),
+            Condition::And(ref lh, ref rh) => {
+                Ok(try!(lh.evaluate(context)) && try!(rh.evaluate(context)))
+            }
+            Condition::Or(ref lh, ref rh) => {
+                Ok(try!(lh.evaluate(context)) || try!(rh.evaluate(context)))
+            }
+        }
+    }
+}
+
+impl Conditional {
+    fn compare(&self, context: &Context) -> Result<bool> {
+        let result = try!(self.condition.evaluate(context));
         Ok(result == self.mode)
     }
 }
@@ -51,86 +168,183 @@ impl Renderable for Conditional {
             self.if_true.render(context)
         } else {
             match self.if_false {
-                Some(ref template) => template.render(context),
-                _ => Ok(None),
+                Some(Otherwise::Else(ref template)) => template.render(context),
+                Some(Otherwise::Elsif(ref conditional)) => conditional.render(context),
+                None => Ok(None),
+            }
+        }
+    }
+
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        if try!(self.compare(context)) {
+            self.if_true.render_fmt(out, context)
+        } else {
+            match self.if_false {
+                Some(Otherwise::Else(ref template)) => template.render_fmt(out, context),
+                Some(Otherwise::Elsif(ref conditional)) => conditional.render_fmt(out, context),
+                None => Ok(()),
             }
         }
     }
 }
 
-/// Common parsing for "if" and "unless" condition
-fn condition(arguments: &[Token]) -> Result<Condition> {
-    let mut args = arguments.iter();
+impl Renderable for InlineConditional {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        if try!(self.condition.evaluate(context)) {
+            self.if_true.render(context)
+        } else {
+            match self.if_false {
+                Some(ref output) => output.render(context),
+                None => Ok(None),
+            }
+        }
+    }
+}
 
-    let lh = try!(consume_value_token(&mut args));
+/// Parses a single comparison, or a parenthesised group of conditions if
+/// grouping has been enabled.
+fn primary_condition(args: &mut Iter<Token>, state: &ParseState) -> Result<Condition> {
+    // `(a..b)` is a range literal rather than a group
+    let ahead = args.as_slice();
+    let is_group = ahead.first() == Some(&OpenRound) && ahead.get(2) != Some(&DotDot);
+    if state.options().grouped_conditions && is_group {
+        args.next();
+        let grouped = try!(chained_condition(args, state));
+        try!(expect(args, CloseRound));
+        return Ok(grouped);
+    }
 
-    let (comp, rh) = match args.next() {
+    let lh = try!(consume_value_token(args));
+
+    let (comp, rh) = match args.as_slice().first() {
         Some(&Comparison(ref x)) => {
-            let rhs = try!(consume_value_token(&mut args));
+            args.next();
+            let rhs = try!(consume_value_token(args));
             (x.clone(), rhs)
         },
-        None => {
+        _ => {
             // no trailing operator or RHS value implies "== true"
             (ComparisonOperator::Equals, Token::BooleanLiteral(true))
         },
-        x @ Some(_) => return Error::parser("comparison operator", x)
     };
 
-    Ok(Condition {lh: lh, comparison: comp, rh: rh})
+    Ok(Condition::Comparison(BinaryCondition {lh: lh, comparison: comp, rh: rh}))
+}
+
+/// Parses a chain of conditions joined by `and` and `or`. Like Ruby Liquid,
+/// there is no operator precedence; the chain is evaluated from right to
+/// left, so `a or b and c` means `a or (b and c)`.
+fn chained_condition(args: &mut Iter<Token>, state: &ParseState) -> Result<Condition> {
+    let lh = try!(primary_condition(args, state));
+
+    match args.as_slice().first() {
+        Some(&Identifier(ref op)) if op == "and" => {
+            args.next();
+            let rh = try!(chained_condition(args, state));
+            Ok(Condition::And(Box::new(lh), Box::new(rh)))
+        },
+        Some(&Identifier(ref op)) if op == "or" => {
+            args.next();
+            let rh = try!(chained_condition(args, state));
+            Ok(Condition::Or(Box::new(lh), Box::new(rh)))
+        },
+        _ => Ok(lh),
+    }
+}
+
+/// Common parsing for "if" and "unless" condition
+fn condition(arguments: &[Token], state: &ParseState) -> Result<Condition> {
+    let mut args = arguments.iter();
+    let cond = try!(chained_condition(&mut args, state));
+
+    match args.next() {
+        None => Ok(cond),
+        x => Error::parser("comparison operator | and | or", x),
+    }
+}
+
+/// Parses an output of the form `{{ a if condition else b }}`, where the
+/// `else` part is optional. Each branch is an ordinary output, and may
+/// include filters.
+pub fn inline_conditional(tokens: &[Token], state: &ParseState) -> Result<Box<Renderable>> {
+    let if_pos = tokens.iter().position(|t| *t == Identifier("if".into())).unwrap_or(0);
+    let else_pos = tokens.iter()
+                         .position(|t| *t == Identifier("else".into()))
+                         .unwrap_or(tokens.len());
+
+    if if_pos == 0 {
+        return Error::parser("output", tokens.first());
+    }
+    if else_pos < if_pos || else_pos == tokens.len() - 1 {
+        return Error::parser("output", tokens.get(else_pos + 1));
+    }
+
+    let cond = try!(condition(&tokens[if_pos + 1..else_pos], state));
+    let if_true = try!(output(&tokens[..if_pos], state.symbols()));
+    let if_false = match else_pos {
+        n if n == tokens.len() => None,
+        n => Some(try!(output(&tokens[n + 1..], state.symbols()))),
+    };
+
+    Ok(Box::new(TagNode::InlineConditional(InlineConditional {
+        condition: cond,
+        if_true: if_true,
+        if_false: if_false,
+    })))
 }
 
 pub fn unless_block(_tag_name: &str,
                     arguments: &[Token],
                     tokens: Vec<Element>,
-                    options: &LiquidOptions)
+                    state: &ParseState)
                             -> Result<Box<Renderable>> {
-    let cond = try!(condition(arguments));
-    Ok(Box::new(Conditional {
+    let cond = try!(condition(arguments, state));
+    Ok(Box::new(BlockNode::If(Conditional {
         condition: cond,
         mode: false,
-        if_true: Template::new(try!(parse(&tokens[..], options))),
+        if_true: Template::new(try!(parse(&tokens[..], state))),
         if_false: None,
-    }))
+    })))
 }
 
 pub fn if_block(_tag_name: &str,
                 arguments: &[Token],
                 tokens: Vec<Element>,
-                options: &LiquidOptions)
+                state: &ParseState)
                             -> Result<Box<Renderable>> {
-    let cond = try!(condition(arguments));
+    Ok(Box::new(BlockNode::If(try!(conditional(arguments, &tokens, state)))))
+}
 
-    let (leading_tokens, trailing_tokens) = split_block(&tokens[..],
-                                                        &["else", "elsif"],
-                                                        options);
+/// Parses an `if` block, or the `elsif` part of one along with whatever
+/// follows it.
+fn conditional(arguments: &[Token], tokens: &[Element], state: &ParseState) -> Result<Conditional> {
+    let cond = try!(condition(arguments, state));
+
+    let (leading_tokens, trailing_tokens) = split_block(tokens, &["else", "elsif"], state.options());
     let if_false = match trailing_tokens {
         None => None,
 
         Some(ref split) if split.delimiter == "else" => {
-            let parsed = try!(parse(&split.trailing[1..], options));
-            Some(Template::new(parsed))
+            let parsed = try!(parse(&split.trailing[1..], state));
+            Some(Otherwise::Else(Template::new(parsed)))
         },
 
         Some(ref split) if split.delimiter == "elsif" => {
-            let child_tokens : Vec<Element> = split.trailing.iter()
-                                                            .skip(1)
-                                                            .cloned()
-                                                            .collect();
-            let parsed = try!(if_block("if", &split.args[1..], child_tokens, options));
-            Some(Template::new(vec!(parsed)))
+            let parsed = try!(conditional(&split.args[1..], &split.trailing[1..], state));
+            Some(Otherwise::Elsif(Box::new(parsed)))
         },
 
         Some(split) => panic!("Unexpected delimiter: {:?}", split.delimiter)
     };
 
-    let if_true = Template::new(try!(parse(leading_tokens, options)));
+    let if_true = Template::new(try!(parse(leading_tokens, state)));
 
-    Ok(Box::new(Conditional {
+    Ok(Conditional {
         condition: cond,
         mode: true,
         if_true: if_true,
         if_false: if_false,
-    }))
+    })
 }
 
 #[cfg(test)]
@@ -217,6 +431,135 @@ mod test {
                    Some("unless body".to_string()));
     }
 
+    #[test]
+    fn contains_string() {
+        let text = "{% if 'helloworld' contains 'low' %}yes{% else %}no{% endif %}";
+        let output = parse(text, LiquidOptions::default()).unwrap().render(&mut Context::new());
+        assert_eq!(output.unwrap(), Some("yes".to_owned()));
+
+        let text = "{% if 'helloworld' contains 'xyz' %}yes{% else %}no{% endif %}";
+        let output = parse(text, LiquidOptions::default()).unwrap().render(&mut Context::new());
+        assert_eq!(output.unwrap(), Some("no".to_owned()));
+    }
+
+    #[test]
+    fn contains_array_and_object() {
+        use value::Value;
+        use value::Object;
+
+        let text = concat!(
+            "{% if arr contains needle %}array {% endif %}",
+            "{% if obj contains needle %}object {% endif %}",
+            "{% unless arr contains 'nope' %}unless{% endunless %}");
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let mut obj = Object::default();
+        obj.insert("beta".to_owned(), Value::Num(1f32));
+
+        let mut context = Context::new();
+        context.set_val("arr", Value::Array(vec![Value::str("alpha"), Value::str("beta")]));
+        context.set_val("obj", Value::Object(obj));
+        context.set_val("needle", Value::str("beta"));
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("array object unless".to_owned()));
+
+        context.set_val("needle", Value::str("gamma"));
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("unless".to_owned()));
+    }
+
+    #[test]
+    fn boolean_operators() {
+        use value::Value;
+
+        let text = "{% if a == 1 and b == 2 %}and {% endif %}{% if a == 2 or b == 2 %}or{% endif %}";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("a", Value::Num(1f32));
+        context.set_val("b", Value::Num(2f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("and or".to_owned()));
+
+        context.set_val("b", Value::Num(3f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("".to_owned()));
+    }
+
+    #[test]
+    fn boolean_operators_evaluate_right_to_left() {
+        use value::Value;
+
+        let mut context = Context::new();
+        context.set_val("t", Value::Bool(true));
+        context.set_val("f", Value::Bool(false));
+
+        // true or (false and false) => true
+        let text = "{% if t or f and f %}yes{% else %}no{% endif %}";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+        assert_eq!(template.render(&mut context).unwrap(), Some("yes".to_owned()));
+
+        // false and (false or true) => false
+        let text = "{% if f and f or t %}yes{% else %}no{% endif %}";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+        assert_eq!(template.render(&mut context).unwrap(), Some("no".to_owned()));
+    }
+
+    #[test]
+    fn grouped_conditions() {
+        use value::Value;
+
+        let text = "{% if (f and f) or t %}yes{% else %}no{% endif %}";
+
+        // parentheses are an extension, and are rejected by default
+        assert!(parse(text, LiquidOptions::default()).is_err());
+
+        let options = LiquidOptions { grouped_conditions: true, ..Default::default() };
+        let template = parse(text, options).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("t", Value::Bool(true));
+        context.set_val("f", Value::Bool(false));
+        assert_eq!(template.render(&mut context).unwrap(), Some("yes".to_owned()));
+
+        let options = LiquidOptions { grouped_conditions: true, ..Default::default() };
+        assert!(parse("{% if (t or f %}yes{% endif %}", options).is_err());
+    }
+
+    #[test]
+    fn inline_conditionals() {
+        use value::Value;
+
+        let text = "{{ 'yes' if a == 1 else 'no' }}|{{ name | upcase if name }}";
+
+        // inline conditionals are an extension, and are rejected by default
+        assert!(parse(text, LiquidOptions::default()).is_err());
+
+        let options = LiquidOptions { inline_conditionals: true, ..Default::default() };
+        let template = parse(text, options).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("a", Value::Num(1f32));
+        context.set_val("name", Value::str("fred"));
+        assert_eq!(template.render(&mut context).unwrap(), Some("yes|FRED".to_owned()));
+
+        context.set_val("a", Value::Num(2f32));
+        context.set_val("name", Value::Bool(false));
+        assert_eq!(template.render(&mut context).unwrap(), Some("no|".to_owned()));
+    }
+
+    #[test]
+    fn malformed_inline_conditionals() {
+        for text in &["{{ if a }}", "{{ 'x' if }}", "{{ 'x' if a else }}", "{{ 'x' else 'y' if a }}"] {
+            let options = LiquidOptions { inline_conditionals: true, ..Default::default() };
+            assert!(parse(text, options).is_err());
+        }
+    }
+
+    #[test]
+    fn trailing_tokens_are_an_error() {
+        assert!(parse("{% if a b %}yes{% endif %}", LiquidOptions::default()).is_err());
+        assert!(parse("{% if a and %}yes{% endif %}", LiquidOptions::default()).is_err());
+    }
+
     #[test]
     fn nested_if_else() {
         use value::Value;
@@ -272,4 +615,62 @@ mod test {
         assert_eq!(template.render(&mut context).unwrap(),
                    Some("fourth".to_string()));
     }
+
+    #[test]
+    fn keyword_comparisons() {
+        use value::Value;
+        use value::Object;
+
+        let text = concat!(
+            "{% if a == nil %}nil {% endif %}",
+            "{% if a == empty %}empty {% endif %}",
+            "{% if a == blank %}blank {% endif %}",
+            "{% if a != blank %}present{% endif %}");
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let cases = vec![(None, "nil blank "),
+                         (Some(Value::str("")), "empty blank "),
+                         (Some(Value::str("  ")), "blank "),
+                         (Some(Value::Array(vec![])), "empty blank "),
+                         (Some(Value::Object(Object::default())), "empty blank "),
+                         (Some(Value::Bool(false)), "blank "),
+                         (Some(Value::Bool(true)), "present"),
+                         (Some(Value::str("x")), "present")];
+        for (value, expected) in cases {
+            let mut context = Context::new();
+            if let Some(v) = value {
+                context.set_val("a", v);
+            }
+            assert_eq!(template.render(&mut context).unwrap(), Some(expected.to_owned()));
+        }
+    }
+
+    #[test]
+    fn range_literals_in_conditions() {
+        use value::Value;
+
+        let text = "{% if (1..n) contains 3 and (x) %}yes{% else %}no{% endif %}";
+        let options = LiquidOptions { grouped_conditions: true, ..Default::default() };
+        let template = parse(text, options).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("x", Value::Bool(true));
+        context.set_val("n", Value::Num(4f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("yes".to_owned()));
+
+        context.set_val("n", Value::Num(3f32));
+        assert_eq!(template.render(&mut context).unwrap(), Some("no".to_owned()));
+    }
+
+    #[test]
+    fn keywords_are_not_variables() {
+        use value::Value;
+
+        let template = parse("{% if nil %}a{% endif %}{% unless blank == blank %}b{% endunless %}",
+                             LiquidOptions::default()).unwrap();
+        let mut context = Context::new();
+        context.set_val("nil", Value::Bool(true));
+        context.set_val("blank", Value::str("x"));
+        assert_eq!(template.render(&mut context).unwrap(), Some("".to_owned()));
+    }
 }
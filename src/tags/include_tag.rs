@@ -2,47 +2,162 @@
 use Renderable;
 use context::Context;
 use token::Token;
-use LiquidOptions;
+use ParseState;
 use template::Template;
 use parser;
 use lexer;
 use error::{Result, Error};
+use tags::TagNode;
 
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-struct Include {
-    partial: Template,
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Include {
+    partial: Arc<Template>,
 }
 
 impl Renderable for Include {
     fn render(&self, mut context: &mut Context) -> Result<Option<String>> {
+        context.update_stats(|stats| stats.includes += 1);
         self.partial.render(&mut context)
     }
+
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        context.update_stats(|stats| stats.includes += 1);
+        self.partial.render_fmt(out, context)
+    }
 }
 
-fn parse_partial<P: AsRef<Path>>(path: P, options: &LiquidOptions) -> Result<Template> {
+/// Loads and parses the named partial template, looking first at the
+/// templates added by name, then at each of the partial sources, then at
+/// those loaded ahead of the parse, and finally relative to the configured
+/// file system root.
+pub fn parse_partial(name: &str, state: &ParseState) -> Result<Arc<Template>> {
+    let options = state.options();
+    if let Some(content) = options.templates.get(name) {
+        return parse_source(content, name, None, state);
+    }
+    for source in &options.partials {
+        if let Some(content) = source.get(name) {
+            return parse_source(&content, name, None, state);
+        }
+    }
+    if let Some(content) = state.loaded_partial(name) {
+        return parse_source(content, name, None, state);
+    }
     let file_system = options.file_system.clone().unwrap_or(PathBuf::new());
-    let path = file_system.join(path);
+    parse_file(file_system.join(name), state)
+}
 
+/// Loads and parses the named partial template, relative to the directory
+/// of the partial currently being parsed. Top-level templates are treated
+/// as living in the file system root.
+fn parse_relative_partial<P: AsRef<Path>>(path: P, state: &ParseState) -> Result<Arc<Template>> {
+    let current_dir = state.current_file()
+                           .and_then(|p| p.parent().map(|dir| dir.to_path_buf()));
+    let base = match current_dir {
+        Some(dir) => dir,
+        None => state.options().file_system.clone().unwrap_or(PathBuf::new()),
+    };
+    parse_file(base.join(path), state)
+}
+
+fn parse_file(path: PathBuf, state: &ParseState) -> Result<Arc<Template>> {
     // check if file exists
     if !path.exists() {
         return Err(Error::from(&*format!("{:?} does not exist", path)));
     }
 
-    let mut file = try!(File::open(path));
+    let mut file = try!(File::open(&path));
 
     let mut content = String::new();
     try!(file.read_to_string(&mut content));
 
-    let tokens = try!(lexer::tokenize(&content));
-    parser::parse(&tokens, &options).map(Template::new)
+    let name = path.to_string_lossy().into_owned();
+    parse_source(&content, &name, Some(path), state)
+}
+
+/// Parses the source of a partial, after checking that it isn't already
+/// being parsed further out and that partials aren't nested too deeply.
+fn parse_source(content: &str,
+                name: &str,
+                path: Option<PathBuf>,
+                state: &ParseState)
+                -> Result<Arc<Template>> {
+    state.including(name, || parse_cached(content, name, path, state))
+}
+
+/// Parses the source of a partial, or reuses the template parsed from the
+/// same source before when partials are being cached. Partials that
+/// recorded errors or warnings aren't cached, since those would otherwise
+/// only be reported the first time.
+fn parse_cached(content: &str,
+                name: &str,
+                path: Option<PathBuf>,
+                state: &ParseState)
+                -> Result<Arc<Template>> {
+    let cache = match state.options().partial_cache {
+        Some(ref cache) => cache,
+        None => return parse_uncached(content, name, path, state).map(Arc::new),
+    };
+    state.record_include(name);
+    if let Some(template) = cache.get(name, content) {
+        return Ok(template);
+    }
+
+    let errors = state.error_count();
+    let warnings = state.warning_count();
+    let (result, includes) = state.recording_includes(|| {
+        parse_uncached(content, name, path, state).map(Arc::new)
+    });
+    if let Ok(ref template) = result {
+        if state.error_count() == errors && state.warning_count() == warnings {
+            cache.insert(name, content, template.clone(), includes);
+        }
+    }
+    result
+}
+
+/// Parses the source of a partial. Partials read from a file are marked
+/// as the current file of the parse while they are parsed, so that
+/// `include_relative` can find their directory.
+fn parse_uncached(content: &str,
+                  name: &str,
+                  path: Option<PathBuf>,
+                  state: &ParseState)
+                  -> Result<Template> {
+    let collected = state.error_count();
+
+    let content: Arc<str> = Arc::from(content);
+    let result = state.in_file(path, || {
+        state.with_source(&content, || {
+            lexer::tokenize_with_options(&content, state)
+                .and_then(|tokens| parser::parse(&tokens, state))
+                .map(Template::new)
+        })
+    });
+
+    // errors collected from the partial are located within its source,
+    // not the including template's
+    state.resolve_errors(collected, &content, Some(name));
+
+    match result {
+        Ok(mut template) => {
+            template.set_shared_origin(content.clone(), Some(name));
+            Ok(template)
+        }
+        Err(e) => Err(e.resolve_location(&content, Some(name))),
+    }
 }
 
 pub fn include_tag(_tag_name: &str,
                    arguments: &[Token],
-                   options: &LiquidOptions)
+                   state: &ParseState)
                    -> Result<Box<Renderable>> {
     let mut args = arguments.iter();
 
@@ -52,7 +167,26 @@ pub fn include_tag(_tag_name: &str,
     };
 
 
-    Ok(Box::new(Include { partial: try!(parse_partial(&path, &options)) }))
+    let partial = try!(parse_partial(&path[..], state));
+    Ok(Box::new(TagNode::Include(Include { partial: partial })))
+}
+
+/// Includes a partial relative to the including template, rather than the
+/// file system root. Like Jekyll, the path may be given unquoted.
+pub fn include_relative_tag(_tag_name: &str,
+                            arguments: &[Token],
+                            state: &ParseState)
+                            -> Result<Box<Renderable>> {
+    let mut args = arguments.iter();
+
+    let path = match args.next() {
+        Some(&Token::StringLiteral(ref path)) |
+        Some(&Token::Identifier(ref path)) => path,
+        arg => return Error::parser("String Literal | Path", arg),
+    };
+
+    let partial = try!(parse_relative_partial(&path[..], state));
+    Ok(Box::new(TagNode::Include(Include { partial: partial })))
 }
 
 #[cfg(test)]
@@ -87,12 +221,70 @@ mod test {
         let output = parse(text, options());
 
         assert!(output.is_err());
-        if let Err(Error::Other(val)) = output {
-            assert_eq!(format!("{}", val),
-                       "\"tests/fixtures/input/file_does_not_exist.liquid\" does not exist"
-                           .to_owned());
-        } else {
-            assert!(false);
+        if let Err(Error::Located(_, err)) = output {
+            if let Error::Other(val) = *err {
+                assert_eq!(format!("{}", val),
+                           "\"tests/fixtures/input/file_does_not_exist.liquid\" does not exist"
+                               .to_owned());
+                return;
+            }
         }
+        assert!(false);
+    }
+
+    #[test]
+    fn errors_in_partials_report_the_partial_location() {
+        let text = "one\n{% include 'bad_partial.txt' %}";
+        let err = parse(text, options()).err().unwrap();
+        assert_eq!(err.without_snippet().to_string(),
+                   "tests/fixtures/input/bad_partial.txt:2:3: Parsing error: Expected String \
+                    Literal, found None");
+    }
+
+    #[test]
+    fn include_relative_tag() {
+        let text = "{% include 'relative/outer.txt' %}";
+        let template = parse(text, options()).unwrap();
+
+        let mut context = Context::new();
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("outer [inner [innermost]\n]\n".to_owned()));
+    }
+
+    #[test]
+    fn include_relative_at_top_level_uses_root() {
+        let text = "{% include_relative 'relative/inner.txt' %}";
+        let template = parse(text, options()).unwrap();
+
+        let mut context = Context::new();
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("inner [innermost]\n".to_owned()));
+    }
+
+    #[test]
+    fn include_cycles_are_errors() {
+        let mut options = options();
+        options.templates.insert("a".to_owned(), "{% include 'b' %}".to_owned());
+        options.templates.insert("b".to_owned(), "{% include 'a' %}".to_owned());
+        let err = parse("{% include 'a' %}", options).err().unwrap();
+        assert_eq!(err.without_snippet().to_string(),
+                   "b:1:1: Parsing error: Include cycle: a -> b -> a");
+    }
+
+    #[test]
+    fn include_depth_is_limited() {
+        use Limits;
+
+        let mut options = options();
+        options.templates.insert("a".to_owned(), "{% include 'b' %}".to_owned());
+        options.templates.insert("b".to_owned(), "b".to_owned());
+        options.limits = Limits { max_include_depth: Some(2), ..Default::default() };
+        assert!(parse("{% include 'a' %}", options).is_ok());
+
+        let mut options = self::options();
+        options.templates.insert("a".to_owned(), "{% include 'b' %}".to_owned());
+        options.templates.insert("b".to_owned(), "b".to_owned());
+        options.limits = Limits { max_include_depth: Some(1), ..Default::default() };
+        assert!(parse("{% include 'a' %}", options).err().unwrap().is_limit());
     }
 }
@@ -0,0 +1,249 @@
+use Renderable;
+use context::Context;
+use ParseState;
+use template::Template;
+use token::Token::{self, Identifier, StringLiteral};
+use lexer::Element::{self, Tag};
+use parser::parse;
+use error::{Error, Result};
+use value::{Value, Object};
+use super::include_tag::parse_partial;
+use tags::BlockNode;
+
+use std::sync::Arc;
+
+/// A named, overridable section of a template.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Block {
+    name: String,
+    default: Arc<Template>,
+}
+
+/// Renders the chain of overrides for a block, starting at the given
+/// index. Each link in the chain can refer to the rendered output of the
+/// next link via `{{ block.super }}`.
+fn render_chain(chain: &[Arc<Template>], context: &mut Context) -> Result<Option<String>> {
+    let super_output = match chain.len() {
+        0 => return Ok(None),
+        1 => String::new(),
+        _ => try!(render_chain(&chain[1..], context)).unwrap_or("".to_owned()),
+    };
+
+    context.run_in_scope(|mut scope| {
+        let mut block_vars = Object::default();
+        block_vars.insert("super".to_owned(), Value::Str(super_output));
+        scope.set_local_val("block", Value::Object(block_vars));
+        chain[0].render(&mut scope)
+    })
+}
+
+impl Renderable for Block {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        let mut chain = context.block_overrides(&self.name);
+        chain.push(self.default.clone());
+        render_chain(&chain, context)
+    }
+}
+
+/// A template that inherits its layout from a parent template, overriding
+/// some or all of the parent's blocks.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Extends {
+    parent: Arc<Template>,
+    blocks: Vec<(String, Arc<Template>)>,
+}
+
+impl Renderable for Extends {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        for &(ref name, ref template) in &self.blocks {
+            context.push_block_override(name, template.clone());
+        }
+
+        let result = self.parent.render(context);
+
+        for &(ref name, _) in &self.blocks {
+            context.pop_block_override(name);
+        }
+
+        result
+    }
+}
+
+fn block_name(arguments: &[Token]) -> Result<String> {
+    let mut args = arguments.iter();
+    let name = match args.next() {
+        Some(&Identifier(ref x)) |
+        Some(&StringLiteral(ref x)) => x.to_string(),
+        x => return Error::parser("Identifier | String", x),
+    };
+
+    // there should be no trailing tokens after this
+    if let t @ Some(_) = args.next() {
+        return Error::parser("%}", t);
+    };
+
+    Ok(name)
+}
+
+/// Collects all of the top-level `{% block %}` definitions from a list of
+/// elements, ignoring everything else.
+fn collect_blocks(elements: &[Element],
+                  state: &ParseState)
+                  -> Result<Vec<(String, Arc<Template>)>> {
+    let mut blocks = vec![];
+    let mut iter = elements.iter();
+
+    while let Some(element) = iter.next() {
+        let args = match *element {
+            Tag(ref tokens, _, _) if tokens[0] == Identifier("block".into()) => &tokens[1..],
+            _ => continue,
+        };
+        let name = try!(block_name(args));
+
+        // collect everything up to the matching endblock, taking care
+        // to skip over any blocks nested inside this one.
+        let mut children = vec![];
+        let mut nesting_depth = 0;
+        for t in iter.by_ref() {
+            if let Tag(ref tokens, _, _) = *t {
+                match tokens[0] {
+                    Identifier(ref n) if n == "block" => nesting_depth += 1,
+                    Identifier(ref n) if n == "endblock" && nesting_depth == 0 => break,
+                    Identifier(ref n) if n == "endblock" => nesting_depth -= 1,
+                    _ => {}
+                }
+            }
+            children.push(t.clone());
+        }
+
+        let template = Template::new(try!(parse(&children, state)));
+        blocks.push((name, Arc::new(template)));
+    }
+
+    Ok(blocks)
+}
+
+pub fn block_block(_tag_name: &str,
+                   arguments: &[Token],
+                   tokens: Vec<Element>,
+                   state: &ParseState)
+                   -> Result<Box<Renderable>> {
+    let name = try!(block_name(arguments));
+    let default = Template::new(try!(parse(&tokens, state)));
+    Ok(Box::new(BlockNode::Block(Block {
+        name: name,
+        default: Arc::new(default),
+    })))
+}
+
+/// Parses an `{% extends 'parent' %}` tag. There is no matching
+/// `endextends` tag; the extends tag implicitly runs to the end of the
+/// template, and everything after it other than `{% block %}` definitions
+/// is discarded.
+pub fn extends_block(_tag_name: &str,
+                     arguments: &[Token],
+                     tokens: Vec<Element>,
+                     state: &ParseState)
+                     -> Result<Box<Renderable>> {
+    let mut args = arguments.iter();
+    let path = match args.next() {
+        Some(&StringLiteral(ref path)) => path,
+        x => return Error::parser("String Literal", x),
+    };
+
+    if let t @ Some(_) = args.next() {
+        return Error::parser("%}", t);
+    };
+
+    let parent = try!(parse_partial(&path[..], state));
+    let blocks = try!(collect_blocks(&tokens, state));
+
+    Ok(Box::new(BlockNode::Extends(Extends {
+        parent: parent,
+        blocks: blocks,
+    })))
+}
+
+#[cfg(test)]
+mod test {
+    use context::Context;
+    use Renderable;
+    use parse;
+    use value::Value;
+    use LiquidOptions;
+    use std::path::PathBuf;
+
+    fn options() -> LiquidOptions {
+        LiquidOptions {
+            file_system: Some(PathBuf::from("tests/fixtures/input")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn blocks_render_their_defaults() {
+        let text = "<{% block content %}default{% endblock %}>";
+        let template = parse(text, options()).unwrap();
+
+        let mut context = Context::new();
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("<default>".to_owned()));
+    }
+
+    #[test]
+    fn child_overrides_parent_blocks() {
+        let text = concat!(
+            "{% extends 'base_layout.txt' %}",
+            "this text is ignored",
+            "{% block content %}Hello, {{ name }}!{% endblock %}");
+        let template = parse(text, options()).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("name", Value::str("world"));
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("<title>Default title</title>\n<body>Hello, world!</body>\n".to_owned()));
+    }
+
+    #[test]
+    fn block_super_renders_parent_block() {
+        let text = concat!(
+            "{% extends 'base_layout.txt' %}",
+            "{% block title %}Page - {{ block.super }}{% endblock %}");
+        let template = parse(text, options()).unwrap();
+
+        let mut context = Context::new();
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("<title>Page - Default title</title>\n<body></body>\n".to_owned()));
+    }
+
+    #[test]
+    fn multi_level_inheritance() {
+        let text = concat!(
+            "{% extends 'middle_layout.txt' %}",
+            "{% block content %}[{{ block.super }}]{% endblock %}");
+        let template = parse(text, options()).unwrap();
+
+        let mut context = Context::new();
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("<title>Middle title</title>\n<body>[middle content]</body>\n".to_owned()));
+    }
+
+    #[test]
+    fn overrides_do_not_leak_between_renders() {
+        let child = parse("{% extends 'base_layout.txt' %}{% block content %}child{% endblock %}",
+                          options()).unwrap();
+        let plain = parse("{% block content %}plain{% endblock %}", options()).unwrap();
+
+        let mut context = Context::new();
+        child.render(&mut context).unwrap();
+        assert_eq!(plain.render(&mut context).unwrap(), Some("plain".to_owned()));
+    }
+
+    #[test]
+    fn extends_requires_a_path() {
+        assert!(parse("{% extends %}", options()).is_err());
+        assert!(parse("{% extends base_layout %}", options()).is_err());
+    }
+}
@@ -1,13 +1,19 @@
 use error::{Error, Result};
 use context::{Context, Interrupt};
 use Token;
-use LiquidOptions;
+use ParseState;
 use Renderable;
+use tags::TagNode;
 
-struct Break;
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Break;
 
 impl Renderable for Break {
     fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        if !context.in_loop() {
+            return Error::renderer("break used outside of a loop");
+        }
         context.set_interrupt(Interrupt::Break);
         Ok(None)
     }
@@ -15,19 +21,24 @@ impl Renderable for Break {
 
 pub fn break_tag(_tag_name: &str,
                  arguments: &[Token],
-                 _options: &LiquidOptions) -> Result<Box<Renderable>> {
+                 _state: &ParseState) -> Result<Box<Renderable>> {
 
     // no arguments should be supplied, trying to supply them is an error
     if arguments.len() > 0 {
         return Error::parser("%}", arguments.first());
     }
-    return Ok(Box::new(Break));
+    return Ok(Box::new(TagNode::Break(Break)));
 }
 
-struct Continue;
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Continue;
 
 impl Renderable for Continue {
     fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        if !context.in_loop() {
+            return Error::renderer("continue used outside of a loop");
+        }
         context.set_interrupt(Interrupt::Continue);
         Ok(None)
     }
@@ -35,12 +46,12 @@ impl Renderable for Continue {
 
 pub fn continue_tag(_tag_name: &str,
                     arguments: &[Token],
-                    _options: &LiquidOptions) -> Result<Box<Renderable>> {
+                    _state: &ParseState) -> Result<Box<Renderable>> {
     // no arguments should be supplied, trying to supply them is an error
     if arguments.len() > 0 {
         return Error::parser("%}", arguments.first());
     }
-    return Ok(Box::new(Continue));
+    return Ok(Box::new(TagNode::Continue(Continue)));
 }
 
 
@@ -137,5 +148,34 @@ mod test {
         ));
     }
 
+    #[test]
+    fn test_break_does_not_escape_loop() {
+        let text = concat!(
+            "before;",
+            "{% for i in (0..10) %}{{i}};{% if i == 1 %}{% break %}{% endif %}{% endfor %}",
+            "after");
+        let template = parse(text, LiquidOptions::default()).unwrap();
+
+        let mut ctx = Context::new();
+        let output = template.render(&mut ctx);
+        assert_eq!(output.unwrap(), Some("before;0;1;after".to_owned()));
+        assert!(!ctx.interrupted());
+    }
+
+    #[test]
+    fn test_interrupts_outside_loop_are_errors() {
+        let mut ctx = Context::new();
+
+        let template = parse("before {% break %} after", LiquidOptions::default()).unwrap();
+        assert!(template.render(&mut ctx).is_err());
+
+        let template = parse("before {% continue %} after", LiquidOptions::default()).unwrap();
+        assert!(template.render(&mut ctx).is_err());
+
+        // the else branch of a loop is not inside the loop body
+        let text = "{% for i in (0..0) %}{% else %}{% break %}{% endfor %}";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+        assert!(template.render(&mut ctx).is_err());
+    }
 
 }
\ No newline at end of file
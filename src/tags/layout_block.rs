@@ -0,0 +1,114 @@
+use Renderable;
+use context::Context;
+use ParseState;
+use template::Template;
+use token::Token::{self, Identifier, StringLiteral};
+use parser::parse;
+use lexer::Element;
+use error::{Error, Result};
+use value::Value;
+use super::include_tag::parse_partial;
+use tags::BlockNode;
+
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Layout {
+    layout: Option<Arc<Template>>,
+    body: Template,
+}
+
+impl Renderable for Layout {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        let body = try!(self.body.render(context));
+
+        match self.layout {
+            Some(ref layout) => {
+                let content = Value::Str(body.unwrap_or("".to_owned()));
+                context.run_in_scope(|mut scope| {
+                    scope.set_local_val("content_for_layout", content);
+                    layout.render(&mut scope)
+                })
+            }
+            None => Ok(body),
+        }
+    }
+}
+
+/// Parses a `{% layout 'name' %}` tag, which wraps the rest of the template
+/// in the named layout. The layout refers to the wrapped page body as
+/// `{{ content_for_layout }}`. `{% layout none %}` renders the page without
+/// any layout.
+///
+/// Like `extends`, there is no matching `endlayout` tag; the layout tag
+/// implicitly runs to the end of the template.
+pub fn layout_block(_tag_name: &str,
+                    arguments: &[Token],
+                    tokens: Vec<Element>,
+                    state: &ParseState)
+                    -> Result<Box<Renderable>> {
+    let mut args = arguments.iter();
+    let layout = match args.next() {
+        Some(&StringLiteral(ref name)) => Some(try!(parse_partial(&name[..], state))),
+        Some(&Identifier(ref x)) if x == "none" => None,
+        x => return Error::parser("String Literal | none", x),
+    };
+
+    if let t @ Some(_) = args.next() {
+        return Error::parser("%}", t);
+    };
+
+    Ok(Box::new(BlockNode::Layout(Layout {
+        layout: layout,
+        body: Template::new(try!(parse(&tokens, state))),
+    })))
+}
+
+#[cfg(test)]
+mod test {
+    use context::Context;
+    use Renderable;
+    use parse;
+    use value::Value;
+    use LiquidOptions;
+    use std::path::PathBuf;
+
+    fn options() -> LiquidOptions {
+        LiquidOptions {
+            file_system: Some(PathBuf::from("tests/fixtures/input")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn layout_wraps_body() {
+        let text = "{% layout 'theme_layout.txt' %}Hello, {{ name }}!";
+        let template = parse(text, options()).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("name", Value::str("world"));
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("<html>Hello, world!</html>\n".to_owned()));
+        assert_eq!(context.get_val("content_for_layout"), None);
+    }
+
+    #[test]
+    fn layout_none() {
+        let text = "{% layout none %}Hello, {{ name }}!";
+        let template = parse(text, options()).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("name", Value::str("world"));
+        assert_eq!(template.render(&mut context).unwrap(),
+                   Some("Hello, world!".to_owned()));
+    }
+
+    #[test]
+    fn bad_layout_arguments() {
+        assert!(parse("{% layout %}", options()).is_err());
+        assert!(parse("{% layout theme %}", options()).is_err());
+        assert!(parse("{% layout 'theme_layout.txt' 'x' %}", options()).is_err());
+        assert!(parse("{% layout 'no_such_layout.txt' %}", options()).is_err());
+    }
+}
@@ -1,21 +1,167 @@
 mod assign_tag;
+mod cache_block;
 mod capture_block;
+mod case_block;
 mod comment_block;
+mod content_for_block;
 mod for_block;
 mod if_block;
 mod include_tag;
+mod inheritance_blocks;
 mod interrupt_tags;
+mod layout_block;
 mod raw_block;
 mod cycle_tag;
 
 pub use self::assign_tag::assign_tag;
+pub use self::cache_block::cache_block;
 pub use self::capture_block::capture_block;
+pub use self::case_block::case_block;
 pub use self::comment_block::comment_block;
+pub use self::comment_block::inline_comment_tag;
+pub use self::comment_block::doc_block;
+pub use self::content_for_block::content_for_block;
+pub use self::content_for_block::yield_tag;
 pub use self::cycle_tag::cycle_tag;
 pub use self::for_block::for_block;
 pub use self::if_block::if_block;
 pub use self::if_block::unless_block;
+pub use self::if_block::inline_conditional;
 pub use self::include_tag::include_tag;
+pub use self::include_tag::include_relative_tag;
+pub use self::inheritance_blocks::block_block;
+pub use self::inheritance_blocks::extends_block;
 pub use self::interrupt_tags::break_tag;
 pub use self::interrupt_tags::continue_tag;
-pub use self::raw_block::raw_block;
\ No newline at end of file
+pub use self::layout_block::layout_block;
+pub use self::raw_block::raw_block;
+use self::assign_tag::Assign;
+use self::cache_block::Cache;
+use self::capture_block::Capture;
+use self::case_block::Case;
+use self::comment_block::{Comment, Doc};
+use self::content_for_block::{ContentFor, Yield};
+use self::cycle_tag::Cycle;
+use self::for_block::For;
+use self::if_block::{Conditional, InlineConditional};
+use self::include_tag::Include;
+use self::inheritance_blocks::{Block, Extends};
+use self::interrupt_tags::{Break, Continue};
+use self::layout_block::Layout;
+use self::raw_block::RawT;
+
+use Renderable;
+use context::Context;
+use error::Result;
+use instrument::NodeKind;
+use lexer::Span;
+use located::Located;
+use node::Node;
+
+use std::fmt;
+use std::mem;
+
+/// A tag parsed by one of the built-in tag functions, or an inline
+/// conditional, which is an output with a condition.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TagNode {
+    Assign(Assign),
+    Break(Break),
+    Continue(Continue),
+    Cycle(Cycle),
+    Include(Include),
+    Comment(Comment),
+    Yield(Yield),
+    InlineConditional(InlineConditional),
+}
+
+/// A block parsed by one of the built-in block functions.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum BlockNode {
+    Capture(Capture),
+    Case(Case),
+    Comment(Comment),
+    Doc(Doc),
+    ContentFor(ContentFor),
+    For(For),
+    If(Conditional),
+    Block(Block),
+    Extends(Extends),
+    Cache(Cache),
+    Layout(Layout),
+    Raw(RawT),
+}
+
+impl TagNode {
+    fn inner(&self) -> &Renderable {
+        match *self {
+            TagNode::Assign(ref x) => x,
+            TagNode::Break(ref x) => x,
+            TagNode::Continue(ref x) => x,
+            TagNode::Cycle(ref x) => x,
+            TagNode::Include(ref x) => x,
+            TagNode::Comment(ref x) => x,
+            TagNode::Yield(ref x) => x,
+            TagNode::InlineConditional(ref x) => x,
+        }
+    }
+}
+
+impl BlockNode {
+    fn inner(&self) -> &Renderable {
+        match *self {
+            BlockNode::Capture(ref x) => x,
+            BlockNode::Case(ref x) => x,
+            BlockNode::Comment(ref x) => x,
+            BlockNode::Doc(ref x) => x,
+            BlockNode::ContentFor(ref x) => x,
+            BlockNode::For(ref x) => x,
+            BlockNode::If(ref x) => x,
+            BlockNode::Block(ref x) => x,
+            BlockNode::Extends(ref x) => x,
+            BlockNode::Cache(ref x) => x,
+            BlockNode::Layout(ref x) => x,
+            BlockNode::Raw(ref x) => x,
+        }
+    }
+}
+
+impl Renderable for TagNode {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        self.inner().render(context)
+    }
+
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        self.inner().render_fmt(out, context)
+    }
+
+    fn documentation(&self) -> Option<&str> {
+        self.inner().documentation()
+    }
+
+    fn take_node(&mut self, kind: NodeKind, span: Span) -> Option<Node> {
+        let node = mem::replace(self, TagNode::Break(Break));
+        Some(Node::Tag(Located::new(node, kind, span)))
+    }
+}
+
+impl Renderable for BlockNode {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        self.inner().render(context)
+    }
+
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        self.inner().render_fmt(out, context)
+    }
+
+    fn documentation(&self) -> Option<&str> {
+        self.inner().documentation()
+    }
+
+    fn take_node(&mut self, kind: NodeKind, span: Span) -> Option<Node> {
+        let node = mem::replace(self, BlockNode::Comment(Comment));
+        Some(Node::Block(Located::new(node, kind, span)))
+    }
+}
@@ -1,11 +1,14 @@
 use Renderable;
 use context::Context;
-use LiquidOptions;
+use ParseState;
 use token::Token;
 use lexer::Element::{self, Expression, Tag, Raw};
 use error::Result;
+use tags::BlockNode;
 
-struct RawT {
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RawT {
     content: String,
 }
 
@@ -18,28 +21,30 @@ impl Renderable for RawT {
 pub fn raw_block(_tag_name: &str,
                  _arguments: &[Token],
                  tokens: Vec<Element>,
-                 _options: &LiquidOptions)
+                 _state: &ParseState)
                  -> Result<Box<Renderable>> {
     let content = tokens.iter().fold("".to_owned(), |a, b| {
         match *b {
-            Expression(_, ref text) |
-            Tag(_, ref text) |
+            Expression(_, ref text, _) |
+            Tag(_, ref text, _) |
             Raw(ref text) => text,
         }
-        .to_owned() + &a
+        .to_string() + &a
     });
-    Ok(Box::new(RawT { content: content }))
+    Ok(Box::new(BlockNode::Raw(RawT { content: content })))
 }
 
 #[test]
 fn test_raw() {
     use std::default::Default;
+    use lexer::Span;
+    use LiquidOptions;
 
     let options: LiquidOptions = Default::default();
     let raw = raw_block("raw",
                         &[],
-                        vec![Expression(vec![], "This is a test".to_owned())],
-                        &options);
+                        vec![Expression(vec![], "This is a test".into(), Span::default())],
+                        &ParseState::new(&options));
     assert_eq!(raw.unwrap().render(&mut Default::default()).unwrap(),
                Some("This is a test".to_owned()));
 }
@@ -1,30 +1,208 @@
 use Renderable;
+use node::Node;
+use tags::TagNode;
 use context::Context;
-use filters::{size, upcase, minus, plus, replace, times, divided_by, ceil, floor, round};
-use error::Result;
+use filters::Filter;
+use error::{Error, Result};
+use validate::{self, Warning};
+use LiquidOptions;
+use ErrorMode;
+#[cfg(feature = "serde")]
+use bincode;
+#[cfg(feature = "parallel")]
+use context::ContextSnapshot;
+#[cfg(feature = "parallel")]
+use error::catch_panic;
+#[cfg(feature = "parallel")]
+use stats::RenderStats;
+#[cfg(feature = "parallel")]
+use std::cmp;
+#[cfg(feature = "parallel")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "parallel")]
+use std::thread;
+#[cfg(feature = "async")]
+use async_render::RenderAsync;
 
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// What rendering a section of a page on another thread gives back.
+#[cfg(feature = "parallel")]
+struct Section {
+    output: Result<String>,
+    errors: Vec<Error>,
+    warnings: Vec<Warning>,
+    stats: RenderStats,
+}
+
+/// Renders the sections of a page that no other thread has taken, one at a
+/// time, until there are none left, giving back each with its position.
+#[cfg(feature = "parallel")]
+fn render_sections(sections: &[&Template],
+                   next: &AtomicUsize,
+                   snapshot: &ContextSnapshot)
+                   -> Vec<(usize, Section)> {
+    let mut rendered = vec![];
+    loop {
+        let i = next.fetch_add(1, Ordering::SeqCst);
+        match sections.get(i) {
+            Some(section) => rendered.push((i, section.render_section(snapshot))),
+            None => return rendered,
+        }
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Template {
-    pub elements: Vec<Box<Renderable>>,
+    pub elements: Vec<Node>,
+
+    /// Non-fatal problems found while parsing the template.
+    pub warnings: Vec<String>,
+
+    /// Filters registered with the `Parser` that produced this template.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub filters: Option<Arc<HashMap<String, Box<Filter>>>>,
+
+    /// The text and name of a top-level template, used to give the line
+    /// and column of errors raised while rendering it.
+    text: Option<Arc<str>>,
+    name: Option<String>,
+
+    /// A guess at the size of the rendered template, used to allocate
+    /// enough room for it up front.
+    size_hint: usize,
+}
+
+impl fmt::Debug for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Template")
+         .field("elements", &self.elements)
+         .field("warnings", &self.warnings)
+         .field("name", &self.name)
+         .finish()
+    }
+}
+
+/// A guess at how many bytes each output, tag or block adds to a rendered
+/// template.
+const NODE_SIZE_GUESS: usize = 16;
+
+fn estimate_size(elements: &[Node]) -> usize {
+    elements.iter()
+            .map(|node| {
+                match *node {
+                    Node::Text(ref text) => text.as_str().len(),
+                    _ => NODE_SIZE_GUESS,
+                }
+            })
+            .sum()
 }
 
 impl Renderable for Template {
     fn render(&self, context: &mut Context) -> Result<Option<String>> {
-        context.add_filter("size", Box::new(size));
-        context.add_filter("upcase", Box::new(upcase));
-        context.add_filter("minus", Box::new(minus));
-        context.add_filter("plus", Box::new(plus));
-        context.add_filter("times", Box::new(times));
-        context.add_filter("divided_by", Box::new(divided_by));
-        context.add_filter("ceil", Box::new(ceil));
-        context.add_filter("floor", Box::new(floor));
-        context.add_filter("round", Box::new(round));
-        context.add_filter("replace", Box::new(replace));
+        let mut buf = String::with_capacity(self.size_hint);
+        try!(self.render_fmt(&mut buf, context));
+        Ok(Some(buf))
+    }
 
-        let mut buf = String::new();
-        for el in &self.elements {
-            if let Some(ref x) = try!(el.render(context)) {
-                buf = buf + x;
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        Template::render_fmt(self, out, context)
+    }
+}
+
+/// Lets a render write to an `io::Write` through `fmt::Write`, keeping the
+/// error of the first write that fails.
+struct IoWriter<'a> {
+    inner: &'a mut io::Write,
+    error: Option<io::Error>,
+}
+
+impl<'a> fmt::Write for IoWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|err| {
+            self.error = Some(err);
+            fmt::Error
+        })
+    }
+}
+
+/// Counts the bytes written through it, for the statistics of a render.
+struct CountingWriter<'a> {
+    inner: &'a mut fmt::Write,
+    bytes: usize,
+}
+
+impl<'a> fmt::Write for CountingWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.bytes += s.len();
+        self.inner.write_str(s)
+    }
+}
+
+/// Writes `s` to `out`, as a render writes each piece of its output.
+pub fn write(out: &mut fmt::Write, s: &str) -> Result<()> {
+    out.write_str(s).map_err(|_| {
+        Error::Write(io::Error::new(io::ErrorKind::Other, "formatter error"))
+    })
+}
+
+impl Template {
+    /// A guess at how many bytes the rendered template takes up: the size
+    /// of its text plus a little for each output, tag and block. Rendering
+    /// to a `String` allocates this much up front.
+    ///
+    /// ## Example
+    /// ```
+    /// use liquid::LiquidOptions;
+    ///
+    /// let template = liquid::parse("Hello, {{ name }}!", LiquidOptions::default()).unwrap();
+    /// assert!(template.size_hint() >= "Hello, !".len());
+    /// ```
+    pub fn size_hint(&self) -> usize {
+        self.size_hint
+    }
+
+    /// The text of a template that has no outputs, tags or blocks in it, so
+    /// that it renders as itself whatever the context. Such templates are
+    /// rendered by copying this text, without setting anything up.
+    ///
+    /// ## Example
+    /// ```
+    /// use liquid::LiquidOptions;
+    ///
+    /// let template = liquid::parse("Just text", LiquidOptions::default()).unwrap();
+    /// assert_eq!(template.plain_text(), Some("Just text"));
+    ///
+    /// let template = liquid::parse("Hi {{ name }}", LiquidOptions::default()).unwrap();
+    /// assert_eq!(template.plain_text(), None);
+    /// ```
+    pub fn plain_text(&self) -> Option<&str> {
+        match self.elements.len() {
+            0 => Some(""),
+            1 => {
+                match self.elements[0] {
+                    Node::Text(ref text) => Some(text.as_str()),
+                    _ => None,
+                }
             }
+            _ => None,
+        }
+    }
+
+    fn render_elements(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        if let Some(text) = self.plain_text() {
+            try!(context.check_deadline());
+            return write(out, text);
+        }
+
+        for el in &self.elements {
+            try!(context.check_deadline());
+            try!(el.render_fmt(out, context));
 
             // Did the last element we processed set an interrupt? If so, we
             // need to abandon the rest of our child elements and just
@@ -34,12 +212,972 @@ impl Renderable for Template {
                 break;
             }
         }
-        Ok(Some(buf))
+        Ok(())
+    }
+
+    /// Renders the template, writing the output of each top-level output,
+    /// tag and block to `out` as soon as it is rendered. `for` loops and
+    /// `if` blocks write their contents bit by bit too, when the context
+    /// [can stream](struct.Context.html#method.can_stream), so a long loop
+    /// is never held in memory all at once. `render` is this with a
+    /// `String`. If the render fails, `out` keeps whatever was written
+    /// before the failure.
+    pub fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        let outermost = context.start_rendering();
+        // templates rendered within another, such as partials, use the
+        // filters of the outer one unless they have their own
+        let previous = if outermost || self.filters.is_some() {
+            Some(context.replace_shared_filters(self.filters.clone()))
+        } else {
+            None
+        };
+        let result = if outermost {
+            let mut counted = CountingWriter {
+                inner: out,
+                bytes: 0,
+            };
+            let result = self.render_resolved(&mut counted, context);
+            context.update_stats(|stats| stats.bytes += counted.bytes);
+            context.finish_rendering();
+            result
+        } else {
+            self.render_resolved(out, context)
+        };
+        if let Some(previous) = previous {
+            context.replace_shared_filters(previous);
+        }
+        result
+    }
+
+    /// Renders the template, resolving the location of errors and warnings
+    /// raised while rendering it against its source.
+    fn render_resolved(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        let name = self.name.as_ref().map(|n| &n[..]);
+        match self.text {
+            Some(ref text) => {
+                let kept = context.render_errors().len();
+                let warned = context.render_warning_count();
+                let result = self.render_elements(out, context)
+                                 .map_err(|e| e.resolve_location(text, name));
+                context.resolve_render_errors(kept, text, name);
+                context.resolve_render_warnings(warned, text, name);
+                result
+            }
+            None => self.render_elements(out, context),
+        }
+    }
+
+    /// Renders the template onto the end of `buf`, so that a server can
+    /// keep one buffer per worker and reuse its memory from one render to
+    /// the next. If the render fails, `buf` keeps whatever was rendered
+    /// before the failure.
+    ///
+    /// ## Example
+    /// ```
+    /// use liquid::{Context, LiquidOptions};
+    ///
+    /// let template = liquid::parse("Hi {{ name }}", LiquidOptions::default()).unwrap();
+    /// let mut buf = String::with_capacity(1024);
+    /// for name in &["Ann", "Bo"] {
+    ///     buf.clear();
+    ///     let mut context = Context::new();
+    ///     context.set_val("name", *name);
+    ///     template.render_into(&mut buf, &mut context).unwrap();
+    ///     assert_eq!(buf, format!("Hi {}", name));
+    /// }
+    /// ```
+    pub fn render_into(&self, buf: &mut String, context: &mut Context) -> Result<()> {
+        buf.reserve(self.size_hint);
+        self.render_fmt(buf, context)
+    }
+
+    /// Renders the template to `writer`, like `render_fmt`, so that the
+    /// output doesn't have to be held in memory all at once. If writing
+    /// fails, the error is `Error::Write`.
+    ///
+    /// ## Example
+    /// ```
+    /// use liquid::{Context, LiquidOptions};
+    ///
+    /// let template = liquid::parse("Hi {{ name }}", LiquidOptions::default()).unwrap();
+    /// let mut context = Context::new();
+    /// context.set_val("name", "Ann");
+    /// let mut out = vec![];
+    /// template.render_to(&mut out, &mut context).unwrap();
+    /// assert_eq!(out, b"Hi Ann");
+    /// ```
+    pub fn render_to(&self, writer: &mut io::Write, context: &mut Context) -> Result<()> {
+        let mut out = IoWriter {
+            inner: writer,
+            error: None,
+        };
+        let result = self.render_fmt(&mut out, context);
+        match out.error {
+            Some(err) => Err(Error::Write(err)),
+            None => result,
+        }
+    }
+
+    /// Renders the whole template however many outputs and tags fail,
+    /// rendering each of those as nothing, and returns the output along
+    /// with every error in the order they happened, each with its
+    /// location. This is meant for linting templates, so the error mode
+    /// of the context is ignored, though an error handler still gets the
+    /// first say. Errors that stop any render, such as running out of
+    /// time, end the list and leave the output empty.
+    ///
+    /// ## Example
+    /// ```
+    /// use liquid::{Context, LiquidOptions};
+    ///
+    /// let template = liquid::parse("{{ 'a' | nope }}b\n{{ 1 | plus: 'x' }}c",
+    ///                              LiquidOptions::default()).unwrap();
+    /// let (output, errors) = template.render_collecting_errors(&mut Context::new());
+    /// assert_eq!(output, "b\nc");
+    /// let lines: Vec<_> = errors.iter().map(|e| e.line_column().unwrap().0).collect();
+    /// assert_eq!(lines, vec![1, 2]);
+    /// ```
+    pub fn render_collecting_errors(&self, context: &mut Context) -> (String, Vec<Error>) {
+        let mode = context.error_mode();
+        let earlier = context.take_render_errors();
+        context.set_error_mode(ErrorMode::Warn);
+        let result = self.render(context);
+        context.set_error_mode(mode);
+
+        let mut errors = context.take_render_errors();
+        for err in earlier {
+            context.keep_render_error(err);
+        }
+        match result {
+            Ok(output) => (output.unwrap_or_default(), errors),
+            Err(err) => {
+                errors.push(err);
+                (String::new(), errors)
+            }
+        }
+    }
+
+    /// Renders the template like `render`, first fetching what it needs
+    /// without blocking: the async globals it uses, set with
+    /// `Context::set_async_val`, and the fragments of its `cache` blocks
+    /// when the context has an async cache store. The fragments the render
+    /// produces afresh are stored once it has finished. Only available
+    /// with the `async` feature.
+    ///
+    /// What is needed is found by rendering the template on a fork of the
+    /// context, and again whenever what was fetched leads it somewhere
+    /// new. These trial renders change nothing in the context and use
+    /// random numbers of their own, though filters and the undefined
+    /// handler are called by each of them.
+    ///
+    /// ## Example
+    /// ```
+    /// use liquid::{Context, LiquidOptions, Value};
+    /// use std::future::{self, Future};
+    /// use std::task::{Context as Task, Poll, Waker};
+    ///
+    /// let template = liquid::parse("Hi {{ user }}", LiquidOptions::default()).unwrap();
+    /// let mut context = Context::new();
+    /// context.set_async_val("user", |_| Box::pin(future::ready(Value::str("Ann"))));
+    ///
+    /// // any executor will do; this future is ready straight away
+    /// let mut render = template.render_async(&mut context);
+    /// let mut task = Task::from_waker(Waker::noop());
+    /// let output = match std::pin::Pin::new(&mut render).poll(&mut task) {
+    ///     Poll::Ready(output) => output.unwrap(),
+    ///     Poll::Pending => unreachable!(),
+    /// };
+    /// assert_eq!(output, Some("Hi Ann".to_owned()));
+    /// ```
+    #[cfg(feature = "async")]
+    pub fn render_async<'a>(&'a self, context: &'a mut Context) -> RenderAsync<'a> {
+        RenderAsync::new(self, context)
+    }
+
+    /// Returns the contents of all the top-level `{% doc %}` blocks in this
+    /// template, in the order they appear.
+    pub fn docs(&self) -> Vec<&str> {
+        self.elements.iter().filter_map(|e| e.documentation()).collect()
+    }
+
+    pub fn new(elements: Vec<Node>) -> Template {
+        Template {
+            size_hint: estimate_size(&elements),
+            elements: elements,
+            warnings: vec![],
+            filters: None,
+            text: None,
+            name: None,
+        }
+    }
+
+    /// Parses a template with the standard tags and blocks, as
+    /// [liquid::parse](fn.parse.html) does with the default options. Use
+    /// a [Parser](struct.Parser.html) to add filters and tags of your own.
+    ///
+    /// ```
+    /// use liquid::{Context, Renderable, Template, Value};
+    ///
+    /// let template = Template::parse("Hi {{ name | upcase }}").unwrap();
+    /// let mut context = Context::new();
+    /// context.set_val("name", Value::str("ann"));
+    /// assert_eq!(template.render(&mut context).unwrap(), Some("Hi ANN".to_owned()));
+    /// ```
+    pub fn parse(text: &str) -> Result<Template> {
+        ::parse(text, LiquidOptions::default())
+    }
+
+    /// Joins several templates into one that renders each of them in turn.
+    /// See [embed](#method.embed).
+    ///
+    /// ```
+    /// use liquid::{LiquidOptions, Renderable, Context, Template};
+    ///
+    /// let header = liquid::parse("<h1>{{ title }}</h1>", LiquidOptions::default()).unwrap();
+    /// let body = liquid::parse("<p>{{ text }}</p>", LiquidOptions::default()).unwrap();
+    /// let page = Template::concat(vec![header, body]);
+    ///
+    /// let mut context = Context::new();
+    /// context.set_val("title", liquid::Value::str("Hi"));
+    /// context.set_val("text", liquid::Value::str("there"));
+    /// assert_eq!(page.render(&mut context).unwrap(),
+    ///            Some("<h1>Hi</h1><p>there</p>".to_owned()));
+    /// ```
+    pub fn concat(templates: Vec<Template>) -> Template {
+        let mut result = Template::new(vec![]);
+        for template in templates {
+            result.append(template);
+        }
+        result
+    }
+
+    /// A template made of the top-level nodes of this one in `range`. It
+    /// shares this template's filters and source, so it renders those nodes
+    /// just as this template would, and errors raised while rendering it
+    /// are located the same way. Warnings are left with this template.
+    ///
+    /// ```
+    /// use liquid::{Context, Renderable, Template};
+    ///
+    /// let template = Template::parse("a{{ 'b' }}c").unwrap();
+    /// let middle = template.slice(1..2);
+    /// assert_eq!(middle.render(&mut Context::new()).unwrap(), Some("b".to_owned()));
+    /// ```
+    pub fn slice(&self, range: Range<usize>) -> Template {
+        let mut slice = Template::new(self.elements[range].to_vec());
+        slice.filters = self.filters.clone();
+        slice.text = self.text.clone();
+        slice.name = self.name.clone();
+        slice
+    }
+
+    /// Splits the template into sections for
+    /// [render_parallel](#method.render_parallel), using
+    /// [slice](#method.slice). Each top-level `include` becomes a section
+    /// of its own, and so does each run of nodes between them. Rendering the
+    /// sections separately gives the same output as rendering the template,
+    /// as long as no section uses a variable that an earlier one assigns.
+    ///
+    /// ```
+    /// use liquid::{Context, ParserBuilder, Renderable};
+    ///
+    /// let parser = ParserBuilder::with_liquid()
+    ///     .include_source("header", "<h1>{{ title }}</h1>")
+    ///     .include_source("footer", "<p>bye</p>")
+    ///     .build();
+    /// let page = parser.parse("{% include 'header' %}\n{{ body }}\n{% include 'footer' %}")
+    ///                  .unwrap();
+    /// let sections = page.sections();
+    /// assert_eq!(sections.len(), 3);
+    ///
+    /// let mut context = Context::new();
+    /// let rendered: Vec<String> = sections.iter()
+    ///                                     .map(|s| s.render(&mut context).unwrap().unwrap())
+    ///                                     .collect();
+    /// assert_eq!(Some(rendered.concat()), page.render(&mut context).unwrap());
+    /// ```
+    pub fn sections(&self) -> Vec<Template> {
+        let mut sections = vec![];
+        let mut start = 0;
+        for (i, node) in self.elements.iter().enumerate() {
+            let include = match *node {
+                Node::Tag(ref tag) => {
+                    match *tag.node() {
+                        TagNode::Include(_) => true,
+                        _ => false,
+                    }
+                }
+                _ => false,
+            };
+            if include {
+                if start < i {
+                    sections.push(self.slice(start..i));
+                }
+                sections.push(self.slice(i..i + 1));
+                start = i + 1;
+            }
+        }
+        if start < self.elements.len() {
+            sections.push(self.slice(start..self.elements.len()));
+        }
+        sections
+    }
+
+    /// Renders several independent sections of a page at the same time,
+    /// such as those [sections](#method.sections) splits a page into, on a
+    /// pool of at most as many threads as the machine can run at once,
+    /// and joins their output in the order the sections are given. Each
+    /// section is rendered with its own context, made from a snapshot of
+    /// `context` taken with
+    /// [with_render_snapshot](struct.Context.html#method.with_render_snapshot),
+    /// so it renders just as it would with `context` itself, but nothing
+    /// one section assigns is seen by another. The errors kept in
+    /// `ErrorMode::Warn`, the warnings and the statistics of every section
+    /// are added to `context`, and the first section to fail, in order,
+    /// gives the error. Only available with the `parallel` feature.
+    ///
+    /// ```
+    /// use liquid::{Context, LiquidOptions, Template, Value};
+    ///
+    /// let header = liquid::parse("<h1>{{ title }}</h1>", LiquidOptions::default()).unwrap();
+    /// let body = liquid::parse("<p>{{ title | size }}</p>", LiquidOptions::default()).unwrap();
+    ///
+    /// let mut context = Context::new();
+    /// context.set_val("title", Value::str("Hi"));
+    /// assert_eq!(Template::render_parallel(&[&header, &body], &mut context).unwrap(),
+    ///            "<h1>Hi</h1><p>2</p>");
+    /// ```
+    #[cfg(feature = "parallel")]
+    pub fn render_parallel(sections: &[&Template], context: &mut Context) -> Result<String> {
+        let cores = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let workers = cmp::min(cores, sections.len());
+        let next = AtomicUsize::new(0);
+
+        let rendered: Vec<Section> = context.with_render_snapshot(|snapshot| {
+            let next = &next;
+            let mut rendered: Vec<Option<Section>> = sections.iter().map(|_| None).collect();
+            thread::scope(|scope| {
+                let threads: Vec<_> = (0..workers)
+                                          .map(|_| {
+                                              scope.spawn(move || {
+                                                  render_sections(sections, next, snapshot)
+                                              })
+                                          })
+                                          .collect();
+                for thread in threads {
+                    for (i, section) in thread.join().unwrap() {
+                        rendered[i] = Some(section);
+                    }
+                }
+            });
+            rendered.into_iter().map(|section| section.unwrap()).collect()
+        });
+
+        let mut page = String::new();
+        let mut failure = None;
+        for section in rendered {
+            for err in section.errors {
+                context.keep_render_error(err);
+            }
+            context.keep_render_warnings(section.warnings);
+            let stats = section.stats;
+            context.update_stats(|total| {
+                total.nodes += stats.nodes;
+                total.filters += stats.filters;
+                total.bytes += stats.bytes;
+                total.includes += stats.includes;
+                total.cache_hits += stats.cache_hits;
+            });
+            match section.output {
+                Ok(ref output) if failure.is_none() => page.push_str(output),
+                Ok(_) => {}
+                Err(err) => {
+                    if failure.is_none() {
+                        failure = Some(err);
+                    }
+                }
+            }
+        }
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(page),
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    fn render_section(&self, snapshot: &ContextSnapshot) -> Section {
+        let mut context = snapshot.context();
+        let mut buf = String::new();
+        let output = catch_panic(|| self.render_into(&mut buf, &mut context))
+                         .unwrap_or_else(|m| Err(Error::Panic(m)));
+        Section {
+            output: output.map(|()| buf),
+            errors: context.take_render_errors(),
+            warnings: context.take_render_warnings(),
+            stats: context.stats(),
+        }
+    }
+
+    /// Adds another template to the end of this one. See
+    /// [embed](#method.embed).
+    pub fn append(&mut self, template: Template) {
+        let index = self.elements.len();
+        self.embed(index, template);
+    }
+
+    /// Inserts another template as a single node before the element at
+    /// `index`, so that it is rendered in that position. The embedded
+    /// template keeps its own filters, and errors raised while rendering it
+    /// are located within its own source. Its warnings are added to this
+    /// template's.
+    ///
+    /// Templates that have others embedded in them can't be serialized.
+    pub fn embed(&mut self, index: usize, template: Template) {
+        self.warnings.extend(template.warnings.iter().cloned());
+        self.size_hint += template.size_hint;
+        self.elements.insert(index, Node::Custom(Arc::new(Embedded(template))));
+    }
+
+    /// Records the text a template was parsed from and its name, so that
+    /// errors raised while rendering it can give their line and column.
+    pub fn set_origin(&mut self, text: &str, name: Option<&str>) {
+        self.set_shared_origin(Arc::from(text), name);
+    }
+
+    /// Records the text and name of a template like
+    /// [set_origin](#method.set_origin), sharing text that is already held
+    /// elsewhere, such as by the template's own text nodes.
+    pub fn set_shared_origin(&mut self, text: Arc<str>, name: Option<&str>) {
+        self.text = Some(text);
+        self.name = name.map(|n| n.to_owned());
+    }
+
+    /// Checks the template for likely mistakes without rendering it: filters
+    /// and tags that aren't registered, built-in filters given the wrong
+    /// number of arguments, and `else` tags that can never be reached.
+    ///
+    /// Filters are looked for among the built-in ones, those registered
+    /// with the `Parser` that produced the template and, if given, those
+    /// of the `sample` context. When a sample is given, variables that are
+    /// neither in it nor set by the template are reported as well.
+    ///
+    /// Only templates parsed from text can be checked; for others, such as
+    /// those parsed by `Parser::parse_reader`, nothing is reported.
+    ///
+    /// ```
+    /// use liquid::{LiquidOptions, WarningKind};
+    ///
+    /// let template = liquid::parse("{{ 'a' | shout }}", LiquidOptions::default()).unwrap();
+    /// let warnings = template.validate(&LiquidOptions::with_known_blocks(), None);
+    /// assert_eq!(warnings[0].kind, WarningKind::UnknownFilter("shout".to_owned()));
+    /// assert_eq!(warnings[0].to_string(), "line 1, column 1: Unknown filter shout");
+    /// ```
+    pub fn validate(&self, options: &LiquidOptions, sample: Option<&Context>) -> Vec<Warning> {
+        match self.text {
+            Some(ref text) => {
+                validate::validate(text,
+                                   self.name.as_ref().map(|n| &n[..]),
+                                   self.filters.as_ref(),
+                                   options,
+                                   sample)
+            }
+            None => vec![],
+        }
     }
 }
 
+/// A template embedded in another one.
+struct Embedded(Template);
+
+impl Renderable for Embedded {
+    fn render(&self, context: &mut Context) -> Result<Option<String>> {
+        self.0.render(context).map_err(unlocated)
+    }
+
+    fn render_fmt(&self, out: &mut fmt::Write, context: &mut Context) -> Result<()> {
+        self.0.render_fmt(out, context).map_err(unlocated)
+    }
+}
+
+/// Drops the location of an error raised by an embedded template that it
+/// couldn't resolve itself, since the location refers to a source the
+/// outer template doesn't have, rather than resolving it against the wrong
+/// text.
+fn unlocated(e: Error) -> Error {
+    match e {
+        Error::Located(location, err) => {
+            if location.line == 0 {
+                *err
+            } else {
+                Error::Located(location, err)
+            }
+        }
+        err => err,
+    }
+}
+
+/// The version of the serialized template format. Templates serialized by
+/// a different version are rejected.
+#[cfg(feature = "serde")]
+const FORMAT_VERSION: u32 = 3;
+
+#[cfg(feature = "serde")]
 impl Template {
-    pub fn new(elements: Vec<Box<Renderable>>) -> Template {
-        Template { elements: elements }
+    /// Serializes a template returned by `parse`, so that it can be stored
+    /// and restored later with `from_bytes` without being parsed again.
+    /// What's stored is the parsed template itself, including the partials
+    /// it includes, along with its text and name so that errors are still
+    /// located in it. Its filters aren't stored.
+    ///
+    /// Only the built-in tags and blocks can be serialized: templates with
+    /// tags or blocks registered by the user, nodes returned by a
+    /// transform, or others embedded in them, give an error.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(&(FORMAT_VERSION, self))
+            .map_err(|e| Error::Other(format!("Could not serialize template: {}", e)))
+    }
+
+    /// Restores a template serialized with `to_bytes`. Nothing is parsed
+    /// and no partials are read. Use
+    /// [Parser::from_bytes](struct.Parser.html#method.from_bytes) to
+    /// restore a template along with the filters of a parser.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Template> {
+        let invalid = |e| Error::Other(format!("Could not deserialize template: {}", e));
+        // the version comes first, so it can be checked before the rest is read
+        let version: u32 = try!(bincode::deserialize(bytes).map_err(&invalid));
+        if version != FORMAT_VERSION {
+            return Err(Error::Other(format!("Unsupported template format version {}", version)));
+        }
+        let (_, template): (u32, Template) = try!(bincode::deserialize(bytes).map_err(&invalid));
+        Ok(template)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use Renderable;
+    use LiquidOptions;
+    use parse;
+    use context::Context;
+    use value::Value;
+    use super::Template;
+
+    #[test]
+    fn collecting_errors_leaves_the_context_as_it_was() {
+        use ErrorMode;
+
+        let template = parse("{{ 'a' | nope }}b{% for i in (1..3) %}{{ i | upcase }}{% endfor %}",
+                             LiquidOptions::default())
+                           .unwrap();
+        let mut context = Context::new();
+        context.keep_render_error(::error::Error::Render("earlier".to_owned()));
+        let (output, errors) = template.render_collecting_errors(&mut context);
+        assert_eq!(output, "b");
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].kind(), ::error::ErrorKind::UnknownFilter);
+        assert_eq!(context.render_errors().len(), 1);
+        assert_eq!(context.error_mode(), ErrorMode::Strict);
+    }
+
+    #[test]
+    fn templates_render_from_several_threads() {
+        use builder::ParserBuilder;
+        use std::sync::Arc;
+        use std::thread;
+
+        let parser = ParserBuilder::new()
+                         .filter("shout",
+                                 Box::new(|input: &Value, _: &[Value]| {
+                                     Ok(Value::Str(input.to_string().to_uppercase()))
+                                 }))
+                         .build();
+        let text = "{{ n | shout }}{% for i in (0..2) %}{{ i }}{% endfor %}";
+        let template = Arc::new(parser.parse(text).unwrap());
+        let threads: Vec<_> = (0..4)
+                                  .map(|n| {
+                                      let template = template.clone();
+                                      thread::spawn(move || {
+                                          let mut context = Context::new();
+                                          context.set_val("n", Value::str(&format!("t{}", n)));
+                                          template.render(&mut context).unwrap().unwrap()
+                                      })
+                                  })
+                                  .collect();
+        let outputs: Vec<String> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(outputs, vec!["T001", "T101", "T201", "T301"]);
+    }
+
+    #[test]
+    fn render_into_appends() {
+        let template = parse("{{ 'b' }}{% for i in (1..3) %}{{ i }}{% endfor %}",
+                             LiquidOptions::default())
+                           .unwrap();
+        let mut buf = "a".to_owned();
+        template.render_into(&mut buf, &mut Context::new()).unwrap();
+        assert_eq!(buf, "ab12");
+
+        let template = parse("c{{ 'x' | nope }}", LiquidOptions::default()).unwrap();
+        assert!(template.render_into(&mut buf, &mut Context::new()).is_err());
+        assert_eq!(buf, "ab12c");
+    }
+
+    #[test]
+    fn rendering_registers_no_filters() {
+        let template = parse("{{ 'a' | upcase }}{{ 1 | plus: 1 }}", LiquidOptions::default())
+                           .unwrap();
+        let mut context = Context::new();
+        let registered: Vec<String> = context.filters.keys().cloned().collect();
+        assert_eq!(template.render(&mut context).unwrap(), Some("A2".to_owned()));
+        assert_eq!(template.render(&mut context).unwrap(), Some("A2".to_owned()));
+        assert_eq!(context.filters.len(), registered.len());
+        assert!(registered.iter().all(|name| context.filters.contains_key(name)));
+    }
+
+    #[test]
+    fn parser_filters_last_only_for_the_render() {
+        use builder::ParserBuilder;
+        use error::ErrorKind;
+
+        let parser = ParserBuilder::new()
+                         .filter("shout",
+                                 Box::new(|input: &Value, _: &[Value]| {
+                                     Ok(Value::Str(input.to_string() + "!"))
+                                 }))
+                         .build();
+        let mut context = Context::new();
+        let with_filters = parser.parse("{{ 'a' | shout }}").unwrap();
+        assert_eq!(with_filters.render(&mut context).unwrap(), Some("a!".to_owned()));
+
+        let without = parse("{{ 'b' | shout }}", LiquidOptions::default()).unwrap();
+        assert_eq!(without.render(&mut context).unwrap_err().kind(),
+                   ErrorKind::UnknownFilter);
+
+        // but a template embedded in one from the parser can use them
+        let mut outer = parser.parse("{{ 'a' | shout }}").unwrap();
+        outer.append(without);
+        assert_eq!(outer.render(&mut context).unwrap(), Some("a!b!".to_owned()));
+    }
+
+    #[test]
+    fn sections_are_split_at_top_level_includes() {
+        use builder::ParserBuilder;
+
+        let parser = ParserBuilder::with_liquid()
+                         .include_source("a", "{% assign x = 1 %}{{ x }}")
+                         .build();
+        let page = parser.parse("{% include 'a' %}-{% if true %}{% include 'a' %}{% endif %}\
+                                 {% include 'a' %}")
+                         .unwrap();
+        let sections = page.sections();
+        assert_eq!(sections.iter().map(|s| s.elements.len()).collect::<Vec<_>>(),
+                   vec![1, 2, 1]);
+
+        let mut context = Context::new();
+        let rendered: Vec<String> = sections.iter()
+                                            .map(|s| s.render(&mut context).unwrap().unwrap())
+                                            .collect();
+        assert_eq!(rendered, vec!["1", "-1", "1"]);
+        assert_eq!(page.slice(1..1).render(&mut context).unwrap(), Some(String::new()));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_sections_keep_their_order_and_scope() {
+        let mut context = Context::new();
+        let sections: Vec<Template> = (0..8)
+                                          .map(|n| {
+                                              let text = format!("{{% assign x = {} %}}{{{{ x }}}}",
+                                                                 n);
+                                              parse(&text, LiquidOptions::default()).unwrap()
+                                          })
+                                          .collect();
+        let sections: Vec<&Template> = sections.iter().collect();
+        assert_eq!(Template::render_parallel(&sections, &mut context).unwrap(), "01234567");
+        assert_eq!(context.get_val("x"), None);
+
+        let failing = parse("{{ 1 | divided_by: 0 }}", LiquidOptions::default()).unwrap();
+        let sections = [sections[0], &failing, sections[1]];
+        assert!(Template::render_parallel(&sections, &mut context).is_err());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_sections_render_as_the_context_would() {
+        use ErrorMode;
+        use builder::ParserBuilder;
+
+        let parser = ParserBuilder::new()
+                         .filter("wrap",
+                                 Box::new(|input: &Value, _: &[Value]| {
+                                     Ok(Value::Str(format!("<{}>", input.to_string())))
+                                 }))
+                         .build();
+        let sections: Vec<Template> = ["{{ name | wrap }}", "{{ name | shout }}",
+                                       "{{ 1 | divided_by: 0 }}", "{{ missing }}"]
+                                          .iter()
+                                          .map(|text| parser.parse(text).unwrap())
+                                          .collect();
+        let sections: Vec<&Template> = sections.iter().collect();
+
+        let mut context = Context::new();
+        context.set_val("name", "a&b");
+        context.set_autoescape(true);
+        context.set_error_mode(ErrorMode::Warn);
+        context.add_filter("shout", Box::new(|input, _| Ok(Value::Str(input.to_string() + "!"))));
+
+        let mut expected = String::new();
+        for section in &sections {
+            expected.push_str(&section.render(&mut context).unwrap().unwrap());
+        }
+        let errors = context.take_render_errors().len();
+        let stats = context.stats();
+        context.reset();
+
+        assert_eq!(Template::render_parallel(&sections, &mut context).unwrap(), expected);
+        assert_eq!(expected, "&lt;a&amp;b&gt;a&amp;b!");
+        assert_eq!(context.render_errors().len(), errors);
+        assert_eq!(context.stats(), stats);
+        assert!(context.get_filter("shout").is_some());
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn pages_split_into_sections_render_in_parallel() {
+        use builder::ParserBuilder;
+
+        let parser = ParserBuilder::with_liquid()
+                         .include_source("item", "<li>{{ name }}</li>")
+                         .build();
+        let text = "<ul>\n{% include 'item' %}{% include 'item' %}\n</ul>{{ name | plus: 1 }}";
+        let page = parser.parse(text).unwrap();
+        let sections = page.sections();
+        assert_eq!(sections.len(), 4);
+        let sections: Vec<&Template> = sections.iter().collect();
+
+        let mut context = Context::new();
+        context.set_val("name", "a");
+        assert_eq!(Template::render_parallel(&sections[..3], &mut context).unwrap(),
+                   "<ul>\n<li>a</li><li>a</li>");
+        // errors are located within the page the sections came from
+        let err = Template::render_parallel(&sections, &mut context).unwrap_err();
+        assert_eq!(err.line_column().unwrap().0, 3);
+    }
+
+    #[test]
+    fn size_hints_count_text_and_guess_the_rest() {
+        let template = parse("abc{{ x }}{% if y %}long text{% endif %}", LiquidOptions::default())
+                           .unwrap();
+        assert_eq!(template.size_hint(), 3 + 2 * super::NODE_SIZE_GUESS);
+
+        let mut page = parse("<p>", LiquidOptions::default()).unwrap();
+        page.append(template);
+        assert_eq!(page.size_hint(), 3 + 3 + 2 * super::NODE_SIZE_GUESS);
+
+        let mut buf = String::new();
+        page.render_into(&mut buf, &mut Context::new()).unwrap();
+        assert!(buf.capacity() >= page.size_hint());
+    }
+
+    #[test]
+    fn loops_write_each_item_as_it_is_rendered() {
+        use ErrorMode;
+        use std::io::{self, Write};
+
+        struct Pieces(Vec<String>);
+
+        impl Write for Pieces {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(String::from_utf8_lossy(buf).into_owned());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let text = "<{% for i in (0..3) %}{% if i > 0 %},{% endif %}{{ i }}{% endfor %}>";
+        let template = parse(text, LiquidOptions::default()).unwrap();
+        let mut pieces = Pieces(vec![]);
+        template.render_to(&mut pieces, &mut Context::new()).unwrap();
+        assert_eq!(pieces.0, vec!["<", "0", ",", "1", ",", "2", ">"]);
+
+        // a node whose output might be replaced is rendered whole
+        let mut pieces = Pieces(vec![]);
+        let mut context = Context::new();
+        context.set_error_mode(ErrorMode::Lax);
+        template.render_to(&mut pieces, &mut context).unwrap();
+        assert_eq!(pieces.0, vec!["<", "0,1,2", ">"]);
+    }
+
+    #[test]
+    fn render_stats_count_loops_includes_and_cache_hits() {
+        use cache::MemoryCacheStore;
+        use stats::RenderStats;
+
+        let mut options = LiquidOptions::default();
+        options.templates.insert("item".to_owned(), "[{{ i }}]".to_owned());
+        let text = "{% for i in (0..2) %}{% include 'item' %}{% endfor %}\
+                    {% cache 'k' %}{{ 'x' | upcase }}{% endcache %}";
+        let template = parse(text, options).unwrap();
+        let mut context = Context::new();
+        context.set_cache_store(Box::new(MemoryCacheStore::new()));
+
+        template.render(&mut context).unwrap();
+        template.render(&mut context).unwrap();
+        // per render: the loop, two includes each with an output, and the
+        // cache block, whose output is only rendered the first time
+        assert_eq!(context.stats(),
+                   RenderStats {
+                       nodes: 2 * 6 + 1,
+                       filters: 1,
+                       bytes: 2 * "[0][1]X".len(),
+                       includes: 4,
+                       cache_hits: 1,
+                   });
+
+        context.reset();
+        assert_eq!(context.stats(), RenderStats::default());
+    }
+
+    #[test]
+    fn plain_text_renders_as_itself() {
+        let template = parse("no {markup} here", LiquidOptions::default()).unwrap();
+        assert_eq!(template.plain_text(), Some("no {markup} here"));
+        let mut buf = "> ".to_owned();
+        template.render_into(&mut buf, &mut Context::new()).unwrap();
+        assert_eq!(buf, "> no {markup} here");
+
+        let template = parse("", LiquidOptions::default()).unwrap();
+        assert_eq!(template.render(&mut Context::new()).unwrap(), Some("".to_owned()));
+
+        let template = parse("{% raw %}a{% endraw %}", LiquidOptions::default()).unwrap();
+        assert_eq!(template.plain_text(), None);
+    }
+
+    #[test]
+    fn write_errors_are_kept_apart_from_template_errors() {
+        use std::io::{self, Write};
+
+        struct Full;
+
+        impl Write for Full {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let template = parse("{{ 'a' }}", LiquidOptions::default()).unwrap();
+        match template.render_to(&mut Full, &mut Context::new()) {
+            Err(::error::Error::Write(ref err)) => assert_eq!(err.to_string(), "disk full"),
+            other => panic!("{:?}", other),
+        }
+
+        // output is written as it is rendered
+        let template = parse("a{{ 'a' | nope }}", LiquidOptions::default()).unwrap();
+        let mut out = vec![];
+        assert!(template.render_to(&mut out, &mut Context::new()).is_err());
+        assert_eq!(out, b"a");
+    }
+
+    #[test]
+    fn concatenates_templates() {
+        let mut context = Context::new();
+        context.set_val("x", Value::str("x"));
+
+        let first = parse("a{{ x }}", LiquidOptions::default()).unwrap();
+        let second = parse("{% if true %}b{% endif %}", LiquidOptions::default()).unwrap();
+        let mut template = Template::concat(vec![first, second]);
+        assert_eq!(template.render(&mut context).unwrap(), Some("axb".to_owned()));
+
+        // each template is a single element of the result
+        template.embed(1, parse("-", LiquidOptions::default()).unwrap());
+        assert_eq!(template.render(&mut context).unwrap(), Some("ax-b".to_owned()));
+    }
+
+    #[test]
+    fn embedded_templates_locate_their_own_errors() {
+        let options = LiquidOptions { template_name: Some("inner".to_owned()), ..Default::default() };
+        let inner = parse("\n{{ 'a' | plus: 1 }}", options).unwrap();
+        let mut outer = parse("outer\nline {{ 'b' }}", LiquidOptions::default()).unwrap();
+        outer.append(inner);
+
+        let err = outer.render(&mut Context::new()).err().unwrap();
+        assert!(err.to_string().starts_with("inner:2:1: "), "{}", err);
+
+        // a template without its source can't locate errors, and they
+        // mustn't be located within the wrong one either
+        let inner = Template::new(vec![]);
+        let mut outer = parse("{{ 'a' }}", LiquidOptions::default()).unwrap();
+        outer.append(inner);
+        assert!(outer.render(&mut Context::new()).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn round_trips_through_bytes() {
+        let text = "{% for x in list %}{{ x | upcase }}{% if x == 'b' %}!{% endif %}{% endfor %}";
+        let bytes = parse(text, LiquidOptions::default()).unwrap().to_bytes().unwrap();
+        let template = Template::from_bytes(&bytes).unwrap();
+
+        let mut context = Context::new();
+        context.set_val("list", Value::Array(vec![Value::str("a"), Value::str("b")]));
+        assert_eq!(template.render(&mut context).unwrap(), Some("AB!".to_owned()));
+
+        // and the restored template can itself be serialized again
+        assert_eq!(template.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn restored_templates_keep_their_origin() {
+        let mut options = LiquidOptions::default();
+        options.template_name = Some("page.liquid".to_owned());
+        let text = "a\n{{ 1 | plus: 'x' }}";
+        let bytes = parse(text, options).unwrap().to_bytes().unwrap();
+        let template = Template::from_bytes(&bytes).unwrap();
+
+        let err = template.render(&mut Context::new()).unwrap_err().without_snippet();
+        assert!(err.to_string().starts_with("page.liquid:2:1:"), "{}", err);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn restored_templates_keep_their_partials() {
+        let mut options = LiquidOptions::default();
+        options.templates.insert("greeting".to_owned(), "hello {{ name }}".to_owned());
+        let template = parse("{% include 'greeting' %}!", options).unwrap();
+        let bytes = template.to_bytes().unwrap();
+
+        // the partial comes with the template, rather than being read again
+        let template = Template::from_bytes(&bytes).unwrap();
+        let mut context = Context::new();
+        context.set_val("name", Value::str("world"));
+        assert_eq!(template.render(&mut context).unwrap(), Some("hello world!".to_owned()));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn rejects_garbage() {
+        assert!(Template::from_bytes(b"not a template").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn custom_nodes_are_not_serialized() {
+        let mut template = parse("a", LiquidOptions::default()).unwrap();
+        template.append(parse("b", LiquidOptions::default()).unwrap());
+        assert!(template.to_bytes().is_err());
+
+        let template = parse("{% if true %}{{ 'a' }}{% endif %}", LiquidOptions::default())
+                           .unwrap();
+        let mut nested = template.clone();
+        nested.append(template);
+        let err = nested.to_bytes().unwrap_err();
+        assert!(err.to_string().contains("Node::Custom"), "{}", err);
     }
 }
@@ -1,19 +1,103 @@
 use Renderable;
 use context::Context;
 use error::Result;
+use template;
 
+use std::fmt;
+use std::sync::Arc;
+#[cfg(feature = "serde")]
+use std::result;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+/// Plain text from a template. Text taken from the source of the template
+/// it is in shares that source, rather than keeping a copy of its own.
+#[derive(Clone)]
 pub struct Text {
-    text: String,
+    source: Arc<str>,
+    start: usize,
+    end: usize,
 }
 
 impl Renderable for Text {
     fn render(&self, _context: &mut Context) -> Result<Option<String>> {
-        Ok(Some(self.text.to_owned()))
+        Ok(Some(self.as_str().to_owned()))
+    }
+
+    fn render_fmt(&self, out: &mut fmt::Write, _context: &mut Context) -> Result<()> {
+        template::write(out, self.as_str())
+    }
+}
+
+impl fmt::Debug for Text {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+// text is stored as just its own text, so restored text nodes each have
+// their own copy rather than sharing the source of their template
+#[cfg(feature = "serde")]
+impl Serialize for Text {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Text {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Text, D::Error> {
+        String::deserialize(deserializer).map(|text| Text::new(&text))
     }
 }
 
 impl Text {
     pub fn new(text: &str) -> Text {
-        Text { text: text.to_owned() }
+        Text {
+            source: Arc::from(text),
+            start: 0,
+            end: text.len(),
+        }
+    }
+
+    /// Makes a text node for `text`, sharing `source` if `text` is a slice
+    /// of it, and copying `text` otherwise.
+    pub fn in_source(source: &Arc<str>, text: &str) -> Text {
+        let start = (text.as_ptr() as usize).wrapping_sub(source.as_ptr() as usize);
+        if text.is_empty() || start > source.len() || source.len() - start < text.len() {
+            return Text::new(text);
+        }
+        Text {
+            source: source.clone(),
+            start: start,
+            end: start + text.len(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source[self.start..self.end]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Text;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn text_from_the_source_shares_it() {
+        let source: Arc<str> = Arc::from("Hello, world");
+        let text = Text::in_source(&source, &source[7..]);
+        assert_eq!(text.as_str(), "world");
+        assert_eq!(Arc::strong_count(&source), 2);
+    }
+
+    #[test]
+    fn other_text_is_copied() {
+        let source: Arc<str> = Arc::from("Hello, world");
+        let text = Text::in_source(&source, "world");
+        assert_eq!(text.as_str(), "world");
+        assert_eq!(Arc::strong_count(&source), 1);
     }
 }
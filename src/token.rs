@@ -1,8 +1,10 @@
 use self::Token::*;
 use self::ComparisonOperator::*;
+use std::borrow::Cow;
 use std::fmt;
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ComparisonOperator {
     Equals,
     NotEquals,
@@ -14,7 +16,8 @@ pub enum ComparisonOperator {
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub enum Token {
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Token<'a> {
     Pipe,
     Dot,
     Colon,
@@ -27,15 +30,49 @@ pub enum Token {
     Dash,
     Assignment,
 
-    Identifier(String),
-    StringLiteral(String),
+    Identifier(Cow<'a, str>),
+    StringLiteral(Cow<'a, str>),
     NumberLiteral(f32),
     BooleanLiteral(bool),
+    NilLiteral,
+    EmptyLiteral,
+    BlankLiteral,
+    Range(Box<Token<'a>>, Box<Token<'a>>),
     DotDot,
     Comparison(ComparisonOperator),
 }
 
-impl fmt::Display for Token {
+impl<'a> Token<'a> {
+    /// Converts a token that may borrow from the template source into one
+    /// that owns its text, so that it can be kept after parsing.
+    pub fn into_owned(self) -> Token<'static> {
+        match self {
+            Pipe => Pipe,
+            Dot => Dot,
+            Colon => Colon,
+            Comma => Comma,
+            OpenSquare => OpenSquare,
+            CloseSquare => CloseSquare,
+            OpenRound => OpenRound,
+            CloseRound => CloseRound,
+            Question => Question,
+            Dash => Dash,
+            Assignment => Assignment,
+            Identifier(x) => Identifier(Cow::Owned(x.into_owned())),
+            StringLiteral(x) => StringLiteral(Cow::Owned(x.into_owned())),
+            NumberLiteral(x) => NumberLiteral(x),
+            BooleanLiteral(x) => BooleanLiteral(x),
+            NilLiteral => NilLiteral,
+            EmptyLiteral => EmptyLiteral,
+            BlankLiteral => BlankLiteral,
+            Range(start, stop) => Range(Box::new(start.into_owned()), Box::new(stop.into_owned())),
+            DotDot => DotDot,
+            Comparison(x) => Comparison(x),
+        }
+    }
+}
+
+impl<'a> fmt::Display for Token<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let out = match *self {
             Pipe => "|".to_owned(),
@@ -58,9 +95,13 @@ impl fmt::Display for Token {
             Comparison(LessThan) => "<".to_owned(),
             Comparison(GreaterThan) => ">".to_owned(),
             Comparison(Contains) => "contains".to_owned(),
-            Identifier(ref x) | StringLiteral(ref x) => x.clone(),
+            Identifier(ref x) | StringLiteral(ref x) => x.to_string(),
             NumberLiteral(ref x) => x.to_string(),
-            BooleanLiteral(ref x) => x.to_string()
+            BooleanLiteral(ref x) => x.to_string(),
+            NilLiteral => "nil".to_owned(),
+            EmptyLiteral => "empty".to_owned(),
+            BlankLiteral => "blank".to_owned(),
+            Range(ref start, ref stop) => format!("({}..{})", start, stop),
         };
         write!(f, "{}", out)
     }
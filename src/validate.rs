@@ -0,0 +1,359 @@
+use LiquidOptions;
+use ParseState;
+use context::Context;
+use error::Location;
+use filters::{self, Filter};
+use lexer::{self, Span};
+use token::Token::{self, Identifier, Pipe, Colon, Comma, BooleanLiteral};
+use visitor::{Visitor, walk};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+/// Variables that are set by the blocks themselves while rendering.
+const IMPLICIT_VARIABLES: &'static [&'static str] = &["for_loop",
+                                                      "block",
+                                                      "content_for_layout",
+                                                      "now",
+                                                      "today"];
+
+/// The tags that divide up the bodies of the built-in blocks.
+const BLOCK_DELIMITERS: &'static [&'static str] = &["else", "elsif", "when"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WarningKind {
+    /// A filter that isn't built in, and wasn't registered with the parser
+    /// or the sample context.
+    UnknownFilter(String),
+    /// A tag that no tag or block has been registered for.
+    UnknownTag(String),
+    /// A built-in filter given a different number of arguments than it
+    /// takes.
+    Arity {
+        filter: String,
+        expected: usize,
+        found: usize,
+    },
+    /// An `else` or `elsif` that can never be reached, either because an
+    /// `else` has already been seen or because the condition is always
+    /// true.
+    UnreachableElse,
+    /// A variable that is neither in the sample data nor set by the
+    /// template itself, or, while rendering, that wasn't defined.
+    UndefinedVariable(String),
+    /// A filter marked as deprecated with `Context::deprecate_filter` was
+    /// used while rendering.
+    DeprecatedFilter(String),
+}
+
+/// A problem found by [Template::validate](struct.Template.html#method.validate),
+/// or by a render that still succeeded. See
+/// [Context::render_warnings](struct.Context.html#method.render_warnings).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub location: Location,
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WarningKind::UnknownFilter(ref name) => write!(f, "Unknown filter {}", name),
+            WarningKind::UnknownTag(ref name) => write!(f, "Unknown tag {}", name),
+            WarningKind::Arity { ref filter, expected, found } => {
+                try!(write!(f,
+                            "Filter {} takes {} arguments, but was given {}",
+                            filter,
+                            expected,
+                            found));
+                match filters::signature(filter) {
+                    Some(signature) => write!(f, " (usage: {})", signature),
+                    None => Ok(()),
+                }
+            }
+            WarningKind::UnreachableElse => write!(f, "Unreachable else"),
+            WarningKind::UndefinedVariable(ref name) => write!(f, "Undefined variable {}", name),
+            WarningKind::DeprecatedFilter(ref name) => write!(f, "Deprecated filter {}", name),
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+/// What's known about a block while walking its contents.
+struct OpenBlock {
+    name: String,
+    always_true: bool,
+    seen_else: bool,
+}
+
+struct Validator<'a> {
+    options: &'a LiquidOptions,
+    filters: Option<&'a HashMap<String, Box<Filter>>>,
+    sample: Option<&'a Context>,
+    blocks: Vec<OpenBlock>,
+    defined: HashSet<String>,
+    used: Vec<(String, Span)>,
+    found: Vec<(WarningKind, Span)>,
+}
+
+impl<'a> Validator<'a> {
+    fn knows_filter(&self, name: &str) -> bool {
+        filters::arity(name).is_some() ||
+        self.filters.map_or(false, |f| f.contains_key(name)) ||
+        self.sample.map_or(false, |c| c.get_filter(name).is_some())
+    }
+
+    fn check_filters(&mut self, tokens: &[Token], span: Span) {
+        let mut i = 0;
+        while i < tokens.len() {
+            let name: &str = match (&tokens[i], tokens.get(i + 1)) {
+                (&Pipe, Some(&Identifier(ref name))) => name,
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            i += 2;
+
+            // count the arguments up to the next filter, treating all of
+            // the `key: value` ones as a single argument
+            let mut positional = 0;
+            let mut named = false;
+            if tokens.get(i) == Some(&Colon) {
+                i += 1;
+                while i < tokens.len() && tokens[i] != Pipe {
+                    match tokens[i] {
+                        Comma => {}
+                        Identifier(_) if tokens.get(i + 1) == Some(&Colon) => {
+                            named = true;
+                            i += 1;
+                        }
+                        _ => positional += 1,
+                    }
+                    i += 1;
+                }
+            }
+            let found = positional + if named { 1 } else { 0 };
+
+            if !self.knows_filter(name) {
+                self.found.push((WarningKind::UnknownFilter(name.to_owned()), span));
+            } else if self.filters.map_or(true, |f| !f.contains_key(name)) {
+                match filters::arity(name) {
+                    Some(expected) if expected != found => {
+                        self.found.push((WarningKind::Arity {
+                                             filter: name.to_owned(),
+                                             expected: expected,
+                                             found: found,
+                                         },
+                                         span))
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// The variable an output refers to, ignoring any properties or indices.
+fn root_variable(name: &str) -> &str {
+    match name.find(|c| c == '.' || c == '[') {
+        Some(i) => &name[..i],
+        None => name,
+    }
+}
+
+impl<'a> Visitor for Validator<'a> {
+    fn visit_output(&mut self, tokens: &[Token], span: Span) {
+        match tokens[0] {
+            Identifier(ref name) if !self.options.tags.contains_key(&name[..]) => {
+                self.used.push((root_variable(name).to_owned(), span));
+            }
+            _ => {}
+        }
+        self.check_filters(tokens, span);
+    }
+
+    fn visit_tag(&mut self, name: &str, arguments: &[Token], span: Span) {
+        if name == "assign" {
+            if let Some(&Identifier(ref var)) = arguments.first() {
+                self.defined.insert(var.to_string());
+            }
+        }
+
+        if name == "else" || name == "elsif" {
+            if let Some(block) = self.blocks.last_mut() {
+                if block.seen_else || block.always_true {
+                    self.found.push((WarningKind::UnreachableElse, span));
+                }
+                block.seen_else = block.seen_else || name == "else";
+            }
+            return;
+        }
+
+        if !self.options.tags.contains_key(name) && !BLOCK_DELIMITERS.contains(&name) {
+            self.found.push((WarningKind::UnknownTag(name.to_owned()), span));
+        }
+    }
+
+    fn visit_block(&mut self, name: &str, arguments: &[Token], _span: Span) -> bool {
+        if name == "capture" || name == "for" {
+            if let Some(&Identifier(ref var)) = arguments.first() {
+                self.defined.insert(var.to_string());
+            }
+        }
+
+        let always_true = (name == "if" && arguments == &[BooleanLiteral(true)][..]) ||
+                          (name == "unless" && arguments == &[BooleanLiteral(false)][..]);
+        self.blocks.push(OpenBlock {
+            name: name.to_owned(),
+            always_true: always_true,
+            seen_else: false,
+        });
+
+        // the contents of these aren't markup at all
+        name != "raw" && name != "comment" && name != "doc"
+    }
+
+    fn leave_block(&mut self, name: &str) {
+        if self.blocks.last().map_or(false, |b| b.name == name) {
+            self.blocks.pop();
+        }
+    }
+}
+
+/// Checks the source of a template, returning any warnings found. See
+/// `Template::validate`.
+pub fn validate(text: &str,
+                name: Option<&str>,
+                filters: Option<&Arc<HashMap<String, Box<Filter>>>>,
+                options: &LiquidOptions,
+                sample: Option<&Context>)
+                -> Vec<Warning> {
+    let elements = match lexer::tokenize_with_options(text, &ParseState::new(options)) {
+        Ok(elements) => elements,
+        // the template has already been parsed, so this shouldn't happen
+        // unless the options differ, and then there is nothing to check
+        Err(_) => return vec![],
+    };
+
+    let mut validator = Validator {
+        options: options,
+        filters: filters.map(|f| &**f),
+        sample: sample,
+        blocks: vec![],
+        defined: HashSet::new(),
+        used: vec![],
+        found: vec![],
+    };
+    walk(&elements, options, &mut validator);
+
+    let mut found = validator.found;
+    if let Some(sample) = sample {
+        for (var, span) in validator.used {
+            if sample.get_val(&var).is_none() && !validator.defined.contains(&var) &&
+               !IMPLICIT_VARIABLES.contains(&&var[..]) {
+                found.push((WarningKind::UndefinedVariable(var), span));
+            }
+        }
+    }
+    found.sort_by_key(|&(_, span)| span.start);
+
+    found.into_iter()
+         .map(|(kind, span)| {
+             let mut location = Location {
+                 template: name.map(|n| n.to_owned()),
+                 span: span,
+                 ..Default::default()
+             };
+             location.resolve(text);
+             Warning {
+                 kind: kind,
+                 location: location,
+             }
+         })
+         .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::WarningKind::{self, UnknownFilter, UnknownTag, Arity, UnreachableElse,
+                             UndefinedVariable};
+    use LiquidOptions;
+    use UnknownTagMode;
+    use parse;
+    use context::Context;
+    use value::Value;
+
+    fn kinds(text: &str, sample: Option<&Context>) -> Vec<WarningKind> {
+        let options = LiquidOptions { unknown_tags: UnknownTagMode::Drop, ..Default::default() };
+        let template = parse(text, options).unwrap();
+        template.validate(&LiquidOptions::with_known_blocks(), sample)
+                .into_iter()
+                .map(|w| w.kind)
+                .collect()
+    }
+
+    #[test]
+    fn finds_unknown_filters_and_tags() {
+        assert_eq!(kinds("{{ x | upcase | shout }}{% frob %}", None),
+                   vec![UnknownFilter("shout".to_owned()), UnknownTag("frob".to_owned())]);
+
+        let mut sample = Context::new();
+        sample.add_filter("shout", Box::new(|_, _| Ok(Value::str(""))));
+        sample.set_val("x", Value::str(""));
+        assert_eq!(kinds("{{ x | upcase | shout }}", Some(&sample)), vec![]);
+    }
+
+    #[test]
+    fn finds_suspicious_arity() {
+        assert_eq!(kinds("{{ 1 | plus }}{{ 1 | plus: 2 }}{{ 'a' | replace: 'a' }}", None),
+                   vec![Arity {
+                            filter: "plus".to_owned(),
+                            expected: 1,
+                            found: 0,
+                        },
+                        Arity {
+                            filter: "replace".to_owned(),
+                            expected: 2,
+                            found: 1,
+                        }]);
+    }
+
+    #[test]
+    fn finds_unreachable_else() {
+        assert_eq!(kinds("{% if true %}a{% else %}b{% endif %}", None),
+                   vec![UnreachableElse]);
+        assert_eq!(kinds("{% if x %}a{% else %}b{% elsif y %}c{% endif %}", None),
+                   vec![UnreachableElse]);
+        assert_eq!(kinds("{% if x %}{% if true %}{% endif %}{% else %}b{% endif %}", None),
+                   vec![]);
+    }
+
+    #[test]
+    fn finds_undefined_variables() {
+        let text = concat!("{% assign a = 1 %}",
+                           "{% for i in (1..2) %}{{ i }}{{ for_loop.index }}{% endfor %}",
+                           "{{ a }}{{ page.title }}{{ missing }}{{ missing.x }}");
+        let mut sample = Context::new();
+        sample.set_val("page", Value::Object(Default::default()));
+        assert_eq!(kinds(text, Some(&sample)),
+                   vec![UndefinedVariable("missing".to_owned()),
+                        UndefinedVariable("missing".to_owned())]);
+
+        // without sample data, variables aren't checked at all
+        assert_eq!(kinds(text, None), vec![]);
+    }
+
+    #[test]
+    fn warnings_have_locations() {
+        let template = parse("a\n  {{ b | nope }}", LiquidOptions::default()).unwrap();
+        let warnings = template.validate(&LiquidOptions::with_known_blocks(), None);
+        assert_eq!(warnings[0].to_string(), "line 2, column 3: Unknown filter nope");
+    }
+}
@@ -1,15 +1,28 @@
 use Renderable;
 use context::Context;
+use hash::ObjectHasher;
 use std::collections::HashMap;
 use std::cmp::Ordering;
+use std::hash::BuildHasher;
 use error::Result;
 
+/// The map of an object: the fields of a `Value::Object` and the globals
+/// of a `Context`. Build one with `Object::default()` or by collecting an
+/// iterator, as `HashMap::new()` only makes maps with the standard hasher.
+pub type Object = HashMap<String, Value, ObjectHasher>;
+
 /// An enum to represent different value types
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Value {
     Num(f32),
     Str(String),
-    Object(HashMap<String, Value>),
+    /// Text that is safe to include in the output as it is, so it isn't
+    /// escaped when the context escapes outputs. Made by the `raw` filter,
+    /// `capture` and [SafeStr](struct.SafeStr.html). Otherwise it behaves
+    /// like a `Str`.
+    Safe(String),
+    Object(Object),
     Array(Vec<Value>),
     Bool(bool)
 }
@@ -18,13 +31,69 @@ impl Value {
     pub fn str(val: &str) -> Value {
         Value::Str(val.to_owned())
     }
+
+    /// Makes a string that isn't escaped when it is output. See
+    /// [SafeStr](struct.SafeStr.html).
+    pub fn safe(val: &str) -> Value {
+        Value::Safe(val.to_owned())
+    }
+
+    /// The text of a `Str` or `Safe` value.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Value::Str(ref x) | Value::Safe(ref x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// Whether the value is output as it is, even when the context escapes
+    /// outputs.
+    pub fn is_safe(&self) -> bool {
+        match *self {
+            Value::Safe(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A string that is known to be safe to include in the output, such as
+/// markup from a trusted source, so that it isn't escaped when the context
+/// escapes outputs.
+///
+/// ## Example
+/// ```
+/// use liquid::{Context, LiquidOptions, Renderable, SafeStr};
+///
+/// let template = liquid::parse("{{ a }} {{ b }}", LiquidOptions::default()).unwrap();
+/// let mut ctx = Context::new();
+/// ctx.set_autoescape(true);
+/// ctx.set_val("a", "<b>");
+/// ctx.set_val("b", SafeStr::new("<b>"));
+/// assert_eq!(template.render(&mut ctx).unwrap(), Some("&lt;b&gt; <b>".to_owned()));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct SafeStr(pub String);
+
+impl SafeStr {
+    pub fn new(val: &str) -> SafeStr {
+        SafeStr(val.to_owned())
+    }
+}
+
+impl From<SafeStr> for Value {
+    fn from(val: SafeStr) -> Value {
+        Value::Safe(val.0)
+    }
 }
 
 impl PartialEq<Value> for Value {
     fn eq(&self, other: &Value) -> bool {
         match (self, other) {
             (&Value::Num(x), &Value::Num(y)) => x == y,
-            (&Value::Str(ref x), &Value::Str(ref y)) => x == y,
+            (&Value::Str(ref x), &Value::Str(ref y)) |
+            (&Value::Str(ref x), &Value::Safe(ref y)) |
+            (&Value::Safe(ref x), &Value::Str(ref y)) |
+            (&Value::Safe(ref x), &Value::Safe(ref y)) => x == y,
             (&Value::Bool(x), &Value::Bool(y)) => x == y,
             (&Value::Object(ref x), &Value::Object(ref y)) => x == y,
             (&Value::Array(ref x), &Value::Array(ref y)) => x == y,
@@ -46,7 +115,10 @@ impl PartialOrd<Value> for Value {
     fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
         match (self, other) {
             (&Value::Num(x), &Value::Num(y)) => x.partial_cmp(&y),
-            (&Value::Str(ref x), &Value::Str(ref y)) => x.partial_cmp(y),
+            (&Value::Str(ref x), &Value::Str(ref y)) |
+            (&Value::Str(ref x), &Value::Safe(ref y)) |
+            (&Value::Safe(ref x), &Value::Str(ref y)) |
+            (&Value::Safe(ref x), &Value::Safe(ref y)) => x.partial_cmp(y),
             (&Value::Bool(x), &Value::Bool(y)) => x.partial_cmp(&y),
             _ => None,
         }
@@ -54,7 +126,10 @@ impl PartialOrd<Value> for Value {
     fn lt(&self, other: &Value) -> bool {
         match (self, other) {
             (&Value::Num(x), &Value::Num(y)) => x.lt(&y),
-            (&Value::Str(ref x), &Value::Str(ref y)) => x.lt(y),
+            (&Value::Str(ref x), &Value::Str(ref y)) |
+            (&Value::Str(ref x), &Value::Safe(ref y)) |
+            (&Value::Safe(ref x), &Value::Str(ref y)) |
+            (&Value::Safe(ref x), &Value::Safe(ref y)) => x.lt(y),
             (&Value::Bool(x), &Value::Bool(y)) => x.lt(&y),
             _ => false,
         }
@@ -62,7 +137,10 @@ impl PartialOrd<Value> for Value {
     fn le(&self, other: &Value) -> bool {
         match (self, other) {
             (&Value::Num(x), &Value::Num(y)) => x.le(&y),
-            (&Value::Str(ref x), &Value::Str(ref y)) => x.le(y),
+            (&Value::Str(ref x), &Value::Str(ref y)) |
+            (&Value::Str(ref x), &Value::Safe(ref y)) |
+            (&Value::Safe(ref x), &Value::Str(ref y)) |
+            (&Value::Safe(ref x), &Value::Safe(ref y)) => x.le(y),
             (&Value::Bool(x), &Value::Bool(y)) => x.le(&y),
             _ => false,
         }
@@ -70,7 +148,10 @@ impl PartialOrd<Value> for Value {
     fn gt(&self, other: &Value) -> bool {
         match (self, other) {
             (&Value::Num(x), &Value::Num(y)) => x.gt(&y),
-            (&Value::Str(ref x), &Value::Str(ref y)) => x.gt(y),
+            (&Value::Str(ref x), &Value::Str(ref y)) |
+            (&Value::Str(ref x), &Value::Safe(ref y)) |
+            (&Value::Safe(ref x), &Value::Str(ref y)) |
+            (&Value::Safe(ref x), &Value::Safe(ref y)) => x.gt(y),
             (&Value::Bool(x), &Value::Bool(y)) => x.gt(&y),
             _ => false,
         }
@@ -78,7 +159,10 @@ impl PartialOrd<Value> for Value {
     fn ge(&self, other: &Value) -> bool {
         match (self, other) {
             (&Value::Num(x), &Value::Num(y)) => x.ge(&y),
-            (&Value::Str(ref x), &Value::Str(ref y)) => x.ge(y),
+            (&Value::Str(ref x), &Value::Str(ref y)) |
+            (&Value::Str(ref x), &Value::Safe(ref y)) |
+            (&Value::Safe(ref x), &Value::Str(ref y)) |
+            (&Value::Safe(ref x), &Value::Safe(ref y)) => x.ge(y),
             (&Value::Bool(x), &Value::Bool(y)) => x.ge(&y),
             _ => false,
         }
@@ -90,7 +174,7 @@ impl ToString for Value {
         match *self {
             Value::Bool(ref x) => x.to_string(),
             Value::Num(ref x) => x.to_string(),
-            Value::Str(ref x) => x.to_owned(),
+            Value::Str(ref x) | Value::Safe(ref x) => x.to_owned(),
             Value::Array(ref x) => {
                 let arr: Vec<String> = x.iter().map(|v| v.to_string()).collect();
                 arr.join(", ")
@@ -103,6 +187,72 @@ impl ToString for Value {
     }
 }
 
+impl From<f32> for Value {
+    fn from(val: f32) -> Value {
+        Value::Num(val)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(val: f64) -> Value {
+        Value::Num(val as f32)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(val: i32) -> Value {
+        Value::Num(val as f32)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(val: i64) -> Value {
+        Value::Num(val as f32)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(val: u32) -> Value {
+        Value::Num(val as f32)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(val: usize) -> Value {
+        Value::Num(val as f32)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(val: bool) -> Value {
+        Value::Bool(val)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(val: &'a str) -> Value {
+        Value::str(val)
+    }
+}
+
+impl From<String> for Value {
+    fn from(val: String) -> Value {
+        Value::Str(val)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(val: Vec<T>) -> Value {
+        Value::Array(val.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>, S: BuildHasher> From<HashMap<String, T, S>> for Value {
+    fn from(val: HashMap<String, T, S>) -> Value {
+        Value::Object(val.into_iter().map(|(k, v)| (k, v.into())).collect())
+    }
+}
+
 impl Renderable for Value {
     fn render(&self, _context: &mut Context) -> Result<Option<String>> {
         Ok(Some(self.to_string()))
@@ -138,6 +288,21 @@ mod test {
         assert_eq!(&val.to_string(), "3, test, 5.3");
     }
 
+    #[test]
+    fn conversions() {
+        assert_eq!(Value::from(3), Value::Num(3f32));
+        assert_eq!(Value::from(2.5f64), Value::Num(2.5f32));
+        assert_eq!(Value::from("a"), Value::str("a"));
+        assert_eq!(Value::from(vec![1, 2]), Value::Array(vec![Value::Num(1f32), Value::Num(2f32)]));
+
+        let mut map = HashMap::new();
+        map.insert("on".to_owned(), true);
+        match Value::from(map) {
+            Value::Object(ref x) => assert_eq!(x.get("on"), Some(&Value::Bool(true))),
+            x => panic!("not an object: {:?}", x),
+        }
+    }
+
     // TODO make a test for object, remember values are in arbitrary orders in HashMaps
 
     #[test]
@@ -162,6 +327,15 @@ mod test {
         assert!(Value::str("beta") != Value::str("alpha"));
     }
 
+    #[test]
+    fn safe_strings_compare_as_strings() {
+        assert_eq!(Value::safe("a"), Value::str("a"));
+        assert_eq!(Value::str("a"), Value::safe("a"));
+        assert!(Value::safe("a") < Value::str("b"));
+        assert_eq!(Value::safe("<b>").to_string(), "<b>");
+        assert_eq!(Value::from(SafeStr::new("a")), Value::Safe("a".to_owned()));
+    }
+
     #[test]
     fn strings_have_ruby_truthiness() {
         // all strings in ruby are true
@@ -185,7 +359,7 @@ mod test {
 
     #[test]
     fn object_equality() {
-        let mut values = HashMap::<String, Value>::new();
+        let mut values = Object::default();
         values.insert("alpha".to_owned(), Value::str("1"));
         values.insert("beta".to_owned(), Value::Num(2f32));
 
@@ -201,7 +375,7 @@ mod test {
 
     #[test]
     fn objects_have_ruby_truthiness() {
-        assert_eq!(TRUE, Value::Object(HashMap::new()));
+        assert_eq!(TRUE, Value::Object(Object::default()));
     }
 
 
@@ -224,7 +398,7 @@ mod test {
     fn mixed_comparisons_are_false() {
         // assers that all comparisons between different types of values
         // are false
-        let mut values = HashMap::<String, Value>::new();
+        let mut values = Object::default();
         values.insert("alpha".to_owned(), Value::str("1"));
 
         let terms = vec!(Value::Num(1f32),
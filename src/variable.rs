@@ -1,15 +1,18 @@
 use Renderable;
 use context::Context;
 use error::Result;
+use context::VariablePath;
+use symbol::Symbol;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Variable {
-    name: String,
+    path: VariablePath,
 }
 
 impl Renderable for Variable {
     fn render(&self, context: &mut Context) -> Result<Option<String>> {
-        let res = match context.get_val(&self.name) {
+        let res = match try!(context.lookup_path(&self.path)) {
             Some(val) => Some(val.to_string()),
             None => None,
         };
@@ -20,9 +23,24 @@ impl Renderable for Variable {
 
 impl Variable {
     pub fn new(name: &str) -> Variable {
-        Variable { name: name.to_owned() }
+        Variable::from_symbol(Symbol::from(name))
     }
+
+    pub fn from_symbol(name: Symbol) -> Variable {
+        Variable { path: VariablePath::from_symbol(name) }
+    }
+
     pub fn name(&self) -> String {
-        self.name.clone()
+        self.path.name().to_owned()
+    }
+
+    /// The name, without copying it.
+    pub fn as_str(&self) -> &str {
+        self.path.name()
+    }
+
+    /// The path of the variable, split into its parts while parsing.
+    pub fn path(&self) -> &VariablePath {
+        &self.path
     }
 }
@@ -0,0 +1,159 @@
+use LiquidOptions;
+use token::Token::{self, Identifier};
+use lexer::Element::{self, Expression, Tag, Raw};
+use lexer::Span;
+
+use std::slice::Iter;
+
+/// Receives the pieces of a template as it is walked by
+/// [walk](fn.walk.html) or [visit](../fn.visit.html). Every method does
+/// nothing by default, so a visitor only needs to implement the ones it
+/// is interested in.
+///
+/// Tags such as `else` and `when` that divide up the body of a block are
+/// passed to `visit_tag` in the order they appear within the block.
+pub trait Visitor {
+    /// Called for the plain text between markup.
+    fn visit_text(&mut self, _text: &str) {}
+
+    /// Called for each `{{ }}` output, with all of its tokens.
+    fn visit_output(&mut self, _tokens: &[Token], _span: Span) {}
+
+    /// Called for each `{% %}` tag that isn't a block, including tags
+    /// that no tag has been registered for.
+    fn visit_tag(&mut self, _name: &str, _arguments: &[Token], _span: Span) {}
+
+    /// Called when a block is entered. The contents of the block are only
+    /// walked if this returns `true`.
+    fn visit_block(&mut self, _name: &str, _arguments: &[Token], _span: Span) -> bool {
+        true
+    }
+
+    /// Called once everything inside a block has been walked.
+    fn leave_block(&mut self, _name: &str) {}
+}
+
+/// Walks a list of lexed elements, using the blocks registered in
+/// `options` to work out how the elements are nested.
+pub fn walk<V: Visitor>(elements: &[Element], options: &LiquidOptions, visitor: &mut V) {
+    let mut iter = elements.iter();
+    while let Some(element) = iter.next() {
+        match *element {
+            Expression(ref tokens, _, span) => visitor.visit_output(tokens, span),
+            Tag(ref tokens, _, span) => walk_tag(&mut iter, tokens, span, options, visitor),
+            Raw(ref text) => visitor.visit_text(text),
+        }
+    }
+}
+
+fn walk_tag<V: Visitor>(iter: &mut Iter<Element>,
+                        tokens: &[Token],
+                        span: Span,
+                        options: &LiquidOptions,
+                        visitor: &mut V) {
+    let name: &str = match tokens[0] {
+        Identifier(ref x) => x,
+        _ => return visitor.visit_tag(&tokens[0].to_string(), &tokens[1..], span),
+    };
+
+    if options.tags.contains_key(name) || !options.blocks.contains_key(name) {
+        return visitor.visit_tag(name, &tokens[1..], span);
+    }
+
+    // gather the body of the block the same way the parser does, so that
+    // nested blocks of the same kind don't end it early
+    let tag = Identifier(name.into());
+    let end_tag = Identifier(format!("end{}", name).into());
+    let mut children = vec![];
+    let mut nesting_depth = 0;
+    for t in iter {
+        if let Tag(ref tokens, _, _) = *t {
+            if tokens[0] == tag {
+                nesting_depth += 1;
+            } else if tokens[0] == end_tag {
+                if nesting_depth == 0 {
+                    break;
+                }
+                nesting_depth -= 1;
+            }
+        }
+        children.push(t.clone());
+    }
+
+    if visitor.visit_block(name, &tokens[1..], span) {
+        walk(&children, options, visitor);
+    }
+    visitor.leave_block(name);
+}
+
+#[cfg(test)]
+mod test {
+    use super::Visitor;
+    use visit;
+    use LiquidOptions;
+    use lexer::Span;
+    use token::Token;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl Visitor for Recorder {
+        fn visit_text(&mut self, text: &str) {
+            self.events.push(format!("text {:?}", text));
+        }
+
+        fn visit_output(&mut self, tokens: &[Token], _span: Span) {
+            self.events.push(format!("output {}", tokens[0]));
+        }
+
+        fn visit_tag(&mut self, name: &str, _arguments: &[Token], _span: Span) {
+            self.events.push(format!("tag {}", name));
+        }
+
+        fn visit_block(&mut self, name: &str, _arguments: &[Token], _span: Span) -> bool {
+            self.events.push(format!("block {}", name));
+            name != "comment"
+        }
+
+        fn leave_block(&mut self, name: &str) {
+            self.events.push(format!("end {}", name));
+        }
+    }
+
+    #[test]
+    fn walks_nested_blocks() {
+        let text = concat!("a{{ x }}",
+                           "{% if x %}{% if y %}{{ y }}{% endif %}{% else %}b{% endif %}",
+                           "{% assign z = 1 %}");
+        let mut recorder = Recorder::default();
+        visit(text, LiquidOptions::default(), &mut recorder).unwrap();
+        assert_eq!(recorder.events,
+                   vec!["text \"a\"",
+                        "output x",
+                        "block if",
+                        "block if",
+                        "output y",
+                        "end if",
+                        "tag else",
+                        "text \"b\"",
+                        "end if",
+                        "tag assign"]);
+    }
+
+    #[test]
+    fn skips_blocks_on_request() {
+        let text = "{% comment %}{{ hidden }}{% endcomment %}{% unknown %}";
+        let mut recorder = Recorder::default();
+        visit(text, LiquidOptions::default(), &mut recorder).unwrap();
+        assert_eq!(recorder.events,
+                   vec!["block comment", "end comment", "tag unknown"]);
+    }
+
+    #[test]
+    fn lexer_errors_are_reported() {
+        let mut recorder = Recorder::default();
+        assert!(visit("{{ 1.2.3 }}", LiquidOptions::default(), &mut recorder).is_err());
+    }
+}
@@ -1,7 +1,9 @@
 extern crate liquid;
 
 use liquid::LiquidOptions;
+use liquid::ParseState;
 use liquid::Token;
+use liquid::Template;
 use liquid::Renderable;
 use liquid::Context;
 use liquid::Value;
@@ -24,7 +26,7 @@ fn run() {
 
     fn multiply_tag(_tag_name: &str,
                     arguments: &[Token],
-                    _options: &LiquidOptions)
+                    _state: &ParseState)
                     -> Result<Box<Renderable>, Error> {
 
         let numbers = arguments.iter()
@@ -43,6 +45,7 @@ fn run() {
         tags: Default::default(),
         file_system: Default::default(),
         error_mode: Default::default(),
+        ..Default::default()
     };
     options.register_tag("multiply", Box::new(multiply_tag));
 
@@ -57,3 +60,170 @@ fn run() {
     assert_eq!(output.unwrap(),
                Some("wat\nworld\n15{{multiply 5 3}} test".to_string()));
 }
+
+#[test]
+fn custom_tag_with_arguments_and_context() {
+    struct Greet {
+        name: Token<'static>,
+    }
+
+    impl Renderable for Greet {
+        fn render(&self, context: &mut Context) -> Result<Option<String>, Error> {
+            let name = try!(context.evaluate(&self.name)).unwrap_or(Value::str("nobody"));
+            Ok(Some(format!("Hello, {}!", name.to_string())))
+        }
+    }
+
+    let mut options = LiquidOptions::default();
+    options.register_tag("greet", Box::new(|_tag_name, arguments, _options| {
+        match arguments.first() {
+            Some(t) => Ok(Box::new(Greet { name: t.clone().into_owned() })),
+            None => Err(Error::Parser("greet requires an argument".to_owned())),
+        }
+    }));
+
+    let template = parse("{% greet user %} {% greet 'world' %}", options).unwrap();
+
+    let mut data = Context::new();
+    data.set_val("user", Value::str("Alice"));
+    assert_eq!(template.render(&mut data).unwrap(),
+               Some("Hello, Alice! Hello, world!".to_owned()));
+}
+
+#[test]
+fn custom_tags_override_builtin_tags() {
+    struct Nothing;
+
+    impl Renderable for Nothing {
+        fn render(&self, _context: &mut Context) -> Result<Option<String>, Error> {
+            Ok(Some("overridden".to_owned()))
+        }
+    }
+
+    let mut options = LiquidOptions::default();
+    options.register_tag("cycle", Box::new(|_tag_name, _arguments, _options| {
+        Ok(Box::new(Nothing))
+    }));
+
+    let template = parse("{% cycle 'a', 'b' %}", options).unwrap();
+    let mut data = Context::new();
+    assert_eq!(template.render(&mut data).unwrap(),
+               Some("overridden".to_owned()));
+}
+
+#[test]
+fn custom_parsed_block() {
+    struct Repeat {
+        count: usize,
+        body: Template,
+    }
+
+    impl Renderable for Repeat {
+        fn render(&self, context: &mut Context) -> Result<Option<String>, Error> {
+            let mut buf = String::new();
+            for _ in 0..self.count {
+                buf = buf + &try!(self.body.render(context)).unwrap_or(String::new());
+            }
+            Ok(Some(buf))
+        }
+    }
+
+    let mut options = LiquidOptions::default();
+    options.register_parsed_block("repeat", Box::new(|_block_name, arguments, body, _options| {
+        match arguments.first() {
+            Some(&Token::NumberLiteral(n)) => {
+                Ok(Box::new(Repeat {
+                    count: n as usize,
+                    body: body,
+                }))
+            }
+            _ => Err(Error::Parser("repeat requires a count".to_owned())),
+        }
+    }));
+
+    let template = parse("{% repeat 3 %}{{ x }},{% endrepeat %}", options).unwrap();
+
+    let mut data = Context::new();
+    data.set_val("x", Value::Num(7f32));
+    assert_eq!(template.render(&mut data).unwrap(), Some("7,7,7,".to_owned()));
+}
+
+#[test]
+fn custom_block_with_its_own_scope() {
+    struct With {
+        name: String,
+        value: Token<'static>,
+        body: Template,
+    }
+
+    impl Renderable for With {
+        fn render(&self, context: &mut Context) -> Result<Option<String>, Error> {
+            let value = try!(context.evaluate(&self.value)).unwrap_or(Value::str(""));
+            context.run_in_scope(|scope| {
+                scope.set_local_val(&self.name, value);
+                self.body.render(scope)
+            })
+        }
+    }
+
+    let mut options = LiquidOptions::default();
+    options.register_parsed_block("with", Box::new(|_block_name, arguments, body, _options| {
+        match (arguments.get(0), arguments.get(1)) {
+            (Some(&Token::Identifier(ref name)), Some(value)) => {
+                Ok(Box::new(With {
+                    name: name.to_string(),
+                    value: value.clone().into_owned(),
+                    body: body,
+                }))
+            }
+            _ => Err(Error::Parser("with requires a name and a value".to_owned())),
+        }
+    }));
+
+    let text = concat!("{{ x }}",
+                       "{% with x 'outer' %}{{ x }}",
+                       "{% with x 'inner' %}{{ x }}{% assign y = x %}{% endwith %}",
+                       "{{ x }}{% endwith %}",
+                       "{{ x }}{{ y }}");
+    let template = parse(text, options).unwrap();
+
+    let mut data = Context::new();
+    data.set_val("x", Value::str("global"));
+    assert_eq!(template.render(&mut data).unwrap(),
+               Some("globalouterinnerouterglobalinner".to_owned()));
+}
+
+#[test]
+fn custom_block_keeping_state_in_registers() {
+    struct IfChanged {
+        body: Template,
+    }
+
+    impl Renderable for IfChanged {
+        fn render(&self, context: &mut Context) -> Result<Option<String>, Error> {
+            let output = try!(self.body.render(context)).unwrap_or(String::new());
+            if context.get_register::<String>("ifchanged", "last") == Some(&output) {
+                return Ok(None);
+            }
+            context.set_register("ifchanged", "last", output.clone());
+            Ok(Some(output))
+        }
+    }
+
+    let mut options = LiquidOptions::default();
+    options.register_parsed_block("ifchanged", Box::new(|_block_name, _arguments, body, _options| {
+        Ok(Box::new(IfChanged { body: body }))
+    }));
+
+    let template = parse("{% for x in list %}{% ifchanged %}{{ x }}{% endifchanged %}{% endfor %}",
+                         options)
+                       .unwrap();
+
+    let mut data = Context::new();
+    data.set_val("list",
+                 Value::Array(vec![Value::str("a"), Value::str("a"), Value::str("b"),
+                                   Value::str("a")]));
+    assert_eq!(template.render(&mut data).unwrap(), Some("aba".to_owned()));
+    // registers aren't variables
+    assert_eq!(data.get_val("last"), None);
+}
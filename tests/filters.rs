@@ -72,3 +72,44 @@ pub fn replace() {
     let output = template.render(&mut data);
     assert_eq!(output.unwrap(), Some("foo2foo".to_string()));
 }
+
+#[test]
+pub fn replace_arity_error() {
+    let text = "{{ text | replace: 'bar' }}";
+    let options : LiquidOptions = Default::default();
+    let template = parse(&text, options).unwrap();
+
+    let mut data = Context::new();
+    data.set_val("text", Value::Str("bar".to_string()));
+
+    let output = template.render(&mut data);
+    assert_eq!(output.unwrap_err().inner().to_string(),
+               "Filtering error in filter #1 (replace): Invalid number of arguments : \
+                expected 2, 1 given (usage: replace: <search>, <replacement>)");
+}
+
+#[test]
+pub fn quoted_arguments() {
+    let text = r#"{{ text | replace: 'it\'s here', "\"gone\" \\" }}"#;
+    let options : LiquidOptions = Default::default();
+    let template = parse(&text, options).unwrap();
+
+    let mut data = Context::new();
+    data.set_val("text", Value::Str("it's here!".to_string()));
+
+    let output = template.render(&mut data);
+    assert_eq!(output.unwrap(), Some("\"gone\" \\!".to_string()));
+}
+
+#[test]
+pub fn signed_and_decimal_arguments() {
+    let text = "{{ num | plus: -1 }} {{ num | times: 0.5 }} {{ num | times: .25 }}";
+    let options : LiquidOptions = Default::default();
+    let template = parse(&text, options).unwrap();
+
+    let mut data = Context::new();
+    data.set_val("num", Value::Num(4f32));
+
+    let output = template.render(&mut data);
+    assert_eq!(output.unwrap(), Some("3 2 1".to_string()));
+}
@@ -7,12 +7,15 @@ use std::io::Read;
 use liquid::*;
 
 fn compare(name: &str, context: &mut Context) {
+    compare_with(name, Default::default(), context)
+}
+
+fn compare_with(name: &str, options: LiquidOptions, context: &mut Context) {
     let input_file = format!("tests/fixtures/input/{}.txt", name);
     let output_file = format!("tests/fixtures/output/{}.txt", name);
     let mut input = String::new();
     File::open(Path::new(&input_file)).unwrap().read_to_string(&mut input).unwrap();
 
-    let options: LiquidOptions = Default::default();
     let template = parse(&input, options).unwrap();
 
     let output = template.render(context).unwrap();
@@ -44,3 +47,15 @@ pub fn include_with_context() {
 
     compare("include_with_context", &mut context);
 }
+
+/// The expected output of this fixture is what Ruby Liquid gives for it.
+#[test]
+pub fn ruby_whitespace() {
+    let mut context = Context::new();
+    context.set_val("show", Value::Bool(true));
+    context.set_val("name", Value::str("N"));
+    context.set_val("items", Value::Array(vec![Value::str("a"), Value::str("b")]));
+
+    let options = LiquidOptions { whitespace: WhitespaceMode::Ruby, ..Default::default() };
+    compare_with("ruby_whitespace", options, &mut context);
+}